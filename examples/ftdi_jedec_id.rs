@@ -0,0 +1,23 @@
+//! Reads the JEDEC ID of a chip behind an FT232H, with CS on ADBUS3.
+//!
+//! Run with `cargo run --example ftdi_jedec_id --no-default-features --features ftdi`.
+
+use spi_flash::{FtdiTransmitter, SPIFlash};
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let device = ftdi::find_by_vid_pid(0x0403, 0x6014)
+        .interface(ftdi::Interface::A)
+        .open()?;
+    let mut flash = SPIFlash::new(FtdiTransmitter::new(device, 3, 1_000_000)?);
+
+    let id = flash.read_jedec_id();
+    println!(
+        "manufacturer {:#04x}, memory type {:#04x}, capacity {:#04x}",
+        id.manufacturer, id.memory_type, id.capacity
+    );
+    match flash.identify() {
+        Ok(info) => println!("{} KiB", info.capacity / 1024),
+        Err(error) => println!("could not identify the chip: {}", error),
+    }
+    Ok(())
+}
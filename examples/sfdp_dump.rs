@@ -0,0 +1,146 @@
+//! Dumps the SFDP parameter headers and the decoded basic flash parameter table of the DK's flash over the UART,
+//! e.g. to bring up an unknown part. Connect to the DK's virtual COM port with 115200 baud.
+
+#![no_main]
+#![no_std]
+
+use core::fmt::Write;
+
+use cortex_m_rt::entry;
+
+#[allow(unused_imports)]
+use panic_semihosting;
+
+use nrf52840_hal::{
+    spim::{
+        Spim,
+    },
+    gpio::{
+        Pin,
+        Output,
+        PushPull,
+    },
+    uarte::{
+        Uarte,
+    },
+    nrf52840_pac::{
+        self as nrf52,
+    },
+};
+use nrf52840_dk_bsp::{
+    hal::{
+        prelude::*,
+    },
+    nRF52840DK,
+};
+
+use spi_flash::{
+    self,
+    ReadMode,
+    Transmitter
+};
+
+struct SPITransmitter {
+    spi: Spim<nrf52::SPIM2>,
+    cs: Pin<Output<PushPull>>,
+}
+
+impl SPITransmitter {
+    pub fn new(spi: Spim<nrf52::SPIM2>, cs: Pin<Output<PushPull>>) -> SPITransmitter {
+        SPITransmitter { spi, cs }
+    }
+}
+
+impl Transmitter for SPITransmitter {
+    fn send(&mut self, buffer: &[u8]) {
+        self.spi.write(&mut self.cs, buffer);
+    }
+
+    fn read(&mut self, buffer: &mut [u8]) {
+        self.spi.read(&mut self.cs, &[], buffer);
+    }
+
+    fn send_read(&mut self, buffer_tx: &[u8], buffer_rx: &mut [u8]) {
+        self.spi.read(&mut self.cs, buffer_tx, buffer_rx);
+    }
+}
+
+/// Lets `write!` print to the UART.
+struct Com<'a>(&'a mut Uarte<nrf52::UARTE0>);
+
+impl<'a> Write for Com<'a> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        // EasyDMA can only read from RAM, so string literals in flash are copied to the stack first.
+        let mut buffer = [0; 64];
+        for chunk in s.as_bytes().chunks(buffer.len()) {
+            buffer[..chunk.len()].copy_from_slice(chunk);
+            self.0.write(&buffer[..chunk.len()]).map_err(|_| core::fmt::Error)?;
+        }
+        Ok(())
+    }
+}
+
+#[entry]
+fn main() -> ! {
+    let mut nrf52 = nRF52840DK::take().unwrap();
+
+    let mut com = Com(&mut nrf52.com);
+
+    let mut flash = spi_flash::SPIFlash::new(SPITransmitter::new(nrf52.flash, nrf52.flash_cs));
+
+    let _ = dump(&mut flash, &mut com);
+
+    loop {}
+}
+
+fn dump<T: Transmitter>(flash: &mut spi_flash::SPIFlash<T>, com: &mut Com) -> core::fmt::Result {
+    let id = flash.read_jedec_id();
+    writeln!(com, "JEDEC ID: {:02X} {:02X} {:02X}\r", id.manufacturer, id.memory_type, id.capacity)?;
+
+    // The SFDP header, followed by one 8 byte header per parameter table.
+    let mut header = [0; 8];
+    if let Err(e) = flash.read_sfdp(0, &mut header) {
+        writeln!(com, "SFDP read failed: {:?}\r", e)?;
+        return Ok(());
+    }
+    let headers = if &header[..4] == b"SFDP" {
+        writeln!(com, "SFDP revision {}.{}\r", header[5], header[4])?;
+        header[6] as u32 + 1
+    } else {
+        // Without SFDP, `identify()` falls back to the chip database.
+        writeln!(com, "no SFDP signature, got {:02X?}\r", &header[..4])?;
+        0
+    };
+    for i in 0..headers {
+        // This can only fail like the read of the SFDP header above.
+        let _ = flash.read_sfdp((i + 1) * 8, &mut header);
+        let id = (header[7] as u16) << 8 | header[0] as u16;
+        let pointer = header[4] as u32 | (header[5] as u32) << 8 | (header[6] as u32) << 16;
+        writeln!(
+            com,
+            "  table {:04X} revision {}.{}, {} DWORDs at {:#08X}\r",
+            id, header[2], header[1], header[3], pointer
+        )?;
+    }
+
+    match flash.identify() {
+        Ok(info) => {
+            writeln!(com, "density: {} KiB\r", info.capacity / 1024)?;
+            writeln!(com, "page size: {} bytes\r", info.page_size)?;
+            for &(size, opcode) in info.erase_ops.iter().filter(|(size, _)| *size > 0) {
+                writeln!(com, "erase: {} KiB with {:02X}\r", size / 1024, opcode)?;
+            }
+            writeln!(com, "4-byte addresses: {:?}, now using {:?}\r", info.four_byte_mode, flash.address_width())?;
+            writeln!(
+                com,
+                "dummy cycles: fast read {}, dual I/O {}, quad I/O {}\r",
+                flash.dummy_cycles(ReadMode::Fast),
+                flash.dummy_cycles(ReadMode::DualIo),
+                flash.dummy_cycles(ReadMode::QuadIo)
+            )?;
+            writeln!(com, "max program time: {} us, sector erase: {} us\r", info.program_time_us, info.erase_time_us)?;
+        }
+        Err(e) => writeln!(com, "identification failed: {}\r", e)?,
+    }
+    Ok(())
+}
@@ -0,0 +1,97 @@
+#![no_main]
+#![no_std]
+
+use core::cell::RefCell;
+
+use cortex_m_rt::entry;
+use nb::block;
+
+#[allow(unused_imports)]
+use panic_semihosting;
+
+use nrf52840_hal::{
+    spim::{
+        Spim,
+    },
+    gpio::{
+        Level,
+        Pin,
+        Output,
+        PushPull,
+    },
+    nrf52840_pac::{
+        self as nrf52,
+    },
+};
+use nrf52840_dk_bsp::{
+    hal::{
+        prelude::*,
+        timer::Timer,
+    },
+    nRF52840DK,
+};
+
+use spi_flash::{
+    self,
+    Transmitter
+};
+
+/// A transmitter for one of several chips on a shared bus.
+/// Each chip gets its own instance with its own CS pin.
+struct SharedTransmitter<'a> {
+    spi: &'a RefCell<Spim<nrf52::SPIM2>>,
+    cs: Pin<Output<PushPull>>,
+}
+
+impl<'a> SharedTransmitter<'a> {
+    pub fn new(spi: &'a RefCell<Spim<nrf52::SPIM2>>, cs: Pin<Output<PushPull>>) -> SharedTransmitter<'a> {
+        SharedTransmitter { spi, cs }
+    }
+}
+
+impl<'a> Transmitter for SharedTransmitter<'a> {
+    fn send(&mut self, buffer: &[u8]) {
+        self.spi.borrow_mut().write(&mut self.cs, buffer);
+    }
+
+    fn read(&mut self, buffer: &mut [u8]) {
+        self.spi.borrow_mut().read(&mut self.cs, &[], buffer);
+    }
+
+    fn send_read(&mut self, buffer_tx: &[u8], buffer_rx: &mut [u8]) {
+        self.spi.borrow_mut().read(&mut self.cs, buffer_tx, buffer_rx);
+    }
+}
+
+#[entry]
+fn main() -> ! {
+    let mut nrf52 = nRF52840DK::take().unwrap();
+
+    let mut timer = nrf52.TIMER0.constrain();
+
+    let bus = RefCell::new(nrf52.flash);
+    // The second chip is wired to the same SPI lines with its own CS.
+    let cs_2 = nrf52.pins.P0_26.into_push_pull_output(Level::High).degrade();
+
+    let mut flash_1 = spi_flash::SPIFlash::new(SharedTransmitter::new(&bus, nrf52.flash_cs));
+    let mut flash_2 = spi_flash::SPIFlash::new(SharedTransmitter::new(&bus, cs_2));
+
+    // Each driver only ever talks to its own chip, so the state they track stays separate.
+    let byte = flash_1.read_byte(0).unwrap();
+    flash_2.write_byte(0, byte).unwrap();
+
+    loop {
+        nrf52.leds.led_2.enable();
+        delay(&mut timer, 1_000_000); // 250ms
+        nrf52.leds.led_2.disable();
+        delay(&mut timer, 1_000_000); // 1s
+    }
+}
+
+fn delay<T>(timer: &mut Timer<T>, cycles: u32)
+where
+    T: TimerExt,
+{
+    timer.start(cycles);
+    let _ = block!(timer.wait());
+}
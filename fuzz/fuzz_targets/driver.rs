@@ -0,0 +1,64 @@
+//! Runs arbitrary sequences of driver calls against the `RamFlash` mock.
+//! Neither the driver nor the mock may panic, whatever the calls and their arguments.
+//!
+//! Run with `cargo fuzz run driver`.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use spi_flash::{mock::RamFlash, AddressWidth, SPIFlash};
+
+/// Size of the emulated chip. Small enough for chip erases to stay fast, large enough for several sectors.
+const CAPACITY: usize = 64 * 1024;
+
+/// Takes a big endian `u32` from the front of `data`, padding missing bytes with zeros.
+fn take_u32(data: &mut &[u8]) -> u32 {
+    let mut bytes = [0; 4];
+    let n = data.len().min(4);
+    bytes[..n].copy_from_slice(&data[..n]);
+    *data = &data[n..];
+    u32::from_be_bytes(bytes)
+}
+
+fuzz_target!(|data: &[u8]| {
+    let mut flash = SPIFlash::new(RamFlash::new(CAPACITY));
+    let mut data = data;
+    let mut buffer = [0; 512];
+    while let Some((&op, rest)) = data.split_first() {
+        data = rest;
+        // Addresses may point past the end of the chip, the driver has to reject them.
+        let address = take_u32(&mut data) % (2 * CAPACITY as u32);
+        let len = take_u32(&mut data) as usize % buffer.len();
+        let _ = match op % 14 {
+            0 => flash.identify().map(drop),
+            1 => flash.read_bytes(address, &mut buffer[..len]),
+            2 => {
+                let n = len.min(data.len());
+                let (payload, rest) = data.split_at(n);
+                data = rest;
+                flash.write_bytes(address, payload)
+            }
+            3 => flash.erase_4k_block(address),
+            4 => flash.erase_range(address, len as u32).map(drop),
+            5 => flash.erase(address, len as u32 * 4096),
+            6 => flash.chip_erase(),
+            7 => flash.set_quad_enable(address & 1 > 0),
+            8 => flash.read_bytes_dual_io(address, &mut buffer[..len], len as u8),
+            9 => flash.read_bytes_quad_io(address, &mut buffer[..len], len as u8),
+            10 => flash.enter_qpi(),
+            11 => {
+                flash.exit_qpi();
+                Ok(())
+            }
+            12 => flash.set_address_width(if address & 1 > 0 {
+                AddressWidth::FourByte
+            } else {
+                AddressWidth::ThreeByte
+            }),
+            _ => {
+                flash.read_status();
+                Ok(())
+            }
+        };
+    }
+});
@@ -0,0 +1,131 @@
+//! An opt-in read cache for repeated small reads.
+
+use crate::{Error, SPIFlash, Transmitter};
+
+/// Caches the most recently read sector of a `SPIFlash` in a caller-provided buffer.
+///
+/// The cache line has the size of the buffer, which should be a power of two no larger than a sector,
+/// e.g. a 4 KiB buffer caching a whole 4K sector.
+/// Reads within the cached line are served without any bus traffic.
+/// Writes and erases have to go through the cache, so it can invalidate the line if they touch it.
+pub struct SectorCache<'a, T>
+where
+    T: Transmitter
+{
+    flash: SPIFlash<T>,
+    buffer: &'a mut [u8],
+    /// Base address of the cached line.
+    line: Option<u32>,
+}
+
+impl<'a, T> SectorCache<'a, T>
+where
+    T: Transmitter,
+{
+    /// Wraps `flash` and uses `buffer` to cache one line.
+    pub fn new(flash: SPIFlash<T>, buffer: &'a mut [u8]) -> Self {
+        Self {
+            flash,
+            buffer,
+            line: None,
+        }
+    }
+
+    /// Releases the wrapped `SPIFlash`.
+    pub fn release(self) -> SPIFlash<T> {
+        self.flash
+    }
+
+    /// Drops the cached line, so the next read goes to the chip.
+    pub fn invalidate(&mut self) {
+        self.line = None;
+    }
+
+    /// Invalidates the cached line if it overlaps `len` bytes starting at `address`.
+    fn invalidate_range(&mut self, address: u32, len: u32) {
+        if let Some(line) = self.line {
+            let line_end = line + self.buffer.len() as u32;
+            if address < line_end && line < address.saturating_add(len) {
+                self.line = None;
+            }
+        }
+    }
+
+    /// Reads a single byte at `address`, from the cache if possible.
+    pub fn read_byte(&mut self, address: u32) -> Result<u8, Error> {
+        let mut byte = [0; 1];
+        self.read_bytes(address, &mut byte)?;
+        Ok(byte[0])
+    }
+
+    /// Reads `buffer.len()` bytes at `address`, from the cache if possible.
+    /// Every line the read touches is loaded into the cache in turn, leaving the last one cached.
+    pub fn read_bytes(&mut self, mut address: u32, buffer: &mut [u8]) -> Result<(), Error> {
+        let size = self.buffer.len() as u32;
+        if size == 0 {
+            return self.flash.read_bytes(address, buffer);
+        }
+        let mut done = 0;
+        while done < buffer.len() {
+            let line = address - address % size;
+            if self.line != Some(line) {
+                self.flash.read_bytes(line, self.buffer)?;
+                self.line = Some(line);
+            }
+            let offset = (address - line) as usize;
+            let n = (self.buffer.len() - offset).min(buffer.len() - done);
+            buffer[done..done + n].copy_from_slice(&self.buffer[offset..offset + n]);
+            done += n;
+            address += n as u32;
+        }
+        Ok(())
+    }
+
+    /// Writes a single byte at `address` and invalidates the cached line if it contains `address`.
+    pub fn write_byte(&mut self, address: u32, byte: u8) -> Result<(), Error> {
+        self.invalidate_range(address, 1);
+        self.flash.write_byte(address, byte)
+    }
+
+    /// Erases the 4k block containing `address` and invalidates the cached line if it overlaps the block.
+    pub fn erase_4k_block(&mut self, address: u32) -> Result<(), Error> {
+        self.invalidate_range(address & !0xFFF, 0x1000);
+        self.flash.erase_4k_block(address)
+    }
+
+    /// Erases the entire flash memory and invalidates the cache.
+    pub fn chip_erase(&mut self) -> Result<(), Error> {
+        self.invalidate();
+        self.flash.chip_erase()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::RamFlash;
+
+    #[test]
+    fn sector_cache_hit_avoids_bus() {
+        let mut ram = RamFlash::new(16 * 1024);
+        ram.memory[0x1010] = 0x42;
+        let mut buffer = [0; 4096];
+        let mut cache = SectorCache::new(SPIFlash::new(ram), &mut buffer);
+
+        assert_eq!(cache.read_byte(0x1010), Ok(0x42));
+        let transfers = cache.flash.transmitter.log.len();
+        let mut bytes = [0; 4];
+        cache.read_bytes(0x100E, &mut bytes).unwrap();
+        assert_eq!(bytes, [0xFF, 0xFF, 0x42, 0xFF]);
+        assert_eq!(cache.flash.transmitter.log.len(), transfers);
+
+        // Writes into the cached line invalidate it.
+        cache.write_byte(0x1011, 0x17).unwrap();
+        assert_eq!(cache.read_byte(0x1011), Ok(0x17));
+
+        // Reads spanning two lines are stitched together.
+        let mut bytes = [0; 2];
+        cache.read_bytes(0x1FFF, &mut bytes).unwrap();
+        assert_eq!(bytes, [0xFF; 2]);
+    }
+}
@@ -0,0 +1,85 @@
+//! A small database of known SPI Flash parts.
+//! It is consulted by `SPIFlash::identify()` when a chip does not implement SFDP.
+
+use crate::{ChipInfo, JedecId, SPIFLASH_BLOCKERASE_64K, SPIFLASH_CHIPERASE_ALT, SPIFLASH_STATUSREAD_3};
+
+const fn id(manufacturer: u8, memory_type: u8, capacity: u8) -> JedecId {
+    JedecId::new(manufacturer, memory_type, capacity)
+}
+
+/// Sets the worst-case program, sector erase and chip erase times from the datasheet.
+const fn timed(info: ChipInfo, program_us: u32, erase_ms: u32, chip_erase_s: u32) -> ChipInfo {
+    ChipInfo {
+        program_time_us: program_us,
+        erase_time_us: erase_ms * 1000,
+        chip_erase_time_us: chip_erase_s * 1_000_000,
+        ..info
+    }
+}
+
+/// Marks a part which can suspend both erases and page programs.
+const fn suspendable(info: ChipInfo) -> ChipInfo {
+    ChipInfo {
+        erase_suspend: true,
+        program_suspend: true,
+        ..info
+    }
+}
+
+/// A Spansion S25FL-S part with uniform 64K sectors, which reports program and erase failures
+/// in status register 1. Its 4K erase only works on the parameter sectors some variants have, so it is not used.
+const fn spansion_fl_s(capacity: u32) -> ChipInfo {
+    ChipInfo {
+        sector_size: 64 * 1024,
+        erase_ops: [(64 * 1024, SPIFLASH_BLOCKERASE_64K), (0, 0), (0, 0), (0, 0)],
+        status_errors: true,
+        ..ChipInfo::new(capacity)
+    }
+}
+
+const KNOWN_CHIPS: &[(JedecId, ChipInfo)] = &[
+    // Atmel-Adesto AT25DF041A
+    (id(0x1F, 0x44, 0x01), ChipInfo::new(512 * 1024)),
+    // Winbond W25X40CL
+    (id(0xEF, 0x30, 0x13), ChipInfo::new(512 * 1024)),
+    // Winbond W25Q80, like all W25Q parts able to suspend programs and erases
+    (id(0xEF, 0x40, 0x14), suspendable(timed(ChipInfo::new(1024 * 1024), 3000, 400, 6))),
+    // Winbond W25Q16
+    (id(0xEF, 0x40, 0x15), suspendable(timed(ChipInfo::new(2 * 1024 * 1024), 3000, 400, 25))),
+    // Winbond W25Q32
+    (id(0xEF, 0x40, 0x16), suspendable(timed(ChipInfo::new(4 * 1024 * 1024), 3000, 400, 50))),
+    // Winbond W25Q64
+    (id(0xEF, 0x40, 0x17), suspendable(timed(ChipInfo::new(8 * 1024 * 1024), 3000, 400, 100))),
+    // Winbond W25Q128
+    (id(0xEF, 0x40, 0x18), suspendable(timed(ChipInfo::new(16 * 1024 * 1024), 3000, 400, 200))),
+    // Winbond W25Q256, reflecting 4-byte address mode in ADS (status register 3, bit 0)
+    (
+        id(0xEF, 0x40, 0x19),
+        ChipInfo {
+            four_byte_flag: Some((SPIFLASH_STATUSREAD_3, 1 << 0)),
+            ..suspendable(timed(ChipInfo::new(32 * 1024 * 1024), 3000, 400, 400))
+        },
+    ),
+    // Macronix MX25R6435F, used on the nRF52840-DK
+    (id(0xC2, 0x28, 0x17), timed(ChipInfo::new(8 * 1024 * 1024), 10_000, 240, 240)),
+    // Spansion S25FL128S
+    (id(0x01, 0x20, 0x18), timed(spansion_fl_s(16 * 1024 * 1024), 750, 2600, 165)),
+    // Spansion S25FL256S
+    (id(0x01, 0x02, 0x19), timed(spansion_fl_s(32 * 1024 * 1024), 750, 2600, 330)),
+    // Micron N25Q128, which only knows the 0xC7 bulk erase
+    (
+        id(0x20, 0xBA, 0x18),
+        ChipInfo {
+            chip_erase_opcode: SPIFLASH_CHIPERASE_ALT,
+            ..suspendable(timed(ChipInfo::new(16 * 1024 * 1024), 5000, 800, 250))
+        },
+    ),
+];
+
+/// Looks up the geometry of a chip by its JEDEC ID.
+pub(crate) fn lookup(id: JedecId) -> Option<ChipInfo> {
+    KNOWN_CHIPS
+        .iter()
+        .find(|(known, _)| *known == id)
+        .map(|(_, info)| *info)
+}
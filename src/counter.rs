@@ -0,0 +1,127 @@
+//! A counter in flash, for boot counters and sequence numbers, see `SPIFlash::fetch_add_u32()`.
+//!
+//! Flash can not increment a value in place, so every new value is appended to a log instead.
+//! The counter occupies two 4K sectors of 512 slots each. A slot holds the value (u32, little endian)
+//! followed by its inverse, so a slot torn by power loss is told apart from a valid one and ignored.
+//! Once the active sector is full, the next value goes to the first slot of the other sector,
+//! and only then is the full sector erased. Whatever the power does, at least one valid value survives.
+//! If both sectors hold values after a power loss, the newer one is the one following the other.
+
+use crate::{Error, SPIFlash, Transmitter};
+
+/// Size of each of the two sectors of a counter.
+const SECTOR_SIZE: u32 = 4096;
+
+/// Length of a slot: the value and its inverse.
+const SLOT_LEN: u32 = 8;
+
+/// Slots read at once while scanning a sector.
+const SLOTS_PER_READ: usize = 8;
+
+/// What a scan of one counter sector found.
+#[derive(Debug, Clone, Copy)]
+struct Scan {
+    /// The value in the last valid slot, if any.
+    value: Option<u32>,
+    /// Offset of the slot after the last written one, which may lie past the end of the sector.
+    next: u32,
+}
+
+impl<T: Transmitter> SPIFlash<T> {
+    /// Increments the counter stored in the two 4K sectors at `address` and returns its previous value,
+    /// wrapping around after `u32::MAX`. A counter which was never written starts at zero.
+    /// Flash can not increment in place, so every new value is appended to a slot of its own,
+    /// and a sector is only erased once per 1024 increments: about 100 million increments on parts rated for
+    /// 100k erase cycles. A power loss at any point leaves either the previous or the new value behind.
+    /// Returns `Error::OutOfBounds` if `address` is not aligned to 4K.
+    pub fn fetch_add_u32(&mut self, address: u32) -> Result<u32, Error> {
+        if address & (SECTOR_SIZE - 1) != 0 {
+            return Err(Error::OutOfBounds);
+        }
+        let sectors = [address, address.checked_add(SECTOR_SIZE).ok_or(Error::OutOfBounds)?];
+        let scans = [self.scan_counter(sectors[0])?, self.scan_counter(sectors[1])?];
+        let active = match (scans[0].value, scans[1].value) {
+            (Some(a), Some(b)) if b.wrapping_sub(a) as i32 > 0 => 1,
+            (None, Some(_)) => 1,
+            _ => 0,
+        };
+        let value = scans[active].value.unwrap_or(0);
+        let slot = value.wrapping_add(1).to_le_bytes();
+        let inverse = (!value.wrapping_add(1)).to_le_bytes();
+        let mut entry = [0; SLOT_LEN as usize];
+        entry[..4].copy_from_slice(&slot);
+        entry[4..].copy_from_slice(&inverse);
+
+        if scans[active].next < SECTOR_SIZE {
+            self.write_bytes(sectors[active] + scans[active].next, &entry)?;
+        } else {
+            let other = sectors[1 - active];
+            if scans[1 - active].next > 0 {
+                self.erase_4k_block(other)?;
+            }
+            self.write_bytes(other, &entry)?;
+            self.erase_4k_block(sectors[active])?;
+        }
+        Ok(value)
+    }
+
+    /// Scans the counter sector at `address` for its last valid value and the first free slot.
+    fn scan_counter(&mut self, address: u32) -> Result<Scan, Error> {
+        let mut scan = Scan { value: None, next: 0 };
+        let mut buffer = [0; SLOTS_PER_READ * SLOT_LEN as usize];
+        for chunk in (0..SECTOR_SIZE).step_by(buffer.len()) {
+            self.read_bytes(address + chunk, &mut buffer)?;
+            for (i, slot) in buffer.chunks_exact(SLOT_LEN as usize).enumerate() {
+                if slot.iter().all(|&b| b == 0xFF) {
+                    continue;
+                }
+                let value = u32::from_le_bytes([slot[0], slot[1], slot[2], slot[3]]);
+                let inverse = u32::from_le_bytes([slot[4], slot[5], slot[6], slot[7]]);
+                if value == !inverse {
+                    scan.value = Some(value);
+                }
+                scan.next = chunk + (i as u32 + 1) * SLOT_LEN;
+            }
+        }
+        Ok(scan)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::RamFlash;
+
+    #[test]
+    fn counts_across_sectors() {
+        let mut flash = SPIFlash::new(RamFlash::new(4 * 4096));
+        assert_eq!(flash.fetch_add_u32(0x1001), Err(Error::OutOfBounds));
+        for i in 0..1100 {
+            assert_eq!(flash.fetch_add_u32(0x1000), Ok(i));
+        }
+        // The first sector filled up twice, the second once.
+        let erases = flash.transmitter.log.iter().filter(|c| c[0] == 0x20).count();
+        assert_eq!(erases, 2);
+
+        // A torn slot is ignored.
+        let next = 0x2000 + (1100 - 1024) * SLOT_LEN as usize;
+        flash.transmitter.memory[next..next + 4].copy_from_slice(&[0, 0, 0, 0]);
+        assert_eq!(flash.fetch_add_u32(0x1000), Ok(1100));
+        assert_eq!(flash.fetch_add_u32(0x1000), Ok(1101));
+    }
+
+    #[test]
+    fn survives_power_loss_in_switch() {
+        let mut flash = SPIFlash::new(RamFlash::new(4 * 4096));
+        for _ in 0..512 {
+            flash.fetch_add_u32(0).unwrap();
+        }
+        // The new value made it to the second sector, but the first was not erased yet.
+        let mut entry = [0; 8];
+        entry[..4].copy_from_slice(&513u32.to_le_bytes());
+        entry[4..].copy_from_slice(&(!513u32).to_le_bytes());
+        flash.transmitter.memory[0x1000..0x1008].copy_from_slice(&entry);
+        assert_eq!(flash.fetch_add_u32(0), Ok(513));
+        assert_eq!(flash.fetch_add_u32(0), Ok(514));
+    }
+}
@@ -0,0 +1,85 @@
+//! A small table-less CRC-32 for checking data written to and read from flash.
+
+/// The CRC-32 used by Ethernet, zlib and most firmware image formats
+/// (reflected polynomial 0xEDB88320, initial value and final XOR 0xFFFFFFFF).
+/// It is computed bit by bit to keep the code size small.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Crc32 {
+    state: u32,
+}
+
+impl Crc32 {
+    pub const fn new() -> Self {
+        Self {
+            state: 0xFFFF_FFFF,
+        }
+    }
+
+    /// Feeds `data` into the checksum.
+    pub fn update(&mut self, data: &[u8]) {
+        for byte in data {
+            self.state ^= *byte as u32;
+            for _ in 0..8 {
+                let mask = (self.state & 1).wrapping_neg();
+                self.state = (self.state >> 1) ^ (0xEDB8_8320 & mask);
+            }
+        }
+    }
+
+    /// Returns the checksum of all data fed so far.
+    pub fn finish(&self) -> u32 {
+        !self.state
+    }
+}
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A checksum computed over a stream of bytes, e.g. to match the format of a CRC stored along with a blob,
+/// see `SPIFlash::read_verified()`. `Crc32` implements it, other algorithms can be plugged in by implementing it.
+pub trait Checksum {
+    /// The checksum value, e.g. `u16` for a CRC-16.
+    type Output: PartialEq;
+
+    /// Feeds `data` into the checksum.
+    fn update(&mut self, data: &[u8]);
+
+    /// Returns the checksum of all data fed so far.
+    fn finish(&self) -> Self::Output;
+}
+
+impl Checksum for Crc32 {
+    type Output = u32;
+
+    fn update(&mut self, data: &[u8]) {
+        Crc32::update(self, data)
+    }
+
+    fn finish(&self) -> u32 {
+        Crc32::finish(self)
+    }
+}
+
+/// Computes the CRC-32 of `data` in one go.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(data);
+    crc.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        let mut crc = Crc32::new();
+        crc.update(b"1234");
+        crc.update(b"56789");
+        assert_eq!(crc.finish(), 0xCBF4_3926);
+    }
+}
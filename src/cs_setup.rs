@@ -0,0 +1,161 @@
+//! A transmitter wrapper waiting a moment after asserting CS, for slow parts or long wires.
+
+use core::mem::MaybeUninit;
+
+use crate::{DualTransmitter, QuadTransmitter, Transmitter};
+
+/// Wraps a transmitter so every CS assertion is followed by a call to `delay` before the first clock,
+/// giving marginal hardware the CS setup time the first byte would otherwise be corrupted without.
+///
+/// The delay is inserted by asserting CS with `Transmitter::begin()`, calling `delay`,
+/// clocking the transfer and deasserting CS with `end()`. A transaction held with `begin()` on the wrapper
+/// gets the delay once, right after CS is asserted, and none for the transfers within it.
+/// Transmitters which can not hold CS get no delay at all, their own CS handling has to provide it.
+pub struct CsSetup<T, F> {
+    transmitter: T,
+    delay: F,
+    /// Whether CS is held by `begin()` on the wrapper.
+    held: bool,
+}
+
+impl<T, F> CsSetup<T, F>
+where
+    T: Transmitter,
+    F: FnMut(),
+{
+    /// Wraps `transmitter`, calling `delay` after every CS assertion.
+    pub fn new(transmitter: T, delay: F) -> Self {
+        Self {
+            transmitter,
+            delay,
+            held: false,
+        }
+    }
+
+    /// Releases the wrapped transmitter.
+    pub fn release(self) -> T {
+        self.transmitter
+    }
+
+    /// Runs `transfer` in a CS assertion of its own, followed by the delay, unless CS is held already.
+    fn framed<R>(&mut self, transfer: impl FnOnce(&mut T) -> R) -> R {
+        if self.held || !self.transmitter.begin() {
+            return transfer(&mut self.transmitter);
+        }
+        (self.delay)();
+        let result = transfer(&mut self.transmitter);
+        self.transmitter.end();
+        result
+    }
+}
+
+impl<T, F> Transmitter for CsSetup<T, F>
+where
+    T: Transmitter,
+    F: FnMut(),
+{
+    fn send(&mut self, buffer: &[u8]) {
+        self.framed(|spi| spi.send(buffer));
+    }
+
+    fn read(&mut self, buffer: &mut [u8]) {
+        self.framed(|spi| spi.read(buffer));
+    }
+
+    fn send_read(&mut self, buffer_tx: &[u8], buffer_rx: &mut [u8]) {
+        self.framed(|spi| spi.send_read(buffer_tx, buffer_rx));
+    }
+
+    fn read_uninit<'a>(&mut self, buffer: &'a mut [MaybeUninit<u8>]) -> &'a mut [u8] {
+        self.framed(move |spi| spi.read_uninit(buffer))
+    }
+
+    fn send_read_uninit<'a>(&mut self, buffer_tx: &[u8], buffer_rx: &'a mut [MaybeUninit<u8>]) -> &'a mut [u8] {
+        self.framed(move |spi| spi.send_read_uninit(buffer_tx, buffer_rx))
+    }
+
+    fn begin(&mut self) -> bool {
+        if !self.transmitter.begin() {
+            return false;
+        }
+        (self.delay)();
+        self.held = true;
+        true
+    }
+
+    fn end(&mut self) {
+        self.held = false;
+        self.transmitter.end();
+    }
+
+    fn is_full_duplex(&self) -> bool {
+        self.transmitter.is_full_duplex()
+    }
+}
+
+impl<T, F> DualTransmitter for CsSetup<T, F>
+where
+    T: DualTransmitter,
+    F: FnMut(),
+{
+    fn read_dual(&mut self, single: &[u8], dual: &[u8], buffer: &mut [u8]) {
+        self.framed(|spi| spi.read_dual(single, dual, buffer));
+    }
+}
+
+impl<T, F> QuadTransmitter for CsSetup<T, F>
+where
+    T: QuadTransmitter,
+    F: FnMut(),
+{
+    fn write_quad(&mut self, header: &[u8], data: &[u8]) {
+        self.framed(|spi| spi.write_quad(header, data));
+    }
+
+    fn read_quad(&mut self, single: &[u8], quad: &[u8], buffer: &mut [u8]) {
+        self.framed(|spi| spi.read_quad(single, quad, buffer));
+    }
+
+    fn set_qpi(&mut self, enabled: bool) {
+        self.transmitter.set_qpi(enabled);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::RamFlash;
+    use crate::SPIFlash;
+    use core::cell::Cell;
+
+    #[test]
+    fn delays_after_cs() {
+        let delays = Cell::new(0);
+        let mut ram = RamFlash::new(4096);
+        ram.memory[..4].copy_from_slice(&[1, 2, 3, 4]);
+        let mut flash = SPIFlash::new(CsSetup::new(ram, || delays.set(delays.get() + 1)));
+
+        assert_eq!(flash.read_jedec_id().manufacturer, 0xEF);
+        assert_eq!(delays.get(), 1);
+        let mut buffer = [0; 4];
+        flash.read_bytes(0, &mut buffer).unwrap();
+        assert_eq!(buffer, [1, 2, 3, 4]);
+        // The status check and the read.
+        assert_eq!(delays.get(), 3);
+
+        // A held transaction is delayed once.
+        flash.transaction(|t| {
+            t.push(&[0x9F]);
+            t.read(&mut buffer[..3]);
+        }).unwrap();
+        assert_eq!(delays.get(), 4);
+        assert_eq!(buffer[..3], [0xEF, 0x40, 0x18]);
+
+        // Without CS control, there is no place for the delay.
+        let mut ram = RamFlash::new(4096);
+        ram.cs_hold = false;
+        let mut flash = SPIFlash::new(CsSetup::new(ram, || delays.set(delays.get() + 1)));
+        assert_eq!(flash.read_jedec_id().manufacturer, 0xEF);
+        assert_eq!(delays.get(), 4);
+    }
+}
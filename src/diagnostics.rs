@@ -0,0 +1,92 @@
+//! Destructive diagnostics for incoming inspection of flash parts.
+//!
+//! Everything in here erases and overwrites the tested region, so only point it at flash
+//! whose contents can be thrown away.
+
+use core::ops::Range;
+
+use crate::{Error, SPIFlash, Transmitter};
+
+/// The outcome of `SPIFlash::stress_test()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StressReport {
+    /// Number of bits written and read back, over all patterns.
+    pub bits_checked: u64,
+    /// Number of bits which read back differently from the written pattern.
+    pub bit_errors: u64,
+    /// The first address which read back wrong, if any.
+    pub first_error: Option<u32>,
+}
+
+impl<T> SPIFlash<T>
+where
+    T: Transmitter,
+{
+    /// Erases `region`, fills it with every byte of `patterns` in turn and reads it back,
+    /// counting the bits that did not stick. `scratch` holds one chunk of the region at a time,
+    /// so a larger one means fewer, longer transfers.
+    /// `region` has to start and end on a boundary of the smallest erase size (4K without geometry, see `identify()`),
+    /// as anything else would erase data around it, and `scratch` must not be empty.
+    /// Otherwise `Error::OutOfBounds` is returned.
+    /// Destroys the contents of `region`. Blocks until all patterns are done.
+    pub fn stress_test(&mut self, region: Range<u32>, patterns: &[u8], scratch: &mut [u8]) -> Result<StressReport, Error> {
+        let unit = self.erase_unit();
+        if region.start > region.end || (region.start | region.end) & (unit - 1) != 0 || scratch.is_empty() {
+            return Err(Error::OutOfBounds);
+        }
+        let mut report = StressReport {
+            bits_checked: 0,
+            bit_errors: 0,
+            first_error: None,
+        };
+        for &pattern in patterns {
+            let erased = self.erase_range(region.start, region.end - region.start)?;
+            let mut address = region.start;
+            while address < region.end {
+                let n = ((region.end - address) as usize).min(scratch.len());
+                let chunk = &mut scratch[..n];
+                chunk.iter_mut().for_each(|b| *b = pattern);
+                self.write_erased(&erased, address, chunk)?;
+                self.read_bytes(address, chunk)?;
+                for (i, byte) in chunk.iter().enumerate() {
+                    let errors = (byte ^ pattern).count_ones();
+                    if errors > 0 && report.first_error.is_none() {
+                        report.first_error = Some(address + i as u32);
+                    }
+                    report.bit_errors += errors as u64;
+                }
+                report.bits_checked += n as u64 * 8;
+                address += n as u32;
+            }
+        }
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::RamFlash;
+
+    #[test]
+    fn counts_stuck_bits() {
+        let mut ram = RamFlash::new(4 * 4096);
+        // Two bytes no longer take any programming and stay erased.
+        ram.stuck = Some(0x1800..0x1802);
+        let mut flash = SPIFlash::new(ram);
+        let mut scratch = [0; 256];
+
+        let report = flash.stress_test(0x1000..0x3000, &[0xFF, 0x55, 0x00], &mut scratch).unwrap();
+        assert_eq!(report.bits_checked, 3 * 0x2000 * 8);
+        // 0xFF always sticks, 0x55 misses 4 bits per stuck byte, 0x00 all 8.
+        assert_eq!(report.bit_errors, 2 * 4 + 2 * 8);
+        assert_eq!(report.first_error, Some(0x1800));
+
+        assert_eq!(flash.stress_test(0x1000..0x1800, &[0], &mut scratch), Err(Error::OutOfBounds));
+        assert_eq!(flash.stress_test(0..0x1000, &[0], &mut []), Err(Error::OutOfBounds));
+
+        // A part which only erases 64K blocks would lose the data around a 4K region.
+        flash.info = Some(crate::ChipInfo { sector_size: 64 * 1024, ..crate::ChipInfo::new(4 * 4096) });
+        assert_eq!(flash.stress_test(0x1000..0x2000, &[0], &mut scratch), Err(Error::OutOfBounds));
+    }
+}
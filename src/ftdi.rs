@@ -0,0 +1,152 @@
+//! A `Transmitter` for FTDI USB bridges with an MPSSE engine (FT232H, FT2232H, FT4232H),
+//! to use the driver as a flash programmer on a PC.
+//!
+//! The chip is wired to the lower MPSSE pins: ADBUS0 to SCK, ADBUS1 to MOSI, ADBUS2 to MISO
+//! and one of ADBUS3 to ADBUS7 to CS. The bus runs in SPI mode 0.
+
+use ftdi_mpsse::{ClockDataIn, ClockDataOut, MpsseCmdBuilder, MpsseCmdExecutor, MpsseSettings};
+
+use crate::{Error, Transmitter};
+
+const SCK: u8 = 1 << 0;
+const MOSI: u8 = 1 << 1;
+
+/// The most bytes a single MPSSE clock command can move.
+const MAX_CLOCKED: usize = 65536;
+
+/// Talks to the chip through an FTDI MPSSE device, e.g. an `ftdi::Device` opened on interface A.
+///
+/// The `Transmitter` methods can not fail, so the first error of the device is kept
+/// and everything after it is skipped, with reads returning 0xFF.
+/// Call `check()` after a driver call to find out whether it actually reached the chip.
+pub struct FtdiTransmitter<D>
+where
+    D: MpsseCmdExecutor,
+{
+    device: D,
+    /// The pin mask of CS.
+    cs: u8,
+    /// Whether CS is held by `begin()`.
+    held: bool,
+    error: Option<D::Error>,
+}
+
+impl<D> FtdiTransmitter<D>
+where
+    D: MpsseCmdExecutor,
+{
+    /// Sets up `device` for SPI at `clock_hz`, with CS on ADBUS`cs_pin`.
+    /// Returns `Error::OutOfBounds` if `cs_pin` is not one of 3 to 7, and `Error::Bus` if the device can not be set up.
+    pub fn new(mut device: D, cs_pin: u8, clock_hz: u32) -> Result<Self, Error> {
+        if !(3..=7).contains(&cs_pin) {
+            return Err(Error::OutOfBounds);
+        }
+        let cs = 1 << cs_pin;
+        let settings = MpsseSettings {
+            clock_frequency: Some(clock_hz),
+            mask: SCK | MOSI | cs,
+            ..MpsseSettings::default()
+        };
+        device.init(&settings).map_err(|_| Error::Bus)?;
+        let mut transmitter = Self {
+            device,
+            cs,
+            held: false,
+            error: None,
+        };
+        transmitter.execute(MpsseCmdBuilder::new().set_gpio_lower(cs, transmitter.direction()), &mut []);
+        transmitter.check()?;
+        Ok(transmitter)
+    }
+
+    /// Releases the device.
+    pub fn release(self) -> D {
+        self.device
+    }
+
+    /// Returns `Error::Bus` if the device failed since the last check, clearing the error.
+    /// Use `take_error()` to get the error of the device itself.
+    pub fn check(&mut self) -> Result<(), Error> {
+        match self.error.take() {
+            Some(_) => Err(Error::Bus),
+            None => Ok(()),
+        }
+    }
+
+    /// Takes the first error of the device since the last check, if any.
+    pub fn take_error(&mut self) -> Option<D::Error> {
+        self.error.take()
+    }
+
+    fn direction(&self) -> u8 {
+        SCK | MOSI | self.cs
+    }
+
+    /// Sends `command` and receives `response.len()` bytes, unless an earlier error is pending.
+    fn execute(&mut self, command: MpsseCmdBuilder, response: &mut [u8]) {
+        if self.error.is_none() {
+            let result = match response.is_empty() {
+                true => self.device.send(command.as_slice()),
+                false => self.device.xfer(command.send_immediate().as_slice(), response),
+            };
+            if let Err(error) = result {
+                self.error = Some(error);
+            }
+        }
+        if self.error.is_some() {
+            response.iter_mut().for_each(|b| *b = 0xFF);
+        }
+    }
+
+    /// Clocks out `tx`, then clocks in `rx.len()` bytes, framed by CS unless it is held.
+    fn transfer(&mut self, tx: &[u8], rx: &mut [u8]) {
+        let direction = self.direction();
+        let mut command = MpsseCmdBuilder::new();
+        if !self.held {
+            command = command.set_gpio_lower(0, direction);
+        }
+        for chunk in tx.chunks(MAX_CLOCKED) {
+            command = command.clock_data_out(ClockDataOut::MsbNeg, chunk);
+        }
+        let mut remaining = rx.len();
+        while remaining > 0 {
+            let n = remaining.min(MAX_CLOCKED);
+            command = command.clock_data_in(ClockDataIn::MsbPos, n);
+            remaining -= n;
+        }
+        if !self.held {
+            command = command.set_gpio_lower(self.cs, direction);
+        }
+        self.execute(command, rx);
+    }
+}
+
+impl<D> Transmitter for FtdiTransmitter<D>
+where
+    D: MpsseCmdExecutor,
+{
+    fn send(&mut self, buffer: &[u8]) {
+        self.transfer(buffer, &mut []);
+    }
+
+    fn read(&mut self, buffer: &mut [u8]) {
+        self.transfer(&[], buffer);
+    }
+
+    fn send_read(&mut self, buffer_tx: &[u8], buffer_rx: &mut [u8]) {
+        self.transfer(buffer_tx, buffer_rx);
+    }
+
+    fn begin(&mut self) -> bool {
+        let direction = self.direction();
+        self.execute(MpsseCmdBuilder::new().set_gpio_lower(0, direction), &mut []);
+        self.held = true;
+        true
+    }
+
+    fn end(&mut self) {
+        self.held = false;
+        let (cs, direction) = (self.cs, self.direction());
+        self.execute(MpsseCmdBuilder::new().set_gpio_lower(cs, direction), &mut []);
+    }
+}
@@ -0,0 +1,124 @@
+//! `std::io` adapters, so host tools can treat the flash like a file.
+
+use std::io;
+
+use crate::{Error, SPIFlash, Transmitter};
+
+impl From<Error> for io::Error {
+    fn from(error: Error) -> Self {
+        let kind = match error {
+            Error::OutOfBounds => io::ErrorKind::InvalidInput,
+            Error::Protected => io::ErrorKind::PermissionDenied,
+            Error::Timeout => io::ErrorKind::TimedOut,
+            Error::Unsupported => io::ErrorKind::Unsupported,
+            Error::InvalidUtf8 => io::ErrorKind::InvalidData,
+            _ => io::ErrorKind::Other,
+        };
+        io::Error::new(kind, error)
+    }
+}
+
+/// Reads, writes and seeks the flash through `std::io`, starting at address 0.
+///
+/// Reads and writes stop at the end of the chip, if its capacity is known.
+/// Writes go through `SPIFlash::write_bytes()`, so the target has to be erased first.
+pub struct FlashIo<'a, T>
+where
+    T: Transmitter,
+{
+    flash: &'a mut SPIFlash<T>,
+    position: u32,
+}
+
+impl<'a, T> FlashIo<'a, T>
+where
+    T: Transmitter,
+{
+    pub fn new(flash: &'a mut SPIFlash<T>) -> Self {
+        Self { flash, position: 0 }
+    }
+
+    /// The address the next read or write starts at.
+    pub fn position(&self) -> u32 {
+        self.position
+    }
+
+    /// How many of `len` bytes fit between the current position and the end of the chip.
+    fn clamp(&self, len: usize) -> usize {
+        match self.flash.capacity() {
+            Ok(capacity) => (capacity.saturating_sub(self.position) as usize).min(len),
+            Err(_) => len,
+        }
+    }
+}
+
+impl<'a, T> io::Read for FlashIo<'a, T>
+where
+    T: Transmitter,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.clamp(buf.len());
+        self.flash.read_bytes(self.position, &mut buf[..n])?;
+        self.position += n as u32;
+        Ok(n)
+    }
+}
+
+impl<'a, T> io::Write for FlashIo<'a, T>
+where
+    T: Transmitter,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.clamp(buf.len());
+        self.flash.write_bytes(self.position, &buf[..n])?;
+        self.position += n as u32;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(self.flash.flush()?)
+    }
+}
+
+impl<'a, T> io::Seek for FlashIo<'a, T>
+where
+    T: Transmitter,
+{
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            io::SeekFrom::Start(offset) => Some(offset),
+            io::SeekFrom::Current(offset) => (self.position as u64).checked_add_signed(offset),
+            io::SeekFrom::End(offset) => (self.flash.capacity()? as u64).checked_add_signed(offset),
+        };
+        match position {
+            Some(position) if position <= u32::MAX as u64 => {
+                self.position = position as u32;
+                Ok(position)
+            }
+            _ => Err(Error::OutOfBounds.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::RamFlash;
+    use crate::ChipInfo;
+    use std::io::{Read, Seek, SeekFrom, Write};
+
+    #[test]
+    fn read_write_seek() {
+        let mut flash = SPIFlash::new(RamFlash::new(4096));
+        flash.info = Some(ChipInfo::new(4096));
+        let mut file = FlashIo::new(&mut flash);
+        file.seek(SeekFrom::End(-2)).unwrap();
+        assert_eq!(file.write(&[1, 2, 3]).unwrap(), 2);
+        file.seek(SeekFrom::Current(-2)).unwrap();
+        let mut contents = std::vec::Vec::new();
+        file.read_to_end(&mut contents).unwrap();
+        assert_eq!(contents, [1, 2]);
+        assert_eq!(file.position(), 4096);
+        assert!(file.seek(SeekFrom::Current(-5000)).is_err());
+    }
+}
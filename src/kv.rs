@@ -0,0 +1,305 @@
+//! A small key-value store for configuration data, kept in two sectors.
+//!
+//! One sector is active and records are appended to it until it is full.
+//! Then the live records are compacted into the other sector, which becomes the active one.
+//!
+//! Every sector starts with a 12 byte header: the magic `KVS1`, a sequence number (u32, little endian)
+//! and its inverse (see `log_sector`). Only a sector with an intact header is valid, and of two valid sectors
+//! the one with the newer sequence number is active. The header of a compacted sector is written last,
+//! so a compaction torn by power loss leaves the previous sector active.
+//!
+//! A record consists of the key (u16), the value length (u16, `TOMBSTONE` for a removed key),
+//! the value and the CRC-32 of everything before it, all little endian.
+//! Record validation is left to `log_sector`: mounting stops at the first record which fails its CRC,
+//! so a `set()` torn by power loss leaves the key at its previous value, and the next write compacts
+//! because the torn bytes can not be programmed again.
+
+use crate::log_sector::{self, HEADER_LEN};
+use crate::{Error, SPIFlash, Transmitter};
+
+/// Size of each of the two sectors.
+pub const SECTOR_SIZE: u32 = log_sector::SECTOR_SIZE;
+
+/// The first four bytes of a valid sector.
+const MAGIC: [u8; 4] = *b"KVS1";
+
+/// Length of a record without its value: key, length and CRC.
+const RECORD_OVERHEAD: u32 = 8;
+
+/// The length of a record marking its key as removed.
+const TOMBSTONE: u16 = 0xFFFE;
+
+/// The key of an erased record header, which can not be used.
+const ERASED_KEY: u16 = 0xFFFF;
+
+/// Size of the stack buffer records are copied with.
+const CHUNK: usize = 64;
+
+/// A record found in a sector. Offsets are relative to the sector.
+#[derive(Debug, Clone, Copy)]
+struct Record {
+    key: u16,
+    /// Length of the value, `None` for a removed key.
+    len: Option<u16>,
+    start: u32,
+    end: u32,
+}
+
+/// A key-value store in two sectors. See the module documentation for the layout.
+pub struct KvStore {
+    sectors: [u32; 2],
+    /// Index of the active sector in `sectors`.
+    active: usize,
+    sequence: u32,
+    /// Offset of the first free byte in the active sector.
+    end: u32,
+}
+
+impl KvStore {
+    /// Opens the store kept in the sectors at `first` and `second`, which must be 4K aligned.
+    /// If neither holds a valid store, an empty one is created in `first`.
+    pub fn mount<T: Transmitter>(flash: &mut SPIFlash<T>, first: u32, second: u32) -> Result<Self, Error> {
+        if (first | second) & (SECTOR_SIZE - 1) != 0 || first == second {
+            return Err(Error::OutOfBounds);
+        }
+        let mut store = Self {
+            sectors: [first, second],
+            active: 0,
+            sequence: 0,
+            end: HEADER_LEN,
+        };
+        match (log_sector::read_header(flash, first, MAGIC)?, log_sector::read_header(flash, second, MAGIC)?) {
+            (Some(a), Some(b)) => {
+                // The newer sequence number wins, allowing for wrap around.
+                let second_newer = (b.wrapping_sub(a) as i32) > 0;
+                store.active = second_newer as usize;
+                store.sequence = if second_newer { b } else { a };
+            }
+            (Some(a), None) => store.sequence = a,
+            (None, Some(b)) => {
+                store.active = 1;
+                store.sequence = b;
+            }
+            (None, None) => {
+                flash.erase_4k_block(first)?;
+                log_sector::write_header(flash, first, MAGIC, 0)?;
+            }
+        }
+        let base = store.base();
+        while let Some(record) = Self::next_record(flash, base, store.end)? {
+            store.end = record.end;
+        }
+        Ok(store)
+    }
+
+    /// Reads the value of `key` into `buffer` and returns its length, or `None` if the key is not set.
+    /// Returns `Error::OutOfBounds` if `buffer` is too small for the value.
+    pub fn get<T: Transmitter>(&self, flash: &mut SPIFlash<T>, key: u16, buffer: &mut [u8]) -> Result<Option<usize>, Error> {
+        let (start, len) = match self.lookup(flash, key)? {
+            Some(Record { len: Some(len), start, .. }) => (start, len as usize),
+            _ => return Ok(None),
+        };
+        if buffer.len() < len {
+            return Err(Error::OutOfBounds);
+        }
+        flash.read_bytes(self.base() + start + 4, &mut buffer[..len])?;
+        Ok(Some(len))
+    }
+
+    /// Sets `key` to `value`, compacting the store into the other sector if the active one is full.
+    /// Returns `Error::OutOfBounds` for the reserved key 0xFFFF or a value which can never fit a sector,
+    /// and `Error::StoreFull` if the live records and the new one do not fit even after compaction.
+    pub fn set<T: Transmitter>(&mut self, flash: &mut SPIFlash<T>, key: u16, value: &[u8]) -> Result<(), Error> {
+        if key == ERASED_KEY || value.len() > (SECTOR_SIZE - HEADER_LEN - RECORD_OVERHEAD) as usize {
+            return Err(Error::OutOfBounds);
+        }
+        self.append(flash, key, Some(value))
+    }
+
+    /// Removes `key`. Removing a key which is not set does nothing.
+    pub fn remove<T: Transmitter>(&mut self, flash: &mut SPIFlash<T>, key: u16) -> Result<(), Error> {
+        match self.lookup(flash, key)? {
+            Some(Record { len: Some(_), .. }) => self.append(flash, key, None),
+            _ => Ok(()),
+        }
+    }
+
+    /// Address of the active sector.
+    fn base(&self) -> u32 {
+        self.sectors[self.active]
+    }
+
+    /// Finds the latest record of `key` in the active sector.
+    fn lookup<T: Transmitter>(&self, flash: &mut SPIFlash<T>, key: u16) -> Result<Option<Record>, Error> {
+        let mut found = None;
+        let mut offset = HEADER_LEN;
+        while let Some(record) = Self::next_record(flash, self.base(), offset)? {
+            if record.key == key {
+                found = Some(record);
+            }
+            offset = record.end;
+        }
+        Ok(found)
+    }
+
+    /// Appends a record, or a tombstone if `value` is `None`, compacting first if it does not fit.
+    fn append<T: Transmitter>(&mut self, flash: &mut SPIFlash<T>, key: u16, value: Option<&[u8]>) -> Result<(), Error> {
+        let len = RECORD_OVERHEAD + value.map_or(0, |value| value.len() as u32);
+        if log_sector::fits(flash, self.base(), self.end, len)? {
+            self.end = Self::write_record(flash, self.base(), self.end, key, value)?;
+            return Ok(());
+        }
+        self.compact(flash, key, value)
+    }
+
+    /// Copies the live records except the one of `key` into the other sector, appends the new record
+    /// of `key` and makes the other sector the active one.
+    fn compact<T: Transmitter>(&mut self, flash: &mut SPIFlash<T>, key: u16, value: Option<&[u8]>) -> Result<(), Error> {
+        let old = self.base();
+        let new = self.sectors[1 - self.active];
+        flash.erase_4k_block(new)?;
+        let mut end = HEADER_LEN;
+        let mut offset = HEADER_LEN;
+        while let Some(record) = Self::next_record(flash, old, offset)? {
+            offset = record.end;
+            if record.key == key || record.len.is_none() || Self::superseded(flash, old, &record)? {
+                continue;
+            }
+            let mut chunk = [0; CHUNK];
+            let mut copied = record.start;
+            while copied < record.end {
+                let n = ((record.end - copied) as usize).min(CHUNK);
+                flash.read_bytes(old + copied, &mut chunk[..n])?;
+                flash.write_bytes(new + end + (copied - record.start), &chunk[..n])?;
+                copied += n as u32;
+            }
+            end += record.end - record.start;
+        }
+        if let Some(value) = value {
+            if end + RECORD_OVERHEAD + value.len() as u32 > SECTOR_SIZE {
+                return Err(Error::StoreFull);
+            }
+            end = Self::write_record(flash, new, end, key, Some(value))?;
+        }
+        // Only the header makes the new sector valid, so everything before it can be torn safely.
+        let sequence = self.sequence.wrapping_add(1);
+        log_sector::write_header(flash, new, MAGIC, sequence)?;
+        self.active = 1 - self.active;
+        self.sequence = sequence;
+        self.end = end;
+        Ok(())
+    }
+
+    /// Whether a later record in the sector at `base` replaces `record`.
+    fn superseded<T: Transmitter>(flash: &mut SPIFlash<T>, base: u32, record: &Record) -> Result<bool, Error> {
+        let mut offset = record.end;
+        while let Some(later) = Self::next_record(flash, base, offset)? {
+            if later.key == record.key {
+                return Ok(true);
+            }
+            offset = later.end;
+        }
+        Ok(false)
+    }
+
+    /// Decodes the record at `offset` in the sector at `base`.
+    /// Returns `None` at the end of the log, i.e. at an erased or torn record.
+    fn next_record<T: Transmitter>(flash: &mut SPIFlash<T>, base: u32, offset: u32) -> Result<Option<Record>, Error> {
+        let data_len = |header: &[u8; 4]| match (u16::from_le_bytes([header[0], header[1]]), u16::from_le_bytes([header[2], header[3]])) {
+            (ERASED_KEY, _) => None,
+            (_, TOMBSTONE) => Some(0),
+            (_, len) => Some(len as u32),
+        };
+        Ok(log_sector::next_record(flash, base, offset, data_len)?.map(|(header, end)| {
+            let len = u16::from_le_bytes([header[2], header[3]]);
+            Record {
+                key: u16::from_le_bytes([header[0], header[1]]),
+                len: if len == TOMBSTONE { None } else { Some(len) },
+                start: offset,
+                end,
+            }
+        }))
+    }
+
+    /// Writes a record at `offset` in the sector at `base` and returns the offset after it.
+    fn write_record<T: Transmitter>(flash: &mut SPIFlash<T>, base: u32, offset: u32, key: u16, value: Option<&[u8]>) -> Result<u32, Error> {
+        let len = value.map_or(TOMBSTONE, |value| value.len() as u16);
+        let key = key.to_le_bytes();
+        let len = len.to_le_bytes();
+        log_sector::write_record(flash, base, offset, &[key[0], key[1], len[0], len[1]], value.unwrap_or(&[]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::RamFlash;
+
+    fn get(store: &KvStore, flash: &mut SPIFlash<RamFlash>, key: u16) -> Option<std::vec::Vec<u8>> {
+        let mut buffer = [0; 256];
+        store.get(flash, key, &mut buffer).unwrap().map(|len| buffer[..len].to_vec())
+    }
+
+    #[test]
+    fn set_get_remove() {
+        let mut flash = SPIFlash::new(RamFlash::new(4 * 4096));
+        let mut store = KvStore::mount(&mut flash, 0x1000, 0x2000).unwrap();
+        store.set(&mut flash, 1, b"hello").unwrap();
+        store.set(&mut flash, 2, b"world").unwrap();
+        store.set(&mut flash, 1, b"again").unwrap();
+        assert_eq!(get(&store, &mut flash, 1), Some(b"again".to_vec()));
+        store.remove(&mut flash, 2).unwrap();
+        assert_eq!(get(&store, &mut flash, 2), None);
+        assert_eq!(get(&store, &mut flash, 3), None);
+        assert_eq!(store.set(&mut flash, ERASED_KEY, b""), Err(Error::OutOfBounds));
+
+        // The store survives a remount.
+        let store = KvStore::mount(&mut flash, 0x1000, 0x2000).unwrap();
+        assert_eq!(get(&store, &mut flash, 1), Some(b"again".to_vec()));
+        assert_eq!(get(&store, &mut flash, 2), None);
+    }
+
+    #[test]
+    fn compacts_into_spare() {
+        let mut flash = SPIFlash::new(RamFlash::new(4 * 4096));
+        let mut store = KvStore::mount(&mut flash, 0x1000, 0x2000).unwrap();
+        store.set(&mut flash, 7, b"kept").unwrap();
+        // Overwriting the same key over and over fills the sector and forces compactions.
+        for i in 0..200u8 {
+            store.set(&mut flash, 1, &[i; 100]).unwrap();
+        }
+        assert_eq!(get(&store, &mut flash, 1), Some([199; 100].to_vec()));
+        assert_eq!(get(&store, &mut flash, 7), Some(b"kept".to_vec()));
+
+        let store = KvStore::mount(&mut flash, 0x1000, 0x2000).unwrap();
+        assert_eq!(get(&store, &mut flash, 1), Some([199; 100].to_vec()));
+
+        let mut store = store;
+        assert_eq!(store.set(&mut flash, 2, &[0; 4000]), Err(Error::StoreFull));
+        assert_eq!(get(&store, &mut flash, 7), Some(b"kept".to_vec()));
+    }
+
+    #[test]
+    fn survives_torn_writes() {
+        let mut flash = SPIFlash::new(RamFlash::new(4 * 4096));
+        let mut store = KvStore::mount(&mut flash, 0x1000, 0x2000).unwrap();
+        store.set(&mut flash, 1, b"old").unwrap();
+        let end = 0x1000 + store.end as usize;
+
+        // A record torn halfway: its header made it, its value and CRC did not.
+        flash.transmitter.memory[end..end + 4].copy_from_slice(&[1, 0, 3, 0]);
+        flash.transmitter.memory[end + 4] = b'n';
+        // A compaction torn before its header: the spare holds a record but is not valid.
+        flash.transmitter.memory[0x2000 + HEADER_LEN as usize..0x2000 + HEADER_LEN as usize + 4].copy_from_slice(&[1, 0, 3, 0]);
+
+        let mut store = KvStore::mount(&mut flash, 0x1000, 0x2000).unwrap();
+        assert_eq!(store.active, 0);
+        assert_eq!(get(&store, &mut flash, 1), Some(b"old".to_vec()));
+
+        // The torn bytes can not be programmed again, so the next write compacts.
+        store.set(&mut flash, 1, b"new").unwrap();
+        assert_eq!(store.active, 1);
+        let store = KvStore::mount(&mut flash, 0x1000, 0x2000).unwrap();
+        assert_eq!(get(&store, &mut flash, 1), Some(b"new".to_vec()));
+    }
+}
@@ -8,177 +8,5441 @@ const SPIFLASH_BLOCKERASE_32K: u8 = 0x52;        // erase one 32K block of flash
 const SPIFLASH_BLOCKERASE_64K: u8 = 0xD8;        // erase one 64K block of flash memory
 const SPIFLASH_CHIPERASE: u8 =  0x60;        // chip erase (may take several seconds depending on size)
                                               // but no actual need to wait for completion (instead need to check the status register BUSY bit)
+const SPIFLASH_CHIPERASE_ALT: u8 = 0xC7;        // chip erase, the only one accepted by some parts (e.g. Micron bulk erase)
 const SPIFLASH_STATUSREAD: u8 = 0x05;        // read status register
+const SPIFLASH_STATUSREAD_2: u8 = 0x35;        // read status register 2
+const SPIFLASH_STATUSREAD_3: u8 = 0x15;        // read status register 3
 const SPIFLASH_STATUSWRITE: u8 = 0x01;        // write status register
+const SPIFLASH_STATUSWRITE_3: u8 = 0x11;        // write status register 3
+const SPIFLASH_VOLATILESTATUSENABLE: u8 = 0x50;        // write enable for volatile status register
 const SPIFLASH_ARRAYREAD: u8 = 0x0B;        // read array (fast, need to add 1 dummy byte after 3 address bytes)
-const SPIFLASH_ARRAYREADLOWFREQ: u8 = 0x03;        // read array (low frequency)
+const SPIFLASH_ARRAYREADDUALIO: u8 = 0xBB;        // read array, address, mode byte and data on two lines
+const SPIFLASH_ARRAYREADQUADIO: u8 = 0xEB;        // read array, address, mode byte and data on four lines (need to add 2 dummy bytes after the mode byte)
+const SPIFLASH_CONTINUOUSREADRESET: u8 = 0xFF;        // exits continuous read mode when clocked in place of the address and mode byte
 
 const SPIFLASH_SLEEP: u8 = 0xB9;        // deep power down
 const SPIFLASH_WAKE: u8 = 0xAB;        // deep power wake up
 const SPIFLASH_BYTEPAGEPROGRAM: u8 = 0x02;        // write (1 to 256bytes)
+const SPIFLASH_QUADPAGEPROGRAM: u8 = 0x32;        // write (1 to 256bytes), data on four lines
 const SPIFLASH_IDREAD: u8 = 0x9F;        // read JEDEC manufacturer and device ID (2 bytes, specific bytes for each manufacturer and device)
+const SPIFLASH_IDREAD_QPI: u8 = 0xAF;        // read JEDEC manufacturer and device ID in QPI mode
                                               // Example for Atmel-Adesto 4Mbit AT25DF041A: 0x1F44 (page 27: http://www.adestotech.com/sites/default/files/datasheets/doc3668.pdf)
                                               // Example for Winbond 4Mbit W25X40CL: 0xEF30 (page 14: http://www.winbond.com/NR/rdonlyres/6E25084C-0BFE-4B25-903D-AE10221A0929/0/W25X40CL.pdf)
+const SPIFLASH_MANUFACTURERID: u8 = 0x90;        // read manufacturer and device ID (legacy, need to add 3 zero address bytes)
+const SPIFLASH_ENTER4BYTE: u8 = 0xB7;        // enter 4-byte address mode
+const SPIFLASH_EXIT4BYTE: u8 = 0xE9;        // exit 4-byte address mode
+const SPIFLASH_BANKWRITE_SPANSION: u8 = 0x17;        // write bank register, bit 7 selects 4-byte address mode (Spansion)
+const SPIFLASH_ENTERQPI: u8 = 0x38;        // enter QPI mode, all following commands are clocked on four lines
+const SPIFLASH_EXITQPI: u8 = 0xFF;        // exit QPI mode (sent on four lines)
 const SPIFLASH_MACREAD: u8 = 0x4B;        // read unique ID number (MAC)
+const SPIFLASH_SFDPREAD: u8 = 0x5A;        // read SFDP parameters (need to add 1 dummy byte after 3 address bytes)
+const SPIFLASH_BANKWRITE: u8 = 0xC5;        // write bank (extended address) register, selects the upper address byte in 3-byte mode
+const SPIFLASH_BANKREAD: u8 = 0xC8;        // read bank (extended address) register
+const SPIFLASH_FLAGSTATUSREAD: u8 = 0x70;        // read flag status register (Micron)
+const SPIFLASH_FLAGSTATUSCLEAR: u8 = 0x50;        // clear flag status register (Micron)
+const SPIFLASH_STATUSCLEAR: u8 = 0x30;        // clear program and erase error bits in status register 1 (Spansion)
+const SPIFLASH_NVCONFIGREAD: u8 = 0xB5;        // read non-volatile configuration register (Micron)
+const SPIFLASH_NVCONFIGWRITE: u8 = 0xB1;        // write non-volatile configuration register (Micron)
+const SPIFLASH_VCONFIGREAD: u8 = 0x85;        // read volatile configuration register (Micron)
+const SPIFLASH_VCONFIGWRITE: u8 = 0x81;        // write volatile configuration register (Micron)
+const SPIFLASH_EVCONFIGREAD: u8 = 0x65;        // read enhanced volatile configuration register (Micron)
+const SPIFLASH_EVCONFIGWRITE: u8 = 0x61;        // write enhanced volatile configuration register (Micron)
+const SPIFLASH_READANYREGISTER: u8 = 0x65;        // read the register at the following address (Infineon SEMPER)
+const SPIFLASH_RESETENABLE: u8 = 0x66;        // enable reset, must directly precede the reset command
+const SPIFLASH_RESET: u8 = 0x99;        // software reset, aborts any running operation and returns to the power-up state
+const SPIFLASH_GLOBALLOCK: u8 = 0x7E;        // set the individual block lock of every block (WPS = 1)
+const SPIFLASH_GLOBALUNLOCK: u8 = 0x98;        // clear the individual block lock of every block (WPS = 1)
+const SPIFLASH_BLOCKLOCKREAD: u8 = 0x3D;        // read the individual block lock of the block at the following address (WPS = 1)
+const SPIFLASH_SUSPEND: u8 = 0x75;        // suspend the running program or erase
+const SPIFLASH_RESUME: u8 = 0x7A;        // resume the suspended program or erase
+
+/// JEDEC manufacturer ID of Micron, whose parts have a flag status register.
+const MANUFACTURER_MICRON: u8 = 0x20;
+
+/// JEDEC manufacturer ID of Macronix, whose parts have no status register 2.
+const MANUFACTURER_MACRONIX: u8 = 0xC2;
+
+/// Number of bytes that can be reached with 3 address bytes.
+/// Everything above this has to go through the bank register.
+const BANK_SIZE: u32 = 1 << 24;
+
+/// Maximum number of bytes sent with a single page program.
+const MAX_PROGRAM: usize = 256;
+
+/// Worst-case time a chip takes to come back from a software reset while idle or reading (Winbond tRST).
+const RESET_RECOVERY_IDLE_US: u32 = 30;
+/// The same, but when the reset aborts a page program (Macronix tREADY2).
+const RESET_RECOVERY_PROGRAM_US: u32 = 310;
+/// The same, but when the reset aborts a sector or block erase, or a status register write (Macronix tREADY2).
+const RESET_RECOVERY_ERASE_US: u32 = 40_000;
+/// The same, but when the reset aborts a chip erase (Macronix tREADY2).
+const RESET_RECOVERY_CHIP_ERASE_US: u32 = 1_000_000;
+/// Worst-case time a chip takes to accept commands after the release from deep power-down (tRES1 of common parts).
+const RELEASE_POWER_DOWN_US: u32 = 30;
+/// How often the JEDEC ID is probed after a reset without a poll interval to derive the reset time from.
+const RESET_PROBES: u32 = 64;
+
+/// Most dummy bytes a read can be configured for, see `SPIFlash::set_dummy_cycles()`.
+const MAX_DUMMY_BYTES: usize = 8;
+
+/// Size of the command header buffer kept in `SPIFlash`.
+/// The longest header is an I/O read in 4-byte address mode:
+/// opcode, 4 address bytes, mode byte and the most dummy bytes.
+const HEADER_LEN: usize = 6 + MAX_DUMMY_BYTES;
+
+/// Bit 1 of status register 2.
+const STATUS_QE: u8 = 1 << 1;
+/// Bits 4:2 of status register 1.
+const STATUS_BP: u8 = 0b111 << 2;
+/// Bit 5 of status register 1.
+const STATUS_TB: u8 = 1 << 5;
+/// Bit 6 of status register 1.
+const STATUS_SEC: u8 = 1 << 6;
+/// Bit 6 of status register 2.
+const STATUS_CMP: u8 = 1 << 6;
+/// Bit 7 of status register 2.
+const STATUS_SUS: u8 = 1 << 7;
+/// Bit 7 of status register 1.
+const STATUS_SRP0: u8 = 1 << 7;
+/// Bit 0 of status register 2.
+const STATUS_SRP1: u8 = 1 << 0;
+
+/// Size of the stack buffer used when scanning through larger regions.
+const SCAN_CHUNK: usize = 64;
+
+/// How often a read segment is read again until two reads agree, see `SPIFlash::set_max_read_len()`.
+const SEGMENT_READS: u32 = 3;
 
 /// IMPORTANT: NAND FLASH memory requires erase before write, because
 ///            it can only transition from 1s to 0s and only the erase command can reset all 0s to 1s
 /// See http://en.wikipedia.org/wiki/Flash_memory
 /// The smallest range that can be erased is a sector (4K, 32K, 64K); there is also a chip erase command
 
-/// Constructor. JedecID is optional but recommended, since this will ensure that the device is present and has a valid response
-/// get this from the datasheet of your flash chip
-/// Example for Atmel-Adesto 4Mbit AT25DF041A: 0x1F44 (page 27: http://www.adestotech.com/sites/default/files/datasheets/doc3668.pdf)
-/// Example for Winbond 4Mbit W25X40CL: 0xEF30 (page 14: http://www.winbond.com/NR/rdonlyres/6E25084C-0BFE-4B25-903D-AE10221A0929/0/W25X40CL.pdf)
-
 use nb::block;
 use nb;
 
+use core::mem::MaybeUninit;
+use core::ops::Range;
+
+#[cfg(any(test, feature = "std"))]
+#[macro_use]
+extern crate std;
+
+mod cache;
+mod chips;
+mod counter;
+pub mod crc;
+mod cs_setup;
+#[cfg(feature = "diagnostics")]
+pub mod diagnostics;
+#[cfg(feature = "kv")]
+pub mod kv;
+#[cfg(feature = "ftdi")]
+mod ftdi;
+#[cfg(feature = "std")]
+mod io;
+#[cfg(any(feature = "kv", feature = "ringlog"))]
+mod log_sector;
+#[cfg(any(test, feature = "std"))]
+pub mod mock;
+#[cfg(feature = "bytemuck")]
+mod pod;
+#[cfg(feature = "ota")]
+pub mod ota;
+#[cfg(feature = "remap")]
+pub mod remap;
+#[cfg(feature = "ringlog")]
+pub mod ringlog;
+mod sfdp;
+mod stacked;
+mod verifier;
+mod wear;
+mod writer;
+
+pub use cache::SectorCache;
+pub use cs_setup::CsSetup;
+#[cfg(feature = "ftdi")]
+pub use crate::ftdi::FtdiTransmitter;
+#[cfg(feature = "std")]
+pub use io::FlashIo;
+pub use stacked::StackedFlash;
+pub use verifier::Verifier;
+pub use wear::EraseCounter;
+pub use writer::SectorWriter;
+
+/// Writes one hex dump line, padding short lines to `width` so the ASCII columns line up.
+fn write_hex_line<W: core::fmt::Write>(out: &mut W, address: u32, line: &[u8], width: usize) -> core::fmt::Result {
+    write!(out, "{:08x}:", address)?;
+    for byte in line {
+        write!(out, " {:02x}", byte)?;
+    }
+    for _ in line.len()..width {
+        out.write_str("   ")?;
+    }
+    out.write_str(" |")?;
+    for &byte in line {
+        out.write_char(if (0x20..0x7F).contains(&byte) { byte as char } else { '.' })?;
+    }
+    out.write_str("|\n")
+}
+
+/// Errors reported by the SPI Flash driver.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The SPI Flash refused the operation because the target is write protected.
+    Protected,
+    /// The chip does not implement SFDP, or its SFDP area could not be decoded.
+    NoSfdp,
+    /// The chip geometry is not known.
+    /// Either `identify()` was not called yet or the chip could not be identified.
+    Unidentified,
+    /// The chip or the transmitter does not support the requested operation.
+    Unsupported,
+    /// The requested range does not fit into the chip.
+    OutOfBounds,
+    /// The chip reported that a program operation failed.
+    ProgramFailed,
+    /// The chip reported that an erase operation failed.
+    EraseFailed,
+    /// The chip is busy with a program or erase, see `ReadDuringBusy`.
+    Busy,
+    /// A quad command was requested but the QE bit is not set.
+    QuadNotEnabled,
+    /// The chip's protection configuration differs from the expected one.
+    ProtectionMismatch,
+    /// The chip did not reach the expected state in time.
+    Timeout,
+    /// The operation was aborted by the caller.
+    Aborted,
+    /// A bad sector could not be remapped because all spare sectors are used up.
+    OutOfSpares,
+    /// The contents of the flash did not match the expected data, starting at the given address.
+    VerifyMismatch(u32),
+    /// A dry run write found a byte at the given address that would need an erase first.
+    NotErased(u32),
+    /// No terminating zero byte was found within the buffer.
+    Unterminated,
+    /// A string read from the flash is not valid UTF-8.
+    InvalidUtf8,
+    /// Writing formatted output failed.
+    Format,
+    /// The part did not reflect the requested address width after switching, see `ChipInfo::four_byte_flag`.
+    ModeSwitchFailed,
+    /// The key-value store has no room for the new value, even after compaction.
+    StoreFull,
+    /// The transmitter could not talk to the chip, e.g. because a USB bridge was unplugged.
+    Bus,
+    /// The chip kept answering with implausible values, even after a reset, see `SPIFlash::recover()`.
+    Wedged,
+    /// Neither copy of a redundant record has a valid CRC, see `SPIFlash::read_redundant()`.
+    BothCopiesBad,
+    /// The chip did not answer with a valid JEDEC ID after waking from deep power-down, see `SPIFlash::wakeup()`.
+    WakeFailed,
+    /// A configuration register read back differently from what was written to it.
+    ConfigMismatch,
+    /// SFDP and the JEDEC ID disagree about the capacity of the chip, see `SPIFlash::set_strict_geometry()`.
+    GeometryConflict,
+    /// A read segment starting at this address kept reading back differently, see `SPIFlash::set_max_read_len()`.
+    ReadUnstable(u32),
+    /// The chip did not set WEL after a write enable, so it ignores programs and erases. See `WelCheck`.
+    WriteNotEnabled,
+    /// An update image does not match its CRC-32, or a slot no longer holds its image. See `ota::OtaController`.
+    BadImage,
+    /// The source and the target of `SPIFlash::copy()` overlap, the copies of `SPIFlash::write_redundant()` share a sector,
+    /// or regions passed to `ota::OtaController::mount()` overlap.
+    Overlap,
+}
+
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            Error::Protected => f.write_str("the target is write protected"),
+            Error::NoSfdp => f.write_str("no valid SFDP area"),
+            Error::Unidentified => f.write_str("the chip geometry is not known"),
+            Error::Unsupported => f.write_str("the operation is not supported by the chip or the transmitter"),
+            Error::OutOfBounds => f.write_str("the range does not fit"),
+            Error::ProgramFailed => f.write_str("program failed"),
+            Error::EraseFailed => f.write_str("erase failed"),
+            Error::Busy => f.write_str("the chip is busy"),
+            Error::QuadNotEnabled => f.write_str("the QE bit is not set"),
+            Error::ProtectionMismatch => f.write_str("the protection configuration differs from the expected one"),
+            Error::Timeout => f.write_str("timed out"),
+            Error::Aborted => f.write_str("aborted"),
+            Error::OutOfSpares => f.write_str("no spare sector left"),
+            Error::VerifyMismatch(address) => write!(f, "verification failed at {:#010x}", address),
+            Error::NotErased(address) => write!(f, "not erased at {:#010x}", address),
+            Error::Unterminated => f.write_str("no terminating zero byte"),
+            Error::InvalidUtf8 => f.write_str("invalid UTF-8"),
+            Error::Format => f.write_str("formatting failed"),
+            Error::ModeSwitchFailed => f.write_str("the address width switch did not take"),
+            Error::StoreFull => f.write_str("the key-value store is full"),
+            Error::Bus => f.write_str("the transmitter could not talk to the chip"),
+            Error::Wedged => f.write_str("the chip does not respond plausibly"),
+            Error::BothCopiesBad => f.write_str("both copies of the record are corrupted"),
+            Error::WakeFailed => f.write_str("the chip did not wake up"),
+            Error::ConfigMismatch => f.write_str("the configuration register did not take the new value"),
+            Error::GeometryConflict => f.write_str("SFDP and the JEDEC ID disagree about the capacity"),
+            Error::ReadUnstable(address) => write!(f, "reads at {:#x} did not agree", address),
+            Error::WriteNotEnabled => f.write_str("the write enable did not take"),
+            Error::BadImage => f.write_str("the slot does not hold a valid image"),
+            Error::Overlap => f.write_str("regions which must be apart overlap"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for Error {}
+
+/// What reads do while the chip is busy with a program or erase.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadDuringBusy {
+    /// Reads fail with `Error::Busy`. This is the default, as a busy chip returns indeterminate data.
+    Reject,
+    /// Reads are issued anyway.
+    /// Only use this if you know the region being read is not the one being programmed or erased,
+    /// and the chip supports reads while busy.
+    Allow,
+}
+
+/// When programs and erases check that the chip took the write enable (WEL) sent before them.
+/// A write enable which does not take, e.g. because of a glitch on the bus, makes the chip silently ignore the operation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WelCheck {
+    /// WEL is never checked. Saves a status read per page, but an ignored write only shows when reading it back.
+    Never,
+    /// WEL is read back after every write enable, failing with `Error::WriteNotEnabled` if it is not set.
+    /// This is the default. It costs a status read per page and per erase.
+    EveryOp,
+    /// WEL is only checked once `verify_pattern()` or a `Verifier` found a mismatch:
+    /// a write enable is sent and read back, reporting `Error::WriteNotEnabled` instead of the mismatch if it does not take.
+    /// This keeps the fast path free of extra transfers while still telling a dead write enable from worn out cells.
+    OnError,
+}
+
+/// Whether status register writes go to the non-volatile bits or only to their volatile copies,
+/// which decides the write enable sent before them, see `SPIFlash::set_status_write_mode()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatusWriteMode {
+    /// The normal write enable (0x06) precedes the write, which persists across power cycles. This is the default.
+    NonVolatile,
+    /// The volatile status write enable (0x50) precedes the write, which is lost on power down.
+    /// This spares the non-volatile bits the wear and the longer write time, but not all parts support it.
+    /// On Micron parts 0x50 clears the flag status register instead.
+    Volatile,
+}
+
+/// The maximum number of extended device information bytes kept by `SPIFlash::read_jedec_id_extended()`.
+pub const MAX_EXTENDED_ID: usize = 16;
+
+/// The JEDEC manufacturer and device ID of a chip.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct JedecId {
+    pub manufacturer: u8,
+    pub memory_type: u8,
+    pub capacity: u8,
+    extended: [u8; MAX_EXTENDED_ID],
+    extended_len: u8,
+}
+
+impl JedecId {
+    /// Creates an ID without extended device information.
+    pub const fn new(manufacturer: u8, memory_type: u8, capacity: u8) -> Self {
+        Self {
+            manufacturer,
+            memory_type,
+            capacity,
+            extended: [0; MAX_EXTENDED_ID],
+            extended_len: 0,
+        }
+    }
+
+    /// The capacity in bytes the capacity byte stands for, if it follows one of the common encodings:
+    /// 2^N bytes for 0x10 to 0x1F, and the continuation 0x20 = 64 MiB to 0x22 = 256 MiB of Micron and Macronix.
+    /// Other values, like the bit-packed IDs of older Atmel parts, return `None`.
+    pub fn capacity_bytes(&self) -> Option<u32> {
+        match self.capacity {
+            0x10..=0x1F => Some(1 << self.capacity),
+            0x20..=0x22 => Some(1 << (self.capacity - 6)),
+            _ => None,
+        }
+    }
+
+    /// The extended device information following the three ID bytes, without its length byte.
+    /// Empty unless read by `SPIFlash::read_jedec_id_extended()` from a part which has some.
+    pub fn extended(&self) -> &[u8] {
+        &self.extended[..self.extended_len as usize]
+    }
+}
+
+/// The geometry of a SPI Flash, either discovered via SFDP or looked up from the chip database.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChipInfo {
+    /// Total size of the memory in bytes.
+    pub capacity: u32,
+    /// Size of the program page in bytes. A single program can not cross a page boundary.
+    pub page_size: u32,
+    /// Size of the smallest erasable unit in bytes.
+    pub sector_size: u32,
+    /// The supported erase types as (size in bytes, opcode), used by `SPIFlash::erase()`.
+    /// Unused entries have a size of zero.
+    pub erase_ops: [(u32, u8); 4],
+    /// Whether the part has a flag status register (0x70) reporting program and erase failures, like Micron parts.
+    pub flag_status: bool,
+    /// Whether the part has Micron-style volatile and non-volatile configuration registers.
+    pub config_registers: bool,
+    /// Whether status register 1 reports program (bit 6) and erase (bit 5) failures, like Spansion S25FL-S parts.
+    /// On other parts, including newer Spansion families, these bits configure the write protection,
+    /// so this is only set for the parts in the chip database which have them.
+    pub status_errors: bool,
+    /// Worst-case time of a page program in microseconds.
+    pub program_time_us: u32,
+    /// Worst-case time of a sector erase in microseconds.
+    pub erase_time_us: u32,
+    /// Worst-case time of a chip erase in microseconds.
+    pub chip_erase_time_us: u32,
+    /// The typical times from SFDP, if the part lists them, see `SPIFlash::estimated_duration()`.
+    /// The chip database only has worst-case times.
+    pub typical_times: Option<TypicalTimes>,
+    /// How the part switches to 4-byte addresses, see `SPIFlash::set_address_width()`.
+    pub four_byte_mode: FourByteMode,
+    /// The chip erase opcode, 0x60 or 0xC7. Some parts only accept one of them.
+    pub chip_erase_opcode: u8,
+    /// The address width the part powers up with, if known, see `SPIFlash::identify()`.
+    /// Parts with a non-volatile configuration bit for it can power up in either.
+    pub power_up_width: Option<AddressWidth>,
+    /// Where the part reflects 4-byte address mode, as (register read opcode, bit mask), if it does.
+    /// `SPIFlash::set_address_width()` reads it back to confirm the switch took.
+    pub four_byte_flag: Option<(u8, u8)>,
+    /// Dummy clocks of the dual I/O read after the mode byte, see `SPIFlash::dummy_cycles()`.
+    pub dual_io_dummy_cycles: u8,
+    /// Dummy clocks of the quad I/O read after the mode byte, see `SPIFlash::dummy_cycles()`.
+    pub quad_io_dummy_cycles: u8,
+    /// The smallest unit a program writes in bytes, see `SPIFlash::write_granularity()`.
+    /// Plain NOR takes single bytes, parts with on-die ECC program whole ECC units.
+    pub program_granularity: u32,
+    /// Whether a running erase can be suspended with 0x75, see `SPIFlash::suspend()`.
+    pub erase_suspend: bool,
+    /// Whether a running page program can be suspended with 0x75 as well. Fewer parts support this.
+    pub program_suspend: bool,
+}
+
+impl ChipInfo {
+    /// Creates a `ChipInfo` with the given capacity and the common 256 byte pages and 4K sectors.
+    /// The worst-case times are pessimistic enough for all common parts.
+    pub const fn new(capacity: u32) -> Self {
+        Self {
+            capacity,
+            page_size: 256,
+            sector_size: 4096,
+            erase_ops: [
+                (4 * 1024, SPIFLASH_BLOCKERASE_4K),
+                (32 * 1024, SPIFLASH_BLOCKERASE_32K),
+                (64 * 1024, SPIFLASH_BLOCKERASE_64K),
+                (0, 0),
+            ],
+            flag_status: false,
+            config_registers: false,
+            status_errors: false,
+            program_time_us: 10_000,
+            erase_time_us: 1_000_000,
+            chip_erase_time_us: 400_000_000,
+            typical_times: None,
+            four_byte_mode: FourByteMode::Command,
+            chip_erase_opcode: SPIFLASH_CHIPERASE,
+            power_up_width: None,
+            four_byte_flag: None,
+            dual_io_dummy_cycles: 0,
+            quad_io_dummy_cycles: 4,
+            program_granularity: 1,
+            erase_suspend: false,
+            program_suspend: false,
+        }
+    }
+
+    /// The worst-case time of `operation` in microseconds.
+    fn operation_time_us(&self, operation: Operation) -> u32 {
+        match operation {
+            Operation::Program => self.program_time_us,
+            // Larger blocks take longer, but never longer than erasing their sectors one by one.
+            Operation::Erase(size) => self.erase_time_us.saturating_mul((size / self.sector_size.max(1)).max(1)),
+            Operation::ChipErase => self.chip_erase_time_us,
+        }
+    }
+}
+
+/// The typical times of a part's operations in microseconds, as listed in its SFDP tables.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TypicalTimes {
+    /// Typical time of a page program.
+    pub program_us: u32,
+    /// Typical time of every erase type in `ChipInfo::erase_ops`, 0 for unused entries.
+    pub erase_us: [u32; 4],
+    /// Typical time of a chip erase.
+    pub chip_erase_us: u32,
+}
+
+/// The reads whose latency can be configured, see `SPIFlash::dummy_cycles()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadMode {
+    /// The single line fast read (0x0B) used by `SPIFlash::read_bytes()`.
+    Fast,
+    /// The dual I/O read (0xBB), see `SPIFlash::read_bytes_dual_io()`.
+    DualIo,
+    /// The quad I/O read (0xEB), see `SPIFlash::read_bytes_quad_io()`.
+    QuadIo,
+}
+
+impl ReadMode {
+    /// The number of clocks a byte takes after the opcode, on one, two or four lines.
+    fn clocks_per_byte(self) -> u8 {
+        match self {
+            ReadMode::Fast => 8,
+            ReadMode::DualIo => 4,
+            ReadMode::QuadIo => 2,
+        }
+    }
+
+    /// The number of dummy bytes `cycles` dummy clocks take on the lines of this read,
+    /// or `None` if they are not a whole number of bytes or too many.
+    fn dummy_bytes(self, cycles: u8) -> Option<usize> {
+        let bytes = (cycles / self.clocks_per_byte()) as usize;
+        if bytes as u8 * self.clocks_per_byte() != cycles || bytes > MAX_DUMMY_BYTES {
+            return None;
+        }
+        Some(bytes)
+    }
+}
+
+/// The number of address bytes sent with reads, programs and erases.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressWidth {
+    /// 3 address bytes, reaching 16 MiB. Larger parts go through the bank register.
+    ThreeByte,
+    /// 4 address bytes, reaching the whole chip.
+    FourByte,
+}
+
+/// How a part enters and leaves 4-byte address mode, as advertised in DWORD 16 of the SFDP basic table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FourByteMode {
+    /// With 0xB7 to enter and 0xE9 to leave.
+    Command,
+    /// With 0xB7 and 0xE9, each preceded by a write enable.
+    WriteEnableCommand,
+    /// By setting bit 7 of the bank register with 0x17, like Spansion parts.
+    BankRegister,
+    /// The part always uses 4-byte addresses.
+    Always,
+    /// The part can not switch to 4-byte addresses.
+    Unsupported,
+}
+
+/// How the status register is protected against writes, as configured by the SRP0/SRP1 bits.
+/// Hardware write protection via the /WP pin only takes effect in `HardwareProtected` mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WpMode {
+    /// SRP1 = 0, SRP0 = 0: The /WP pin has no effect.
+    /// The status register can be written after a write enable.
+    Software,
+    /// SRP1 = 0, SRP0 = 1: The status register is locked while /WP is low
+    /// and can be written after a write enable while /WP is high.
+    HardwareProtected,
+    /// SRP1 = 1, SRP0 = 0: The status register is locked until the next power cycle.
+    PowerLockDown,
+    /// SRP1 = 1, SRP0 = 1: The status register is permanently locked.
+    /// Not all parts support this. Setting it can not be undone.
+    OneTimeProgram,
+}
+
+/// Output driver strength as configured by the DRV1/DRV0 bits in status register 3.
+/// The percentages follow the common Winbond convention and are relative to the strongest setting.
+/// A weaker driver reduces ringing on short traces, a stronger one helps with long traces and high clock rates.
+///
+/// As a starting point, by the load on the data lines (traces, connectors and every other device on the bus):
+///
+/// | Load          | Setting      | Typical bus                                   |
+/// |---------------|--------------|-----------------------------------------------|
+/// | up to 10 pF   | `Percent25`  | flash right next to the MCU, short traces     |
+/// | 10 to 15 pF   | `Percent50`  | a few cm of trace on the same board           |
+/// | 15 to 30 pF   | `Percent75`  | longer traces, several devices on the bus     |
+/// | over 30 pF    | `Percent100` | cables, connectors or a probe on the lines    |
+///
+/// Too weak a driver shows up as read errors at high clock rates, too strong a one as ringing and EMI.
+/// Verify the choice with a scope or a read back test like `verify_pattern()` at the target clock rate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DriveStrength {
+    /// DRV1 = 0, DRV0 = 0
+    Percent100,
+    /// DRV1 = 0, DRV0 = 1
+    Percent75,
+    /// DRV1 = 1, DRV0 = 0
+    Percent50,
+    /// DRV1 = 1, DRV0 = 1
+    Percent25,
+}
+
+impl DriveStrength {
+    /// Bits 6:5 of status register 3.
+    const MASK: u8 = 0b11 << 5;
+
+    fn from_bits(bits: u8) -> Self {
+        match (bits & Self::MASK) >> 5 {
+            0b00 => DriveStrength::Percent100,
+            0b01 => DriveStrength::Percent75,
+            0b10 => DriveStrength::Percent50,
+            _ => DriveStrength::Percent25,
+        }
+    }
+
+    fn bits(self) -> u8 {
+        let bits = match self {
+            DriveStrength::Percent100 => 0b00,
+            DriveStrength::Percent75 => 0b01,
+            DriveStrength::Percent50 => 0b10,
+            DriveStrength::Percent25 => 0b11,
+        };
+        bits << 5
+    }
+}
+
+/// The contents of status register 1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StatusRegister(pub u8);
+
+impl StatusRegister {
+    /// A program, erase or status register write is in progress.
+    pub fn busy(&self) -> bool {
+        self.0 & (1 << 0) > 0
+    }
+
+    /// The write enable latch is set.
+    pub fn write_enabled(&self) -> bool {
+        self.0 & (1 << 1) > 0
+    }
+
+    /// The last program failed. Only meaningful if `ChipInfo::status_errors` is set.
+    pub fn program_error(&self) -> bool {
+        self.0 & (1 << 6) > 0
+    }
+
+    /// The last erase failed. Only meaningful if `ChipInfo::status_errors` is set.
+    pub fn erase_error(&self) -> bool {
+        self.0 & (1 << 5) > 0
+    }
+}
+
+/// The contents of status register 3, found on parts like the Winbond W25Q series.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StatusRegister3(pub u8);
+
+impl StatusRegister3 {
+    /// The configured output driver strength.
+    pub fn drive_strength(&self) -> DriveStrength {
+        DriveStrength::from_bits(self.0)
+    }
+
+    /// WPS: write protection uses the individual block locks instead of the block protect bits.
+    pub fn individual_locks(&self) -> bool {
+        self.0 & (1 << 2) > 0
+    }
+}
+
+/// The contents of the flag status register found on Micron parts.
+/// The error bits latch until cleared with `SPIFlash::clear_flag_status()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FlagStatus(pub u8);
+
+impl FlagStatus {
+    /// The program/erase controller is ready.
+    pub fn ready(&self) -> bool {
+        self.0 & (1 << 7) > 0
+    }
+
+    /// The last erase failed.
+    pub fn erase_error(&self) -> bool {
+        self.0 & (1 << 5) > 0
+    }
+
+    /// The last program failed.
+    pub fn program_error(&self) -> bool {
+        self.0 & (1 << 4) > 0
+    }
+
+    /// The last program or erase targeted a protected region.
+    pub fn protection_error(&self) -> bool {
+        self.0 & (1 << 1) > 0
+    }
+
+    /// An erase is suspended.
+    pub fn erase_suspended(&self) -> bool {
+        self.0 & (1 << 6) > 0
+    }
+
+    /// A program is suspended.
+    pub fn program_suspended(&self) -> bool {
+        self.0 & (1 << 2) > 0
+    }
+}
+
+/// The Micron volatile configuration register.
+/// Changes take effect immediately and are lost on power down.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VolatileConfig(pub u8);
+
+impl VolatileConfig {
+    /// Number of dummy clock cycles for fast reads, bits 7:4.
+    /// The number needed depends on the read command and the clock frequency, see the datasheet.
+    pub fn dummy_cycles(&self) -> u8 {
+        self.0 >> 4
+    }
+
+    /// Returns a copy with the dummy clock cycles set to `cycles`, which must be below 16.
+    pub fn with_dummy_cycles(self, cycles: u8) -> Self {
+        VolatileConfig(self.0 & 0x0F | (cycles & 0x0F) << 4)
+    }
+
+    /// Whether XIP is enabled, bit 3 (active low).
+    pub fn xip(&self) -> bool {
+        self.0 & (1 << 3) == 0
+    }
+}
+
+/// The Micron non-volatile configuration register.
+/// Its contents are loaded into the volatile registers on power up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NonVolatileConfig(pub u16);
+
+impl NonVolatileConfig {
+    /// Number of dummy clock cycles for fast reads after power up, bits 15:12.
+    /// 0 and 15 both select the default for the read command.
+    pub fn dummy_cycles(&self) -> u8 {
+        (self.0 >> 12) as u8
+    }
+
+    /// Returns a copy with the dummy clock cycles set to `cycles`, which must be below 16.
+    pub fn with_dummy_cycles(self, cycles: u8) -> Self {
+        NonVolatileConfig(self.0 & 0x0FFF | ((cycles & 0x0F) as u16) << 12)
+    }
+
+    /// Whether the part powers up with 4-byte addressing, bit 0 (active low).
+    pub fn four_byte_address(&self) -> bool {
+        self.0 & 1 == 0
+    }
+}
+
+/// The Micron enhanced volatile configuration register,
+/// controlling quad/dual protocol, the reset/hold function and the output driver strength.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EnhancedVolatileConfig(pub u8);
+
+impl EnhancedVolatileConfig {
+    /// Output driver strength selection, bits 2:0. See the datasheet for the impedance of each value.
+    pub fn output_driver(&self) -> u8 {
+        self.0 & 0b111
+    }
+}
+
+/// How much of an append-only region is consumed, see `SPIFlash::append_region_stats()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RegionStats {
+    /// Number of written bytes at the start of the region.
+    pub used: u32,
+    /// Number of erased bytes left at the end of the region. Zero if the region is full.
+    pub free: u32,
+}
+
+/// A partition placed by `SPIFlash::layout_partitions()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Partition {
+    /// Address of the first byte, aligned to the smallest erase size.
+    pub offset: u32,
+    /// The requested size in bytes. The padding up to the next partition is not included.
+    pub size: u32,
+}
+
+/// What the driver assumes about the chip's modes, as returned by `SPIFlash::state()`.
+/// This is the driver's bookkeeping, not something read from the chip, so comparing it to the chip's registers
+/// shows where the two went out of step.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DriverState {
+    /// The address width commands are sent with, see `SPIFlash::set_address_width()`.
+    pub address_width: AddressWidth,
+    /// Whether the chip is in QPI mode, see `SPIFlash::enter_qpi()`.
+    pub qpi: bool,
+    /// The mode byte of the continuous read mode the chip was left in, if any, see `SPIFlash::continuous_read_mode()`.
+    pub continuous_read: Option<u8>,
+    /// The program or erase issued without waiting for it, see `SPIFlash::flush()`.
+    pub pending: Option<Operation>,
+    /// The program or erase suspended by `SPIFlash::suspend()`.
+    pub suspended: Option<Operation>,
+    /// The bank register value last written to the chip.
+    pub bank: u8,
+    /// When the write enable is checked, see `SPIFlash::set_wel_check()`.
+    pub wel_check: WelCheck,
+    /// The write enable sent before status register writes, see `SPIFlash::set_status_write_mode()`.
+    pub status_write_mode: StatusWriteMode,
+}
+
+/// A sector of the chip, as yielded by `SPIFlash::sector_map()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sector {
+    /// Address of the first byte.
+    pub address: u32,
+    /// Size in bytes, the smallest erase size of the chip.
+    pub size: u32,
+}
+
+/// The erase units `SPIFlash::erase_containing()` works with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EraseSize {
+    /// The smallest erasable unit of the chip, see `SPIFlash::min_erase_size()`. 4K unless identified otherwise.
+    Sector,
+    /// A 32K block.
+    Block32K,
+    /// A 64K block.
+    Block64K,
+}
+
+/// A program or erase about to be issued, or a warning, reported to the hook set with `SPIFlash::set_trace_hook()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceEvent {
+    /// A page program of `len` bytes at `address`.
+    Program { address: u32, len: u32 },
+    /// An erase of the `size` byte block at `address`.
+    Erase { address: u32, size: u32 },
+    /// A chip erase.
+    ChipErase,
+    /// The capacity in the SFDP tables differs from the one the JEDEC ID stands for, see `SPIFlash::discover()`.
+    GeometryConflict { sfdp: u32, jedec: u32 },
+}
+
+/// Proof that a span of the flash was erased, returned by `SPIFlash::erase_range()`.
+/// `SPIFlash::write_erased()` requires it, so writing that flow without erasing first does not compile.
+/// It can only be created by erasing.
+#[derive(Debug, PartialEq, Eq)]
+pub struct ErasedRegion {
+    range: Range<u32>,
+}
+
+impl ErasedRegion {
+    /// The erased span, which covers whole blocks of the smallest erase size.
+    pub fn range(&self) -> Range<u32> {
+        self.range.clone()
+    }
+}
+
+/// A 4K sector whose contents may be destroyed, see `SPIFlash::probe_capacity()`.
+/// Creating one is the explicit consent to erase and program it.
+#[derive(Debug, PartialEq, Eq)]
+pub struct ScratchSector {
+    address: u32,
+}
+
+impl ScratchSector {
+    /// Gives up the contents of the 4K sector containing `address`.
+    pub fn consent_to_erase(address: u32) -> Self {
+        Self { address: address & !0xFFF }
+    }
+}
+
+/// The marker `SPIFlash::probe_capacity()` writes to its scratch sector.
+const PROBE_MARKER: [u8; 8] = *b"SPIFPRB1";
+
+/// The outcome of `SPIFlash::self_test_write_protect()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WpSelfTest {
+    /// Whether a status register write was rejected while /WP was low.
+    pub blocked_when_low: bool,
+    /// Whether a status register write was accepted again once /WP was high.
+    pub accepted_when_high: bool,
+}
+
+impl WpSelfTest {
+    /// Whether /WP behaves as expected, i.e. it is wired to the chip and not stuck.
+    pub fn passed(&self) -> bool {
+        self.blocked_when_low && self.accepted_when_high
+    }
+}
+
+/// The registers of a chip read in one go by `SPIFlash::diagnostics()`, e.g. to dump them when something goes wrong.
+/// Registers the part does not have are `None`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DiagSnapshot {
+    pub jedec_id: JedecId,
+    pub status: StatusRegister,
+    /// Status register 2, found on all parts except Micron and Macronix ones.
+    pub status_2: Option<u8>,
+    /// Status register 3, found on Winbond parts.
+    pub status_3: Option<StatusRegister3>,
+    /// The flag status register, found on Micron parts.
+    pub flag_status: Option<FlagStatus>,
+}
+
+/// Wear information decoded from a vendor register by `SPIFlash::health()`.
+/// Fields the register does not report are `None`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct HealthInfo {
+    /// The number of erase cycles the part counted.
+    pub erase_count: Option<u32>,
+    /// The estimated remaining lifetime in percent.
+    pub remaining_percent: Option<u8>,
+}
+
+/// A vendor register reporting wear, see `SPIFlash::set_health_register()`.
+#[derive(Debug, Clone, Copy)]
+pub struct HealthRegister {
+    /// The read opcode.
+    pub opcode: u8,
+    /// The register address sent after the opcode in the current address width, for registers read with
+    /// an addressed command like Read Any Register, or `None` for a register read with the opcode alone.
+    pub address: Option<u32>,
+    /// The number of dummy bytes between the address and the register contents, at most 4.
+    pub dummy_bytes: usize,
+    /// Decodes the first four bytes clocked out after the dummy bytes.
+    pub decode: fn([u8; 4]) -> HealthInfo,
+}
+
+impl HealthRegister {
+    /// The sector erase counter of Infineon SEMPER parts (S25HL-T, S25HS-T), read with Read Any Register (0x65)
+    /// at the default latency of 8 cycles. `address` is the erase count register in the part's register map.
+    /// The count is read as three bytes, little endian.
+    pub const fn semper_erase_count(address: u32) -> Self {
+        HealthRegister {
+            opcode: SPIFLASH_READANYREGISTER,
+            address: Some(address),
+            dummy_bytes: 1,
+            decode: |bytes| HealthInfo {
+                erase_count: Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], 0])),
+                remaining_percent: None,
+            },
+        }
+    }
+}
+
+/// The complete write protection configuration of a chip.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProtectionSummary {
+    /// The range protected by the block protect bits, if any.
+    pub protected: Option<Range<u32>>,
+    /// How the status register itself is protected.
+    pub wp_mode: WpMode,
+}
+
 pub trait Transmitter {
     fn send(&mut self, buffer: &[u8]);
     fn read(&mut self, buffer: &mut [u8]);
     fn send_read(&mut self, buffer_tx: & [u8], buffer_rx: &mut [u8]);
+
+    /// Asserts CS and keeps it asserted until `end()` is called.
+    /// While CS is held, `send`, `read` and `send_read` must only clock bytes and must not touch CS.
+    /// Returns `false` if the transmitter can not control CS explicitly, which is the default.
+    fn begin(&mut self) -> bool {
+        false
+    }
+
+    /// Deasserts CS after a successful `begin()`.
+    fn end(&mut self) {}
+
+    /// Like `read`, but into a buffer which need not be initialized, see `SPIFlash::read_bytes_uninit()`.
+    /// Returns `buffer` as the initialized slice; any other slice makes the read fail.
+    /// The default zero-fills `buffer` and forwards to `read`.
+    /// Transmitters whose DMA writes the buffer directly can skip the fill.
+    fn read_uninit<'a>(&mut self, buffer: &'a mut [MaybeUninit<u8>]) -> &'a mut [u8] {
+        let buffer = zero_fill(buffer);
+        self.read(buffer);
+        buffer
+    }
+
+    /// Like `send_read`, but into a buffer which need not be initialized, see `read_uninit()`.
+    /// The default zero-fills `buffer_rx` and forwards to `send_read`.
+    fn send_read_uninit<'a>(&mut self, buffer_tx: &[u8], buffer_rx: &'a mut [MaybeUninit<u8>]) -> &'a mut [u8] {
+        let buffer_rx = zero_fill(buffer_rx);
+        self.send_read(buffer_tx, buffer_rx);
+        buffer_rx
+    }
+
+    /// Whether `send_read` can clock out and in at the same time, which is the default.
+    /// Half-duplex transmitters return `false`. Every command that reads a response
+    /// (status, configuration and bank register reads, JEDEC ID, SFDP and array reads)
+    /// is then sent as a `send` followed by a `read` while CS is held by `begin()`.
+    /// If `begin()` fails, these commands are not sent: the reads returning a `Result`, including `identify()`,
+    /// fail with `Error::Unsupported`, and the plain register reads like `SPIFlash::read_status()` return zeros.
+    fn is_full_duplex(&self) -> bool {
+        true
+    }
+}
+
+/// Sends `command` and reads the response into `buffer`, in one CS assertion.
+/// Uses `send_read`, or `send` and `read` with CS held if the transmitter is half-duplex.
+/// Returns `Error::Unsupported` without sending anything if a half-duplex transmitter can not hold CS.
+fn command_read<T: Transmitter>(spi: &mut T, command: &[u8], buffer: &mut [u8]) -> Result<(), Error> {
+    if spi.is_full_duplex() {
+        spi.send_read(command, buffer);
+    } else if spi.begin() {
+        spi.send(command);
+        spi.read(buffer);
+        spi.end();
+    } else {
+        return Err(Error::Unsupported);
+    }
+    Ok(())
+}
+
+/// Zeroes `buffer` and returns it as the initialized slice.
+fn zero_fill(buffer: &mut [MaybeUninit<u8>]) -> &mut [u8] {
+    let len = buffer.len();
+    // SAFETY: all `len` bytes are initialized by `ptr::write_bytes` first, and `MaybeUninit<u8>` has the layout of `u8`.
+    unsafe {
+        core::ptr::write_bytes(buffer.as_mut_ptr(), 0, len);
+        core::slice::from_raw_parts_mut(buffer.as_mut_ptr() as *mut u8, len)
+    }
+}
+
+/// Like `command_read`, but into a buffer which need not be initialized, using the `_uninit` reads.
+/// Returns `Error::Bus` if the transmitter returns another slice than `buffer`, which leaves it uninitialized.
+fn command_read_uninit<T: Transmitter>(spi: &mut T, command: &[u8], buffer: &mut [MaybeUninit<u8>]) -> Result<(), Error> {
+    let (ptr, len) = (buffer.as_ptr() as *const u8, buffer.len());
+    let read = if spi.is_full_duplex() {
+        spi.send_read_uninit(command, buffer)
+    } else if spi.begin() {
+        spi.send(command);
+        let read = spi.read_uninit(buffer);
+        spi.end();
+        read
+    } else {
+        return Err(Error::Unsupported);
+    };
+    if read.as_ptr() != ptr || read.len() != len {
+        return Err(Error::Bus);
+    }
+    Ok(())
+}
+
+/// A transmitter that can clock data over two lines (IO0 and IO1).
+pub trait DualTransmitter: Transmitter {
+    /// Sends `single` on a single line, then `dual` on two lines,
+    /// then reads `buffer.len()` bytes on two lines, in one CS assertion.
+    fn read_dual(&mut self, single: &[u8], dual: &[u8], buffer: &mut [u8]);
 }
 
+/// A transmitter that can clock data over four lines (IO0 to IO3).
+pub trait QuadTransmitter: Transmitter {
+    /// Sends `header` on a single line, followed by `data` on four lines, in one CS assertion.
+    fn write_quad(&mut self, header: &[u8], data: &[u8]);
+
+    /// Sends `single` on a single line, then `quad` on four lines,
+    /// then reads `buffer.len()` bytes on four lines, in one CS assertion.
+    fn read_quad(&mut self, single: &[u8], quad: &[u8], buffer: &mut [u8]);
+
+    /// Switches QPI framing on or off. While it is on, everything, including the opcodes
+    /// and the `single` parts above, has to be clocked on four lines.
+    fn set_qpi(&mut self, enabled: bool);
+}
+
+/// A raw transaction on the bus, keeping CS asserted across all its calls.
+/// Created by `SPIFlash::transaction()`. CS is deasserted when it is dropped, even if the closure panics.
+pub struct Transaction<'a, T>
+where
+    T: Transmitter
+{
+    transmitter: &'a mut T,
+}
+
+impl<'a, T> Transaction<'a, T>
+where
+    T: Transmitter,
+{
+    /// Clocks out `buffer`.
+    pub fn push(&mut self, buffer: &[u8]) {
+        self.transmitter.send(buffer);
+    }
+
+    /// Clocks in `buffer.len()` bytes.
+    pub fn read(&mut self, buffer: &mut [u8]) {
+        self.transmitter.read(buffer);
+    }
+}
+
+impl<'a, T> Drop for Transaction<'a, T>
+where
+    T: Transmitter,
+{
+    fn drop(&mut self) {
+        self.transmitter.end();
+    }
+}
+
+/// A driver for a single SPI Flash chip.
+///
+/// The driver caches some of the chip's state, like the selected bank.
+/// This state belongs to the chip selected by the `Transmitter`'s CS line, not to the bus.
+/// Multiple chips can share one bus by giving each `SPIFlash` its own `Transmitter`
+/// that borrows the shared bus and drives a different CS line, see `examples/two_chips.rs`.
+/// Commands sent to one chip never reach the others, so their cached state stays valid.
+/// If something outside the driver talks to the chip with the same CS (e.g. a bootloader),
+/// call `read_bank()` afterwards to resynchronize.
 pub struct SPIFlash<T>
 where
     T: Transmitter
 {
-    spi: T,
+    transmitter: T,
+    /// The bank register value we last wrote to the chip.
+    /// Parts power up with bank 0 selected.
+    bank: u8,
+    /// The geometry found by `identify()`.
+    info: Option<ChipInfo>,
+    read_during_busy: ReadDuringBusy,
+    /// How long one status poll takes, see `set_poll_interval()`.
+    poll_interval_us: Option<u32>,
+    /// Reused for the command headers of reads and writes, so they do not need their own stack arrays.
+    header: Header,
+    /// Called for every program and erase and for warnings, see `set_trace_hook()`.
+    trace: Option<fn(TraceEvent)>,
+    /// The vendor register `health()` reads, if the part has one.
+    health_register: Option<HealthRegister>,
+    /// Whether programs and erases are only validated and traced, see `set_dry_run()`.
+    dry_run: bool,
+    /// Whether a geometry conflict fails `discover()`, see `set_strict_geometry()`.
+    strict_geometry: bool,
+    /// The value erased bytes read as, see `set_blank_byte()`.
+    blank_byte: u8,
+    /// Whether the chip is in QPI mode, see `enter_qpi()`.
+    qpi: bool,
+    /// The multi-line read and mode byte of the continuous read mode the chip is in, if any,
+    /// along with the transmitter method clocking that read, so `read_bytes()` can continue it.
+    continuous: Option<(IoRead, u8, IoReader<T>)>,
+    /// The program or erase issued without waiting for it, see `flush()`.
+    pending: Option<Operation>,
+    /// The addresses the pending operation works on.
+    pending_region: Range<u32>,
+    /// The operation put on hold by `suspend()` along with its region, see `resume()`.
+    suspended: Option<(Operation, Range<u32>)>,
+    /// The number of consecutive implausible status reads after which the chip is recovered, see `set_auto_recovery()`.
+    auto_recovery: Option<u32>,
+    /// The number of dummy bytes of every `ReadMode`, see `dummy_cycles()`.
+    dummy_bytes: [usize; 3],
+    /// The number of dummy bytes of every `ReadMode` once in continuous read mode, if it differs from `dummy_bytes`,
+    /// see `set_continuous_read_dummy()`.
+    continuous_dummy_bytes: [Option<usize>; 3],
+    /// When the write enable before programs and erases is checked, see `set_wel_check()`.
+    wel_check: WelCheck,
+    /// The write enable sent before status register writes, see `set_status_write_mode()`.
+    status_write_mode: StatusWriteMode,
+    /// The most bytes `read_bytes()` reads with one read command, see `set_max_read_len()`.
+    max_read_len: Option<usize>,
+    /// Whether every segment of a split read is checked by reading it again.
+    verify_segments: bool,
+    /// Whether the chip was last seen idle and nothing which could make it busy was sent since,
+    /// so reads can skip the BUSY check.
+    known_idle: bool,
+}
+
+/// The multi-line reads that support continuous read mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum IoRead {
+    Dual,
+    Quad,
+}
+
+impl IoRead {
+    /// The `ReadMode` whose dummy clocks this read uses.
+    fn read_mode(self) -> ReadMode {
+        match self {
+            IoRead::Dual => ReadMode::DualIo,
+            IoRead::Quad => ReadMode::QuadIo,
+        }
+    }
+}
+
+/// `DualTransmitter::read_dual` or `QuadTransmitter::read_quad`, whichever clocks the continuous read.
+type IoReader<T> = fn(&mut T, &[u8], &[u8], &mut [u8]);
+
+/// Whether `mode` makes the chip enter continuous read mode (M5-4 = 10).
+fn enters_continuous(mode: u8) -> bool {
+    mode & 0x30 == 0x20
+}
+
+/// Decodes the block protect bits of `status` and `status_2` on a chip of `capacity` bytes, see `SPIFlash::protected_range()`.
+fn decode_protection(capacity: u32, status: u8, status_2: u8) -> Option<Range<u32>> {
+    let bp = (status & STATUS_BP) >> 2;
+    let len = if bp == 0 {
+        0
+    } else if bp == 0b111 {
+        capacity
+    } else if status & STATUS_SEC > 0 {
+        // 4K sectors, up to 32K.
+        (4096 << (bp - 1).min(3)).min(capacity)
+    } else {
+        // 64K blocks on small parts, 1/64th of the chip on larger ones, doubling with every step.
+        ((capacity / 64).max(64 * 1024) << (bp - 1)).min(capacity)
+    };
+    let bottom = status & STATUS_TB > 0;
+    let range = match (status_2 & STATUS_CMP > 0, bottom) {
+        (false, false) => capacity - len..capacity,
+        (false, true) => 0..len,
+        // The complement protects everything but the selected range.
+        (true, false) => 0..capacity - len,
+        (true, true) => len..capacity,
+    };
+    if range.is_empty() { None } else { Some(range) }
+}
+
+/// The command header buffer, along with the address width it is filled with.
+struct Header {
+    buffer: [u8; HEADER_LEN],
+    width: AddressWidth,
+}
+
+impl Header {
+    /// Fills in `opcode` and the address, zeroing the rest for mode and dummy bytes.
+    /// Returns the number of bytes filled in.
+    fn fill(&mut self, opcode: u8, address: u32) -> usize {
+        let address_len = match self.width {
+            AddressWidth::ThreeByte => 3,
+            AddressWidth::FourByte => 4,
+        };
+        self.buffer = [0; HEADER_LEN];
+        self.buffer[0] = opcode;
+        self.buffer[1..1 + address_len].copy_from_slice(&address.to_be_bytes()[4 - address_len..]);
+        1 + address_len
+    }
+}
+
+/// The kinds of operations the chip can be busy with, each with its own worst-case time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    /// Programming a page.
+    Program,
+    /// Erasing a block of the given size in bytes. Anything up to a sector takes as long as a sector.
+    Erase(u32),
+    /// Erasing the whole chip.
+    ChipErase,
 }
 
 impl<T> SPIFlash<T>
 where
     T: Transmitter,
 {
-    pub fn new(spi: T) -> Self {
+    /// Creates a driver talking to the chip through `transmitter`.
+    pub fn new(transmitter: T) -> Self {
         Self {
-            spi
+            transmitter,
+            bank: 0,
+            info: None,
+            read_during_busy: ReadDuringBusy::Reject,
+            poll_interval_us: None,
+            header: Header {
+                buffer: [0; HEADER_LEN],
+                width: AddressWidth::ThreeByte,
+            },
+            trace: None,
+            dry_run: false,
+            strict_geometry: false,
+            blank_byte: 0xFF,
+            qpi: false,
+            continuous: None,
+            pending: None,
+            pending_region: 0..0,
+            suspended: None,
+            auto_recovery: None,
+            dummy_bytes: [1, 0, 2],
+            continuous_dummy_bytes: [None; 3],
+            wel_check: WelCheck::EveryOp,
+            status_write_mode: StatusWriteMode::NonVolatile,
+            max_read_len: None,
+            verify_segments: false,
+            known_idle: false,
+            health_register: None,
         }
     }
 
-    /// Checks whether the SPI flash is busy.
-    /// Returns `true` if it is still busy.
-    fn is_busy(&mut self) -> bool {
-        self.read_status() & 1 > 0
+    /// Sets a hook which is called with every program and erase right before it is issued,
+    /// and with warnings like a `TraceEvent::GeometryConflict`.
+    pub fn set_trace_hook(&mut self, hook: Option<fn(TraceEvent)>) {
+        self.trace = hook;
     }
 
-    /// Blocks until the SPI flash completes it's current action.
-    fn wait(&mut self) {
-        while self.is_busy() {};
+    /// Enables or disables dry run mode.
+    /// In dry run mode, writes and erases do all their validation (bounds, alignment, protection)
+    /// and report every program and erase to the trace hook, but never send a write enable, program or erase.
+    /// Writes additionally check that their target is erased enough to take the data,
+    /// returning `Error::NotErased` otherwise. Reads work as usual.
+    /// Note that `erase_range()` hands out an `ErasedRegion` even though nothing was erased.
+    pub fn set_dry_run(&mut self, dry_run: bool) {
+        self.dry_run = dry_run;
     }
 
-    /// Enables the write mode on the SPI Flash.
-    /// Blocks until the write mode is enabled.
-    fn enable_write(&mut self) {
-        self.spi.send(&[SPIFLASH_STATUSWRITE]);
-        self.wait()
+    /// Reports `event` to the trace hook and returns whether it should actually be issued.
+    fn trace(&self, event: TraceEvent) -> bool {
+        if let Some(hook) = self.trace {
+            hook(event);
+        }
+        !self.dry_run
     }
 
-    /// Reads the SPI Flash status.
-    /// Blocks until the read is done.
-    pub fn read_status(&mut self) -> u8 {
-        let mut byte = [0; 1];
-        self.spi.send_read(&[SPIFLASH_STATUSREAD], &mut byte);
-        byte[0]
+    /// Sets the value erased bytes read as, 0xFF by default.
+    /// Some vendor modes or overlays present another value as blank, e.g. by inverting the data.
+    /// `is_erased()`, `first_free_offset()`, `secure_erase_region()` and the dry run checks compare against it.
+    pub fn set_blank_byte(&mut self, blank: u8) {
+        self.blank_byte = blank;
     }
 
-    /// Reads a single byte at `address` from the SPI Flash and returns it.
-    /// Blocks until the read is done.
-    pub fn read_byte(&mut self, address: u32) -> u8 {
-        let mut byte = [0; 1];
-        self.spi.send_read(&[SPIFLASH_ARRAYREADLOWFREQ, (address >> 16) as u8, (address >> 8) as u8, (address) as u8, 0], &mut byte);
-        byte[0]
+    /// The value erased bytes read as, see `set_blank_byte()`.
+    pub fn blank_byte(&self) -> u8 {
+        self.blank_byte
     }
 
-    /// Reads a `buffer.len()` bytes at `address` from the SPI Flash and stores them in `buffer`.
-    /// Blocks until the read is done.
-    pub fn read_bytes(&mut self, address: u32, buffer: &mut [u8]) {
-        self.spi.send_read(&[SPIFLASH_ARRAYREADLOWFREQ, (address >> 16) as u8, (address >> 8) as u8, (address) as u8, 0], buffer);
+    /// Checks that `data` can be programmed at `address` without an erase,
+    /// i.e. that no bit would have to go back to its blank value (from 0 to 1 with the usual 0xFF).
+    fn check_programmable(&mut self, address: u32, data: &[u8]) -> Result<(), Error> {
+        let blank = self.blank_byte;
+        let mut buffer = [0; SCAN_CHUNK];
+        for (i, chunk) in data.chunks(SCAN_CHUNK).enumerate() {
+            let start = address + (i * SCAN_CHUNK) as u32;
+            let current = &mut buffer[..chunk.len()];
+            self.read_unchecked(start, current)?;
+            // Bits which were programmed already have to stay as they are.
+            if let Some(j) = current.iter().zip(chunk).position(|(old, new)| (old ^ blank) & (old ^ new) != 0) {
+                return Err(Error::NotErased(start + j as u32));
+            }
+        }
+        Ok(())
     }
 
-    /// Writes a single byte to the SPI Flash at `address`
-    /// Blocks until the write is done.
-    pub fn write_byte(&mut self, address: u32, byte: u8) {
-        self.enable_write();
-        self.spi.send(&[SPIFLASH_BYTEPAGEPROGRAM, (address >> 16) as u8, (address >> 8) as u8, (address) as u8, byte]);
+    /// Sets how long one status poll takes in microseconds, including any delay the transmitter adds.
+    /// With it, the blocking waits give up with `Error::Timeout` once the worst-case time
+    /// of the operation from `ChipInfo` has passed. Without it, which is the default, they wait forever.
+    pub fn set_poll_interval(&mut self, interval_us: Option<u32>) {
+        self.poll_interval_us = interval_us;
     }
 
-    /// Erase the entire flash memory.
-    /// Blocks until the erase is done. This can take up to several seconds.
-    pub fn chip_erase(&mut self) {
+    /// The number of polls after which `operation` has exceeded its worst-case time.
+    fn timeout(&self, operation: Operation) -> Option<u32> {
+        let interval = self.poll_interval_us?.max(1);
+        let time = self.info.unwrap_or_else(|| ChipInfo::new(0)).operation_time_us(operation);
+        Some(time / interval + 1)
+    }
+
+    /// How long `operation` typically blocks the chip, in microseconds, from the SFDP tables of the identified part.
+    /// Erases use the time listed for their erase type.
+    /// Useful to show progress or to decide whether to start a long operation now.
+    /// Returns `None` if the chip was not identified or its tables have no typical times,
+    /// like parts found in the chip database, which only lists worst-case times.
+    pub fn estimated_duration(&self, operation: Operation) -> Option<u32> {
+        let info = self.info?;
+        let times = info.typical_times?;
+        match operation {
+            Operation::Program => Some(times.program_us),
+            Operation::Erase(size) => {
+                let typical = info.erase_ops.iter().zip(times.erase_us.iter()).find(|((op_size, _), _)| *op_size == size);
+                typical.map(|(_, &time)| time)
+            }
+            Operation::ChipErase => Some(times.chip_erase_us),
+        }
+    }
+
+    /// The transmitter, for sending commands which start with an opcode.
+    /// Leaves continuous read mode first if it is active, as the chip would take the opcode for an address.
+    fn bus(&mut self) -> &mut T {
+        self.leave_continuous();
+        &mut self.transmitter
+    }
+
+    /// Leaves continuous read mode if the chip is in it.
+    fn leave_continuous(&mut self) {
+        if self.continuous.is_some() {
+            self.reset_continuous_read();
+        }
+    }
+
+    /// Checks whether the SPI flash is busy, e.g. with an operation issued by one of the `_async` methods.
+    /// Returns `true` if it is still busy.
+    pub fn is_busy(&mut self) -> bool {
+        let busy = StatusRegister(self.read_status()).busy();
+        self.known_idle = !busy;
+        busy
+    }
+
+    /// Blocks until the SPI flash completes it's current action.
+    fn wait(&mut self) {
+        // Without a timeout, this can not fail.
+        let _ = self.wait_for_status(|status| !status.busy(), None);
+    }
+
+    /// Polls status register 1 until `predicate` returns `true`.
+    /// `timeout` is the maximum number of polls, `None` waits forever.
+    /// Returns `Error::Timeout` if the condition was not met in time.
+    pub fn wait_for_status<F: Fn(StatusRegister) -> bool>(&mut self, predicate: F, timeout: Option<u32>) -> Result<(), Error> {
+        self.poll_status(predicate, || false, timeout)
+    }
+
+    /// Like `wait_for_status`, but stops with `Error::Aborted` as soon as `abort` returns `true`.
+    /// If the transmitter can hold CS, 0x05 is sent once and the chip keeps clocking out
+    /// the current status for every further byte read, which halves the bus traffic of every poll.
+    /// Otherwise every poll is a separate status read.
+    fn poll_status(&mut self, predicate: impl Fn(StatusRegister) -> bool, abort: impl Fn() -> bool, timeout: Option<u32>) -> Result<(), Error> {
+        let continuous = self.bus().begin();
+        if continuous {
+            self.transmitter.send(&[SPIFLASH_STATUSREAD]);
+        }
+        let mut polls = 0;
+        let mut suspicious = 0;
+        let result = loop {
+            let status = if continuous {
+                let mut byte = [0; 1];
+                self.transmitter.read(&mut byte);
+                byte[0]
+            } else {
+                self.read_status()
+            };
+            if predicate(StatusRegister(status)) {
+                self.known_idle = !StatusRegister(status).busy();
+                break Ok(());
+            }
+            // A chip which does not drive the bus at all reads as all ones, which is never a real status.
+            suspicious = if status == 0xFF { suspicious + 1 } else { 0 };
+            if matches!(self.auto_recovery, Some(limit) if suspicious >= limit) {
+                break Err(Error::Wedged);
+            }
+            if abort() {
+                break Err(Error::Aborted);
+            }
+            if matches!(timeout, Some(timeout) if polls >= timeout) {
+                break Err(Error::Timeout);
+            }
+            polls += 1;
+        };
+        if continuous {
+            self.transmitter.end();
+        }
+        result
+    }
+
+    /// Blocks until the current program or erase is done and checks whether it succeeded.
+    /// Only parts with a flag status register or error bits in status register 1 can report failures.
+    fn finish(&mut self, operation: Operation) -> Result<(), Error> {
+        self.finish_abortable(operation, || false)
+    }
+
+    /// Blocks until the program or erase issued by one of the `_async` methods is done
+    /// and checks whether it succeeded, just like the blocking forms do.
+    /// Returns immediately if nothing is pending.
+    /// Every `_async` method flushes the previous operation first, so its errors are never lost.
+    ///
+    /// Only page programs and erases have `_async` forms. Writes of the status, configuration, bank and lock registers
+    /// always block: they take a few milliseconds at most, and the driver's bookkeeping of the modes they set
+    /// (quad enable, the selected bank, protection) must not run ahead of the chip.
+    pub fn flush(&mut self) -> Result<(), Error> {
+        self.flush_abortable(|| false)
+    }
+
+    /// Like `flush`, but stops waiting with `Error::Aborted` as soon as `abort` returns `true`.
+    /// The operation stays pending in that case, so the next `flush()` waits for it again.
+    pub fn flush_abortable(&mut self, abort: impl Fn() -> bool) -> Result<(), Error> {
+        let operation = match self.pending.take() {
+            Some(operation) => operation,
+            None => return Ok(()),
+        };
+        let result = self.finish_abortable(operation, abort);
+        if result == Err(Error::Aborted) {
+            self.pending = Some(operation);
+        }
+        result
+    }
+
+    /// Suspends the program or erase issued by one of the `_async` methods, so latency-critical reads can go ahead,
+    /// and returns which operation it was. Returns `None` if nothing is pending or the operation finished in the meantime,
+    /// in which case its result is checked just like `flush()` does.
+    /// Until `resume()`, reads of the addresses the operation works on return `Error::Busy`, as their contents are undefined.
+    /// So do all programs and erases: most parts only accept reads while suspended.
+    /// Returns `Error::Unsupported` for operations the part can not suspend, see `ChipInfo::program_suspend`,
+    /// and always for a chip erase.
+    /// Blocks for the suspend latency of the chip, a few tens of microseconds.
+    pub fn suspend(&mut self) -> Result<Option<Operation>, Error> {
+        if let Some((operation, _)) = self.suspended {
+            return Ok(Some(operation));
+        }
+        let operation = match self.pending {
+            Some(operation) => operation,
+            None => return Ok(None),
+        };
+        let info = self.identified()?;
+        let supported = match operation {
+            Operation::Program => info.program_suspend,
+            Operation::Erase(_) => info.erase_suspend,
+            Operation::ChipErase => false,
+        };
+        if !supported {
+            return Err(Error::Unsupported);
+        }
+        self.bus().send(&[SPIFLASH_SUSPEND]);
+        self.wait();
+        // Parts with a flag status register tell which operation is suspended, the others have a single SUS bit.
+        let suspended = if info.flag_status {
+            let flags = self.read_flag_status();
+            flags.program_suspended() || flags.erase_suspended()
+        } else {
+            self.read_status_2() & STATUS_SUS > 0
+        };
+        if !suspended {
+            self.flush()?;
+            return Ok(None);
+        }
+        self.pending = None;
+        self.suspended = Some((operation, self.pending_region.clone()));
+        Ok(Some(operation))
+    }
+
+    /// Resumes the operation put on hold by `suspend()`, which is pending again afterwards, see `flush()`.
+    /// Does nothing if nothing is suspended.
+    pub fn resume(&mut self) {
+        if let Some((operation, region)) = self.suspended.take() {
+            self.bus().send(&[SPIFLASH_RESUME]);
+            self.known_idle = false;
+            self.pending = Some(operation);
+            self.pending_region = region;
+        }
+    }
+
+    /// The operation put on hold by `suspend()`, if any.
+    pub fn suspended(&self) -> Option<Operation> {
+        self.suspended.as_ref().map(|(operation, _)| *operation)
+    }
+
+    /// Returns `Error::Busy` if a read of `len` bytes at `address` touches the region of a suspended operation.
+    fn check_suspended(&self, address: u32, len: u32) -> Result<(), Error> {
+        match &self.suspended {
+            Some((_, region)) if len > 0 && region.start < address.saturating_add(len) && address < region.end => Err(Error::Busy),
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns `Error::Busy` while an operation is suspended, as programs and erases have to wait for `resume()`.
+    fn check_not_suspended(&self) -> Result<(), Error> {
+        if self.suspended.is_some() {
+            return Err(Error::Busy);
+        }
+        Ok(())
+    }
+
+    /// Like `finish`, but stops waiting with `Error::Aborted` as soon as `abort` returns `true`.
+    /// The chip keeps working on the operation in that case.
+    fn finish_abortable(&mut self, operation: Operation, abort: impl Fn() -> bool) -> Result<(), Error> {
+        let timeout = self.timeout(operation);
+        self.poll_status(|status| !status.busy(), abort, timeout)?;
+        if matches!(self.info, Some(info) if info.status_errors) {
+            let status = StatusRegister(self.read_status());
+            if status.program_error() || status.erase_error() {
+                self.bus().send(&[SPIFLASH_STATUSCLEAR]);
+            }
+            if status.program_error() {
+                return Err(Error::ProgramFailed);
+            } else if status.erase_error() {
+                return Err(Error::EraseFailed);
+            }
+        }
+        if !matches!(self.info, Some(info) if info.flag_status) {
+            return Ok(());
+        }
+        let flags = self.read_flag_status();
+        if flags.protection_error() || flags.program_error() || flags.erase_error() {
+            self.clear_flag_status();
+        }
+        if flags.protection_error() {
+            Err(Error::Protected)
+        } else if flags.program_error() {
+            Err(Error::ProgramFailed)
+        } else if flags.erase_error() {
+            Err(Error::EraseFailed)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Enables or disables automatic recovery of a wedged chip, which is off by default.
+    /// With it, a status poll gives up with `Error::Wedged` after `suspicious_reads` consecutive reads of 0xFF,
+    /// and the blocking erases, chip erases and page programs run `recover()` when they fail with
+    /// `Error::Wedged` or `Error::Timeout` (a BUSY bit stuck beyond the worst-case time, see `set_poll_interval()`),
+    /// then retry the operation once. If that fails as well, the error is returned.
+    ///
+    /// The reset aborts whatever the chip was doing. The retry erases or programs the same bytes again,
+    /// but a program or erase aborted by anything else than the retried operation, e.g. one issued by an `_async` method,
+    /// is lost and leaves its bytes in an undefined state. The `_async` methods and `flush()` are never retried.
+    pub fn set_auto_recovery(&mut self, suspicious_reads: Option<u32>) {
+        self.auto_recovery = suspicious_reads.map(|reads| reads.max(1));
+    }
+
+    /// Resets the chip with 0x66 0x99 and probes it again, to get a wedged chip back into a known state.
+    /// The driver state the reset invalidates (bank register, address width, continuous read mode,
+    /// a pending `_async` operation) is reset along with it, and a chip identified before is identified again.
+    /// The JEDEC ID is probed until it is plausible, for the reset time derived from the poll interval if one is set.
+    ///
+    /// WARNING: A reset aborts any program or erase in progress, leaving the affected bytes in an undefined state,
+    ///          and discards all volatile configuration, like write protection set with volatile status register writes.
+    ///          Only use it when the chip is unresponsive anyway, and erase and rewrite whatever was being changed.
+    /// Returns `Error::Wedged` if the chip still does not answer plausibly,
+    /// and `Error::Unsupported` in QPI mode, which `exit_qpi()` has to leave first.
+    pub fn recover(&mut self) -> Result<(), Error> {
+        if self.qpi {
+            return Err(Error::Unsupported);
+        }
+        let recovery_us = self.reset_recovery_us();
+        self.issue_reset();
+
+        let probes = self.poll_interval_us.map_or(RESET_PROBES, |interval| recovery_us / interval.max(1) + 1);
+        for _ in 0..probes {
+            let id = self.read_jedec_id();
+            if id.manufacturer != 0x00 && id.manufacturer != 0xFF {
+                if self.info.is_some() {
+                    self.identify()?;
+                }
+                return Ok(());
+            }
+        }
+        Err(Error::Wedged)
+    }
+
+    /// Resets the chip with 0x66 0x99 and waits until it accepts commands again, calling `delay_us` with the wait in microseconds.
+    /// The wait is the worst-case recovery time of the common datasheets for what the reset interrupts:
+    /// 30 µs while idle (Winbond tRST), 310 µs during a page program, 40 ms during a sector or block erase
+    /// or an unknown operation, and 1 s during a chip erase (Macronix tREADY2).
+    /// Which one applies follows from the operation pending from an `_async` method or suspended by `suspend()`,
+    /// or the BUSY bit otherwise.
+    /// The driver state the reset invalidates is reset along with it, just like with `recover()`.
+    ///
+    /// WARNING: A reset aborts any program or erase in progress, leaving the affected bytes in an undefined state,
+    ///          and discards all volatile configuration.
+    /// Returns `Error::Unsupported` in QPI mode, which `exit_qpi()` has to leave first.
+    pub fn reset(&mut self, mut delay_us: impl FnMut(u32)) -> Result<(), Error> {
+        if self.qpi {
+            return Err(Error::Unsupported);
+        }
+        let recovery_us = self.reset_recovery_us();
+        self.issue_reset();
+        delay_us(recovery_us);
+        Ok(())
+    }
+
+    /// The worst-case time the chip takes to recover from a reset issued now.
+    /// A suspended operation is aborted by the reset just like a running one, so the longer of both applies.
+    fn reset_recovery_us(&mut self) -> u32 {
+        let recovery_us = |operation| match operation {
+            Operation::Program => RESET_RECOVERY_PROGRAM_US,
+            Operation::Erase(_) => RESET_RECOVERY_ERASE_US,
+            Operation::ChipErase => RESET_RECOVERY_CHIP_ERASE_US,
+        };
+        let pending = self.pending.map(recovery_us);
+        let suspended = self.suspended.as_ref().map(|(operation, _)| recovery_us(*operation));
+        match pending.max(suspended) {
+            Some(us) => us,
+            None if self.is_busy() => RESET_RECOVERY_ERASE_US,
+            None => RESET_RECOVERY_IDLE_US,
+        }
+    }
+
+    /// Sends 0x66 0x99 and resets the driver state to what the chip powers up with.
+    fn issue_reset(&mut self) {
+        self.bus().send(&[SPIFLASH_RESETENABLE]);
+        self.transmitter.send(&[SPIFLASH_RESET]);
+        self.known_idle = false;
+        self.bank = 0;
+        self.header.width = match self.info {
+            Some(info) if info.four_byte_mode == FourByteMode::Always => AddressWidth::FourByte,
+            _ => AddressWidth::ThreeByte,
+        };
+        self.pending = None;
+        self.suspended = None;
+    }
+
+    /// Runs `operation`. If it fails the way a wedged chip does and auto-recovery is enabled,
+    /// the chip is recovered and `operation` runs once more, see `set_auto_recovery()`.
+    fn with_recovery(&mut self, mut operation: impl FnMut(&mut Self) -> Result<(), Error>) -> Result<(), Error> {
+        match operation(self) {
+            Err(Error::Wedged) | Err(Error::Timeout) if self.auto_recovery.is_some() => {
+                self.recover()?;
+                operation(self)
+            }
+            result => result,
+        }
+    }
+
+    /// Enables the write mode on the SPI Flash.
+    /// Blocks until the previous operation is done first, as a busy chip ignores the write enable.
+    fn enable_write(&mut self) {
+        self.wait();
+        self.bus().send(&[SPIFLASH_WRITEENABLE]);
+        // Whatever follows a write enable may start an operation.
+        self.known_idle = false;
+    }
+
+    /// Enables the write mode before a program or erase, checking that it took if the `WelCheck` policy asks for it.
+    fn enable_write_checked(&mut self) -> Result<(), Error> {
         self.enable_write();
-        self.spi.send(&[SPIFLASH_CHIPERASE]);
+        if self.wel_check == WelCheck::EveryOp && !StatusRegister(self.read_status()).write_enabled() {
+            return Err(Error::WriteNotEnabled);
+        }
+        Ok(())
+    }
+
+    /// Sets which write enable the status register writes send, and so whether they persist, see `StatusWriteMode`.
+    /// This applies to every method changing status register bits, like `set_quad_enable()` or `set_drive_strength()`.
+    pub fn set_status_write_mode(&mut self, mode: StatusWriteMode) {
+        self.status_write_mode = mode;
+    }
+
+    /// Sends the write enable matching the `StatusWriteMode` before a status register write.
+    fn enable_status_write(&mut self) {
         self.wait();
+        let opcode = match self.status_write_mode {
+            StatusWriteMode::NonVolatile => SPIFLASH_WRITEENABLE,
+            StatusWriteMode::Volatile => SPIFLASH_VOLATILESTATUSENABLE,
+        };
+        self.bus().send(&[opcode]);
+        self.known_idle = false;
     }
 
-    /// Erase a 4k block of the memory.
-    /// Blocks until the erase is done.
-    pub fn erase_4k_block(&mut self, address: u32) {
+    /// Sets when the write enable before programs and erases is checked, see `WelCheck`.
+    pub fn set_wel_check(&mut self, policy: WelCheck) {
+        self.wel_check = policy;
+    }
+
+    /// Turns a verify `error` into `Error::WriteNotEnabled` if the policy is `WelCheck::OnError`
+    /// and a write enable does not take now either, which explains the mismatch better than the cells do.
+    pub(crate) fn diagnose_mismatch(&mut self, error: Error) -> Error {
+        if self.wel_check != WelCheck::OnError || !matches!(error, Error::VerifyMismatch(_)) || self.dry_run {
+            return error;
+        }
         self.enable_write();
-        // Sanitize the address where we erase at.
-        let aligned_address = address | !0xFFF;
-        self.spi.send(&[SPIFLASH_BLOCKERASE_4K, (aligned_address >> 16) as u8, (aligned_address >> 8) as u8, (aligned_address) as u8]);
+        let enabled = StatusRegister(self.read_status()).write_enabled();
+        self.bus().send(&[SPIFLASH_WRITEDISABLE]);
+        if enabled { error } else { Error::WriteNotEnabled }
+    }
+
+    /// Selects the 16 MiB bank which 3-byte addresses refer to.
+    /// This is only needed for parts above 16 MiB which do not implement true 4-byte commands.
+    /// Reads, programs and erases above 16 MiB select the bank automatically, so calling this by hand is rarely needed.
+    pub fn set_bank(&mut self, bank: u8) {
+        self.enable_write();
+        self.bus().send(&[SPIFLASH_BANKWRITE, bank]);
+        self.bank = bank;
+    }
+
+    /// Reads the bank register from the SPI Flash.
+    /// Blocks until the read is done.
+    pub fn read_bank(&mut self) -> u8 {
+        let mut byte = [0; 1];
+        let _ = command_read(self.bus(), &[SPIFLASH_BANKREAD], &mut byte);
+        self.bank = byte[0];
+        byte[0]
+    }
+
+    /// Makes sure the bank containing `address` is selected.
+    /// Only issues a bank write if the bank actually changes.
+    fn select_bank(&mut self, address: u32) {
+        if self.header.width == AddressWidth::FourByte {
+            return;
+        }
+        let bank = (address >> 24) as u8;
+        if bank != self.bank {
+            self.set_bank(bank);
+        }
+    }
+
+    /// Switches between 3-byte and 4-byte addresses, using the method from `ChipInfo::four_byte_mode`.
+    /// Without a `ChipInfo`, 0xB7 and 0xE9 are used.
+    /// In 4-byte address mode, the bank register is not used.
+    /// If the part reflects the mode in a register (see `ChipInfo::four_byte_flag`), it is read back
+    /// and `Error::ModeSwitchFailed` is returned if the switch did not take, keeping the previous width.
+    /// Returns `Error::Unsupported` if the part can not use the requested width.
+    pub fn set_address_width(&mut self, width: AddressWidth) -> Result<(), Error> {
+        let mode = self.info.map_or(FourByteMode::Command, |info| info.four_byte_mode);
+        let four_byte = width == AddressWidth::FourByte;
+        match mode {
+            FourByteMode::Command | FourByteMode::WriteEnableCommand => {
+                if mode == FourByteMode::WriteEnableCommand {
+                    self.enable_write();
+                }
+                self.bus().send(&[if four_byte { SPIFLASH_ENTER4BYTE } else { SPIFLASH_EXIT4BYTE }]);
+            }
+            FourByteMode::BankRegister => {
+                self.bus().send(&[SPIFLASH_BANKWRITE_SPANSION, if four_byte { 1 << 7 } else { 0 }]);
+                self.bank = 0;
+            }
+            FourByteMode::Always if four_byte => {}
+            FourByteMode::Unsupported if !four_byte => {}
+            _ => return Err(Error::Unsupported),
+        }
+        if let Some((opcode, mask)) = self.info.and_then(|info| info.four_byte_flag) {
+            let mut byte = [0; 1];
+            command_read(self.bus(), &[opcode], &mut byte)?;
+            if (byte[0] & mask != 0) != four_byte {
+                return Err(Error::ModeSwitchFailed);
+            }
+        }
+        self.header.width = width;
+        Ok(())
+    }
+
+    /// The address width currently in use.
+    pub fn address_width(&self) -> AddressWidth {
+        self.header.width
+    }
+
+    /// A snapshot of the modes the driver assumes the chip to be in, for debugging. Does not touch the bus.
+    pub fn state(&self) -> DriverState {
+        DriverState {
+            address_width: self.header.width,
+            qpi: self.qpi,
+            continuous_read: self.continuous_read_mode(),
+            pending: self.pending,
+            suspended: self.suspended(),
+            bank: self.bank,
+            wel_check: self.wel_check,
+            status_write_mode: self.status_write_mode,
+        }
+    }
+
+    /// Reads the SPI Flash status.
+    /// Blocks until the read is done.
+    pub fn read_status(&mut self) -> u8 {
+        let mut byte = [0; 1];
+        let _ = command_read(self.bus(), &[SPIFLASH_STATUSREAD], &mut byte);
+        byte[0]
+    }
+
+    /// Reads the flag status register found on Micron parts.
+    /// Blocks until the read is done.
+    pub fn read_flag_status(&mut self) -> FlagStatus {
+        let mut byte = [0; 1];
+        let _ = command_read(self.bus(), &[SPIFLASH_FLAGSTATUSREAD], &mut byte);
+        FlagStatus(byte[0])
+    }
+
+    /// Clears the latched error bits in the flag status register found on Micron parts.
+    pub fn clear_flag_status(&mut self) {
+        self.bus().send(&[SPIFLASH_FLAGSTATUSCLEAR]);
+    }
+
+    /// Clears latched program and erase errors, either in status register 1 or in the flag status register.
+    /// Returns `Error::Unsupported` if the part reports neither.
+    pub fn clear_errors(&mut self) -> Result<(), Error> {
+        match self.info {
+            Some(info) if info.status_errors => self.bus().send(&[SPIFLASH_STATUSCLEAR]),
+            Some(info) if info.flag_status => self.clear_flag_status(),
+            _ => return Err(Error::Unsupported),
+        }
+        Ok(())
+    }
+
+    /// Fails with `Error::Unsupported` if the part has no Micron-style configuration registers.
+    fn ensure_config_registers(&self) -> Result<(), Error> {
+        match self.info {
+            Some(info) if info.config_registers => Ok(()),
+            Some(_) => Err(Error::Unsupported),
+            None => Err(Error::Unidentified),
+        }
+    }
+
+    /// Reads the volatile configuration register of Micron parts.
+    /// Returns `Error::Unsupported` for parts without it.
+    pub fn read_volatile_config(&mut self) -> Result<VolatileConfig, Error> {
+        self.ensure_config_registers()?;
+        let mut byte = [0; 1];
+        command_read(self.bus(), &[SPIFLASH_VCONFIGREAD], &mut byte)?;
+        Ok(VolatileConfig(byte[0]))
+    }
+
+    /// Writes the volatile configuration register of Micron parts and reads it back,
+    /// as reads framed for dummy cycles the chip did not take return garbage.
+    /// Returns `Error::ConfigMismatch` if it reads back differently, and `Error::Unsupported` for parts without it.
+    pub fn write_volatile_config(&mut self, config: VolatileConfig) -> Result<(), Error> {
+        self.ensure_config_registers()?;
+        self.enable_write();
+        self.bus().send(&[SPIFLASH_VCONFIGWRITE, config.0]);
+        self.finish(Operation::Program)?;
+        if self.read_volatile_config()? != config {
+            return Err(Error::ConfigMismatch);
+        }
+        Ok(())
+    }
+
+    /// Reads the non-volatile configuration register of Micron parts.
+    /// Returns `Error::Unsupported` for parts without it.
+    pub fn read_nonvolatile_config(&mut self) -> Result<NonVolatileConfig, Error> {
+        self.ensure_config_registers()?;
+        let mut bytes = [0; 2];
+        command_read(self.bus(), &[SPIFLASH_NVCONFIGREAD], &mut bytes)?;
+        Ok(NonVolatileConfig(u16::from_le_bytes(bytes)))
+    }
+
+    /// Writes the non-volatile configuration register of Micron parts.
+    /// The new configuration takes effect after the next power cycle, but reads back right away.
+    /// Returns `Error::ConfigMismatch` if it reads back differently, and `Error::Unsupported` for parts without it.
+    /// Blocks until the write is done.
+    pub fn write_nonvolatile_config(&mut self, config: NonVolatileConfig) -> Result<(), Error> {
+        self.ensure_config_registers()?;
+        let bytes = config.0.to_le_bytes();
+        self.enable_write();
+        self.bus().send(&[SPIFLASH_NVCONFIGWRITE, bytes[0], bytes[1]]);
+        // Non-volatile writes take about as long as a sector erase.
+        self.finish(Operation::Erase(0))?;
+        if self.read_nonvolatile_config()? != config {
+            return Err(Error::ConfigMismatch);
+        }
+        Ok(())
+    }
+
+    /// Reads the enhanced volatile configuration register of Micron parts.
+    /// Returns `Error::Unsupported` for parts without it.
+    pub fn read_enhanced_volatile_config(&mut self) -> Result<EnhancedVolatileConfig, Error> {
+        self.ensure_config_registers()?;
+        let mut byte = [0; 1];
+        command_read(self.bus(), &[SPIFLASH_EVCONFIGREAD], &mut byte)?;
+        Ok(EnhancedVolatileConfig(byte[0]))
+    }
+
+    /// Writes the enhanced volatile configuration register of Micron parts and reads it back.
+    /// Returns `Error::ConfigMismatch` if it reads back differently, and `Error::Unsupported` for parts without it.
+    pub fn write_enhanced_volatile_config(&mut self, config: EnhancedVolatileConfig) -> Result<(), Error> {
+        self.ensure_config_registers()?;
+        self.enable_write();
+        self.bus().send(&[SPIFLASH_EVCONFIGWRITE, config.0]);
+        self.finish(Operation::Program)?;
+        if self.read_enhanced_volatile_config()? != config {
+            return Err(Error::ConfigMismatch);
+        }
+        Ok(())
+    }
+
+    /// Reads the SPI Flash status register 2.
+    /// Blocks until the read is done.
+    pub fn read_status_2(&mut self) -> u8 {
+        let mut byte = [0; 1];
+        let _ = command_read(self.bus(), &[SPIFLASH_STATUSREAD_2], &mut byte);
+        byte[0]
+    }
+
+    /// Reads the SPI Flash status register 3.
+    /// Blocks until the read is done.
+    pub fn read_status_3(&mut self) -> StatusRegister3 {
+        let mut byte = [0; 1];
+        let _ = command_read(self.bus(), &[SPIFLASH_STATUSREAD_3], &mut byte);
+        StatusRegister3(byte[0])
+    }
+
+    /// Reads the JEDEC ID and all status registers the part has in one call, for troubleshooting.
+    /// Status register 2 is read from all parts except Micron and Macronix ones,
+    /// status register 3 from Winbond parts and parts reflecting 4-byte address mode in it,
+    /// the flag status register from parts which have one according to `ChipInfo::flag_status`.
+    /// Nothing is cleared or written, so latched error bits stay for whoever handles them.
+    /// Returns `Error::Bus` if the JEDEC ID reads as all zeros or all ones, i.e. nothing answers and the registers
+    /// would read as garbage, and `Error::Unsupported` in QPI mode.
+    pub fn diagnostics(&mut self) -> Result<DiagSnapshot, Error> {
+        if self.qpi {
+            return Err(Error::Unsupported);
+        }
+        let jedec_id = self.read_jedec_id();
+        if jedec_id.manufacturer == 0x00 || jedec_id.manufacturer == 0xFF {
+            return Err(Error::Bus);
+        }
+        let info = self.info;
+        let has_status_3 = self.has_status_3(jedec_id.manufacturer);
+        Ok(DiagSnapshot {
+            jedec_id,
+            status: StatusRegister(self.read_status()),
+            status_2: if self.has_status_2(jedec_id.manufacturer) { Some(self.read_status_2()) } else { None },
+            status_3: if has_status_3 { Some(self.read_status_3()) } else { None },
+            flag_status: if matches!(info, Some(info) if info.flag_status) { Some(self.read_flag_status()) } else { None },
+        })
+    }
+
+    /// Reads the wear information of parts which keep it in a vendor register, e.g. for reporting flash wear via telemetry.
+    /// None of the parts in the chip database has such a register, and SFDP does not describe one,
+    /// so it has to be set with `set_health_register()` first, e.g. to `HealthRegister::semper_erase_count()`.
+    /// Returns `Error::Unsupported` otherwise, or if the register has more than 4 dummy bytes.
+    pub fn health(&mut self) -> Result<HealthInfo, Error> {
+        let register = self.health_register.ok_or(Error::Unsupported)?;
+        if register.dummy_bytes > 4 {
+            return Err(Error::Unsupported);
+        }
+        self.leave_continuous();
+        let n = match register.address {
+            Some(address) => self.header.fill(register.opcode, address),
+            None => {
+                self.header.fill(register.opcode, 0);
+                1
+            }
+        };
+        let mut bytes = [0; 4];
+        command_read(&mut self.transmitter, &self.header.buffer[..n + register.dummy_bytes], &mut bytes)?;
+        Ok((register.decode)(bytes))
+    }
+
+    /// Sets the vendor register `health()` decodes, from the datasheet of the part. `None` removes it, which is the default.
+    pub fn set_health_register(&mut self, register: Option<HealthRegister>) {
+        self.health_register = register;
+    }
+
+    /// Sets the output driver strength in status register 3, leaving all other bits untouched,
+    /// and reads it back to confirm. Tuning this can help with read errors at high clock rates,
+    /// see `DriveStrength` for how to pick a setting.
+    /// Returns `Error::Unsupported` on parts without status register 3
+    /// and `Error::ConfigMismatch` if the part did not take the new setting.
+    /// Blocks until the write is done.
+    pub fn set_drive_strength(&mut self, strength: DriveStrength) -> Result<(), Error> {
+        let manufacturer = self.read_jedec_id().manufacturer;
+        if !self.has_status_3(manufacturer) {
+            return Err(Error::Unsupported);
+        }
+        let status = self.read_status_3().0 & !DriveStrength::MASK;
+        self.enable_status_write();
+        self.bus().send(&[SPIFLASH_STATUSWRITE_3, status | strength.bits()]);
         self.wait();
+        if self.read_status_3().drive_strength() != strength {
+            return Err(Error::ConfigMismatch);
+        }
+        Ok(())
     }
 
-    // pub fn erase_32k_block(uint32_t address) {
-    //     self.command(SPIFLASH_BLOCKERASE_32K, true); // Block Erase
-    //     SPI.transfer(address >> 16);
-    //     SPI.transfer(address >> 8);
-    //     SPI.transfer(address);
-    // }
+    /// Returns whether the part has status register 2 (0x35): all parts except Micron ones, which have configuration
+    /// registers instead, and Macronix ones, which take 0x35 as the command to enter QPI mode.
+    fn has_status_2(&self, manufacturer: u8) -> bool {
+        manufacturer != MANUFACTURER_MICRON && manufacturer != MANUFACTURER_MACRONIX
+    }
 
-    // pub fn erase_64k_block(uint32_t address) {
-    //     self.command(SPIFLASH_BLOCKERASE_64K, true); // Block Erase
-    //     SPI.transfer(address >> 16);
-    //     SPI.transfer(address >> 8);
-    //     SPI.transfer(address);
-    // }
+    /// Returns whether the part has status register 3: Winbond parts and parts reflecting 4-byte address mode in it.
+    fn has_status_3(&self, manufacturer: u8) -> bool {
+        manufacturer == 0xEF || matches!(self.info, Some(ChipInfo { four_byte_flag: Some((SPIFLASH_STATUSREAD_3, _)), .. }))
+    }
 
-    /// Enables sleep mode for the SPI Flash to have it consume less power.
-    pub fn sleep(&mut self) {
-        self.spi.send(&[SPIFLASH_SLEEP]);
-    }
-
-    /// Wakes the SPI Flash from sleep mode.
-    pub fn wakeup(&mut self) {
-        self.spi.send(&[SPIFLASH_WAKE]);
-    }
-}
-
-// /// write multiple bytes to flash memory (up to 64K)
-// /// WARNING: you can only write to previously erased memory locations (see datasheet)
-// ///          use the block erase commands to first clear memory (write 0xFFs)
-// /// This version handles both page alignment and data blocks larger than 256 bytes.
-// ///
-// void SPIFlash::writeBytes(uint32_t address, const void* buf, uint16_t len) {
-//   uint16_t n;
-//   uint16_t maxBytes = 256-(address%256);  // force the first set of bytes to stay within the first page
-//   uint16_t offset = 0;
-//   while (len>0)
-//   {
-//     n = (len<=maxBytes) ? len : maxBytes;
-//     command(SPIFLASH_BYTEPAGEPROGRAM, true);  // Byte/Page Program
-//     SPI.transfer(address >> 16);
-//     SPI.transfer(address >> 8);
-//     SPI.transfer(address);
-    
-//     for (uint16_t i = 0; i < n; i++)
-//       SPI.transfer(((uint8_t*) buf)[offset + i]);
-//     unselect();
-    
-//     address+=n;  // adjust the addresses and remaining bytes by what we've just transferred.
-//     offset +=n;
-//     len -= n;
-//     maxBytes = 256;   // now we can do up to 256 bytes per loop
-//   }
-// }
+    /// Checks whether the QE bit in status register 2 is set, which is required for all quad commands.
+    pub fn quad_enabled(&mut self) -> bool {
+        self.read_status_2() & STATUS_QE > 0
+    }
 
-#[cfg(test)]
-mod tests {
-    #[test]
-    fn it_works() {
-        assert_eq!(2 + 2, 4);
+    /// Sets or clears the QE bit in status register 2, leaving all other bits untouched.
+    /// Returns `Error::Protected` if the chip did not accept the change.
+    pub fn set_quad_enable(&mut self, enable: bool) -> Result<(), Error> {
+        let status = self.read_status();
+        let mut status_2 = self.read_status_2() & !STATUS_QE;
+        if enable {
+            status_2 |= STATUS_QE;
+        }
+        self.write_status(status, status_2);
+        if self.quad_enabled() != enable {
+            return Err(Error::Protected);
+        }
+        Ok(())
+    }
+
+    /// Writes status registers 1 and 2, after the write enable of the `StatusWriteMode`.
+    /// Blocks until the write is done.
+    fn write_status(&mut self, status: u8, status_2: u8) {
+        self.enable_status_write();
+        self.write_status_raw(status, status_2);
+    }
+
+    /// Writes status registers 1 and 2 without sending any write enable first,
+    /// for sequences which need their own, e.g. a vendor specific unlock.
+    /// The chip ignores the write unless a write enable directly precedes it.
+    /// Blocks until the write is done.
+    pub fn write_status_raw(&mut self, status: u8, status_2: u8) {
+        self.bus().send(&[SPIFLASH_STATUSWRITE, status, status_2]);
+        self.known_idle = false;
+        self.wait();
+    }
+
+    /// Reads the status register protection mode from the SRP0/SRP1 bits.
+    pub fn write_protect_mode(&mut self) -> Result<WpMode, Error> {
+        let srp0 = self.read_status() & STATUS_SRP0 > 0;
+        let srp1 = self.read_status_2() & STATUS_SRP1 > 0;
+        Ok(match (srp1, srp0) {
+            (false, false) => WpMode::Software,
+            (false, true) => WpMode::HardwareProtected,
+            (true, false) => WpMode::PowerLockDown,
+            (true, true) => WpMode::OneTimeProgram,
+        })
+    }
+
+    /// Sets the status register protection mode by writing the SRP0/SRP1 bits.
+    /// All other status bits are left untouched.
+    /// Returns `Error::Protected` if the chip did not accept the new mode,
+    /// e.g. because /WP is low in `HardwareProtected` mode or the status register is locked down.
+    pub fn set_write_protect_mode(&mut self, mode: WpMode) -> Result<(), Error> {
+        let (srp1, srp0) = match mode {
+            WpMode::Software => (false, false),
+            WpMode::HardwareProtected => (false, true),
+            WpMode::PowerLockDown => (true, false),
+            WpMode::OneTimeProgram => (true, true),
+        };
+        let mut status = self.read_status() & !STATUS_SRP0;
+        let mut status_2 = self.read_status_2() & !STATUS_SRP1;
+        if srp0 {
+            status |= STATUS_SRP0;
+        }
+        if srp1 {
+            status_2 |= STATUS_SRP1;
+        }
+        self.write_status(status, status_2);
+        if self.write_protect_mode()? != mode {
+            return Err(Error::Protected);
+        }
+        Ok(())
+    }
+
+    /// Checks that the /WP pin actually protects the status register, for board bring-up.
+    /// `set_wp` drives /WP, high for `true` and low for `false`; the driver can not reach the pin itself.
+    ///
+    /// The procedure enables `WpMode::HardwareProtected` with /WP high, pulls /WP low and tries to flip BP0,
+    /// which has to be rejected. Then it releases /WP and flips BP0 again, which has to be accepted.
+    /// Finally the original status registers are restored with /WP high, which is where `set_wp` leaves it.
+    /// For a short time a different block range may be protected, so do not run this while writing.
+    ///
+    /// Returns `Error::Protected` if the status register is locked down (so the test can not run)
+    /// or the original status registers could not be restored.
+    pub fn self_test_write_protect(&mut self, mut set_wp: impl FnMut(bool)) -> Result<WpSelfTest, Error> {
+        let mode = self.write_protect_mode()?;
+        if !matches!(mode, WpMode::Software | WpMode::HardwareProtected) {
+            return Err(Error::Protected);
+        }
+        set_wp(true);
+        let status = self.read_status();
+        let status_2 = self.read_status_2();
+        self.set_write_protect_mode(WpMode::HardwareProtected)?;
+        let protected = self.read_status();
+        let probe = protected ^ (1 << 2);
+
+        set_wp(false);
+        self.write_status(probe, status_2);
+        let blocked_when_low = self.read_status() == protected;
+
+        set_wp(true);
+        self.write_status(probe, status_2);
+        let accepted_when_high = self.read_status() == probe;
+
+        self.write_status(status, status_2);
+        if self.read_status() != status || self.read_status_2() != status_2 {
+            return Err(Error::Protected);
+        }
+        Ok(WpSelfTest {
+            blocked_when_low,
+            accepted_when_high,
+        })
+    }
+
+    /// Runs `f` with CS asserted for its whole duration, so it can chain multiple commands in one assertion.
+    /// This is meant for vendor sequences the driver does not cover; no state tracked by the driver is updated,
+    /// except that the chip is no longer assumed to be idle, as the sequence may have started a program or erase.
+    /// Returns `Error::Unsupported` if the transmitter can not control CS explicitly.
+    pub fn transaction<R>(&mut self, f: impl FnOnce(&mut Transaction<T>) -> R) -> Result<R, Error> {
+        if !self.bus().begin() {
+            return Err(Error::Unsupported);
+        }
+        self.known_idle = false;
+        Ok(f(&mut Transaction { transmitter: self.bus() }))
+    }
+
+    /// Reads the JEDEC manufacturer and device ID, with 0xAF instead of 0x9F in QPI mode.
+    /// Blocks until the read is done.
+    pub fn read_jedec_id(&mut self) -> JedecId {
+        let mut id = [0; 3];
+        let opcode = self.jedec_id_opcode();
+        let _ = command_read(self.bus(), &[opcode], &mut id);
+        JedecId::new(id[0], id[1], id[2])
+    }
+
+    /// Reads the JEDEC ID like `read_jedec_id`, along with the extended device information some parts append:
+    /// a length byte followed by that many bytes, which tells apart variants sharing the first three bytes.
+    /// A length of 0, or 0xFF from a part which does not drive it, leaves `JedecId::extended()` empty.
+    /// Longer information is cut off at `MAX_EXTENDED_ID` bytes.
+    /// Blocks until the read is done.
+    pub fn read_jedec_id_extended(&mut self) -> JedecId {
+        let mut response = [0; 4 + MAX_EXTENDED_ID];
+        let opcode = self.jedec_id_opcode();
+        let _ = command_read(self.bus(), &[opcode], &mut response);
+        let mut id = JedecId::new(response[0], response[1], response[2]);
+        let len = match response[3] {
+            0xFF => 0,
+            len => (len as usize).min(MAX_EXTENDED_ID),
+        };
+        id.extended[..len].copy_from_slice(&response[4..4 + len]);
+        id.extended_len = len as u8;
+        id
+    }
+
+    /// The JEDEC ID command, which has its own opcode in QPI mode.
+    fn jedec_id_opcode(&self) -> u8 {
+        if self.qpi {
+            SPIFLASH_IDREAD_QPI
+        } else {
+            SPIFLASH_IDREAD
+        }
+    }
+
+    /// Reads the manufacturer and device ID with the legacy 0x90 command.
+    /// Some older parts only respond to this and not to the JEDEC ID command.
+    /// The command is followed by three zero address bytes; with an address of 1, most parts return the IDs swapped.
+    /// Returns `Error::Unidentified` if nothing answers, i.e. the IDs read as all ones or all zeros.
+    /// Blocks until the read is done.
+    pub fn read_manufacturer_device_id(&mut self) -> Result<(u8, u8), Error> {
+        if self.qpi {
+            return Err(Error::Unsupported);
+        }
+        let mut id = [0; 2];
+        command_read(self.bus(), &[SPIFLASH_MANUFACTURERID, 0, 0, 0], &mut id)?;
+        match id {
+            [0xFF, 0xFF] | [0x00, 0x00] => Err(Error::Unidentified),
+            [manufacturer, device] => Ok((manufacturer, device)),
+        }
+    }
+
+    /// Checks whether a chip is connected and returns its JEDEC ID.
+    /// A chip in deep power-down (see `sleep()`) ignores the JEDEC ID command, so it looks just like an absent one.
+    /// With `wake` set, the probe tells the two apart in this order:
+    /// 1. The release from deep power-down (0xAB) is sent with three dummy bytes, reading back the legacy electronic signature.
+    ///    Sleeping and awake chips both answer it.
+    /// 2. If the signature is plausible, the chip is awake now, and `delay_us` waits the 30 µs it takes to accept commands.
+    /// 3. The JEDEC ID is read.
+    ///
+    /// Without `wake`, only the JEDEC ID is read and `delay_us` is not called.
+    /// Returns `Error::Unidentified` if the JEDEC ID reads as all zeros or all ones, i.e. nothing answers,
+    /// and `Error::Unsupported` in QPI mode.
+    pub fn probe(&mut self, wake: bool, mut delay_us: impl FnMut(u32)) -> Result<JedecId, Error> {
+        if self.qpi {
+            return Err(Error::Unsupported);
+        }
+        if wake {
+            let mut signature = [0; 1];
+            command_read(self.bus(), &[SPIFLASH_WAKE, 0, 0, 0], &mut signature)?;
+            if signature[0] != 0x00 && signature[0] != 0xFF {
+                delay_us(RELEASE_POWER_DOWN_US);
+            }
+        }
+        let id = self.read_jedec_id();
+        match id.manufacturer {
+            0x00 | 0xFF => Err(Error::Unidentified),
+            _ => Ok(id),
+        }
+    }
+
+    /// Reads `buffer.len()` bytes at `address` from the SFDP area.
+    /// Returns `Error::Unsupported` if the transmitter is half-duplex and can not hold CS.
+    /// Blocks until the read is done.
+    pub fn read_sfdp(&mut self, address: u32, buffer: &mut [u8]) -> Result<(), Error> {
+        command_read(self.bus(), &[SPIFLASH_SFDPREAD, (address >> 16) as u8, (address >> 8) as u8, (address) as u8, 0], buffer)
+    }
+
+    /// Reads the chip geometry from the SFDP basic flash parameter table.
+    /// Returns `Error::NoSfdp` if the chip has no valid SFDP signature, which is the case for chips without SFDP,
+    /// or if the basic flash parameter table is missing.
+    ///
+    /// The density in the table is cross-checked against the capacity byte of the JEDEC ID (see `JedecId::capacity_bytes()`),
+    /// as a mismatch hints at a counterfeit or mislabeled part. SFDP wins: the returned geometry keeps its capacity,
+    /// and the mismatch is reported to the trace hook as `TraceEvent::GeometryConflict`.
+    /// In strict mode it fails with `Error::GeometryConflict` instead, see `set_strict_geometry()`.
+    pub fn discover(&mut self) -> Result<ChipInfo, Error> {
+        // SFDP can not be read in QPI mode.
+        if self.qpi {
+            return Err(Error::Unsupported);
+        }
+        let mut header = [0; sfdp::HEADER_LEN];
+        self.read_sfdp(0, &mut header)?;
+        let headers = sfdp::parse_header(&header)?;
+
+        for i in 0..headers {
+            self.read_sfdp(((i + 1) * sfdp::HEADER_LEN) as u32, &mut header)?;
+            let parameter = sfdp::parse_parameter_header(&header);
+            if parameter.id != sfdp::BASIC_TABLE_ID {
+                continue;
+            }
+
+            let length = (parameter.length as usize).min(sfdp::BASIC_TABLE_DWORDS);
+            let mut bytes = [0; sfdp::BASIC_TABLE_DWORDS * 4];
+            self.read_sfdp(parameter.pointer, &mut bytes[..length * 4])?;
+            let mut dwords = [0; sfdp::BASIC_TABLE_DWORDS];
+            sfdp::to_dwords(&bytes[..length * 4], &mut dwords);
+            let info = sfdp::parse_basic_table(&dwords, length)?;
+            self.check_geometry(info.capacity)?;
+            return Ok(info);
+        }
+
+        Err(Error::NoSfdp)
+    }
+
+    /// Compares the SFDP capacity against the JEDEC ID, see `discover()`.
+    fn check_geometry(&mut self, sfdp: u32) -> Result<(), Error> {
+        let jedec = match self.read_jedec_id().capacity_bytes() {
+            Some(jedec) if jedec != sfdp => jedec,
+            _ => return Ok(()),
+        };
+        if self.strict_geometry {
+            return Err(Error::GeometryConflict);
+        }
+        if let Some(hook) = self.trace {
+            hook(TraceEvent::GeometryConflict { sfdp, jedec });
+        }
+        Ok(())
+    }
+
+    /// Makes `discover()`, and so `identify()`, fail with `Error::GeometryConflict`
+    /// if SFDP and the JEDEC ID disagree about the capacity, e.g. for incoming inspection in manufacturing.
+    /// By default the conflict is only reported to the trace hook.
+    pub fn set_strict_geometry(&mut self, strict: bool) {
+        self.strict_geometry = strict;
+    }
+
+    /// Identifies the chip and remembers its geometry.
+    /// SFDP is tried first. If the chip does not implement it, the JEDEC ID is looked up in the chip database.
+    /// Returns `Error::Unidentified` if neither works, and `Error::OutOfBounds` if the dual or quad I/O latency
+    /// can not be framed (see `set_dummy_cycles()`). Nothing is changed then.
+    ///
+    /// The address width follows the chip: parts reflecting 4-byte mode in a register (see `ChipInfo::four_byte_flag`)
+    /// are asked, 4-byte only parts use 4-byte addresses. Otherwise the driver keeps the 3-byte addresses it starts with,
+    /// which is what SFDP promises for most parts (see `ChipInfo::power_up_width`).
+    /// WARNING: A part whose non-volatile configuration makes it power up in 4-byte mode, without telling in SFDP
+    ///          or a register, takes every address off by a byte. Call `set_address_width()` for such parts.
+    pub fn identify(&mut self) -> Result<ChipInfo, Error> {
+        let id = self.read_jedec_id();
+        let mut info = match self.discover() {
+            Ok(info) => info,
+            Err(Error::NoSfdp) => chips::lookup(id).ok_or(Error::Unidentified)?,
+            Err(e) => return Err(e),
+        };
+        info.flag_status = id.manufacturer == MANUFACTURER_MICRON;
+        info.config_registers = id.manufacturer == MANUFACTURER_MICRON;
+        info.status_errors = chips::lookup(id).is_some_and(|known| known.status_errors);
+        let dual_io = ReadMode::DualIo.dummy_bytes(info.dual_io_dummy_cycles).ok_or(Error::OutOfBounds)?;
+        let quad_io = ReadMode::QuadIo.dummy_bytes(info.quad_io_dummy_cycles).ok_or(Error::OutOfBounds)?;
+        if let Some(width) = self.current_width(&info) {
+            self.header.width = width;
+        }
+        self.dummy_bytes[ReadMode::DualIo as usize] = dual_io;
+        self.dummy_bytes[ReadMode::QuadIo as usize] = quad_io;
+        self.info = Some(info);
+        Ok(info)
+    }
+
+    /// The address width the chip of `info` is in, if it can be told, see `identify()`.
+    fn current_width(&mut self, info: &ChipInfo) -> Option<AddressWidth> {
+        if info.four_byte_mode == FourByteMode::Always || info.power_up_width == Some(AddressWidth::FourByte) {
+            return Some(AddressWidth::FourByte);
+        }
+        let (opcode, mask) = info.four_byte_flag?;
+        let mut byte = [0; 1];
+        let _ = command_read(self.bus(), &[opcode], &mut byte);
+        Some(if byte[0] & mask != 0 { AddressWidth::FourByte } else { AddressWidth::ThreeByte })
+    }
+
+    /// The number of dummy clocks sent between the address (and the mode byte, for the I/O reads) and the data of `mode` reads.
+    /// After `identify()`, the I/O reads use the latency advertised in the SFDP basic flash parameter table.
+    /// Otherwise these are the common 8 clocks for fast reads, none for dual I/O and 4 for quad I/O reads.
+    pub fn dummy_cycles(&self, mode: ReadMode) -> u8 {
+        self.dummy_bytes[mode as usize] as u8 * mode.clocks_per_byte()
+    }
+
+    /// Sets the number of dummy clocks of `mode` reads, e.g. after changing them in a configuration register
+    /// (see `VolatileConfig::with_dummy_cycles()`). This only changes how the driver frames the reads, not the chip.
+    /// Returns `Error::OutOfBounds` if `cycles` is not a whole number of bytes on the lines of the read
+    /// (8 clocks on one line, 4 on two and 2 on four) or more than 8 bytes.
+    pub fn set_dummy_cycles(&mut self, mode: ReadMode, cycles: u8) -> Result<(), Error> {
+        self.dummy_bytes[mode as usize] = mode.dummy_bytes(cycles).ok_or(Error::OutOfBounds)?;
+        Ok(())
+    }
+
+    /// Sets the number of dummy clocks of `mode` reads which continue in continuous read mode, skipping the opcode,
+    /// for parts whose latency after the mode byte differs from that of the first read entering the mode.
+    /// With `None`, which is the default, they use `dummy_cycles(mode)` as well.
+    /// Returns `Error::Unsupported` for `ReadMode::Fast`, which has no continuous read mode,
+    /// and `Error::OutOfBounds` for a number of clocks `set_dummy_cycles()` does not take either.
+    pub fn set_continuous_read_dummy(&mut self, mode: ReadMode, cycles: Option<u8>) -> Result<(), Error> {
+        if mode == ReadMode::Fast {
+            return Err(Error::Unsupported);
+        }
+        self.continuous_dummy_bytes[mode as usize] = match cycles {
+            Some(cycles) => Some(mode.dummy_bytes(cycles).ok_or(Error::OutOfBounds)?),
+            None => None,
+        };
+        Ok(())
+    }
+
+    /// The number of dummy bytes of a `mode` read, which is `continuing` a continuous read or not.
+    fn io_dummy_bytes(&self, mode: ReadMode, continuing: bool) -> usize {
+        match self.continuous_dummy_bytes[mode as usize] {
+            Some(bytes) if continuing => bytes,
+            _ => self.dummy_bytes[mode as usize],
+        }
+    }
+
+    /// Returns the geometry found by `identify()`.
+    pub fn info(&self) -> Option<&ChipInfo> {
+        self.info.as_ref()
+    }
+
+    /// Returns the geometry found by `identify()`, or `Error::Unidentified`.
+    fn identified(&self) -> Result<ChipInfo, Error> {
+        self.info.ok_or(Error::Unidentified)
+    }
+
+    /// Total number of addressable bytes.
+    /// Returns `Error::Unidentified` if the geometry is not known.
+    pub fn capacity(&self) -> Result<u32, Error> {
+        Ok(self.identified()?.capacity)
+    }
+
+    /// Number of sectors, the smallest erasable units.
+    /// Returns `Error::Unidentified` if the geometry is not known.
+    pub fn sector_count(&self) -> Result<u32, Error> {
+        let info = self.identified()?;
+        Ok(info.capacity / info.sector_size)
+    }
+
+    /// Number of program pages.
+    /// Returns `Error::Unidentified` if the geometry is not known.
+    pub fn page_count(&self) -> Result<u32, Error> {
+        let info = self.identified()?;
+        Ok(info.capacity / info.page_size)
+    }
+
+    /// Infers the capacity of a chip that neither SFDP nor the chip database knows, by detecting where addresses wrap.
+    /// A marker is written to `scratch` and read back at every power of two above it, until it reappears, which it does
+    /// at the capacity of the chip. Programming the marker further then confirms that the match really is the same cells.
+    /// This is a last resort for obscure parts, so the capacity is returned but not remembered:
+    /// set `ChipInfo` up from it and the datasheet.
+    ///
+    /// WARNING: This is destructive. `scratch` is erased, programmed and left erased. Everything in it is lost.
+    /// Returns `Error::Unidentified` if the addresses never wrap, which also happens with capacities that are not a power of two.
+    pub fn probe_capacity(&mut self, scratch: ScratchSector) -> Result<u32, Error> {
+        let address = scratch.address;
+        self.erase_4k_block(address)?;
+        self.write_bytes(address, &PROBE_MARKER)?;
+        let mut result = Err(Error::Unidentified);
+        for shift in 12..32 {
+            let candidate = match address.checked_add(1 << shift) {
+                Some(candidate) => candidate,
+                None => break,
+            };
+            let mut marker = [0; PROBE_MARKER.len()];
+            self.read_unchecked(candidate, &mut marker)?;
+            if marker != PROBE_MARKER {
+                continue;
+            }
+            // Something else may hold the same bytes, but it does not change along with the scratch sector.
+            self.write_bytes(address, &[0; PROBE_MARKER.len()])?;
+            self.read_unchecked(candidate, &mut marker)?;
+            if marker == [0; PROBE_MARKER.len()] {
+                result = Ok(1 << shift);
+                break;
+            }
+            self.erase_4k_block(address)?;
+            self.write_bytes(address, &PROBE_MARKER)?;
+        }
+        self.erase_4k_block(address)?;
+        result
+    }
+
+    /// Size of the smallest erasable unit in bytes.
+    /// Returns `Error::Unidentified` if the geometry is not known.
+    pub fn min_erase_size(&self) -> Result<u32, Error> {
+        Ok(self.identified()?.sector_size)
+    }
+
+    /// Whether bytes have to be erased before they can be written again, which is always the case for NOR flash:
+    /// a program only clears bits, only an erase sets them back to 1.
+    /// This is for generic storage layers which also run on media that overwrite in place.
+    pub fn requires_erase_before_write(&self) -> bool {
+        true
+    }
+
+    /// The smallest unit a program writes in bytes, from `ChipInfo::program_granularity`.
+    /// Storage layers should write whole, aligned units of this size, and each unit only once between erases.
+    /// Returns `Error::Unidentified` if the geometry is not known.
+    pub fn write_granularity(&self) -> Result<u32, Error> {
+        Ok(self.identified()?.program_granularity)
+    }
+
+    /// Places one partition per entry of `sizes` into `partitions`, one after the other,
+    /// each starting at the next multiple of `min_erase_size()` so it can be erased on its own.
+    /// Returns `Error::OutOfBounds` if the partitions do not fit into the chip or `partitions` is too short,
+    /// and `Error::Unidentified` if the geometry is not known.
+    pub fn layout_partitions(&self, sizes: &[u32], partitions: &mut [Partition]) -> Result<(), Error> {
+        let info = self.identified()?;
+        if partitions.len() < sizes.len() {
+            return Err(Error::OutOfBounds);
+        }
+        let align = info.sector_size.max(1);
+        let mut offset = 0u32;
+        for (partition, &size) in partitions.iter_mut().zip(sizes) {
+            let end = offset.checked_add(size).filter(|&end| end <= info.capacity).ok_or(Error::OutOfBounds)?;
+            *partition = Partition { offset, size };
+            // The next partition starts at the next erase boundary. Running past the chip is caught above.
+            offset = end.checked_add(align - 1).map_or(u32::MAX, |end| end / align * align);
+        }
+        Ok(())
+    }
+
+    /// Checks that `len` bytes starting at `address` lie within the chip.
+    /// Without known geometry every range which fits the 32-bit address space is accepted.
+    fn check_bounds(&self, address: u32, len: u32) -> Result<(), Error> {
+        let end = address.checked_add(len).ok_or(Error::OutOfBounds)?;
+        match self.info {
+            Some(info) if end > info.capacity => Err(Error::OutOfBounds),
+            _ => Ok(()),
+        }
+    }
+
+    /// Decodes the block protect bits into the range of addresses they protect.
+    /// This follows the Winbond W25Q scheme of BP2:BP0, TB, SEC and CMP, which many other vendors share.
+    /// Returns `None` if nothing is protected, or `Error::Unidentified` if the chip capacity is not known.
+    pub fn protected_range(&mut self) -> Result<Option<Range<u32>>, Error> {
+        let capacity = self.info.ok_or(Error::Unidentified)?.capacity;
+        let status = self.read_status();
+        let status_2 = self.read_status_2();
+        Ok(decode_protection(capacity, status, status_2))
+    }
+
+    /// Reads the complete write protection configuration.
+    /// Returns `Error::Unidentified` if the chip capacity is not known.
+    pub fn protection_summary(&mut self) -> Result<ProtectionSummary, Error> {
+        Ok(ProtectionSummary {
+            protected: self.protected_range()?,
+            wp_mode: self.write_protect_mode()?,
+        })
+    }
+
+    /// Checks that the chip's protection configuration matches `expected`,
+    /// e.g. to verify that a part was locked correctly during provisioning.
+    /// Returns `Error::ProtectionMismatch` if it does not.
+    pub fn assert_protection(&mut self, expected: &ProtectionSummary) -> Result<(), Error> {
+        if self.protection_summary()? != *expected {
+            return Err(Error::ProtectionMismatch);
+        }
+        Ok(())
+    }
+
+    /// Clears the block protect bits (BP, TB, SEC and CMP), which unprotects the whole chip, e.g. before a `chip_erase()`.
+    /// Returns `Error::Protected` if the chip did not take it, e.g. because /WP is low in `WpMode::HardwareProtected`.
+    pub fn clear_block_protection(&mut self) -> Result<(), Error> {
+        let status = self.read_status() & !(STATUS_BP | STATUS_TB | STATUS_SEC);
+        let status_2 = self.read_status_2() & !STATUS_CMP;
+        self.write_status(status, status_2);
+        if self.read_status() & STATUS_BP != 0 || self.read_status_2() & STATUS_CMP != 0 {
+            return Err(Error::Protected);
+        }
+        Ok(())
+    }
+
+    /// Sets the individual lock of every block (0x7E), which protects the whole chip while WPS is set in status register 3.
+    /// A provisioning flow locks everything with it, then unlocks the blocks which have to stay writable.
+    /// With WPS clear, the individual locks have no effect and the block protect bits apply instead.
+    /// Returns `Error::WriteNotEnabled` if the write enable sent first does not take.
+    pub fn global_lock(&mut self) -> Result<(), Error> {
+        self.send_write_enabled(SPIFLASH_GLOBALLOCK)
+    }
+
+    /// Clears the individual lock of every block (0x98), see `global_lock()`.
+    /// Returns `Error::WriteNotEnabled` if the write enable sent first does not take.
+    pub fn global_unlock(&mut self) -> Result<(), Error> {
+        self.send_write_enabled(SPIFLASH_GLOBALUNLOCK)
+    }
+
+    /// Sends the write enable, checks that it took whatever the `WelCheck` policy, then sends `opcode`.
+    fn send_write_enabled(&mut self, opcode: u8) -> Result<(), Error> {
+        self.enable_write();
+        if !StatusRegister(self.read_status()).write_enabled() {
+            return Err(Error::WriteNotEnabled);
+        }
+        self.bus().send(&[opcode]);
+        Ok(())
+    }
+
+    /// Checks whether `address` can currently be written, i.e. is not covered by the block protect bits,
+    /// or, on parts with WPS set in status register 3, by the individual lock of its block (see `global_lock()`).
+    pub fn is_writable(&mut self, address: u32) -> Result<bool, Error> {
+        self.check_bounds(address, 1)?;
+        let manufacturer = self.read_jedec_id().manufacturer;
+        if self.has_status_3(manufacturer) && self.read_status_3().individual_locks() {
+            return Ok(!self.read_block_lock(address)?);
+        }
+        Ok(!matches!(self.protected_range()?, Some(range) if range.contains(&address)))
+    }
+
+    /// Reads the individual lock of the block containing `address` (0x3D), which only applies while WPS is set.
+    /// Blocks are 64K, except for the top and bottom ones, which are locked in 4K sectors.
+    pub fn read_block_lock(&mut self, address: u32) -> Result<bool, Error> {
+        self.check_bounds(address, 1)?;
+        self.leave_continuous();
+        self.select_bank(address);
+        let n = self.header.fill(SPIFLASH_BLOCKLOCKREAD, address);
+        let mut lock = [0; 1];
+        command_read(&mut self.transmitter, &self.header.buffer[..n], &mut lock)?;
+        Ok(lock[0] & 1 > 0)
+    }
+
+    /// Fails with `Error::Protected` if any of the `len` bytes starting at `address` is write protected,
+    /// and `Error::OutOfBounds` if they do not fit the address space.
+    /// Without known geometry protection can not be decoded, so the chip gets to decide.
+    fn ensure_writable(&mut self, address: u32, len: u32) -> Result<(), Error> {
+        self.check_not_suspended()?;
+        let end = address.checked_add(len).ok_or(Error::OutOfBounds)?;
+        if self.info.is_none() || len == 0 {
+            return Ok(());
+        }
+        match self.protected_range()? {
+            Some(range) if range.start < end && address < range.end => Err(Error::Protected),
+            _ => Ok(()),
+        }
+    }
+
+    /// Finds the first free offset in an append-only region of `len` bytes starting at `start`.
+    /// The region is expected to hold written data followed by an erased tail of 0xFF (see `set_blank_byte()`),
+    /// which allows a binary search instead of scanning the whole region.
+    /// A blank byte inside the written data can be mistaken for the start of the tail,
+    /// so records should be framed such that they never contain one.
+    /// Returns the offset relative to `start`, or `None` if the region is full.
+    pub fn first_free_offset(&mut self, start: u32, len: u32) -> Result<Option<u32>, Error> {
+        self.check_bounds(start, len)?;
+        self.check_suspended(start, len)?;
+        self.check_idle()?;
+        // Everything below `low` is written, everything from `high` on is free.
+        let mut low = 0;
+        let mut high = len;
+        let mut byte = [0; 1];
+        while low < high {
+            let middle = low + (high - low) / 2;
+            self.read_unchecked(start + middle, &mut byte)?;
+            if byte[0] == self.blank_byte {
+                high = middle;
+            } else {
+                low = middle + 1;
+            }
+        }
+        Ok(if low < len { Some(low) } else { None })
+    }
+
+    /// Reports how much of the append-only region of `len` bytes starting at `start` is used.
+    /// This uses the same binary search as `first_free_offset()` and has the same caveats.
+    pub fn append_region_stats(&mut self, start: u32, len: u32) -> Result<RegionStats, Error> {
+        let used = self.first_free_offset(start, len)?.unwrap_or(len);
+        Ok(RegionStats { used, free: len - used })
+    }
+
+    /// Finds the first occurrence of `needle` within the `len` bytes starting at `start`, e.g. a firmware or filesystem signature.
+    /// The region is streamed through a small window which keeps the tail of the previous chunk,
+    /// so matches straddling two chunks are found as well.
+    /// Returns the address of the match, or `None` if the region does not contain `needle`.
+    /// Returns `Error::OutOfBounds` if `needle` is longer than 64 bytes.
+    pub fn find(&mut self, needle: &[u8], start: u32, len: u32) -> Result<Option<u32>, Error> {
+        if needle.len() > SCAN_CHUNK {
+            return Err(Error::OutOfBounds);
+        }
+        self.check_bounds(start, len)?;
+        let end = start.checked_add(len).ok_or(Error::OutOfBounds)?;
+        self.check_suspended(start, len)?;
+        self.check_idle()?;
+        if needle.is_empty() {
+            return Ok(Some(start));
+        }
+        let mut window = [0; 2 * SCAN_CHUNK];
+        // The number of bytes at the start of the window carried over from the previous chunk.
+        let mut kept = 0;
+        let mut address = start;
+        while address < end {
+            let n = (end - address).min(SCAN_CHUNK as u32) as usize;
+            self.read_unchecked(address, &mut window[kept..kept + n])?;
+            let filled = kept + n;
+            if let Some(i) = window[..filled].windows(needle.len()).position(|w| w == needle) {
+                return Ok(Some(address - kept as u32 + i as u32));
+            }
+            kept = (needle.len() - 1).min(filled);
+            window.copy_within(filled - kept..filled, 0);
+            address += n as u32;
+        }
+        Ok(None)
+    }
+
+    /// Reads `len` bytes starting at `start` and checks every byte against `pattern(address)`.
+    /// This is meant for stress testing: write a known pattern, then verify it here.
+    /// Returns `Error::VerifyMismatch` with the first address that does not match.
+    pub fn verify_pattern(&mut self, start: u32, len: u32, pattern: impl Fn(u32) -> u8) -> Result<(), Error> {
+        self.check_bounds(start, len)?;
+        self.check_suspended(start, len)?;
+        self.check_idle()?;
+        let mut buffer = [0; SCAN_CHUNK];
+        let mut offset = 0;
+        while offset < len {
+            let n = (len - offset).min(SCAN_CHUNK as u32);
+            let address = start + offset;
+            self.read_unchecked(address, &mut buffer[..n as usize])?;
+            for (i, byte) in buffer[..n as usize].iter().enumerate() {
+                if *byte != pattern(address + i as u32) {
+                    return Err(self.diagnose_mismatch(Error::VerifyMismatch(address + i as u32)));
+                }
+            }
+            offset += n;
+        }
+        Ok(())
+    }
+
+    /// Returns whether all `len` bytes starting at `start` are erased, i.e. read as 0xFF (see `set_blank_byte()`).
+    pub fn is_erased(&mut self, start: u32, len: u32) -> Result<bool, Error> {
+        self.check_bounds(start, len)?;
+        self.check_suspended(start, len)?;
+        self.check_idle()?;
+        let mut buffer = [0; SCAN_CHUNK];
+        let mut offset = 0;
+        while offset < len {
+            let n = (len - offset).min(SCAN_CHUNK as u32);
+            self.read_unchecked(start + offset, &mut buffer[..n as usize])?;
+            if buffer[..n as usize].iter().any(|&b| b != self.blank_byte) {
+                return Ok(false);
+            }
+            offset += n;
+        }
+        Ok(true)
+    }
+
+    /// Walks all sectors of the chip, yielding each one along with whether it is erased, e.g. to draw a map of used space.
+    /// With `sample` set, only that many bytes at the start of every sector are checked, which is much faster on large chips
+    /// but takes sectors holding data only further in for erased.
+    /// Yields a single `Error::Unidentified` if the geometry is not known. The walk ends after the first error.
+    pub fn sector_map(&mut self, sample: Option<u32>) -> impl Iterator<Item = Result<(Sector, bool), Error>> + '_ {
+        let geometry = self.identified().map(|info| (info.capacity, info.sector_size));
+        let mut address = 0;
+        let mut done = false;
+        core::iter::from_fn(move || {
+            if done {
+                return None;
+            }
+            let (capacity, size) = match geometry {
+                Ok(geometry) => geometry,
+                Err(e) => {
+                    done = true;
+                    return Some(Err(e));
+                }
+            };
+            if address >= capacity {
+                return None;
+            }
+            let sector = Sector { address, size };
+            let result = self.is_erased(address, sample.map_or(size, |sample| sample.min(size)));
+            done = result.is_err();
+            address = address.saturating_add(size);
+            Some(result.map(|erased| (sector, erased)))
+        })
+    }
+
+    /// Returns whether the flash at `start` holds exactly `expected`.
+    pub fn matches(&mut self, start: u32, expected: &[u8]) -> Result<bool, Error> {
+        self.check_bounds(start, expected.len() as u32)?;
+        self.check_suspended(start, expected.len() as u32)?;
+        self.check_idle()?;
+        let mut buffer = [0; SCAN_CHUNK];
+        for (i, chunk) in expected.chunks(SCAN_CHUNK).enumerate() {
+            let current = &mut buffer[..chunk.len()];
+            self.read_unchecked(start + (i * SCAN_CHUNK) as u32, current)?;
+            if current != chunk {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Compares the flash at `address` against `image` sector by sector, yielding the base address of every sector
+    /// whose contents differ, e.g. so an update only erases and rewrites what changed.
+    /// Sectors which `image` only covers partly are compared over the covered bytes.
+    /// Yields a single `Error::Unidentified` if the geometry is not known. The walk ends after the first error.
+    pub fn diff_sectors<'a>(&'a mut self, address: u32, image: &'a [u8]) -> impl Iterator<Item = Result<u32, Error>> + 'a {
+        let sector_size = self.identified().map(|info| info.sector_size);
+        let mut offset = 0;
+        let mut done = false;
+        core::iter::from_fn(move || {
+            while !done && offset < image.len() {
+                let size = match sector_size {
+                    Ok(size) => size,
+                    Err(e) => {
+                        done = true;
+                        return Some(Err(e));
+                    }
+                };
+                let start = address.wrapping_add(offset as u32);
+                let base = start & !(size - 1);
+                let n = ((size - (start - base)) as usize).min(image.len() - offset);
+                let result = self.matches(start, &image[offset..offset + n]);
+                offset += n;
+                match result {
+                    Ok(true) => {}
+                    Ok(false) => return Some(Ok(base)),
+                    Err(e) => {
+                        done = true;
+                        return Some(Err(e));
+                    }
+                }
+            }
+            None
+        })
+    }
+
+    /// Writes a canonical hex dump of `len` bytes starting at `start` to `out`, 16 bytes per line.
+    /// See `hexdump_width()`.
+    pub fn hexdump<W: core::fmt::Write>(&mut self, start: u32, len: u32, out: &mut W) -> Result<(), Error> {
+        self.hexdump_width(start, len, 16, out)
+    }
+
+    /// Writes a hex dump of `len` bytes starting at `start` to `out`, `width` bytes per line.
+    /// Every line looks like `00001000: 48 65 6c 6c 6f |Hello|`, with non-printable bytes shown as `.`.
+    /// Needs no heap, every line is read into a stack buffer.
+    /// Returns `Error::OutOfBounds` if `width` is zero or larger than 64.
+    pub fn hexdump_width<W: core::fmt::Write>(&mut self, start: u32, len: u32, width: usize, out: &mut W) -> Result<(), Error> {
+        if width == 0 || width > SCAN_CHUNK {
+            return Err(Error::OutOfBounds);
+        }
+        self.check_bounds(start, len)?;
+        self.check_suspended(start, len)?;
+        self.check_idle()?;
+        let mut buffer = [0; SCAN_CHUNK];
+        let mut offset = 0;
+        while offset < len {
+            let n = (len - offset).min(width as u32) as usize;
+            let address = start + offset;
+            let line = &mut buffer[..n];
+            self.read_unchecked(address, line)?;
+            write_hex_line(out, address, line, width).map_err(|_| Error::Format)?;
+            offset += n as u32;
+        }
+        Ok(())
+    }
+
+    /// Sets what reads do while the chip is busy with a program or erase.
+    pub fn set_read_during_busy(&mut self, policy: ReadDuringBusy) {
+        self.read_during_busy = policy;
+    }
+
+    /// Applies the `ReadDuringBusy` policy before a read.
+    fn check_idle(&mut self) -> Result<(), Error> {
+        // Once the chip was seen idle, it stays so until the driver sends something which makes it busy.
+        // A chip in continuous read mode can not be busy either, and a status read would end the mode.
+        if self.read_during_busy == ReadDuringBusy::Reject && !self.known_idle && self.continuous.is_none() && self.is_busy() {
+            return Err(Error::Busy);
+        }
+        Ok(())
+    }
+
+    /// Reads a single byte at `address` from the SPI Flash and returns it.
+    /// Returns `Error::Busy` if the chip is busy, unless reads during busy are allowed.
+    /// Blocks until the read is done.
+    pub fn read_byte(&mut self, address: u32) -> Result<u8, Error> {
+        let mut byte = [0; 1];
+        self.read_bytes(address, &mut byte)?;
+        Ok(byte[0])
+    }
+
+    /// Reads `N` bytes at `address` into an array, for small reads of a known size.
+    /// Returns `Error::OutOfBounds` if they do not fit into the chip.
+    /// Blocks until the read is done.
+    pub fn read_array<const N: usize>(&mut self, address: u32) -> Result<[u8; N], Error> {
+        let mut bytes = [0; N];
+        self.read_bytes(address, &mut bytes)?;
+        Ok(bytes)
+    }
+
+    /// Reads `buffer.len()` bytes at `address` with a fast read, into a buffer which need not be initialized,
+    /// and returns it as the initialized slice. On success every byte of `buffer` holds flash contents.
+    /// The transmitter reads with `Transmitter::read_uninit()` or `send_read_uninit()`,
+    /// which can write the buffer without initializing it first, saving a pass over large buffers.
+    /// Unlike `read_bytes`, the read is not split by `set_max_read_len()`, and a chip in continuous read mode leaves it first.
+    /// Returns `Error::Bus` if the transmitter does not hand back the buffer it was given as initialized.
+    /// Returns `Error::Busy` if the chip is busy, unless reads during busy are allowed.
+    /// Blocks until the read is done.
+    pub fn read_bytes_uninit<'a>(&mut self, address: u32, buffer: &'a mut [MaybeUninit<u8>]) -> Result<&'a mut [u8], Error> {
+        self.check_bounds(address, buffer.len() as u32)?;
+        self.check_suspended(address, buffer.len() as u32)?;
+        self.check_idle()?;
+        self.leave_continuous();
+        let dummy = self.dummy_bytes[ReadMode::Fast as usize];
+        let mut result = Ok(());
+        self.read_banked(address, &mut *buffer, |spi, header, address, buffer| {
+            let n = header.fill(SPIFLASH_ARRAYREAD, address) + dummy;
+            result = result.and_then(|_| command_read_uninit(spi, &header.buffer[..n], buffer));
+        });
+        result?;
+        // SAFETY: the transmitter returned every piece of `buffer` as an initialized `&mut [u8]`,
+        // and `MaybeUninit<u8>` has the layout of `u8`.
+        Ok(unsafe { core::slice::from_raw_parts_mut(buffer.as_mut_ptr() as *mut u8, buffer.len()) })
+    }
+
+    /// Reads a `buffer.len()` bytes at `address` from the SPI Flash and stores them in `buffer`.
+    /// Reads crossing a 16 MiB boundary are split so each part is read from the correct bank.
+    /// Reads longer than the maximum set with `set_max_read_len()` are split into segments.
+    /// This is a single line fast read, unless a dual or quad I/O read left the chip in continuous read mode
+    /// (see `continuous_read_mode()`): then the read continues that mode without opcode and with the same mode byte,
+    /// so mixing the reads does not fall back to the slower command. Reads needing a bank switch leave the mode first.
+    /// Returns `Error::Busy` if the chip is busy, unless reads during busy are allowed.
+    /// Blocks until the read is done.
+    pub fn read_bytes(&mut self, address: u32, buffer: &mut [u8]) -> Result<(), Error> {
+        self.check_bounds(address, buffer.len() as u32)?;
+        self.check_suspended(address, buffer.len() as u32)?;
+        self.check_idle()?;
+        let max_len = match self.max_read_len {
+            Some(max_len) => max_len,
+            None => {
+                self.read_unchecked(address, buffer)?;
+                return Ok(());
+            }
+        };
+        let mut segment_address = address;
+        for segment in buffer.chunks_mut(max_len) {
+            self.read_segment(segment_address, segment)?;
+            segment_address += segment.len() as u32;
+        }
+        Ok(())
+    }
+
+    /// Limits `read_bytes()` to `max_len` bytes per read command, for boards where long transfers pick up errors,
+    /// e.g. due to marginal signal integrity. Longer reads are split into segments,
+    /// each issuing the read command again with the address advanced. `None` removes the limit, which is the default.
+    /// With `verify`, every segment is read a second time and the CRC-32 of both reads is compared.
+    /// A segment which does not match is read again, up to three times,
+    /// before the read fails with `Error::ReadUnstable`.
+    /// This costs at least twice the bus time, but catches corrupted transfers instead of returning them.
+    /// Reads built on `read_bytes()`, like `read_byte()` and `read_array()`, are split as well;
+    /// the multi-line reads, `read_bytes_with()`, `read_bytes_uninit()` and `read_scattered()` are not.
+    pub fn set_max_read_len(&mut self, max_len: Option<usize>, verify: bool) {
+        self.max_read_len = max_len.map(|len| len.max(1));
+        self.verify_segments = verify;
+    }
+
+    /// Reads one segment of a split read, checking it against a second read if segments are verified.
+    fn read_segment(&mut self, address: u32, segment: &mut [u8]) -> Result<(), Error> {
+        self.read_unchecked(address, segment)?;
+        if !self.verify_segments {
+            return Ok(());
+        }
+        for _ in 0..SEGMENT_READS {
+            let crc = crc::crc32(segment);
+            let mut check = crc::Crc32::new();
+            let mut chunk = [0; SCAN_CHUNK];
+            let mut offset = 0;
+            while offset < segment.len() {
+                let n = (segment.len() - offset).min(SCAN_CHUNK);
+                self.read_unchecked(address + offset as u32, &mut chunk[..n])?;
+                check.update(&chunk[..n]);
+                offset += n;
+            }
+            if check.finish() == crc {
+                return Ok(());
+            }
+            self.read_unchecked(address, segment)?;
+        }
+        Err(Error::ReadUnstable(address))
+    }
+
+    /// Reads `buffer.len()` bytes at `address` with exactly `opcode`, followed by the address and `dummy_bytes` zero bytes,
+    /// all on a single line. This is an escape hatch for diagnostics and bring-up, e.g. to try a read mode:
+    /// it bypasses the dummy cycles configured for the `ReadMode`s and does not change them.
+    /// The address width and bank handling are the same as for `read_bytes`.
+    /// Returns `Error::OutOfBounds` if `dummy_bytes` is above 8.
+    /// Returns `Error::Busy` if the chip is busy, unless reads during busy are allowed.
+    /// Blocks until the read is done.
+    pub fn read_bytes_with(&mut self, opcode: u8, dummy_bytes: u8, address: u32, buffer: &mut [u8]) -> Result<(), Error> {
+        if dummy_bytes as usize > MAX_DUMMY_BYTES {
+            return Err(Error::OutOfBounds);
+        }
+        self.check_bounds(address, buffer.len() as u32)?;
+        self.check_suspended(address, buffer.len() as u32)?;
+        self.check_idle()?;
+        self.leave_continuous();
+        let mut result = Ok(());
+        self.read_banked(address, buffer, |spi, header, address, buffer| {
+            let n = header.fill(opcode, address) + dummy_bytes as usize;
+            result = result.and_then(|_| command_read(spi, &header.buffer[..n], buffer));
+        });
+        result
+    }
+
+    /// Performs several reads of scattered addresses, filling every buffer with the bytes at its address.
+    /// The reads are sorted by address in place, and runs of adjacent reads are done as one continuous read
+    /// if the transmitter can hold CS. The buffers must not overlap in flash.
+    /// Returns `Error::Busy` if the chip is busy, unless reads during busy are allowed.
+    /// Blocks until all reads are done.
+    pub fn read_scattered(&mut self, reads: &mut [(u32, &mut [u8])]) -> Result<(), Error> {
+        for (address, buffer) in reads.iter() {
+            self.check_bounds(*address, buffer.len() as u32)?;
+            self.check_suspended(*address, buffer.len() as u32)?;
+        }
+        self.check_idle()?;
+        reads.sort_unstable_by_key(|(address, _)| *address);
+        let mut i = 0;
+        while i < reads.len() {
+            let start = reads[i].0;
+            let mut end = start.checked_add(reads[i].1.len() as u32).ok_or(Error::OutOfBounds)?;
+            let mut j = i + 1;
+            while j < reads.len() && reads[j].0 == end {
+                let next_end = end.checked_add(reads[j].1.len() as u32).ok_or(Error::OutOfBounds)?;
+                // A continuous read can not cross into another bank in 3-byte address mode.
+                if self.header.width == AddressWidth::ThreeByte && (next_end - 1) / BANK_SIZE != start / BANK_SIZE {
+                    break;
+                }
+                end = next_end;
+                j += 1;
+            }
+            if j - i == 1 || !self.read_run(start, &mut reads[i..j]) {
+                for (address, buffer) in reads[i..j].iter_mut() {
+                    self.read_unchecked(*address, buffer)?;
+                }
+            }
+            i = j;
+        }
+        Ok(())
+    }
+
+    /// Reads the adjacent `run` starting at `start` with a single read command, all within one bank.
+    /// Returns `false` without reading anything if the transmitter can not hold CS.
+    fn read_run(&mut self, start: u32, run: &mut [(u32, &mut [u8])]) -> bool {
+        self.select_bank(start);
+        let n = self.header.fill(SPIFLASH_ARRAYREAD, start) + self.dummy_bytes[ReadMode::Fast as usize];
+        if !self.bus().begin() {
+            return false;
+        }
+        self.transmitter.send(&self.header.buffer[..n]);
+        for (_, buffer) in run.iter_mut() {
+            self.transmitter.read(buffer);
+        }
+        self.transmitter.end();
+        true
+    }
+
+    /// Reads a zero terminated string at `address` into `buffer` and returns it without the terminator.
+    /// Reads stop at the terminator, so `buffer` only needs to fit the string.
+    /// Returns `Error::Unterminated` if there is no zero byte within `buffer.len()` bytes
+    /// and `Error::InvalidUtf8` if the string is not valid UTF-8.
+    /// Returns `Error::OutOfBounds` if the string runs past the end of the chip or the address space.
+    pub fn read_cstr<'b>(&mut self, address: u32, buffer: &'b mut [u8]) -> Result<&'b str, Error> {
+        let mut len = 0;
+        while len < buffer.len() {
+            let n = (buffer.len() - len).min(SCAN_CHUNK);
+            let chunk_address = address.checked_add(len as u32).ok_or(Error::OutOfBounds)?;
+            let chunk = &mut buffer[len..len + n];
+            self.read_bytes(chunk_address, chunk)?;
+            if let Some(end) = chunk.iter().position(|&b| b == 0) {
+                return core::str::from_utf8(&buffer[..len + end]).map_err(|_| Error::InvalidUtf8);
+            }
+            len += n;
+        }
+        Err(Error::Unterminated)
+    }
+
+    /// Reads a string at `address` which is prefixed with its length as a single byte.
+    /// Returns `Error::OutOfBounds` if it does not fit into `buffer`
+    /// and `Error::InvalidUtf8` if it is not valid UTF-8.
+    pub fn read_length_prefixed<'b>(&mut self, address: u32, buffer: &'b mut [u8]) -> Result<&'b str, Error> {
+        let len = self.read_byte(address)? as usize;
+        let string = buffer.get_mut(..len).ok_or(Error::OutOfBounds)?;
+        self.read_bytes(address + 1, string)?;
+        core::str::from_utf8(string).map_err(|_| Error::InvalidUtf8)
+    }
+
+    /// Reads `buffer.len()` bytes at `address` without checking bounds or the busy state.
+    fn read_unchecked(&mut self, address: u32, buffer: &mut [u8]) -> Result<(), Error> {
+        if let Some((kind, mode, reader)) = self.continuous {
+            // Continue the multi-line read without opcode, keeping the mode byte, unless it needs a bank switch.
+            let (continuing, mode) = self.begin_io_read(kind, address, buffer.len() as u32, mode);
+            if continuing {
+                let dummy = self.io_dummy_bytes(kind.read_mode(), true);
+                self.read_banked(address, buffer, |spi, header, address, buffer| {
+                    let n = header.fill(SPIFLASH_ARRAYREAD, address);
+                    header.buffer[n] = mode;
+                    reader(spi, &[], &header.buffer[1..n + 1 + dummy], buffer);
+                });
+                self.end_io_read(kind, mode, reader);
+                return Ok(());
+            }
+        }
+        let dummy = self.dummy_bytes[ReadMode::Fast as usize];
+        let mut result = Ok(());
+        self.read_banked(address, buffer, |spi, header, address, buffer| {
+            let n = header.fill(SPIFLASH_ARRAYREAD, address) + dummy;
+            result = result.and_then(|_| command_read(spi, &header.buffer[..n], buffer));
+        });
+        result
+    }
+
+    /// Splits a read at bank boundaries and hands every piece to `read` after selecting its bank,
+    /// along with the header buffer.
+    /// In 4-byte address mode, the read is handed over in one piece.
+    fn read_banked<B>(&mut self, mut address: u32, mut buffer: &mut [B], mut read: impl FnMut(&mut T, &mut Header, u32, &mut [B])) {
+        if self.header.width == AddressWidth::FourByte {
+            read(&mut self.transmitter, &mut self.header, address, buffer);
+            return;
+        }
+        loop {
+            let in_bank = (BANK_SIZE - (address % BANK_SIZE)) as usize;
+            if buffer.len() <= in_bank {
+                break;
+            }
+            let (head, tail) = buffer.split_at_mut(in_bank);
+            self.select_bank(address);
+            read(&mut self.transmitter, &mut self.header, address, head);
+            address += in_bank as u32;
+            buffer = tail;
+        }
+        self.select_bank(address);
+        read(&mut self.transmitter, &mut self.header, address, buffer);
+    }
+
+    /// Makes a chip that was left in continuous read mode listen to opcodes again.
+    /// This clocks 0xFF in place of the address and the mode byte, so the chip reads a mode byte which drops out of the mode.
+    /// That takes 16 clocks with 3-byte addresses and 24 with 4-byte addresses, where the mode byte of a dual I/O read
+    /// only arrives after 20 clocks.
+    /// It is harmless if the chip is not in continuous read mode.
+    /// Alternatively, the last read in continuous read mode can pass a mode byte which leaves it, like 0xFF.
+    pub fn reset_continuous_read(&mut self) {
+        self.continuous = None;
+        let len = match self.header.width {
+            AddressWidth::ThreeByte => 2,
+            AddressWidth::FourByte => 3,
+        };
+        self.transmitter.send(&[SPIFLASH_CONTINUOUSREADRESET; 3][..len]);
+    }
+
+    /// The mode byte of the continuous read mode the chip was put into by a dual or quad I/O read, if any.
+    /// While it is active, further reads of the same kind skip the opcode,
+    /// and every other command leaves continuous read mode first.
+    pub fn continuous_read_mode(&self) -> Option<u8> {
+        self.continuous.map(|(_, mode, _)| mode)
+    }
+
+    /// Prepares a dual or quad I/O read of `len` bytes at `address` with `mode` and selects the bank of `address`.
+    /// Returns whether the chip is already in continuous read mode for this kind of read,
+    /// so the read can skip its opcode, and the mode byte to send.
+    /// Reads in another bank or crossing a bank boundary leave continuous read mode first, as the bank switch needs an opcode.
+    /// Those crossing a boundary do not enter it again either. Call `end_io_read()` once the read is clocked out.
+    fn begin_io_read(&mut self, kind: IoRead, address: u32, len: u32, mode: u8) -> (bool, u8) {
+        let three_byte = self.header.width == AddressWidth::ThreeByte;
+        let crosses_bank = three_byte && len > 0 && address / BANK_SIZE != (address + len - 1) / BANK_SIZE;
+        let switches_bank = three_byte && (address >> 24) as u8 != self.bank;
+        let continuing = !crosses_bank && !switches_bank && matches!(self.continuous, Some((active, ..)) if active == kind);
+        if !continuing {
+            self.leave_continuous();
+        }
+        self.select_bank(address);
+        let mode = if crosses_bank { SPIFLASH_CONTINUOUSREADRESET } else { mode };
+        (continuing, mode)
+    }
+
+    /// Records the continuous read mode a dual or quad I/O read with `mode` left the chip in.
+    fn end_io_read(&mut self, kind: IoRead, mode: u8, reader: IoReader<T>) {
+        self.continuous = if enters_continuous(mode) { Some((kind, mode, reader)) } else { None };
+    }
+
+    /// Writes a single byte to the SPI Flash at `address`
+    /// Blocks until the write is done.
+    pub fn write_byte(&mut self, address: u32, byte: u8) -> Result<(), Error> {
+        self.write_bytes(address, &[byte])
+    }
+
+    /// The program page size, 256 bytes unless the chip was identified otherwise.
+    fn page_size(&self) -> u32 {
+        self.info.map_or(256, |info| info.page_size)
+    }
+
+    /// Converts a linear address into its page number and the offset within that page,
+    /// using the configured page size.
+    pub fn address_to_page(&self, address: u32) -> (u32, u16) {
+        let page_size = self.page_size();
+        (address / page_size, (address % page_size) as u16)
+    }
+
+    /// Converts a page number and an offset within that page into a linear address,
+    /// using the configured page size.
+    /// Returns `Error::OutOfBounds` if `offset` does not lie within a page.
+    pub fn page_to_address(&self, page: u32, offset: u16) -> Result<u32, Error> {
+        let page_size = self.page_size();
+        if offset as u32 >= page_size {
+            return Err(Error::OutOfBounds);
+        }
+        page.checked_mul(page_size)
+            .and_then(|base| base.checked_add(offset as u32))
+            .ok_or(Error::OutOfBounds)
+    }
+
+    /// Splits `data` at page boundaries and hands every piece to `program`, along with the header buffer.
+    /// The bank of every page is selected and writes are enabled before it, and each page is waited for.
+    /// `abort` is checked before every page.
+    fn write_paged(&mut self, mut address: u32, mut data: &[u8], abort: impl Fn() -> bool, mut program: impl FnMut(&mut T, &mut Header, u32, &[u8])) -> Result<(), Error> {
+        self.flush()?;
+        self.check_bounds(address, data.len() as u32)?;
+        self.ensure_writable(address, data.len() as u32)?;
+        let page_size = self.page_size();
+        while !data.is_empty() {
+            if abort() {
+                return Err(Error::Aborted);
+            }
+            let n = ((page_size - address % page_size) as usize).min(data.len()).min(MAX_PROGRAM);
+            let (page, rest) = data.split_at(n);
+            if self.dry_run {
+                self.check_programmable(address, page)?;
+            }
+            if self.trace(TraceEvent::Program { address, len: n as u32 }) {
+                self.with_recovery(|flash| {
+                    flash.select_bank(address);
+                    flash.enable_write_checked()?;
+                    program(&mut flash.transmitter, &mut flash.header, address, page);
+                    flash.finish(Operation::Program)
+                })?;
+            }
+            address += n as u32;
+            data = rest;
+        }
+        Ok(())
+    }
+
+    /// Stores the record `data` twice, at `address_a` and `address_b`, each copy followed by its CRC-32 (little endian),
+    /// so `read_redundant()` can pick a valid one.
+    /// Every copy is erased first, along with the rest of the sectors it overlaps (the smallest erase size,
+    /// see `erase_range()`), so each copy needs sectors of its own.
+    /// Copy A is complete before copy B is touched, so a power loss leaves at least one of them valid.
+    /// Returns `Error::Overlap` if the copies share a sector, as writing one would erase the other.
+    /// Blocks until the write is done.
+    pub fn write_redundant(&mut self, address_a: u32, address_b: u32, data: &[u8]) -> Result<(), Error> {
+        self.check_copies_apart(address_a, address_b, data.len() as u32)?;
+        self.write_copy(address_a, data)?;
+        self.write_copy(address_b, data)
+    }
+
+    /// Reads a record stored by `write_redundant()` into `buffer`, which has the length of the record.
+    /// Copy A is returned if its CRC verifies, otherwise copy B.
+    /// A copy which does not verify is rewritten from the valid one, erasing the sectors it overlaps.
+    /// Returns `Error::BothCopiesBad` if neither copy verifies, and `Error::Overlap` if the copies share a sector.
+    pub fn read_redundant(&mut self, address_a: u32, address_b: u32, buffer: &mut [u8]) -> Result<(), Error> {
+        self.check_copies_apart(address_a, address_b, buffer.len() as u32)?;
+        if self.read_copy(address_a, buffer)? {
+            if !self.copy_valid(address_b, buffer.len() as u32)? {
+                self.write_copy(address_b, buffer)?;
+            }
+            return Ok(());
+        }
+        if !self.read_copy(address_b, buffer)? {
+            return Err(Error::BothCopiesBad);
+        }
+        self.write_copy(address_a, buffer)
+    }
+
+    /// Fails with `Error::Overlap` if the sectors erased for copies of `len` bytes at `address_a` and `address_b` intersect.
+    fn check_copies_apart(&self, address_a: u32, address_b: u32, len: u32) -> Result<(), Error> {
+        let len = len.checked_add(4).ok_or(Error::OutOfBounds)?;
+        let a = self.erase_span(address_a, len)?;
+        let b = self.erase_span(address_b, len)?;
+        if a.start < b.end && b.start < a.end {
+            return Err(Error::Overlap);
+        }
+        Ok(())
+    }
+
+    /// Erases the sectors of the copy at `address` and writes `data` along with its CRC.
+    fn write_copy(&mut self, address: u32, data: &[u8]) -> Result<(), Error> {
+        self.erase_range(address, data.len() as u32 + 4)?;
+        self.write_bytes(address, data)?;
+        self.write_bytes(address + data.len() as u32, &crc::crc32(data).to_le_bytes())
+    }
+
+    /// Reads the copy at `address` into `buffer` and returns whether its CRC verifies.
+    fn read_copy(&mut self, address: u32, buffer: &mut [u8]) -> Result<bool, Error> {
+        self.read_bytes(address, buffer)?;
+        let crc = self.read_array::<4>(address + buffer.len() as u32)?;
+        Ok(u32::from_le_bytes(crc) == crc::crc32(buffer))
+    }
+
+    /// Returns whether the CRC of the `len` byte copy at `address` verifies, without a buffer for the whole copy.
+    fn copy_valid(&mut self, address: u32, len: u32) -> Result<bool, Error> {
+        let mut crc = crc::Crc32::new();
+        let mut chunk = [0; SCAN_CHUNK];
+        let mut offset = 0;
+        while offset < len {
+            let n = (len - offset).min(SCAN_CHUNK as u32) as usize;
+            self.read_bytes(address + offset, &mut chunk[..n])?;
+            crc.update(&chunk[..n]);
+            offset += n as u32;
+        }
+        let stored = self.read_array::<4>(address + len)?;
+        Ok(u32::from_le_bytes(stored) == crc.finish())
+    }
+
+    /// Writes `data` just like `write_bytes` and returns the CRC-32 (see `crc::Crc32`) of the bytes sent to the chip.
+    /// Comparing it against an expected CRC confirms the right data went out without a second pass over the data.
+    /// Unlike a read-back verification, this trusts the bus and the chip to store what was sent,
+    /// so use `verify_pattern` or a read back CRC when the contents must be proven.
+    /// Blocks until the write is done.
+    pub fn write_bytes_crc(&mut self, address: u32, data: &[u8]) -> Result<u32, Error> {
+        let mut crc = crc::Crc32::new();
+        self.write_paged(address, data, || false, |spi, header, address, page| {
+            crc.update(page);
+            Self::program(spi, header, address, page);
+        })?;
+        Ok(crc.finish())
+    }
+
+    /// Reads `buffer.len()` bytes at `address` just like `read_bytes` and feeds them into `checksum`,
+    /// e.g. a fresh `crc::Crc32`, for blobs like firmware images or configuration records stored along with their CRC.
+    /// Returns `Error::VerifyMismatch` with `address` if the result differs from `expected`,
+    /// which catches corrupted reads as well as flash rot. The buffer holds what was read either way.
+    /// Other algorithms can be used by implementing `crc::Checksum`.
+    /// Blocks until the read is done.
+    pub fn read_verified<C: crc::Checksum>(&mut self, address: u32, buffer: &mut [u8], mut checksum: C, expected: C::Output) -> Result<(), Error> {
+        self.read_bytes(address, buffer)?;
+        checksum.update(buffer);
+        if checksum.finish() != expected {
+            return Err(Error::VerifyMismatch(address));
+        }
+        Ok(())
+    }
+
+    /// Issues a single line page program of `page`, which must not cross a page boundary.
+    /// If the transmitter can hold CS, the header and the data are sent separately.
+    /// Otherwise they have to be copied into one buffer on the stack.
+    fn program(spi: &mut T, header: &mut Header, address: u32, page: &[u8]) {
+        let n = header.fill(SPIFLASH_BYTEPAGEPROGRAM, address);
+        if spi.begin() {
+            spi.send(&header.buffer[..n]);
+            spi.send(page);
+            spi.end();
+            return;
+        }
+        let mut buffer = [0; 5 + MAX_PROGRAM];
+        buffer[..n].copy_from_slice(&header.buffer[..n]);
+        buffer[n..n + page.len()].copy_from_slice(page);
+        spi.send(&buffer[..n + page.len()]);
+    }
+
+    /// Writes `data` to the SPI Flash at `address`.
+    /// The write is split at page boundaries, so it can start anywhere and be of any length.
+    /// WARNING: You can only write to previously erased memory locations (see datasheet).
+    ///          Use the block erase commands to first clear memory (write 0xFFs).
+    /// Blocks until the write is done. See `program_page_async()` for the issue-only form of a single page.
+    pub fn write_bytes(&mut self, address: u32, data: &[u8]) -> Result<(), Error> {
+        self.write_bytes_abortable(address, data, || false)
+    }
+
+    /// Writes `data` to `address` just like `write_bytes`, but only within `region`,
+    /// which proves that the target was erased.
+    /// Returns `Error::OutOfBounds` if the write does not lie completely within `region`.
+    /// Blocks until the write is done.
+    pub fn write_erased(&mut self, region: &ErasedRegion, address: u32, data: &[u8]) -> Result<(), Error> {
+        let end = address.checked_add(data.len() as u32).ok_or(Error::OutOfBounds)?;
+        if address < region.range.start || end > region.range.end {
+            return Err(Error::OutOfBounds);
+        }
+        self.write_bytes(address, data)
+    }
+
+    /// Copies `len` bytes from `src` to `dst` within the chip, e.g. to duplicate an OTA slot.
+    /// The data goes through `scratch` in chunks of its length and is written just like `write_bytes`,
+    /// split at page boundaries with a write enable per page.
+    /// The target must be erased, e.g. with `erase_range()`: otherwise `Error::NotErased` is returned
+    /// with the first address that is not, before anything is written.
+    /// Returns `Error::Overlap` if source and target overlap and `Error::OutOfBounds` if `scratch` is empty.
+    /// Blocks until the copy is done.
+    pub fn copy(&mut self, src: u32, dst: u32, len: u32, scratch: &mut [u8]) -> Result<(), Error> {
+        self.check_bounds(src, len)?;
+        self.check_bounds(dst, len)?;
+        if len == 0 {
+            return Ok(());
+        }
+        if scratch.is_empty() {
+            return Err(Error::OutOfBounds);
+        }
+        let src_end = src.checked_add(len).ok_or(Error::OutOfBounds)?;
+        let dst_end = dst.checked_add(len).ok_or(Error::OutOfBounds)?;
+        if src < dst_end && dst < src_end {
+            return Err(Error::Overlap);
+        }
+        let chunk = scratch.len() as u32;
+        let mut offset = 0;
+        while offset < len {
+            let n = (len - offset).min(chunk) as usize;
+            self.read_bytes(dst + offset, &mut scratch[..n])?;
+            if let Some(i) = scratch[..n].iter().position(|&b| b != self.blank_byte) {
+                return Err(Error::NotErased(dst + offset + i as u32));
+            }
+            offset += n as u32;
+        }
+        let mut offset = 0;
+        while offset < len {
+            let n = (len - offset).min(chunk) as usize;
+            self.read_bytes(src + offset, &mut scratch[..n])?;
+            self.write_bytes(dst + offset, &scratch[..n])?;
+            offset += n as u32;
+        }
+        Ok(())
+    }
+
+    /// Writes `data` just like `write_bytes`, but checks `abort` before every page
+    /// and returns `Error::Aborted` once it returns `true`.
+    /// A page program that was already issued can not be interrupted, so the abort takes effect at the next page.
+    /// Everything before that page is written, everything after it is not.
+    pub fn write_bytes_abortable(&mut self, address: u32, data: &[u8], abort: impl Fn() -> bool) -> Result<(), Error> {
+        self.write_paged(address, data, abort, Self::program)
+    }
+
+    /// Programs as much of `data` at `address` as fits before the next page boundary and returns how many bytes that was,
+    /// so custom write loops can advance by it and call again with the rest.
+    /// The write enable is checked according to `set_wel_check()`.
+    /// Blocks until the program is done.
+    pub fn program_page(&mut self, address: u32, data: &[u8]) -> Result<usize, Error> {
+        let page_size = self.page_size();
+        let n = ((page_size - address % page_size) as usize).min(data.len()).min(MAX_PROGRAM);
+        self.write_paged(address, &data[..n], || false, Self::program)?;
+        Ok(n)
+    }
+
+    /// Issues a program of `data` at `address` and returns without waiting for it, see `flush()`.
+    /// This is the issue-only form of `write_bytes`, limited to a single page:
+    /// returns `Error::OutOfBounds` if `data` crosses a page boundary.
+    pub fn program_page_async(&mut self, address: u32, data: &[u8]) -> Result<(), Error> {
+        self.flush()?;
+        self.check_bounds(address, data.len() as u32)?;
+        let page_size = self.page_size();
+        if data.len() > MAX_PROGRAM || (address % page_size) as usize + data.len() > page_size as usize {
+            return Err(Error::OutOfBounds);
+        }
+        if data.is_empty() {
+            return Ok(());
+        }
+        self.ensure_writable(address, data.len() as u32)?;
+        if self.dry_run {
+            self.check_programmable(address, data)?;
+        }
+        if self.trace(TraceEvent::Program { address, len: data.len() as u32 }) {
+            self.select_bank(address);
+            self.enable_write_checked()?;
+            Self::program(&mut self.transmitter, &mut self.header, address, data);
+            self.pending = Some(Operation::Program);
+            self.pending_region = address..address + data.len() as u32;
+        }
+        Ok(())
+    }
+
+    /// Erase the entire flash memory.
+    /// Parts ignore a chip erase while any block is protected, so `Error::Protected` is returned instead of issuing it
+    /// if the block protect bits cover any part of the chip. Without known geometry, the bits are decoded as for
+    /// the largest chip, so only a configuration which protects nothing on any part lets the erase through.
+    /// Call `clear_block_protection()` first to erase a protected chip. Individual block locks are not checked.
+    /// Blocks until the erase is done. This can take up to several seconds.
+    /// See `chip_erase_async()` for the issue-only form.
+    pub fn chip_erase(&mut self) -> Result<(), Error> {
+        self.chip_erase_abortable(|| false)
+    }
+
+    /// Issues a chip erase and returns without waiting for it, see `flush()`.
+    /// This is the issue-only form of `chip_erase`.
+    pub fn chip_erase_async(&mut self) -> Result<(), Error> {
+        self.flush()?;
+        self.check_not_suspended()?;
+        let protected = match self.info {
+            Some(_) => self.protected_range()?.is_some(),
+            None => {
+                let status = self.read_status();
+                let status_2 = self.read_status_2();
+                decode_protection(u32::MAX, status, status_2).is_some()
+            }
+        };
+        if protected {
+            return Err(Error::Protected);
+        }
+        if self.trace(TraceEvent::ChipErase) {
+            self.enable_write_checked()?;
+            let opcode = self.info.map_or(SPIFLASH_CHIPERASE, |info| info.chip_erase_opcode);
+            self.bus().send(&[opcode]);
+            self.pending = Some(Operation::ChipErase);
+            self.pending_region = 0..u32::MAX;
+        }
+        Ok(())
+    }
+
+    /// Erases the entire flash memory just like `chip_erase`, but stops waiting for it
+    /// and returns `Error::Aborted` once `abort` returns `true`.
+    /// A chip erase can not be interrupted: the chip keeps erasing in the background
+    /// and the next command which has to wait for it blocks until it is done.
+    pub fn chip_erase_abortable(&mut self, abort: impl Fn() -> bool) -> Result<(), Error> {
+        if abort() {
+            return Err(Error::Aborted);
+        }
+        self.with_recovery(|flash| {
+            flash.chip_erase_async()?;
+            flash.flush_abortable(&abort)
+        })
+    }
+
+    /// Erases all blocks of the smallest erase size overlapping the `len` bytes starting at `start`,
+    /// 4K blocks before `identify()`. Nothing is erased if `len` is zero.
+    /// Returns an `ErasedRegion` covering those blocks, which can be passed to `write_erased()`.
+    /// Blocks until the erase is done.
+    pub fn erase_range(&mut self, start: u32, len: u32) -> Result<ErasedRegion, Error> {
+        self.erase_range_abortable(start, len, || false)?;
+        Ok(ErasedRegion { range: self.erase_span(start, len)? })
+    }
+
+    /// Erases a range just like `erase_range`, but checks `abort` before every block
+    /// and returns `Error::Aborted` once it returns `true`.
+    /// A block erase that was already issued can not be interrupted, so the abort takes effect at the next block.
+    pub fn erase_range_abortable(&mut self, start: u32, len: u32, abort: impl Fn() -> bool) -> Result<(), Error> {
+        self.check_bounds(start, len)?;
+        let span = self.erase_span(start, len)?;
+        let unit = self.erase_unit();
+        let mut address = span.start;
+        while address < span.end {
+            if abort() {
+                return Err(Error::Aborted);
+            }
+            self.erase(address, unit)?;
+            address += unit;
+        }
+        Ok(())
+    }
+
+    /// The smallest erase size `erase_range()` erases in: the sector size, or 4K without geometry.
+    pub(crate) fn erase_unit(&self) -> u32 {
+        self.info.map_or(4096, |info| info.sector_size)
+    }
+
+    /// The blocks of `erase_unit()` overlapping the `len` bytes starting at `start`. Empty if `len` is zero.
+    /// Returns `Error::OutOfBounds` if they do not fit the address space.
+    fn erase_span(&self, start: u32, len: u32) -> Result<Range<u32>, Error> {
+        if len == 0 {
+            return Ok(start..start);
+        }
+        let unit = self.erase_unit();
+        let end = start.checked_add(len).and_then(|end| end.checked_add(unit - 1)).ok_or(Error::OutOfBounds)?;
+        Ok(start & !(unit - 1)..end & !(unit - 1))
+    }
+
+    /// Erases all blocks overlapping the `len` bytes starting at `start` just like `erase_range`,
+    /// then reads all of them back to prove they are blank, e.g. to sanitize a partition before reprovisioning.
+    /// Returns `Error::VerifyMismatch` with the first address which did not read back as blank (see `set_blank_byte()`).
+    pub fn secure_erase_region(&mut self, start: u32, len: u32) -> Result<ErasedRegion, Error> {
+        let region = self.erase_range(start, len)?;
+        let range = region.range();
+        let blank = self.blank_byte;
+        self.verify_pattern(range.start, range.end - range.start, |_| blank)?;
+        Ok(region)
+    }
+
+    /// Erase a 4k block of the memory.
+    /// Blocks until the erase is done. See `erase_4k_block_async()` for the issue-only form.
+    pub fn erase_4k_block(&mut self, address: u32) -> Result<(), Error> {
+        self.erase(address, 0x1000)
+    }
+
+    /// Issues the erase of a 4k block and returns without waiting for it, see `flush()`.
+    /// This is the issue-only form of `erase_4k_block`.
+    pub fn erase_4k_block_async(&mut self, address: u32) -> Result<(), Error> {
+        self.erase_async(address, 0x1000)
+    }
+
+    /// Erases the block of `size` bytes containing `address`, using the opcode the chip advertises
+    /// for that size in `ChipInfo::erase_ops`. Without a `ChipInfo`, the common 0x20/0x52/0xD8 opcodes are used.
+    /// Returns `Error::Unsupported` if the chip has no erase type of that size and `Error::OutOfBounds` if the block
+    /// does not lie within the chip.
+    /// Blocks until the erase is done. See `erase_async()` for the issue-only form.
+    pub fn erase(&mut self, address: u32, size: u32) -> Result<(), Error> {
+        self.with_recovery(|flash| {
+            flash.erase_async(address, size)?;
+            flash.flush()
+        })
+    }
+
+    /// Erases the `size` unit containing `address` and returns the address it starts at.
+    /// Returns `Error::OutOfBounds` if the unit does not lie within the chip,
+    /// and `Error::Unsupported` if the chip has no erase type of that size.
+    /// Blocks until the erase is done.
+    pub fn erase_containing(&mut self, address: u32, size: EraseSize) -> Result<u32, Error> {
+        let size = match size {
+            EraseSize::Sector => self.erase_unit(),
+            EraseSize::Block32K => 32 * 1024,
+            EraseSize::Block64K => 64 * 1024,
+        };
+        let base = address & !(size - 1);
+        self.check_bounds(base, size)?;
+        self.erase(base, size)?;
+        Ok(base)
+    }
+
+    /// Whether `address` is the base of a block of `size` bytes, and `size` one of the erase sizes in `ChipInfo::erase_ops`
+    /// (4K, 32K and 64K without a `ChipInfo`). `erase()` takes any address within the block,
+    /// so this is for callers which want to reject an unaligned address instead.
+    pub fn is_sector_aligned(&self, address: u32, size: u32) -> bool {
+        let info = self.info.unwrap_or_else(|| ChipInfo::new(0));
+        info.erase_ops.iter().any(|&(op_size, _)| op_size == size && size > 0) && address & (size - 1) == 0
+    }
+
+    /// Issues the erase of the block of `size` bytes containing `address` and returns without waiting for it,
+    /// see `flush()`. This is the issue-only form of `erase`.
+    /// Returns `Error::OutOfBounds` if the block does not lie within the chip.
+    pub fn erase_async(&mut self, address: u32, size: u32) -> Result<(), Error> {
+        self.flush()?;
+        let info = self.info.unwrap_or_else(|| ChipInfo::new(0));
+        let opcode = info.erase_ops
+            .iter()
+            .find(|&&(op_size, _)| op_size == size && size > 0)
+            .map(|&(_, opcode)| opcode)
+            .ok_or(Error::Unsupported)?;
+        // Sanitize the address where we erase at.
+        let aligned_address = address & !(size - 1);
+        self.check_bounds(aligned_address, size)?;
+        let end = aligned_address.checked_add(size).ok_or(Error::OutOfBounds)?;
+        self.ensure_writable(aligned_address, size)?;
+        if self.trace(TraceEvent::Erase { address: aligned_address, size }) {
+            self.select_bank(aligned_address);
+            self.enable_write_checked()?;
+            let n = self.header.fill(opcode, aligned_address);
+            self.transmitter.send(&self.header.buffer[..n]);
+            self.pending = Some(Operation::Erase(size));
+            self.pending_region = aligned_address..end;
+        }
+        Ok(())
+    }
+
+    // pub fn erase_32k_block(uint32_t address) {
+    //     self.command(SPIFLASH_BLOCKERASE_32K, true); // Block Erase
+    //     SPI.transfer(address >> 16);
+    //     SPI.transfer(address >> 8);
+    //     SPI.transfer(address);
+    // }
+
+    // pub fn erase_64k_block(uint32_t address) {
+    //     self.command(SPIFLASH_BLOCKERASE_64K, true); // Block Erase
+    //     SPI.transfer(address >> 16);
+    //     SPI.transfer(address >> 8);
+    //     SPI.transfer(address);
+    // }
+
+    /// Enables sleep mode for the SPI Flash to have it consume less power.
+    pub fn sleep(&mut self) {
+        self.bus().send(&[SPIFLASH_SLEEP]);
+        self.known_idle = false;
+    }
+
+    /// Wakes the SPI Flash from sleep mode and confirms it answers again.
+    /// `delay_us` waits the 30 µs the chip takes to accept commands, then the JEDEC ID is read.
+    /// Returns `Error::WakeFailed` if it reads as all zeros or all ones.
+    pub fn wakeup(&mut self, mut delay_us: impl FnMut(u32)) -> Result<(), Error> {
+        self.wakeup_nocheck();
+        delay_us(RELEASE_POWER_DOWN_US);
+        match self.read_jedec_id().manufacturer {
+            0x00 | 0xFF => Err(Error::WakeFailed),
+            _ => Ok(()),
+        }
+    }
+
+    /// Wakes the SPI Flash from sleep mode without waiting or checking for it.
+    /// The chip only accepts commands again after its release time (tRES1), which the caller has to wait.
+    pub fn wakeup_nocheck(&mut self) {
+        self.bus().send(&[SPIFLASH_WAKE]);
+    }
+}
+
+impl<T> SPIFlash<T>
+where
+    T: DualTransmitter,
+{
+    /// Reads `buffer.len()` bytes at `address` with the dual I/O fast read (0xBB),
+    /// sending the address and the mode byte on two lines as well.
+    ///
+    /// The mode byte decides whether the chip enters continuous read mode.
+    /// On most parts (e.g. Winbond) a mode byte with M5-4 = 10 (like 0xA0 or 0x20) enters it,
+    /// after which the next dual I/O read is sent without opcode, with the dummy clocks of `set_continuous_read_dummy()`.
+    /// That read's mode byte decides again:
+    /// M5-4 = 10 stays in continuous read mode, anything else (like 0xFF) leaves it once the read is done.
+    /// Pass 0xFF to stay in normal mode. Any other command leaves continuous read mode first,
+    /// so the mode is never left behind unnoticed.
+    /// Blocks until the read is done.
+    pub fn read_bytes_dual_io(&mut self, address: u32, buffer: &mut [u8], mode: u8) -> Result<(), Error> {
+        if self.qpi {
+            return Err(Error::Unsupported);
+        }
+        self.check_bounds(address, buffer.len() as u32)?;
+        self.check_suspended(address, buffer.len() as u32)?;
+        // A chip in continuous read mode can not be busy, and a status read would end the mode.
+        if !matches!(self.continuous, Some((IoRead::Dual, ..))) {
+            self.check_idle()?;
+        }
+        let (continuing, mode) = self.begin_io_read(IoRead::Dual, address, buffer.len() as u32, mode);
+        let dummy = self.io_dummy_bytes(ReadMode::DualIo, continuing);
+        self.read_banked(address, buffer, |spi, header, address, buffer| {
+            let n = header.fill(SPIFLASH_ARRAYREADDUALIO, address);
+            header.buffer[n] = mode;
+            let opcode = if continuing { 0 } else { 1 };
+            spi.read_dual(&header.buffer[..opcode], &header.buffer[1..n + 1 + dummy], buffer);
+        });
+        self.end_io_read(IoRead::Dual, mode, T::read_dual);
+        Ok(())
+    }
+}
+
+impl<T> SPIFlash<T>
+where
+    T: QuadTransmitter,
+{
+    /// Reads `buffer.len()` bytes at `address` with the quad I/O fast read (0xEB),
+    /// sending the address, the mode byte and the dummy clocks (4 by default, see `dummy_cycles()`) on four lines as well.
+    ///
+    /// The mode byte is handled just like for `read_bytes_dual_io`:
+    /// a mode byte with M5-4 = 10 (like 0xA0) enters continuous read mode, in which the next quad I/O read
+    /// skips the opcode, 0xFF stays in normal mode, and any other command leaves continuous read mode first.
+    /// Returns `Error::QuadNotEnabled` if the QE bit is not set.
+    /// Blocks until the read is done.
+    pub fn read_bytes_quad_io(&mut self, address: u32, buffer: &mut [u8], mode: u8) -> Result<(), Error> {
+        self.check_bounds(address, buffer.len() as u32)?;
+        self.check_suspended(address, buffer.len() as u32)?;
+        // A chip in continuous read mode can not be busy, and the status reads would end the mode.
+        if !matches!(self.continuous, Some((IoRead::Quad, ..))) {
+            if !self.quad_enabled() {
+                return Err(Error::QuadNotEnabled);
+            }
+            self.check_idle()?;
+        }
+        let (continuing, mode) = self.begin_io_read(IoRead::Quad, address, buffer.len() as u32, mode);
+        let dummy = self.io_dummy_bytes(ReadMode::QuadIo, continuing);
+        self.read_banked(address, buffer, |spi, header, address, buffer| {
+            let n = header.fill(SPIFLASH_ARRAYREADQUADIO, address);
+            header.buffer[n] = mode;
+            let opcode = if continuing { 0 } else { 1 };
+            spi.read_quad(&header.buffer[..opcode], &header.buffer[1..n + 1 + dummy], buffer);
+        });
+        self.end_io_read(IoRead::Quad, mode, T::read_quad);
+        Ok(())
+    }
+
+    /// Puts the chip into QPI mode, in which every command, including its opcode, is clocked on four lines.
+    /// The transmitter is switched to QPI framing with `QuadTransmitter::set_qpi()`, so all other methods keep working,
+    /// except for the commands without a QPI variant (SFDP, the legacy ID and dual I/O reads),
+    /// which return `Error::Unsupported` while QPI mode is active.
+    ///
+    /// A reset or power cycle returns the chip to SPI mode, but the driver keeps framing commands for QPI.
+    /// Call `exit_qpi()` after that: its 0xFF reads as a harmless continuous read reset to a chip in SPI mode.
+    /// Returns `Error::QuadNotEnabled` if the QE bit is not set, see `set_quad_enable`.
+    pub fn enter_qpi(&mut self) -> Result<(), Error> {
+        if self.qpi {
+            return Ok(());
+        }
+        if !self.quad_enabled() {
+            return Err(Error::QuadNotEnabled);
+        }
+        self.bus().send(&[SPIFLASH_ENTERQPI]);
+        self.bus().set_qpi(true);
+        self.qpi = true;
+        Ok(())
+    }
+
+    /// Returns the chip and the transmitter to regular SPI mode.
+    pub fn exit_qpi(&mut self) {
+        if !self.qpi {
+            return;
+        }
+        self.bus().send(&[SPIFLASH_EXITQPI]);
+        self.bus().set_qpi(false);
+        self.qpi = false;
+    }
+
+    /// Whether QPI mode is active, see `enter_qpi()`.
+    pub fn qpi_enabled(&self) -> bool {
+        self.qpi
+    }
+
+    /// Writes `data` to the SPI Flash at `address` using the quad input page program.
+    /// Works just like `write_bytes`, but clocks the data out on four lines.
+    /// Returns `Error::QuadNotEnabled` if the QE bit is not set, see `set_quad_enable`.
+    /// Blocks until the write is done.
+    pub fn write_bytes_quad(&mut self, address: u32, data: &[u8]) -> Result<(), Error> {
+        if !self.quad_enabled() {
+            return Err(Error::QuadNotEnabled);
+        }
+        // In QPI mode, the regular page program already clocks everything on four lines.
+        if self.qpi {
+            return self.write_bytes(address, data);
+        }
+        self.write_paged(address, data, || false, |spi, header, address, page| {
+            let n = header.fill(SPIFLASH_QUADPAGEPROGRAM, address);
+            spi.write_quad(&header.buffer[..n], page);
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::RamFlash;
+
+    #[test]
+    fn it_works() {
+        assert_eq!(2 + 2, 4);
+    }
+
+    /// Builds an SFDP area with a basic flash parameter table of the given DWORDs.
+    fn sfdp_image(dwords: &[u32]) -> std::vec::Vec<u8> {
+        let mut image = vec![b'S', b'F', b'D', b'P', 0x06, 0x01, 0x00, 0xFF];
+        image.extend_from_slice(&[0x00, 0x06, 0x01, dwords.len() as u8, 0x10, 0x00, 0x00, 0xFF]);
+        for dword in dwords {
+            image.extend_from_slice(&dword.to_le_bytes());
+        }
+        image
+    }
+
+    #[test]
+    fn discover_without_sfdp() {
+        let mut flash = SPIFlash::new(RamFlash::new(0));
+        assert_eq!(flash.discover(), Err(Error::NoSfdp));
+        // Falls through to the JEDEC database, which knows the W25Q128.
+        assert_eq!(flash.identify(), Ok(chips::lookup(flash.read_jedec_id()).unwrap()));
+        assert_eq!(flash.info().unwrap().capacity, 16 * 1024 * 1024);
+    }
+
+    #[test]
+    fn geometry() {
+        let mut flash = SPIFlash::new(RamFlash::new(0));
+        assert_eq!(flash.capacity(), Err(Error::Unidentified));
+        flash.identify().unwrap();
+        assert_eq!(flash.capacity(), Ok(16 * 1024 * 1024));
+        assert_eq!(flash.sector_count(), Ok(4096));
+        assert_eq!(flash.page_count(), Ok(65536));
+        assert_eq!(flash.write_granularity(), Ok(1));
+        assert!(flash.requires_erase_before_write());
+    }
+
+    #[test]
+    fn chip_erase_opcode() {
+        let mut flash = SPIFlash::new(RamFlash::new(4096));
+        flash.chip_erase().unwrap();
+        assert!(flash.transmitter.log.contains(&vec![0x60]));
+
+        let mut ram = RamFlash::new(4096);
+        ram.jedec_id = [0x20, 0xBA, 0x18];
+        ram.memory[0] = 0;
+        let mut flash = SPIFlash::new(ram);
+        flash.identify().unwrap();
+        flash.chip_erase().unwrap();
+        assert!(flash.transmitter.log.contains(&vec![0xC7]));
+        assert_eq!(flash.transmitter.memory[0], 0xFF);
+    }
+
+    #[test]
+    fn chip_erase_protected() {
+        let mut ram = RamFlash::new(4096);
+        ram.memory[0] = 0;
+        ram.status[0] = 0b001 << 2;
+        let mut flash = SPIFlash::new(ram);
+        assert_eq!(flash.chip_erase(), Err(Error::Protected));
+        // Without geometry, CMP with no BP bits set still protects the whole chip.
+        flash.transmitter.status = [0, STATUS_CMP];
+        assert_eq!(flash.chip_erase(), Err(Error::Protected));
+        flash.transmitter.status = [0b001 << 2, 0];
+        flash.identify().unwrap();
+        assert_eq!(flash.chip_erase(), Err(Error::Protected));
+        assert!(!flash.transmitter.log.contains(&vec![0x60]));
+        assert_eq!(flash.transmitter.memory[0], 0);
+
+        // CMP with all BP bits set protects nothing.
+        flash.transmitter.status = [0b111 << 2, STATUS_CMP];
+        assert_eq!(flash.protected_range(), Ok(None));
+        flash.info = None;
+        flash.chip_erase().unwrap();
+        assert_eq!(flash.transmitter.memory[0], 0xFF);
+        flash.transmitter.memory[0] = 0;
+        flash.identify().unwrap();
+        flash.transmitter.status = [0b111 << 2, 0];
+        flash.clear_block_protection().unwrap();
+        assert_eq!(flash.transmitter.status, [0, 0]);
+        flash.chip_erase().unwrap();
+        assert_eq!(flash.transmitter.memory[0], 0xFF);
+
+        // A locked status register keeps the protection.
+        static WP: core::sync::atomic::AtomicBool = core::sync::atomic::AtomicBool::new(false);
+        flash.transmitter.wp = Some(&WP);
+        flash.transmitter.status[0] = STATUS_SRP0 | 0b001 << 2;
+        assert_eq!(flash.clear_block_protection(), Err(Error::Protected));
+    }
+
+    #[test]
+    fn global_lock() {
+        let mut flash = SPIFlash::new(RamFlash::new(0));
+        flash.global_lock().unwrap();
+        assert_eq!(flash.transmitter.log[flash.transmitter.log.len() - 3..], [vec![0x06], vec![0x05], vec![0x7E]]);
+        flash.global_unlock().unwrap();
+        assert_eq!(flash.transmitter.log.last(), Some(&vec![0x98]));
+        assert!(!StatusRegister(flash.read_status()).write_enabled());
+
+        flash.transmitter.ignore_write_enable = true;
+        flash.set_wel_check(WelCheck::Never);
+        assert_eq!(flash.global_unlock(), Err(Error::WriteNotEnabled));
+        assert_eq!(flash.transmitter.log.last(), Some(&vec![0x05]));
+    }
+
+    #[test]
+    fn self_test_write_protect() {
+        use core::sync::atomic::{AtomicBool, Ordering};
+
+        static WP: AtomicBool = AtomicBool::new(true);
+        let mut ram = RamFlash::new(4096);
+        ram.wp = Some(&WP);
+        ram.status[0] = 0b001 << 2;
+        let mut flash = SPIFlash::new(ram);
+        let report = flash.self_test_write_protect(|high| WP.store(high, Ordering::SeqCst)).unwrap();
+        assert!(report.passed());
+        assert_eq!(flash.transmitter.status[0], 0b001 << 2);
+        assert!(WP.load(Ordering::SeqCst));
+
+        // An unconnected /WP never protects anything.
+        let mut flash = SPIFlash::new(RamFlash::new(4096));
+        let report = flash.self_test_write_protect(|_| {}).unwrap();
+        assert_eq!(report, WpSelfTest { blocked_when_low: false, accepted_when_high: true });
+        assert_eq!(flash.transmitter.status[0], 0);
+    }
+
+    #[test]
+    fn continuous_status_polling() {
+        let mut ram = RamFlash::new(16 * 1024);
+        ram.operation_polls = 10;
+        let mut flash = SPIFlash::new(ram);
+        flash.erase_4k_block(0).unwrap();
+        // One status read while waiting to enable the write, one checking WEL, one for the whole erase.
+        let status_reads = |flash: &SPIFlash<RamFlash>| flash.transmitter.log.iter().filter(|c| c[..] == [0x05]).count();
+        assert_eq!(status_reads(&flash), 3);
+
+        // Without CS control, every poll is a status read of its own.
+        let mut ram = RamFlash::new(16 * 1024);
+        ram.operation_polls = 10;
+        ram.cs_hold = false;
+        let mut flash = SPIFlash::new(ram);
+        flash.erase_4k_block(0).unwrap();
+        assert_eq!(status_reads(&flash), 13);
+    }
+
+    #[test]
+    fn wel_check() {
+        let mut ram = RamFlash::new(4096);
+        ram.ignore_write_enable = true;
+        let mut flash = SPIFlash::new(ram);
+        assert_eq!(flash.write_bytes(0, &[1, 2]), Err(Error::WriteNotEnabled));
+        assert_eq!(flash.erase_4k_block(0), Err(Error::WriteNotEnabled));
+
+        // Without the check, the ignored write only shows when verifying it.
+        flash.set_wel_check(WelCheck::Never);
+        let frames = flash.transmitter.log.len();
+        assert_eq!(flash.write_bytes(0, &[1, 2]), Ok(()));
+        assert_eq!(flash.transmitter.log.len() - frames, 4);
+        assert_eq!(flash.verify_pattern(0, 2, |a| a as u8 + 1), Err(Error::VerifyMismatch(0)));
+
+        // On error, the mismatch is traced back to the write enable.
+        flash.set_wel_check(WelCheck::OnError);
+        assert_eq!(flash.write_bytes(0, &[1, 2]), Ok(()));
+        assert_eq!(flash.verify_pattern(0, 2, |a| a as u8 + 1), Err(Error::WriteNotEnabled));
+        flash.transmitter.ignore_write_enable = false;
+        flash.write_bytes(0x10, &[1]).unwrap();
+        assert_eq!(flash.verify_pattern(0x10, 1, |_| 2), Err(Error::VerifyMismatch(0x10)));
+        assert!(!StatusRegister(flash.read_status()).write_enabled());
+    }
+
+    #[test]
+    fn probe_capacity() {
+        let mut ram = RamFlash::new(64 * 1024);
+        // A copy of the marker elsewhere is not mistaken for the wrap.
+        ram.memory[0x3000..0x3008].copy_from_slice(&PROBE_MARKER);
+        let mut flash = SPIFlash::new(ram);
+        assert_eq!(flash.probe_capacity(ScratchSector::consent_to_erase(0x1234)), Ok(64 * 1024));
+        assert!(flash.transmitter.memory[0x1000..0x2000].iter().all(|&b| b == 0xFF));
+        assert_eq!(flash.transmitter.memory[0x3000..0x3008], PROBE_MARKER);
+
+        let mut flash = SPIFlash::new(RamFlash::new(48 * 1024));
+        assert_eq!(flash.probe_capacity(ScratchSector::consent_to_erase(0)), Err(Error::Unidentified));
+    }
+
+    #[test]
+    fn layout_partitions() {
+        let mut flash = SPIFlash::new(RamFlash::new(0));
+        flash.info = Some(ChipInfo::new(64 * 1024));
+        let mut partitions = [Partition::default(); 3];
+        flash.layout_partitions(&[100, 8192, 4097], &mut partitions).unwrap();
+        assert_eq!(
+            partitions,
+            [
+                Partition { offset: 0, size: 100 },
+                Partition { offset: 4096, size: 8192 },
+                Partition { offset: 12288, size: 4097 },
+            ]
+        );
+        assert_eq!(flash.layout_partitions(&[4096, 60 * 1024 + 1], &mut partitions), Err(Error::OutOfBounds));
+        assert_eq!(flash.layout_partitions(&[1; 4], &mut partitions), Err(Error::OutOfBounds));
+    }
+
+    #[test]
+    fn geometry_conflict() {
+        use core::sync::atomic::{AtomicU32, Ordering};
+        static SFDP_CAPACITY: AtomicU32 = AtomicU32::new(0);
+
+        // 64 Mbit according to SFDP, while the default JEDEC ID stands for the 128 Mbit W25Q128.
+        let mut ram = RamFlash::new(0);
+        ram.sfdp = sfdp_image(&[0xFFF1_20E5, 0x03FF_FFFF]);
+        let mut flash = SPIFlash::new(ram);
+        flash.set_trace_hook(Some(|event| {
+            if let TraceEvent::GeometryConflict { sfdp, jedec } = event {
+                assert_eq!(jedec, 16 * 1024 * 1024);
+                SFDP_CAPACITY.store(sfdp, Ordering::Relaxed);
+            }
+        }));
+        assert_eq!(flash.identify().map(|info| info.capacity), Ok(8 * 1024 * 1024));
+        assert_eq!(SFDP_CAPACITY.load(Ordering::Relaxed), 8 * 1024 * 1024);
+
+        flash.set_strict_geometry(true);
+        assert_eq!(flash.discover(), Err(Error::GeometryConflict));
+        flash.transmitter.jedec_id = [0xEF, 0x40, 0x17];
+        assert_eq!(flash.discover().map(|info| info.capacity), Ok(8 * 1024 * 1024));
+        // Capacity bytes in no common encoding are not checked.
+        flash.transmitter.jedec_id = [0x1F, 0x44, 0x01];
+        assert!(flash.discover().is_ok());
+        assert_eq!(JedecId::new(0x20, 0xBA, 0x20).capacity_bytes(), Some(64 * 1024 * 1024));
+    }
+
+    #[test]
+    fn discover_unknown_chip() {
+        let mut ram = RamFlash::new(0);
+        ram.jedec_id = [0xFF; 3];
+        let mut flash = SPIFlash::new(ram);
+        assert_eq!(flash.identify(), Err(Error::Unidentified));
+        assert_eq!(flash.info(), None);
+    }
+
+    #[test]
+    fn discover_basic_table() {
+        let mut ram = RamFlash::new(0);
+        // 64 Mbit, 4K and 64K erase.
+        ram.sfdp = sfdp_image(&[0xFFF1_20E5, 0x03FF_FFFF, 0, 0, 0, 0, 0, 0xD810_200C, 0]);
+        let mut flash = SPIFlash::new(ram);
+        let info = ChipInfo {
+            erase_ops: [(4 * 1024, 0x20), (64 * 1024, 0xD8), (0, 0), (0, 0)],
+            power_up_width: Some(AddressWidth::ThreeByte),
+            ..ChipInfo::new(8 * 1024 * 1024)
+        };
+        assert_eq!(flash.identify(), Ok(info));
+
+        // A corrupt erase type exponent is skipped instead of overflowing the shift.
+        flash.transmitter.sfdp = sfdp_image(&[0xFFF1_20E5, 0x03FF_FFFF, 0, 0, 0, 0, 0, 0xD810_2040, 0]);
+        assert_eq!(flash.discover().map(|info| info.sector_size), Ok(64 * 1024));
+        // Without any valid erase type, the table is not usable.
+        flash.transmitter.sfdp = sfdp_image(&[0xFFF1_20E5, 0x03FF_FFFF, 0, 0, 0, 0, 0, 0xD8FF_20FF, 0]);
+        assert_eq!(flash.discover(), Err(Error::NoSfdp));
+    }
+
+    #[test]
+    fn power_up_address_width() {
+        // A 4-byte only part.
+        let mut ram = RamFlash::new(0);
+        ram.sfdp = sfdp_image(&[0xFFF5_20E5, 0x03FF_FFFF]);
+        let mut flash = SPIFlash::new(ram);
+        let info = flash.identify().unwrap();
+        assert_eq!((info.power_up_width, info.four_byte_mode), (Some(AddressWidth::FourByte), FourByteMode::Always));
+        assert_eq!(flash.address_width(), AddressWidth::FourByte);
+
+        // Parts taking both widths may have been configured to power up in 4-byte mode.
+        let mut ram = RamFlash::new(0);
+        ram.sfdp = sfdp_image(&[0xFFF3_20E5, 0x03FF_FFFF]);
+        let mut flash = SPIFlash::new(ram);
+        assert_eq!(flash.identify().unwrap().power_up_width, None);
+        assert_eq!(flash.address_width(), AddressWidth::ThreeByte);
+
+        // Unless a power cycle leaves 4-byte mode, as DWORD 16 bit 21 tells. Bit 16, the exit through the extended address register, does not.
+        let mut dwords = [0; 16];
+        dwords[..2].copy_from_slice(&[0xFFF3_20E5, 0x03FF_FFFF]);
+        dwords[7] = 0xD810_200C;
+        dwords[15] = 1 << 16;
+        flash.transmitter.sfdp = sfdp_image(&dwords);
+        assert_eq!(flash.identify().unwrap().power_up_width, None);
+        dwords[15] = 1 << 21;
+        flash.transmitter.sfdp = sfdp_image(&dwords);
+        assert_eq!(flash.identify().unwrap().power_up_width, Some(AddressWidth::ThreeByte));
+
+        // The W25Q256 tells in ADS.
+        let mut ram = RamFlash::new(0);
+        ram.jedec_id = [0xEF, 0x40, 0x19];
+        ram.four_byte = true;
+        let mut flash = SPIFlash::new(ram);
+        flash.identify().unwrap();
+        assert_eq!(flash.address_width(), AddressWidth::FourByte);
+    }
+
+    #[test]
+    fn discover_dummy_cycles() {
+        let mut ram = RamFlash::new(4096);
+        ram.memory[100..104].copy_from_slice(&[1, 2, 3, 4]);
+        // The W25Q64 tables: quad I/O with 2 mode and 4 wait clocks, dual I/O with 2 and 2.
+        ram.sfdp = sfdp_image(&[0xFFF1_20E5, 0x03FF_FFFF, 0x6B08_EB44, 0xBB42_3B08, 0, 0, 0, 0xD810_200C, 0]);
+        let mut flash = SPIFlash::new(ram);
+        flash.identify().unwrap();
+        assert_eq!(flash.dummy_cycles(ReadMode::Fast), 8);
+        assert_eq!(flash.dummy_cycles(ReadMode::DualIo), 0);
+        assert_eq!(flash.dummy_cycles(ReadMode::QuadIo), 4);
+
+        // A part with 10 clocks of quad I/O latency (1 mode and 9 wait clocks) reads with 4 dummy bytes after the mode byte.
+        flash.transmitter.sfdp = sfdp_image(&[0xFFF1_20E5, 0x03FF_FFFF, 0x6B08_EB29, 0xBB42_3B08, 0, 0, 0, 0xD810_200C, 0]);
+        flash.identify().unwrap();
+        assert_eq!(flash.info().unwrap().quad_io_dummy_cycles, 8);
+        flash.set_quad_enable(true).unwrap();
+        let mut buffer = [0; 4];
+        flash.read_bytes_quad_io(100, &mut buffer, 0xFF).unwrap();
+        assert_eq!(buffer, [1, 2, 3, 4]);
+        assert_eq!(flash.transmitter.log.last(), Some(&vec![0xEB, 0, 0, 100, 0xFF, 0, 0, 0, 0]));
+
+        assert_eq!(flash.set_dummy_cycles(ReadMode::QuadIo, 3), Err(Error::OutOfBounds));
+        flash.set_dummy_cycles(ReadMode::Fast, 16).unwrap();
+        flash.read_bytes(100, &mut buffer).unwrap();
+        assert_eq!(flash.transmitter.log.last(), Some(&vec![0x0B, 0, 0, 100, 0, 0]));
+    }
+
+    #[test]
+    fn secure_erase_region() {
+        let mut ram = RamFlash::new(4 * 4096);
+        ram.memory.iter_mut().for_each(|b| *b = 0x5A);
+        let mut flash = SPIFlash::new(ram);
+        flash.info = Some(ChipInfo::new(4 * 4096));
+
+        let region = flash.secure_erase_region(0x1800, 0x1000).unwrap();
+        assert_eq!(region.range(), 0x1000..0x3000);
+        assert!(flash.transmitter.memory[0x1000..0x3000].iter().all(|&b| b == 0xFF));
+        assert_eq!(flash.transmitter.memory[0x3000], 0x5A);
+
+        // An erase which never reaches the chip leaves the old data behind.
+        flash.set_dry_run(true);
+        assert_eq!(flash.secure_erase_region(0x3000, 1), Err(Error::VerifyMismatch(0x3000)));
+    }
+
+    #[test]
+    fn write_erased() {
+        let mut ram = RamFlash::new(16 * 1024);
+        ram.memory.iter_mut().for_each(|b| *b = 0);
+        let mut flash = SPIFlash::new(ram);
+        let region = flash.erase_range(0x1100, 0x100).unwrap();
+        assert_eq!(region.range(), 0x1000..0x2000);
+        flash.write_erased(&region, 0x1FFE, &[1, 2]).unwrap();
+        assert_eq!(flash.transmitter.memory[0x1FFE..0x2000], [1, 2]);
+        assert_eq!(flash.write_erased(&region, 0x1FFF, &[1, 2]), Err(Error::OutOfBounds));
+        assert_eq!(flash.write_erased(&region, 0xFFF, &[1]), Err(Error::OutOfBounds));
+    }
+
+    #[test]
+    fn copy() {
+        let mut ram = RamFlash::new(16 * 1024);
+        for (i, b) in ram.memory[..0x1000].iter_mut().enumerate() {
+            *b = i as u8;
+        }
+        let mut flash = SPIFlash::new(ram);
+        let mut scratch = [0; 100];
+        flash.copy(0x10, 0x2030, 0x900, &mut scratch).unwrap();
+        assert_eq!(flash.transmitter.memory[0x2030..0x2930], flash.transmitter.memory[0x10..0x910]);
+        assert_eq!(flash.copy(0, 0x2800, 0x100, &mut scratch), Err(Error::NotErased(0x2800)));
+        assert_eq!(flash.copy(0, 0x800, 0x900, &mut scratch), Err(Error::Overlap));
+        assert_eq!(flash.copy(0, 0x3000, 0x100, &mut []), Err(Error::OutOfBounds));
+        // Without known geometry, only ranges wrapping around the address space are rejected.
+        assert_eq!(flash.copy(0, u32::MAX - 0x10, 0x100, &mut scratch), Err(Error::OutOfBounds));
+    }
+
+    #[test]
+    fn dry_run() {
+        use core::sync::atomic::{AtomicU32, Ordering};
+        static EVENTS: AtomicU32 = AtomicU32::new(0);
+
+        let mut ram = RamFlash::new(16 * 1024);
+        ram.memory[0x1100] = 0x0F;
+        let mut flash = SPIFlash::new(ram);
+        flash.identify().unwrap();
+        flash.set_dry_run(true);
+        flash.set_trace_hook(Some(|_| {
+            EVENTS.fetch_add(1, Ordering::Relaxed);
+        }));
+
+        flash.write_bytes(0x10F0, &[0; 32]).unwrap();
+        assert_eq!(flash.write_bytes(0x1100, &[0xF0]), Err(Error::NotErased(0x1100)));
+        flash.erase_range(0x1000, 0x2000).unwrap();
+        flash.chip_erase().unwrap();
+        assert_eq!(EVENTS.load(Ordering::Relaxed), 5);
+        assert!(flash.transmitter.log.iter().all(|c| ![0x06, 0x02, 0x20, 0x60].contains(&c[0])));
+        assert_eq!(flash.read_byte(0x1100), Ok(0x0F));
+    }
+
+    #[test]
+    fn four_byte_addresses() {
+        let mut ram = RamFlash::new(32 * 1024 * 1024);
+        ram.jedec_id = [0xEF, 0x40, 0x19];
+        let mut flash = SPIFlash::new(ram);
+        flash.identify().unwrap();
+        flash.set_address_width(AddressWidth::FourByte).unwrap();
+        assert!(flash.transmitter.four_byte);
+        flash.write_byte(0x0100_0010, 0x42).unwrap();
+        assert!(flash.transmitter.log.contains(&vec![0x02, 0x01, 0x00, 0x00, 0x10, 0x42]));
+        assert_eq!(flash.read_byte(0x0100_0010), Ok(0x42));
+        assert_eq!(flash.transmitter.memory[0x0100_0010], 0x42);
+        assert!(flash.transmitter.log.iter().all(|c| c[0] != 0xC5));
+        flash.set_address_width(AddressWidth::ThreeByte).unwrap();
+        assert!(!flash.transmitter.four_byte);
+
+        // The SFDP basic table asks for the bank register method.
+        let mut ram = RamFlash::new(0);
+        let mut table = [0; 16];
+        table[1] = 0x03FF_FFFF;
+        table[7] = 0xD810_200C;
+        table[15] = 1 << (24 + 3);
+        ram.sfdp = sfdp_image(&table);
+        let mut flash = SPIFlash::new(ram);
+        assert_eq!(flash.identify().unwrap().four_byte_mode, FourByteMode::BankRegister);
+        flash.set_address_width(AddressWidth::FourByte).unwrap();
+        assert_eq!(flash.transmitter.log.last(), Some(&vec![0x17, 0x80]));
+
+        flash.info.as_mut().unwrap().four_byte_mode = FourByteMode::Unsupported;
+        assert_eq!(flash.set_address_width(AddressWidth::FourByte), Err(Error::Unsupported));
+    }
+
+    #[test]
+    fn bank_selected_for_writes() {
+        let mut ram = RamFlash::new(32 * 1024 * 1024);
+        ram.jedec_id = [0xEF, 0x40, 0x19];
+        let mut flash = SPIFlash::new(ram);
+        flash.identify().unwrap();
+        flash.write_bytes(0x0100_0010, &[1, 2]).unwrap();
+        assert!(flash.transmitter.log.contains(&vec![0xC5, 0x01]));
+        assert!(flash.transmitter.log.contains(&vec![0x02, 0x00, 0x00, 0x10, 1, 2]));
+        assert_eq!(flash.transmitter.memory[0x0100_0010..0x0100_0012], [1, 2]);
+        assert_eq!(flash.transmitter.memory[0x10], 0xFF);
+
+        // Staying in the bank does not write the bank register again.
+        flash.erase_4k_block(0x0100_0000).unwrap();
+        flash.write_byte(0x0100_2000, 3).unwrap();
+        assert_eq!(flash.transmitter.log.iter().filter(|c| c[0] == 0xC5).count(), 1);
+        assert_eq!(flash.transmitter.memory[0x0100_0010], 0xFF);
+        assert_eq!(flash.read_byte(0x0100_2000), Ok(3));
+
+        flash.write_byte(0x20, 4).unwrap();
+        assert_eq!(flash.transmitter.bank, 0);
+        assert_eq!(flash.transmitter.memory[0x20], 4);
+    }
+
+    #[test]
+    fn issue_then_flush() {
+        let mut ram = RamFlash::new(16 * 1024);
+        ram.operation_polls = 3;
+        let mut flash = SPIFlash::new(ram);
+
+        flash.erase_4k_block_async(0x1000).unwrap();
+        assert!(flash.is_busy());
+        assert_eq!(flash.read_byte(0x1000), Err(Error::Busy));
+        flash.flush().unwrap();
+        assert!(!flash.is_busy());
+
+        flash.program_page_async(0x10FE, &[1, 2]).unwrap();
+        // The next operation waits for the pending one first.
+        flash.program_page_async(0x1100, &[3]).unwrap();
+        flash.flush().unwrap();
+        assert_eq!(flash.transmitter.memory[0x10FE..0x1101], [1, 2, 3]);
+        assert_eq!(flash.program_page_async(0x10FF, &[1, 2]), Err(Error::OutOfBounds));
+
+        // A flush without anything pending returns right away.
+        let transfers = flash.transmitter.log.len();
+        flash.flush().unwrap();
+        assert_eq!(flash.transmitter.log.len(), transfers);
+    }
+
+    #[test]
+    fn program_page() {
+        let mut flash = SPIFlash::new(RamFlash::new(4096));
+        let data: std::vec::Vec<u8> = (0..300).map(|i| i as u8).collect();
+        let mut written = 0;
+        while written < data.len() {
+            let n = flash.program_page(0xF0 + written as u32, &data[written..]).unwrap();
+            assert!(n > 0);
+            written += n;
+        }
+        assert_eq!(flash.transmitter.log.iter().filter(|c| c[0] == 0x02).count(), 3);
+        assert_eq!(flash.transmitter.memory[0xF0..0xF0 + 300], data[..]);
+
+        assert_eq!(flash.program_page(0x10, &[]), Ok(0));
+        flash.info = Some(ChipInfo::new(4096));
+        assert_eq!(flash.program_page(4095, &[1, 2]), Ok(1));
+        assert_eq!(flash.program_page(4096, &[1]), Err(Error::OutOfBounds));
+    }
+
+    #[test]
+    fn driver_state() {
+        let mut ram = RamFlash::new(16 * 1024);
+        ram.operation_polls = 3;
+        let mut flash = SPIFlash::new(ram);
+        flash.erase_4k_block_async(0).unwrap();
+        flash.set_wel_check(WelCheck::Never);
+        let transfers = flash.transmitter.log.len();
+        assert_eq!(
+            flash.state(),
+            DriverState {
+                address_width: AddressWidth::ThreeByte,
+                qpi: false,
+                continuous_read: None,
+                pending: Some(Operation::Erase(4096)),
+                suspended: None,
+                bank: 0,
+                wel_check: WelCheck::Never,
+                status_write_mode: StatusWriteMode::NonVolatile,
+            }
+        );
+        assert_eq!(flash.transmitter.log.len(), transfers);
+        flash.flush().unwrap();
+        assert_eq!(flash.state().pending, None);
+    }
+
+    #[test]
+    fn estimated_duration() {
+        let mut ram = RamFlash::new(0);
+        ram.jedec_id = [0xEF, 0x40, 0x18];
+        let mut flash = SPIFlash::new(ram);
+        assert_eq!(flash.estimated_duration(Operation::Program), None);
+        // The chip database only has worst-case times.
+        flash.identify().unwrap();
+        assert_eq!(flash.estimated_duration(Operation::Program), None);
+
+        let mut ram = RamFlash::new(0);
+        // 4K erase in 80 ms, 64K erase in 256 ms, programs in 64 us, chip erase in 2.56 s, all typical.
+        ram.sfdp = sfdp_image(&[0xFFF1_20E5, 0x03FF_FFFF, 0, 0, 0, 0, 0, 0xD810_200C, 0, 0x0001_7A40, 0x2900_0701]);
+        let mut flash = SPIFlash::new(ram);
+        flash.identify().unwrap();
+        assert_eq!(flash.estimated_duration(Operation::Program), Some(64));
+        assert_eq!(flash.estimated_duration(Operation::Erase(4096)), Some(80_000));
+        assert_eq!(flash.estimated_duration(Operation::Erase(64 * 1024)), Some(256_000));
+        assert_eq!(flash.estimated_duration(Operation::Erase(32 * 1024)), None);
+        assert_eq!(flash.estimated_duration(Operation::ChipErase), Some(2_560_000));
+    }
+
+    #[test]
+    fn read_array() {
+        let mut ram = RamFlash::new(4096);
+        ram.memory[4092..].copy_from_slice(&[1, 2, 3, 4]);
+        let mut flash = SPIFlash::new(ram);
+        flash.info = Some(ChipInfo::new(4096));
+        assert_eq!(flash.read_array(4092), Ok([1, 2, 3, 4]));
+        assert_eq!(flash.read_array::<2>(4094), Ok([3, 4]));
+        assert_eq!(flash.read_array::<5>(4092), Err(Error::OutOfBounds));
+    }
+
+    #[test]
+    fn read_bytes_with() {
+        let mut ram = RamFlash::new(4096);
+        ram.memory[100..104].copy_from_slice(&[1, 2, 3, 4]);
+        let mut flash = SPIFlash::new(ram);
+        let mut buffer = [0; 4];
+        flash.read_bytes_with(0x03, 0, 100, &mut buffer).unwrap();
+        assert_eq!(buffer, [1, 2, 3, 4]);
+        assert_eq!(flash.transmitter.log.last(), Some(&vec![0x03, 0, 0, 100]));
+        flash.read_bytes_with(0x0B, 2, 100, &mut buffer).unwrap();
+        assert_eq!(flash.transmitter.log.last(), Some(&vec![0x0B, 0, 0, 100, 0, 0]));
+        // The configured fast read is left alone.
+        assert_eq!(flash.dummy_cycles(ReadMode::Fast), 8);
+        assert_eq!(flash.read_bytes_with(0x0B, 9, 100, &mut buffer), Err(Error::OutOfBounds));
+    }
+
+    #[test]
+    fn four_byte_readback() {
+        let mut ram = RamFlash::new(32 * 1024 * 1024);
+        ram.jedec_id = [0xEF, 0x40, 0x19];
+        let mut flash = SPIFlash::new(ram);
+        flash.identify().unwrap();
+        flash.set_address_width(AddressWidth::FourByte).unwrap();
+        assert_eq!(flash.transmitter.log.last(), Some(&vec![0x15]));
+
+        // A chip unexpectedly in QPI mode ignores the mode command, and its status reads as 0xFF.
+        flash.transmitter.qpi_mode = true;
+        assert_eq!(flash.set_address_width(AddressWidth::ThreeByte), Err(Error::ModeSwitchFailed));
+        assert_eq!(flash.address_width(), AddressWidth::FourByte);
+    }
+
+    #[test]
+    fn qpi_mode() {
+        let mut flash = SPIFlash::new(RamFlash::new(4096));
+        assert_eq!(flash.enter_qpi(), Err(Error::QuadNotEnabled));
+        flash.set_quad_enable(true).unwrap();
+        flash.enter_qpi().unwrap();
+        assert!(flash.transmitter.qpi_mode);
+        flash.write_bytes_quad(10, &[1, 2]).unwrap();
+        assert_eq!(flash.read_byte(11), Ok(2));
+        assert_eq!(flash.read_bytes_dual_io(10, &mut [0; 2], 0xFF), Err(Error::Unsupported));
+        assert_eq!(flash.discover(), Err(Error::Unsupported));
+        assert_eq!(flash.read_jedec_id().manufacturer, 0xEF);
+        assert_eq!(flash.transmitter.log.last(), Some(&vec![0xAF]));
+        flash.exit_qpi();
+        assert!(!flash.transmitter.qpi_mode);
+        assert_eq!(flash.read_jedec_id().manufacturer, 0xEF);
+    }
+
+    #[test]
+    fn erase_containing() {
+        let mut flash = SPIFlash::new(RamFlash::new(128 * 1024));
+        flash.transmitter.memory.iter_mut().for_each(|b| *b = 0);
+        flash.info = Some(ChipInfo::new(128 * 1024));
+        assert_eq!(flash.erase_containing(0x1234, EraseSize::Sector), Ok(0x1000));
+        assert!(flash.transmitter.log.contains(&vec![0x20, 0x00, 0x10, 0x00]));
+        assert_eq!(flash.erase_containing(0x9001, EraseSize::Block32K), Ok(0x8000));
+        assert_eq!(flash.erase_containing(0x1FFFF, EraseSize::Block64K), Ok(0x10000));
+        let erased = |flash: &SPIFlash<RamFlash>, range: Range<usize>| flash.transmitter.memory[range].iter().all(|&b| b == 0xFF);
+        assert!(erased(&flash, 0x1000..0x2000) && erased(&flash, 0x8000..0x20000));
+        assert!(!erased(&flash, 0x0FFF..0x1000) && !erased(&flash, 0x2000..0x2001) && !erased(&flash, 0x7FFF..0x8000));
+        assert_eq!(flash.erase_containing(0x20000, EraseSize::Sector), Err(Error::OutOfBounds));
+    }
+
+    #[test]
+    fn is_sector_aligned() {
+        let flash = SPIFlash::new(RamFlash::new(0));
+        let cases = [
+            (0x0000_0000, [true, true, true]),
+            (0x0000_1000, [true, false, false]),
+            (0x0000_8000, [true, true, false]),
+            (0x0001_0000, [true, true, true]),
+            (0x0001_1800, [false, false, false]),
+            (0x00FF_F000, [true, false, false]),
+        ];
+        for &(address, aligned) in &cases {
+            for (&size, &aligned) in [4096, 32 * 1024, 64 * 1024].iter().zip(&aligned) {
+                assert_eq!(flash.is_sector_aligned(address, size), aligned, "{:#x} {}", address, size);
+            }
+        }
+        // Sizes without an erase type are never aligned.
+        assert!(!flash.is_sector_aligned(0, 0));
+        assert!(!flash.is_sector_aligned(0, 256));
+    }
+
+    #[test]
+    fn erase_ops() {
+        let mut flash = SPIFlash::new(RamFlash::new(64 * 1024));
+        flash.erase(0x8123, 32 * 1024).unwrap();
+        assert!(flash.transmitter.log.contains(&vec![0x52, 0x00, 0x80, 0x00]));
+
+        let mut ram = RamFlash::new(64 * 1024);
+        // 4K erase with a nonstandard opcode, no 32K erase.
+        ram.sfdp = sfdp_image(&[0xFFF1_20E5, 0x03FF_FFFF, 0, 0, 0, 0, 0, 0xD810_210C, 0]);
+        let mut flash = SPIFlash::new(ram);
+        flash.identify().unwrap();
+        flash.erase_4k_block(0x1234).unwrap();
+        assert!(flash.transmitter.log.contains(&vec![0x21, 0x00, 0x10, 0x00]));
+        assert_eq!(flash.erase(0, 32 * 1024), Err(Error::Unsupported));
+
+        // An address past the end would wrap around on the bus.
+        flash.transmitter.memory[0x1000] = 0;
+        assert_eq!(flash.erase(8 * 1024 * 1024 + 0x1000, 4096), Err(Error::OutOfBounds));
+        assert_eq!(flash.transmitter.memory[0x1000], 0);
+
+        // The top block of the address space ends past u32::MAX.
+        let mut flash = SPIFlash::new(RamFlash::new(64 * 1024));
+        assert_eq!(flash.erase(0xFFFF_F000, 4096), Err(Error::OutOfBounds));
+    }
+
+    #[test]
+    fn discover_times() {
+        let mut ram = RamFlash::new(0);
+        // 4K erase in 80 ms, programs in 64 us, chip erase in 2.56 s, all typical.
+        ram.sfdp = sfdp_image(&[0xFFF1_20E5, 0x03FF_FFFF, 0, 0, 0, 0, 0, 0xD810_200C, 0, 0x240, 0x2900_0701]);
+        let mut flash = SPIFlash::new(ram);
+        let info = flash.identify().unwrap();
+        assert_eq!(info.erase_time_us, 160_000);
+        assert_eq!(info.program_time_us, 256);
+        assert_eq!(info.chip_erase_time_us, 10_240_000);
+    }
+
+    #[test]
+    fn discover_page_size() {
+        use crate::mock::Command;
+
+        let mut ram = RamFlash::new(4096);
+        // 512 byte pages.
+        ram.sfdp = sfdp_image(&[0xFFF1_20E5, 0x03FF_FFFF, 0, 0, 0, 0, 0, 0xD810_200C, 0, 0x240, 0x2900_0791]);
+        let mut flash = SPIFlash::new(ram);
+        assert_eq!(flash.identify().unwrap().page_size, 512);
+        // A write across the old 256 byte boundary is a single program.
+        flash.write_bytes(254, &[1, 2, 3, 4]).unwrap();
+        assert!(flash.transmitter.commands.contains(&Command::Program { address: 254, len: 4 }));
+        assert_eq!(flash.address_to_page(600), (1, 88));
+    }
+
+    #[test]
+    fn operation_timeouts() {
+        let mut ram = RamFlash::new(4096);
+        ram.operation_polls = 8;
+        let mut flash = SPIFlash::new(ram);
+        flash.set_poll_interval(Some(100_000));
+        // The W25Q128 erases a sector in at most 400 ms, which is 5 polls.
+        flash.identify().unwrap();
+        assert_eq!(flash.erase_4k_block(0), Err(Error::Timeout));
+        // A chip erase may take much longer.
+        assert_eq!(flash.chip_erase(), Ok(()));
+    }
+
+    #[test]
+    fn manufacturer_device_id() {
+        let mut flash = SPIFlash::new(RamFlash::new(0));
+        assert_eq!(flash.read_manufacturer_device_id(), Ok((0xEF, 0x17)));
+        assert_eq!(flash.transmitter.log, [vec![0x90, 0, 0, 0]]);
+        flash.transmitter.jedec_id = [0xFF; 3];
+        flash.transmitter.device_id = 0xFF;
+        assert_eq!(flash.read_manufacturer_device_id(), Err(Error::Unidentified));
+    }
+
+    #[test]
+    fn extended_jedec_id() {
+        let mut flash = SPIFlash::new(RamFlash::new(0));
+        let id = flash.read_jedec_id_extended();
+        assert_eq!(id, JedecId::new(0xEF, 0x40, 0x18));
+        assert_eq!(id.extended(), []);
+
+        flash.transmitter.extended_id = vec![2, 0x11, 0x22, 0x33];
+        let id = flash.read_jedec_id_extended();
+        assert_eq!((id.manufacturer, id.memory_type, id.capacity), (0xEF, 0x40, 0x18));
+        assert_eq!(id.extended(), [0x11, 0x22]);
+        assert_eq!(flash.read_jedec_id().extended(), []);
+
+        flash.transmitter.extended_id = vec![0, 0x11];
+        assert_eq!(flash.read_jedec_id_extended().extended(), []);
+        flash.transmitter.extended_id = vec![40; 40];
+        assert_eq!(flash.read_jedec_id_extended().extended(), [40; MAX_EXTENDED_ID]);
+    }
+
+    #[test]
+    fn probe_sleeping_chip() {
+        let mut flash = SPIFlash::new(RamFlash::new(0));
+        flash.sleep();
+        assert_eq!(flash.probe(false, |_| panic!()), Err(Error::Unidentified));
+
+        let mut delays = std::vec::Vec::new();
+        assert_eq!(flash.probe(true, |us| delays.push(us)), Ok(JedecId::new(0xEF, 0x40, 0x18)));
+        assert_eq!(delays, [RELEASE_POWER_DOWN_US]);
+        assert!(flash.transmitter.log.contains(&vec![0xAB, 0, 0, 0]));
+        assert!(!flash.transmitter.powered_down);
+
+        // Nothing answers at all.
+        flash.transmitter.jedec_id = [0xFF; 3];
+        flash.transmitter.device_id = 0xFF;
+        flash.sleep();
+        assert_eq!(flash.probe(true, |_| panic!()), Err(Error::Unidentified));
+    }
+
+    #[test]
+    fn wakeup() {
+        let mut flash = SPIFlash::new(RamFlash::new(0));
+        flash.sleep();
+        let mut delays = std::vec::Vec::new();
+        assert_eq!(flash.wakeup(|us| delays.push(us)), Ok(()));
+        assert_eq!(delays, [RELEASE_POWER_DOWN_US]);
+        assert!(!flash.transmitter.powered_down);
+
+        flash.sleep();
+        flash.transmitter.wedged = true;
+        assert_eq!(flash.wakeup(|_| {}), Err(Error::WakeFailed));
+        flash.transmitter.wedged = false;
+        flash.wakeup_nocheck();
+        assert_eq!(flash.transmitter.log.last(), Some(&vec![0xAB]));
+        assert!(!flash.transmitter.powered_down);
+    }
+
+    #[test]
+    fn transaction_holds_cs() {
+        let mut flash = SPIFlash::new(RamFlash::new(0));
+        let id = flash.transaction(|t| {
+            let mut id = [0; 3];
+            t.push(&[0x9F]);
+            t.read(&mut id);
+            id
+        });
+        assert_eq!(id, Ok([0xEF, 0x40, 0x18]));
+        assert_eq!(flash.transmitter.log, [vec![0x9F]]);
+
+        // A panic inside the transaction still deasserts CS.
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            flash.transaction(|t| {
+                t.push(&[0x06]);
+                panic!("vendor sequence failed");
+            })
+        }));
+        assert!(result.is_err());
+        assert_eq!(flash.transmitter.log.last(), Some(&vec![0x06]));
+    }
+
+    #[test]
+    fn verify_pattern() {
+        let mut ram = RamFlash::new(4096);
+        for (i, byte) in ram.memory.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        ram.memory[1234] = 0;
+        let mut flash = SPIFlash::new(ram);
+        assert_eq!(flash.verify_pattern(0, 1000, |a| a as u8), Ok(()));
+        assert_eq!(flash.verify_pattern(1000, 1000, |a| a as u8), Err(Error::VerifyMismatch(1234)));
+    }
+
+    #[test]
+    fn sector_map() {
+        let mut ram = RamFlash::new(4 * 4096);
+        ram.memory[0x1000] = 0;
+        ram.memory[0x2FFF] = 0;
+        let mut flash = SPIFlash::new(ram);
+        assert_eq!(flash.sector_map(None).collect::<std::vec::Vec<_>>(), [Err(Error::Unidentified)]);
+
+        flash.info = Some(ChipInfo::new(4 * 4096));
+        let erased = |flash: &mut SPIFlash<RamFlash>, sample| {
+            flash.sector_map(sample).map(|sector| sector.map(|(_, erased)| erased)).collect::<Result<std::vec::Vec<_>, _>>()
+        };
+        assert_eq!(erased(&mut flash, None), Ok(vec![true, false, false, true]));
+        // Sampling misses the data at the end of the third sector.
+        assert_eq!(erased(&mut flash, Some(16)), Ok(vec![true, false, true, true]));
+        assert_eq!(flash.sector_map(None).nth(3), Some(Ok((Sector { address: 0x3000, size: 4096 }, true))));
+    }
+
+    #[test]
+    fn diff_sectors() {
+        let mut ram = RamFlash::new(4 * 4096);
+        ram.memory[0x800..0x3800].iter_mut().for_each(|b| *b = 0x11);
+        let mut flash = SPIFlash::new(ram);
+        let image = vec![0x11; 0x3000];
+        assert_eq!(flash.diff_sectors(0x800, &image).collect::<std::vec::Vec<_>>(), [Err(Error::Unidentified)]);
+
+        flash.info = Some(ChipInfo::new(4 * 4096));
+        let diff = |flash: &mut SPIFlash<RamFlash>, image: &[u8]| flash.diff_sectors(0x800, image).collect::<Result<std::vec::Vec<_>, _>>();
+        assert_eq!(diff(&mut flash, &image), Ok(vec![]));
+        flash.transmitter.memory[0x800] = 0;
+        flash.transmitter.memory[0x2FFF] = 0;
+        assert_eq!(diff(&mut flash, &image), Ok(vec![0, 0x2000]));
+        assert_eq!(diff(&mut flash, &image[..0x100]), Ok(vec![0]));
+        assert_eq!(flash.diff_sectors(0x3000, &image).last(), Some(Err(Error::OutOfBounds)));
+    }
+
+    #[test]
+    fn find() {
+        let mut ram = RamFlash::new(4096);
+        // Straddles the boundary between the first two chunks.
+        ram.memory[62..66].copy_from_slice(b"SIG!");
+        ram.memory[1000..1004].copy_from_slice(b"SIG!");
+        let mut flash = SPIFlash::new(ram);
+        assert_eq!(flash.find(b"SIG!", 0, 4096), Ok(Some(62)));
+        assert_eq!(flash.find(b"SIG!", 63, 4000), Ok(Some(1000)));
+        assert_eq!(flash.find(b"SIG!", 1001, 3000), Ok(None));
+        // A match running past the end of the region does not count.
+        assert_eq!(flash.find(b"SIG!", 900, 103), Ok(None));
+        assert_eq!(flash.find(&[0; 65], 0, 4096), Err(Error::OutOfBounds));
+        flash.info = None;
+        assert_eq!(flash.find(b"SIG!", u32::MAX - 10, 20), Err(Error::OutOfBounds));
+    }
+
+    #[test]
+    fn wait_for_status() {
+        let mut ram = RamFlash::new(4096);
+        ram.busy_polls = 3;
+        let mut flash = SPIFlash::new(ram);
+        assert_eq!(flash.wait_for_status(|status| !status.busy(), Some(1)), Err(Error::Timeout));
+        assert_eq!(flash.wait_for_status(|status| !status.busy(), Some(1)), Ok(()));
+        assert_eq!(flash.transmitter.busy_polls, 0);
+        flash.transmitter.status[0] = 1 << 1;
+        assert_eq!(flash.wait_for_status(|status| status.write_enabled(), Some(0)), Ok(()));
+    }
+
+    #[test]
+    fn half_duplex() {
+        let mut ram = RamFlash::new(4096);
+        ram.half_duplex = true;
+        ram.memory[10] = 42;
+        ram.status[1] = STATUS_QE;
+        let mut flash = SPIFlash::new(ram);
+        assert_eq!(flash.read_byte(10), Ok(42));
+        assert_eq!(flash.read_status_2(), STATUS_QE);
+        assert_eq!(flash.read_jedec_id().manufacturer, 0xEF);
+
+        // Without holding CS, the reads are refused instead of sent as a full-duplex transfer.
+        flash.transmitter.cs_hold = false;
+        assert_eq!(flash.read_byte(10), Err(Error::Unsupported));
+        assert_eq!(flash.identify(), Err(Error::Unsupported));
+        assert_eq!(flash.read_status_2(), 0);
+    }
+
+    #[test]
+    fn read_scattered() {
+        let mut ram = RamFlash::new(4096);
+        for (i, byte) in ram.memory.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        let mut flash = SPIFlash::new(ram);
+        let (mut a, mut b, mut c) = ([0; 2], [0; 3], [0; 1]);
+        flash.read_scattered(&mut [(13, &mut a[..]), (200, &mut c[..]), (10, &mut b[..])]).unwrap();
+        assert_eq!((a, b, c), ([13, 14], [10, 11, 12], [200]));
+        // The adjacent reads at 10 and 13 are done as one.
+        assert_eq!(flash.transmitter.log.iter().filter(|c| c[0] == 0x0B).count(), 2);
+        assert_eq!(flash.read_scattered(&mut [(10, &mut b[..]), (u32::MAX - 1, &mut a[..])]), Err(Error::OutOfBounds));
+    }
+
+    #[test]
+    fn read_strings() {
+        let mut ram = RamFlash::new(4096);
+        ram.memory[..6].copy_from_slice(b"flash\0");
+        ram.memory[100..104].copy_from_slice(&[3, b'a', 0xFF, b'c']);
+        ram.memory[200] = 3;
+        ram.memory[201..204].copy_from_slice(b"url");
+        let mut flash = SPIFlash::new(ram);
+        let mut buffer = [0; 100];
+        assert_eq!(flash.read_cstr(0, &mut buffer), Ok("flash"));
+        assert_eq!(flash.read_cstr(0, &mut buffer[..5]), Err(Error::Unterminated));
+        assert_eq!(flash.read_cstr(u32::MAX - 10, &mut buffer), Err(Error::OutOfBounds));
+        assert_eq!(flash.read_length_prefixed(200, &mut buffer), Ok("url"));
+        assert_eq!(flash.read_length_prefixed(100, &mut buffer), Err(Error::InvalidUtf8));
+        assert_eq!(flash.read_length_prefixed(200, &mut buffer[..2]), Err(Error::OutOfBounds));
+    }
+
+    #[test]
+    fn hexdump() {
+        let mut ram = RamFlash::new(4096);
+        ram.memory[0x10..0x16].copy_from_slice(b"Hello\n");
+        let mut flash = SPIFlash::new(ram);
+        let mut out = std::string::String::new();
+        flash.hexdump_width(0x10, 6, 4, &mut out).unwrap();
+        assert_eq!(out, "00000010: 48 65 6c 6c |Hell|\n00000014: 6f 0a       |o.|\n");
+        assert_eq!(flash.hexdump_width(0, 16, 0, &mut out), Err(Error::OutOfBounds));
+    }
+
+    #[test]
+    fn write_bytes_across_pages() {
+        let mut flash = SPIFlash::new(RamFlash::new(4096));
+        let data: std::vec::Vec<u8> = (0..400).map(|i| i as u8).collect();
+        flash.write_bytes(200, &data).unwrap();
+        assert_eq!(flash.verify_pattern(200, 400, |a| (a - 200) as u8), Ok(()));
+        // Three pages are touched, each with its own write enable and program.
+        assert_eq!(flash.transmitter.log.iter().filter(|c| c[0] == 0x02).count(), 3);
+    }
+
+    #[test]
+    fn read_bytes_io() {
+        let mut ram = RamFlash::new(4096);
+        ram.memory[100..104].copy_from_slice(&[1, 2, 3, 4]);
+        let mut flash = SPIFlash::new(ram);
+        let mut buffer = [0; 4];
+        flash.read_bytes_dual_io(100, &mut buffer, 0xFF).unwrap();
+        assert_eq!(buffer, [1, 2, 3, 4]);
+        assert_eq!(flash.read_bytes_quad_io(100, &mut buffer, 0xFF), Err(Error::QuadNotEnabled));
+        flash.set_quad_enable(true).unwrap();
+        let mut buffer = [0; 4];
+        flash.read_bytes_quad_io(100, &mut buffer, 0xFF).unwrap();
+        assert_eq!(buffer, [1, 2, 3, 4]);
+        assert_eq!(flash.transmitter.log.last(), Some(&vec![0xEB, 0, 0, 100, 0xFF, 0, 0]));
+    }
+
+    #[test]
+    fn continuous_read() {
+        let mut ram = RamFlash::new(4096);
+        ram.memory[100..108].copy_from_slice(&[1, 2, 3, 4, 5, 6, 7, 8]);
+        let mut flash = SPIFlash::new(ram);
+        flash.set_quad_enable(true).unwrap();
+
+        let mut buffer = [0; 4];
+        flash.read_bytes_quad_io(100, &mut buffer, 0xA0).unwrap();
+        assert_eq!(flash.continuous_read_mode(), Some(0xA0));
+        flash.read_bytes_quad_io(104, &mut buffer, 0xA0).unwrap();
+        assert_eq!(buffer, [5, 6, 7, 8]);
+        assert_eq!(flash.transmitter.log.last(), Some(&vec![0, 0, 104, 0xA0, 0, 0]));
+
+        // A regular read continues the mode instead of sending its opcode.
+        flash.read_bytes(102, &mut buffer).unwrap();
+        assert_eq!(buffer, [3, 4, 5, 6]);
+        assert_eq!(flash.transmitter.log.last(), Some(&vec![0, 0, 102, 0xA0, 0, 0]));
+        assert_eq!(flash.continuous_read_mode(), Some(0xA0));
+
+        // A different kind of read leaves continuous read mode, then starts over with its opcode.
+        flash.read_bytes_dual_io(100, &mut buffer, 0xA0).unwrap();
+        assert_eq!(buffer, [1, 2, 3, 4]);
+        let log = &flash.transmitter.log;
+        assert_eq!(log[log.len() - 2..], [vec![0xFF, 0xFF], vec![0xBB, 0, 0, 100, 0xA0]]);
+
+        // Any other command leaves continuous read mode first.
+        flash.read_status();
+        assert_eq!(flash.continuous_read_mode(), None);
+        assert_eq!(flash.transmitter.continuous, None);
+        let log = &flash.transmitter.log;
+        assert_eq!(log[log.len() - 2], [0xFF, 0xFF]);
+    }
+
+    #[test]
+    fn continuous_read_across_banks() {
+        let mut ram = RamFlash::new(32 * 1024 * 1024);
+        ram.status[1] |= STATUS_QE;
+        ram.memory[0x10] = 0x17;
+        ram.memory[0x00FF_FFFF] = 0x11;
+        ram.memory[0x0100_0000] = 0x22;
+        let mut flash = SPIFlash::new(ram);
+        let mut buffer = [0; 1];
+
+        // Entering continuous read mode in another bank.
+        flash.read_bytes_quad_io(0x0100_0000, &mut buffer, 0xA0).unwrap();
+        assert_eq!(buffer, [0x22]);
+        assert_eq!(flash.continuous_read_mode(), Some(0xA0));
+        flash.read_bytes(0x10, &mut buffer).unwrap();
+        assert_eq!(buffer, [0x17]);
+        assert_eq!(flash.continuous_read_mode(), None);
+
+        // Continuing in another bank.
+        flash.read_bytes_quad_io(0x10, &mut buffer, 0xA0).unwrap();
+        flash.read_bytes_quad_io(0x0100_0000, &mut buffer, 0xA0).unwrap();
+        assert_eq!(buffer, [0x22]);
+        assert_eq!(flash.continuous_read_mode(), Some(0xA0));
+
+        // Continuing across a bank boundary, which ends the mode.
+        flash.read_bytes_quad_io(0x0100_0000, &mut buffer, 0xA0).unwrap();
+        let mut two = [0; 2];
+        flash.read_bytes_quad_io(0x00FF_FFFF, &mut two, 0xA0).unwrap();
+        assert_eq!(two, [0x11, 0x22]);
+        assert_eq!(flash.continuous_read_mode(), None);
+        assert_eq!(flash.transmitter.continuous, None);
+    }
+
+    #[test]
+    fn continuous_read_dummy() {
+        let mut ram = RamFlash::new(4096);
+        ram.memory[100..112].copy_from_slice(&[1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12]);
+        let mut flash = SPIFlash::new(ram);
+        flash.set_quad_enable(true).unwrap();
+        assert_eq!(flash.set_continuous_read_dummy(ReadMode::Fast, Some(8)), Err(Error::Unsupported));
+        assert_eq!(flash.set_continuous_read_dummy(ReadMode::QuadIo, Some(3)), Err(Error::OutOfBounds));
+        flash.set_continuous_read_dummy(ReadMode::QuadIo, Some(2)).unwrap();
+
+        // The read entering continuous read mode keeps the normal dummy clocks, the following ones use their own.
+        let mut buffer = [0; 4];
+        flash.read_bytes_quad_io(100, &mut buffer, 0xA0).unwrap();
+        assert_eq!(flash.transmitter.log.last(), Some(&vec![0xEB, 0, 0, 100, 0xA0, 0, 0]));
+        flash.read_bytes_quad_io(104, &mut buffer, 0xA0).unwrap();
+        assert_eq!(flash.transmitter.log.last(), Some(&vec![0, 0, 104, 0xA0, 0]));
+        assert_eq!(buffer, [5, 6, 7, 8]);
+
+        flash.set_continuous_read_dummy(ReadMode::QuadIo, None).unwrap();
+        flash.read_bytes_quad_io(108, &mut buffer, 0xFF).unwrap();
+        assert_eq!(flash.transmitter.log.last(), Some(&vec![0, 0, 108, 0xFF, 0, 0]));
+        assert_eq!(buffer, [9, 10, 11, 12]);
+    }
+
+    #[test]
+    fn continuous_read_mode_byte() {
+        let mut ram = RamFlash::new(4096);
+        ram.memory[100..112].copy_from_slice(&[1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12]);
+        let mut flash = SPIFlash::new(ram);
+        flash.set_quad_enable(true).unwrap();
+
+        // Enter: the opcode is sent along with a mode byte of M5-4 = 10.
+        let mut buffer = [0; 4];
+        flash.read_bytes_quad_io(100, &mut buffer, 0x20).unwrap();
+        assert_eq!(flash.transmitter.log.last(), Some(&vec![0xEB, 0, 0, 100, 0x20, 0, 0]));
+        assert_eq!(flash.continuous_read_mode(), Some(0x20));
+
+        // Stay: no opcode, and the mode byte keeps the chip in continuous read mode.
+        flash.read_bytes_quad_io(104, &mut buffer, 0xA0).unwrap();
+        assert_eq!(flash.transmitter.log.last(), Some(&vec![0, 0, 104, 0xA0, 0, 0]));
+        assert_eq!(flash.continuous_read_mode(), Some(0xA0));
+        assert_eq!(flash.transmitter.continuous, Some(0xEB));
+
+        // Exit: the last read drops out of it with its mode byte, so the next command needs no reset.
+        flash.read_bytes_quad_io(108, &mut buffer, 0xFF).unwrap();
+        assert_eq!(buffer, [9, 10, 11, 12]);
+        assert_eq!(flash.transmitter.log.last(), Some(&vec![0, 0, 108, 0xFF, 0, 0]));
+        assert_eq!(flash.continuous_read_mode(), None);
+        assert_eq!(flash.transmitter.continuous, None);
+        flash.read_status();
+        assert_eq!(flash.transmitter.log.last(), Some(&vec![0x05]));
+
+        // The explicit reset covers the longer address in 4-byte address mode.
+        flash.set_address_width(AddressWidth::FourByte).unwrap();
+        flash.read_bytes_quad_io(100, &mut buffer, 0xA0).unwrap();
+        assert_eq!(flash.transmitter.log.last(), Some(&vec![0xEB, 0, 0, 0, 100, 0xA0, 0, 0]));
+        flash.reset_continuous_read();
+        assert_eq!(flash.transmitter.log.last(), Some(&vec![0xFF; 3]));
+        assert_eq!(flash.transmitter.continuous, None);
+        flash.read_bytes(100, &mut buffer).unwrap();
+        assert_eq!(buffer, [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn mock_decodes_frames() {
+        use crate::mock::Command;
+
+        let mut flash = SPIFlash::new(RamFlash::new(4096));
+        flash.write_bytes(0x10, &[1, 2]).unwrap();
+        assert_eq!(
+            flash.transmitter.commands[flash.transmitter.commands.len() - 4..],
+            [Command::Simple(0x06), Command::Simple(0x05), Command::Program { address: 0x10, len: 2 }, Command::Simple(0x05)]
+        );
+
+        // Malformed frames are logged and ignored.
+        let ram = &mut flash.transmitter;
+        ram.send(&[]);
+        ram.send(&[0x02, 0]);
+        ram.send(&[0xC5]);
+        ram.send(&[0x42, 1, 2, 3]);
+        ram.send_read(&[0x03], &mut [0; 2]);
+        let commands = &ram.commands[ram.commands.len() - 5..];
+        assert_eq!(
+            commands,
+            [
+                Command::Garbled,
+                Command::Truncated(0x02),
+                Command::Truncated(0xC5),
+                Command::Unknown(0x42),
+                Command::Truncated(0x03),
+            ]
+        );
+        assert_eq!(ram.memory[0x10..0x12], [1, 2]);
+    }
+
+    #[test]
+    fn abort_between_steps() {
+        use core::cell::Cell;
+
+        let mut ram = RamFlash::new(16 * 1024);
+        ram.memory.iter_mut().for_each(|b| *b = 0);
+        let mut flash = SPIFlash::new(ram);
+        // Abort after two sectors.
+        let steps = Cell::new(0);
+        let abort = || {
+            steps.set(steps.get() + 1);
+            steps.get() > 2
+        };
+        assert_eq!(flash.erase_range_abortable(0, 16 * 1024, abort), Err(Error::Aborted));
+        assert_eq!(flash.transmitter.memory[0x1FFF], 0xFF);
+        assert_eq!(flash.transmitter.memory[0x2000], 0);
+        assert_eq!(flash.erase_range(0x2000, 1).map(|region| region.range()), Ok(0x2000..0x3000));
+        assert_eq!(flash.transmitter.memory[0x2FFF], 0xFF);
+        assert_eq!(flash.write_bytes_abortable(0, &[1; 512], || true), Err(Error::Aborted));
+        assert_eq!(flash.transmitter.memory[0], 0xFF);
+    }
+
+    #[test]
+    fn erase_range_units() {
+        let mut ram = RamFlash::new(8 * 1024 * 1024);
+        ram.memory.iter_mut().for_each(|b| *b = 0);
+        let mut flash = SPIFlash::new(ram);
+        assert_eq!(flash.erase_range(0x1234, 0).map(|region| region.range()), Ok(0x1234..0x1234));
+        assert_eq!(flash.transmitter.memory[0x1000], 0);
+        assert_eq!(flash.erase_range(0xFFFF_F000, 0x1000).err(), Some(Error::OutOfBounds));
+        assert_eq!(flash.erase_range(u32::MAX - 1, 2).err(), Some(Error::OutOfBounds));
+
+        // A part which only erases 64K blocks.
+        flash.transmitter.sfdp = sfdp_image(&[0xFFF1_20E5, 0x03FF_FFFF, 0, 0, 0, 0, 0, 0x0000_D810, 0]);
+        flash.identify().unwrap();
+        flash.transmitter.log.clear();
+        assert_eq!(flash.erase_range(0x1_2345, 1).map(|region| region.range()), Ok(0x1_0000..0x2_0000));
+        let erases: std::vec::Vec<_> = flash.transmitter.log.iter().filter(|command| command.len() == 4).collect();
+        assert_eq!(erases, [&vec![0xD8, 0x01, 0x00, 0x00]]);
+        assert!(flash.transmitter.memory[0x1_0000..0x2_0000].iter().all(|&b| b == 0xFF));
+        assert_eq!(flash.transmitter.memory[0x2_0000], 0);
+    }
+
+    #[test]
+    fn page_addresses() {
+        let flash = SPIFlash::new(RamFlash::new(0));
+        assert_eq!(flash.address_to_page(0x1234), (0x12, 0x34));
+        assert_eq!(flash.page_to_address(0x12, 0x34), Ok(0x1234));
+        assert_eq!(flash.page_to_address(0, 256), Err(Error::OutOfBounds));
+    }
+
+    #[test]
+    fn read_verified() {
+        /// An 8 bit sum, standing in for a checksum format other than CRC-32.
+        struct Sum(u8);
+
+        impl crc::Checksum for Sum {
+            type Output = u8;
+
+            fn update(&mut self, data: &[u8]) {
+                self.0 = data.iter().fold(self.0, |sum, b| sum.wrapping_add(*b));
+            }
+
+            fn finish(&self) -> u8 {
+                self.0
+            }
+        }
+
+        let mut ram = RamFlash::new(4096);
+        ram.memory[100..109].copy_from_slice(b"123456789");
+        let mut flash = SPIFlash::new(ram);
+        let mut buffer = [0; 9];
+        flash.read_verified(100, &mut buffer, crc::Crc32::new(), 0xCBF4_3926).unwrap();
+        assert_eq!(&buffer, b"123456789");
+        flash.read_verified(100, &mut buffer, Sum(0), 0xDD).unwrap();
+
+        flash.transmitter.corrupt_read = Some(104);
+        assert_eq!(flash.read_verified(100, &mut buffer, crc::Crc32::new(), 0xCBF4_3926), Err(Error::VerifyMismatch(100)));
+    }
+
+    #[test]
+    fn write_bytes_crc() {
+        let mut flash = SPIFlash::new(RamFlash::new(4096));
+        assert_eq!(flash.write_bytes_crc(250, b"123456789"), Ok(0xCBF4_3926));
+        assert_eq!(flash.verify_pattern(250, 9, |a| b"123456789"[(a - 250) as usize]), Ok(()));
+    }
+
+    #[test]
+    fn read_redundant() {
+        let mut flash = SPIFlash::new(RamFlash::new(16 * 1024));
+        let record = [7; 100];
+        flash.write_redundant(0x1000, 0x2000, &record).unwrap();
+
+        // A corrupted copy A is read from B and repaired.
+        flash.transmitter.memory[0x1010] = 0;
+        let mut buffer = [0; 100];
+        flash.read_redundant(0x1000, 0x2000, &mut buffer).unwrap();
+        assert_eq!(buffer, record);
+        assert_eq!(flash.transmitter.memory[0x1000..0x1064], record);
+
+        // So is a corrupted copy B, from A.
+        flash.transmitter.memory[0x2064] = 0;
+        flash.read_redundant(0x1000, 0x2000, &mut buffer).unwrap();
+        assert_eq!(flash.transmitter.memory[0x2064..0x2068], crc::crc32(&record).to_le_bytes());
+
+        flash.transmitter.memory[0x1000] = 0;
+        flash.transmitter.memory[0x2000] = 0;
+        assert_eq!(flash.read_redundant(0x1000, 0x2000, &mut buffer), Err(Error::BothCopiesBad));
+
+        // Copies in one sector, or whose CRC reaches into the other copy's sector, are rejected.
+        assert_eq!(flash.write_redundant(0x1000, 0x1800, &record), Err(Error::Overlap));
+        assert_eq!(flash.write_redundant(0x0FA0, 0x1000, &record), Err(Error::Overlap));
+        flash.info = Some(ChipInfo { sector_size: 64 * 1024, ..ChipInfo::new(128 * 1024) });
+        assert_eq!(flash.write_redundant(0x1000, 0x2000, &record), Err(Error::Overlap));
+        assert_eq!(flash.read_redundant(0x1000, 0x2000, &mut buffer), Err(Error::Overlap));
+    }
+
+    #[test]
+    fn write_bytes_quad_requires_qe() {
+        let mut flash = SPIFlash::new(RamFlash::new(4096));
+        assert_eq!(flash.write_bytes_quad(0, &[1, 2, 3]), Err(Error::QuadNotEnabled));
+        flash.set_quad_enable(true).unwrap();
+        flash.write_bytes_quad(254, &[1, 2, 3]).unwrap();
+        assert_eq!(flash.verify_pattern(254, 3, |a| (a - 253) as u8), Ok(()));
+    }
+
+    #[test]
+    fn protected_range() {
+        let mut ram = RamFlash::new(4096);
+        // BP = 001, TB = 0 protects the upper 256K of a W25Q128.
+        ram.status[0] = 0b001 << 2;
+        let mut flash = SPIFlash::new(ram);
+        assert_eq!(flash.is_writable(0), Err(Error::Unidentified));
+        flash.identify().unwrap();
+        assert_eq!(flash.protected_range(), Ok(Some(0xFC_0000..0x100_0000)));
+        assert_eq!(flash.is_writable(0xFB_FFFF), Ok(true));
+        assert_eq!(flash.is_writable(0xFC_0000), Ok(false));
+        assert_eq!(flash.write_bytes(0xFB_FFFF, &[0, 0]), Err(Error::Protected));
+
+        // With WPS set, the individual block locks apply instead.
+        flash.transmitter.status_3 = 1 << 2;
+        assert_eq!(flash.is_writable(0xFC_0000), Ok(true));
+        flash.global_lock().unwrap();
+        assert_eq!(flash.is_writable(0x1000), Ok(false));
+        assert_eq!(flash.transmitter.log.last(), Some(&vec![0x3D, 0x00, 0x10, 0x00]));
+        flash.global_unlock().unwrap();
+        assert_eq!(flash.read_block_lock(0x1000), Ok(false));
+        flash.transmitter.status_3 = 0;
+
+        // CMP inverts the range.
+        flash.transmitter.status[1] = STATUS_CMP;
+        assert_eq!(flash.protected_range(), Ok(Some(0..0xFC_0000)));
+        flash.transmitter.status = [0, 0];
+        assert_eq!(flash.protected_range(), Ok(None));
+
+        let unlocked = ProtectionSummary {
+            protected: None,
+            wp_mode: WpMode::Software,
+        };
+        assert_eq!(flash.assert_protection(&unlocked), Ok(()));
+        flash.transmitter.status[0] = STATUS_SRP0 | 0b111 << 2;
+        assert_eq!(flash.assert_protection(&unlocked), Err(Error::ProtectionMismatch));
+        let locked = ProtectionSummary {
+            protected: Some(0..0x100_0000),
+            wp_mode: WpMode::HardwareProtected,
+        };
+        assert_eq!(flash.assert_protection(&locked), Ok(()));
+    }
+
+    #[test]
+    fn max_read_len() {
+        use crate::mock::Command;
+        let mut ram = RamFlash::new(4096);
+        for (i, byte) in ram.memory.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        let mut flash = SPIFlash::new(ram);
+        flash.set_max_read_len(Some(128), false);
+        let mut buffer = [0; 300];
+        flash.read_bytes(10, &mut buffer).unwrap();
+        assert!(buffer.iter().enumerate().all(|(i, &b)| b == (i + 10) as u8));
+        let reads: std::vec::Vec<_> = flash.transmitter.commands.iter()
+            .filter_map(|c| match *c {
+                Command::Read { address, .. } => Some(address),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(reads, [10, 138, 266]);
+
+        // A glitch in the first read of a segment is caught by the second one and read again.
+        flash.set_max_read_len(Some(128), true);
+        flash.transmitter.corrupt_read = Some(150);
+        flash.read_bytes(10, &mut buffer).unwrap();
+        assert!(buffer.iter().enumerate().all(|(i, &b)| b == (i + 10) as u8));
+        assert_eq!(flash.transmitter.corrupt_read, None);
+    }
+
+    #[test]
+    fn read_during_busy() {
+        let mut ram = RamFlash::new(4096);
+        ram.status[0] = 0x01;
+        let mut flash = SPIFlash::new(ram);
+        let mut buffer = [0; 4];
+        assert_eq!(flash.read_bytes(0, &mut buffer), Err(Error::Busy));
+        assert!(flash.transmitter.log.iter().all(|c| c[0] != 0x0B));
+        flash.set_read_during_busy(ReadDuringBusy::Allow);
+        assert_eq!(flash.read_bytes(0, &mut buffer), Ok(()));
+        assert_eq!(buffer, [0xFF; 4]);
+    }
+
+    #[test]
+    fn read_bytes_uninit() {
+        let mut ram = RamFlash::new(4096);
+        ram.memory[100..104].copy_from_slice(&[1, 2, 3, 4]);
+        let mut flash = SPIFlash::new(ram);
+        let mut buffer = [MaybeUninit::uninit(); 4];
+        assert_eq!(flash.read_bytes_uninit(100, &mut buffer), Ok(&mut [1, 2, 3, 4][..]));
+        assert_eq!(flash.transmitter.log.last(), Some(&vec![0x0B, 0, 0, 100, 0]));
+        flash.transmitter.half_duplex = true;
+        let mut buffer = [MaybeUninit::uninit(); 2];
+        assert_eq!(flash.read_bytes_uninit(102, &mut buffer), Ok(&mut [3, 4][..]));
+    }
+
+    #[test]
+    fn skips_busy_check_while_known_idle() {
+        let mut flash = SPIFlash::new(RamFlash::new(4096));
+        let status_reads = |flash: &SPIFlash<RamFlash>| flash.transmitter.log.iter().filter(|c| c[0] == 0x05).count();
+        let mut buffer = [0; 4];
+        flash.read_bytes(0, &mut buffer).unwrap();
+        flash.read_bytes(4, &mut buffer).unwrap();
+        flash.read_bytes(8, &mut buffer).unwrap();
+        assert_eq!(status_reads(&flash), 1);
+
+        // A program may leave the chip busy, so the next read checks again.
+        flash.transmitter.operation_polls = 1;
+        flash.program_page_async(0, &[0]).unwrap();
+        let before = status_reads(&flash);
+        assert_eq!(flash.read_bytes(0x100, &mut buffer), Err(Error::Busy));
+        flash.read_bytes(0x100, &mut buffer).unwrap();
+        flash.read_bytes(0x104, &mut buffer).unwrap();
+        assert_eq!(status_reads(&flash), before + 2);
+
+        // So does a raw transaction, which can start any operation.
+        flash.transaction(|t| t.push(&[0x06])).unwrap();
+        flash.transaction(|t| t.push(&[0x20, 0, 0, 0])).unwrap();
+        assert_eq!(flash.read_bytes(0, &mut buffer), Err(Error::Busy));
+    }
+
+    #[test]
+    fn config_registers() {
+        let mut flash = SPIFlash::new(RamFlash::new(4096));
+        flash.identify().unwrap();
+        assert_eq!(flash.read_volatile_config(), Err(Error::Unsupported));
+
+        let mut ram = RamFlash::new(4096);
+        ram.jedec_id = [0x20, 0xBA, 0x18];
+        let mut flash = SPIFlash::new(ram);
+        flash.identify().unwrap();
+        let config = flash.read_volatile_config().unwrap().with_dummy_cycles(10);
+        flash.write_volatile_config(config).unwrap();
+        assert!(flash.transmitter.log.contains(&vec![0x81, 0xAB]));
+        assert_eq!(flash.transmitter.log.last(), Some(&vec![0x85]));
+        assert!(NonVolatileConfig(0xFFFE).four_byte_address());
+        let config = flash.read_nonvolatile_config().unwrap().with_dummy_cycles(8);
+        flash.write_nonvolatile_config(config).unwrap();
+        assert_eq!(flash.transmitter.nonvolatile_config, 0x8FFF);
+
+        // A write the chip does not take is caught.
+        flash.transmitter.ignore_write_enable = true;
+        let config = VolatileConfig(0xFB).with_dummy_cycles(8);
+        assert_eq!(flash.write_volatile_config(config), Err(Error::ConfigMismatch));
+        assert_eq!(flash.write_enhanced_volatile_config(EnhancedVolatileConfig(0xD7)), Err(Error::ConfigMismatch));
+    }
+
+    #[test]
+    fn diagnostics() {
+        let mut ram = RamFlash::new(4096);
+        ram.status = [0b0000_0100, 0b0000_0010];
+        let mut flash = SPIFlash::new(ram);
+        let snapshot = flash.diagnostics().unwrap();
+        assert_eq!(snapshot.jedec_id, JedecId::new(0xEF, 0x40, 0x18));
+        assert_eq!(snapshot.status, StatusRegister(0b0000_0100));
+        assert_eq!(snapshot.status_2, Some(0b0000_0010));
+        assert_eq!(snapshot.status_3, Some(StatusRegister3(0)));
+        assert_eq!(snapshot.flag_status, None);
+
+        // Micron parts have a flag status register, but no status register 3.
+        let mut ram = RamFlash::new(4096);
+        ram.jedec_id = [0x20, 0xBA, 0x18];
+        let mut flash = SPIFlash::new(ram);
+        flash.identify().unwrap();
+        let snapshot = flash.diagnostics().unwrap();
+        assert_eq!(snapshot.status_2, None);
+        assert_eq!(snapshot.status_3, None);
+        assert_eq!(snapshot.flag_status, Some(FlagStatus(0x80)));
+        assert!(flash.transmitter.log.iter().all(|c| c[0] != 0x50 && c[0] != 0x15 && c[0] != 0x35));
+
+        // Nothing answering is an error, not a snapshot of garbage.
+        flash.transmitter.jedec_id = [0xFF; 3];
+        assert_eq!(flash.diagnostics(), Err(Error::Bus));
+    }
+
+    #[test]
+    fn drive_strength() {
+        let mut ram = RamFlash::new(4096);
+        ram.status_3 = 0b1000_0100;
+        let mut flash = SPIFlash::new(ram);
+        flash.set_drive_strength(DriveStrength::Percent75).unwrap();
+        assert_eq!(flash.transmitter.status_3, 0b1010_0100);
+        assert_eq!(flash.read_status_3().drive_strength(), DriveStrength::Percent75);
+
+        flash.transmitter.ignore_write_enable = true;
+        assert_eq!(flash.set_drive_strength(DriveStrength::Percent25), Err(Error::ConfigMismatch));
+
+        let mut ram = RamFlash::new(4096);
+        ram.jedec_id = [0x20, 0xBA, 0x18];
+        let mut flash = SPIFlash::new(ram);
+        assert_eq!(flash.set_drive_strength(DriveStrength::Percent50), Err(Error::Unsupported));
+        assert!(flash.transmitter.log.iter().all(|c| c[0] != 0x11));
+    }
+
+    #[test]
+    fn suspend_resume() {
+        let mut ram = RamFlash::new(16 * 1024);
+        ram.memory[0x2000] = 0x5A;
+        let mut flash = SPIFlash::new(ram);
+        flash.identify().unwrap();
+        assert_eq!(flash.suspend(), Ok(None));
+
+        flash.transmitter.operation_polls = 5;
+        flash.erase_4k_block_async(0x1000).unwrap();
+        assert_eq!(flash.suspend(), Ok(Some(Operation::Erase(4096))));
+        assert_eq!(flash.state().pending, None);
+        assert_eq!(flash.state().suspended, Some(Operation::Erase(4096)));
+        // Reads outside the erased sector go ahead, programs and erases wait for the resume.
+        assert_eq!(flash.read_byte(0x2000), Ok(0x5A));
+        assert_eq!(flash.read_byte(0x1FFF), Err(Error::Busy));
+        assert_eq!(flash.write_bytes(0x3000, &[0]), Err(Error::Busy));
+        assert_eq!(flash.chip_erase_async(), Err(Error::Busy));
+        flash.resume();
+        assert_eq!(flash.suspended(), None);
+        assert_eq!(flash.state().pending, Some(Operation::Erase(4096)));
+        assert!(flash.is_busy());
+        flash.flush().unwrap();
+        assert_eq!(flash.read_byte(0x1FFF), Ok(0xFF));
+
+        // A program can be suspended just the same, unless it is done already.
+        flash.program_page_async(0x3000, &[1, 2, 3]).unwrap();
+        assert_eq!(flash.suspend(), Ok(Some(Operation::Program)));
+        assert_eq!(flash.read_bytes(0x2FFE, &mut [0; 4]), Err(Error::Busy));
+        flash.resume();
+        flash.flush().unwrap();
+        flash.transmitter.operation_polls = 0;
+        flash.program_page_async(0x3010, &[1]).unwrap();
+        assert_eq!(flash.suspend(), Ok(None));
+        assert_eq!(flash.state().pending, None);
+
+        flash.transmitter.operation_polls = 5;
+        flash.chip_erase_async().unwrap();
+        assert_eq!(flash.suspend(), Err(Error::Unsupported));
+        flash.flush().unwrap();
+
+        // Parts without program suspend only suspend erases.
+        flash.info = Some(ChipInfo { erase_suspend: true, ..ChipInfo::new(16 * 1024) });
+        flash.program_page_async(0x3020, &[1]).unwrap();
+        assert_eq!(flash.suspend(), Err(Error::Unsupported));
+        assert_eq!(flash.state().pending, Some(Operation::Program));
+    }
+
+    #[test]
+    fn status_write_mode() {
+        let mut flash = SPIFlash::new(RamFlash::new(4096));
+        flash.set_quad_enable(true).unwrap();
+        let frames = &flash.transmitter.log;
+        let write = frames.iter().position(|frame| frame[0] == 0x01).unwrap();
+        assert_eq!(frames[write - 1], [0x06]);
+
+        // Volatile writes are enabled with 0x50 instead.
+        flash.transmitter.log.clear();
+        flash.set_status_write_mode(StatusWriteMode::Volatile);
+        flash.set_quad_enable(false).unwrap();
+        let frames = &flash.transmitter.log;
+        let write = frames.iter().position(|frame| frame[0] == 0x01).unwrap();
+        assert_eq!(frames[write - 1], [0x50]);
+        assert!(!frames.contains(&vec![0x06]));
+
+        // Without a write enable, the chip ignores the write.
+        flash.transmitter.log.clear();
+        flash.write_status_raw(0, STATUS_QE);
+        assert_eq!(flash.transmitter.log[0], [0x01, 0, STATUS_QE]);
+        assert!(!flash.quad_enabled());
+    }
+
+    #[test]
+    fn blank_byte() {
+        let mut ram = RamFlash::new(4096);
+        ram.memory.iter_mut().for_each(|b| *b = 0);
+        ram.memory[..10].iter_mut().for_each(|b| *b = 0x5A);
+        let mut flash = SPIFlash::new(ram);
+        assert_eq!(flash.blank_byte(), 0xFF);
+        assert_eq!(flash.is_erased(100, 100), Ok(false));
+
+        flash.set_blank_byte(0x00);
+        assert_eq!(flash.is_erased(100, 100), Ok(true));
+        assert_eq!(flash.is_erased(0, 100), Ok(false));
+        assert_eq!(flash.first_free_offset(0, 4096), Ok(Some(10)));
+
+        // Programming moves bits away from the blank value only.
+        flash.set_dry_run(true);
+        flash.write_bytes(0, &[0x5A | 0x81]).unwrap();
+        assert_eq!(flash.write_bytes(1, &[0x50]), Err(Error::NotErased(1)));
+    }
+
+    #[test]
+    fn flag_status_errors() {
+        let mut ram = RamFlash::new(4096);
+        ram.jedec_id = [0x20, 0xBA, 0x18];
+        ram.flag_status = 0x80 | 1 << 4;
+        let mut flash = SPIFlash::new(ram);
+        flash.identify().unwrap();
+        assert_eq!(flash.write_byte(0, 0), Err(Error::ProgramFailed));
+        // The error was cleared, so the next write succeeds.
+        assert_eq!(flash.write_byte(0, 0), Ok(()));
+    }
+
+    #[test]
+    fn status_errors() {
+        let mut ram = RamFlash::new(4096);
+        ram.status[0] = 1 << 5;
+        let mut flash = SPIFlash::new(ram);
+        flash.info = Some(ChipInfo { status_errors: true, ..ChipInfo::new(4096) });
+        assert_eq!(flash.erase_4k_block(0), Err(Error::EraseFailed));
+        assert_eq!(flash.write_byte(0, 0), Ok(()));
+        flash.transmitter.status[0] = 1 << 6;
+        assert_eq!(flash.write_byte(0, 0), Err(Error::ProgramFailed));
+        flash.transmitter.status[0] = 1 << 5;
+        assert_eq!(flash.clear_errors(), Ok(()));
+        assert_eq!(flash.read_status(), 0);
+        flash.info = None;
+        assert_eq!(flash.clear_errors(), Err(Error::Unsupported));
+
+        // Only the S25FL-S parts in the database report errors, not every Spansion part.
+        let mut ram = RamFlash::new(4096);
+        ram.jedec_id = [0x01, 0x20, 0x18];
+        let mut flash = SPIFlash::new(ram);
+        assert!(flash.identify().unwrap().status_errors);
+        flash.transmitter.jedec_id = [0x01, 0x40, 0x17];
+        flash.transmitter.sfdp = sfdp_image(&[0xFFF1_20E5, 0x03FF_FFFF]);
+        assert!(!flash.identify().unwrap().status_errors);
+    }
+
+    #[test]
+    fn first_free_offset() {
+        let mut ram = RamFlash::new(4096);
+        ram.memory[1000..1100].iter_mut().for_each(|b| *b = 0x42);
+        let mut flash = SPIFlash::new(ram);
+        assert_eq!(flash.first_free_offset(1000, 1000), Ok(Some(100)));
+        assert_eq!(flash.first_free_offset(1100, 1000), Ok(Some(0)));
+        assert_eq!(flash.first_free_offset(1000, 100), Ok(None));
+        assert_eq!(flash.append_region_stats(1000, 1000), Ok(RegionStats { used: 100, free: 900 }));
+        assert_eq!(flash.append_region_stats(1000, 100), Ok(RegionStats { used: 100, free: 0 }));
+        assert_eq!(flash.first_free_offset(u32::MAX - 10, 100), Err(Error::OutOfBounds));
+    }
+
+    #[test]
+    fn health() {
+        let mut flash = SPIFlash::new(RamFlash::new(4096));
+        assert_eq!(flash.health(), Err(Error::Unsupported));
+        // The SFDP area stands in for a vendor register, as the mock has none.
+        let mut ram = RamFlash::new(4096);
+        ram.sfdp = vec![0x10, 0x27, 0, 0];
+        let mut flash = SPIFlash::new(ram);
+        flash.set_health_register(Some(HealthRegister {
+            opcode: 0x5A,
+            address: None,
+            dummy_bytes: 4,
+            decode: |bytes| HealthInfo {
+                erase_count: Some(u32::from_le_bytes(bytes)),
+                remaining_percent: None,
+            },
+        }));
+        assert_eq!(flash.health().map(|health| health.erase_count), Ok(Some(10_000)));
+        assert_eq!(flash.transmitter.log.last(), Some(&vec![0x5A, 0, 0, 0, 0]));
+
+        // An addressed register, with the address and the latency byte before the contents.
+        // The mock answers 0x65 with its enhanced volatile configuration register in every byte.
+        flash.transmitter.enhanced_volatile_config = 0x05;
+        flash.set_health_register(Some(HealthRegister::semper_erase_count(0x000001)));
+        assert_eq!(flash.health().map(|health| health.erase_count), Ok(Some(0x05_0505)));
+        assert_eq!(flash.transmitter.log.last(), Some(&vec![0x65, 0, 0, 1, 0]));
+
+        flash.set_health_register(Some(HealthRegister { dummy_bytes: 5, ..HealthRegister::semper_erase_count(0) }));
+        assert_eq!(flash.health(), Err(Error::Unsupported));
+    }
+
+    #[test]
+    fn reset_waits_for_recovery() {
+        let mut ram = RamFlash::new(16 * 1024);
+        ram.operation_polls = 100;
+        let mut flash = SPIFlash::new(ram);
+        let mut waited = 0;
+        flash.reset(|us| waited = us).unwrap();
+        assert_eq!(waited, 30);
+
+        // Aborting an erase takes longest.
+        flash.erase_4k_block_async(0).unwrap();
+        flash.reset(|us| waited = us).unwrap();
+        assert_eq!(waited, 40_000);
+        assert_eq!(flash.transmitter.log[flash.transmitter.log.len() - 2..], [vec![0x66], vec![0x99]]);
+        assert!(!flash.is_busy());
+
+        flash.program_page_async(0, &[1]).unwrap();
+        flash.reset(|us| waited = us).unwrap();
+        assert_eq!(waited, 310);
+        assert_eq!(flash.flush(), Ok(()));
+
+        // A suspended erase is aborted as well, even though the chip is idle.
+        flash.identify().unwrap();
+        flash.erase_4k_block_async(0).unwrap();
+        assert_eq!(flash.suspend(), Ok(Some(Operation::Erase(4096))));
+        flash.reset(|us| waited = us).unwrap();
+        assert_eq!(waited, 40_000);
+        assert_eq!(flash.suspended(), None);
+    }
+
+    #[test]
+    fn auto_recovery() {
+        let mut ram = RamFlash::new(16 * 1024);
+        ram.memory[0x1000] = 0;
+        ram.wedged = true;
+        let mut flash = SPIFlash::new(ram);
+        flash.set_auto_recovery(Some(4));
+
+        // The wedged chip ignores the erase, the reset brings it back and the retry goes through.
+        flash.erase_4k_block(0x1000).unwrap();
+        assert_eq!(flash.transmitter.memory[0x1000], 0xFF);
+        let reset = flash.transmitter.log.iter().position(|frame| frame == &[0x66]).unwrap();
+        assert_eq!(flash.transmitter.log[reset + 1], [0x99]);
+
+        // A BUSY bit stuck beyond the worst-case time is retried once, then returned.
+        flash.set_poll_interval(Some(1000));
+        flash.transmitter.operation_polls = u32::MAX;
+        let resets = |flash: &SPIFlash<RamFlash>| flash.transmitter.log.iter().filter(|frame| frame == &&[0x99]).count();
+        assert_eq!(flash.erase_4k_block(0x1000), Err(Error::Timeout));
+        assert_eq!(resets(&flash), 2);
+        flash.transmitter.operation_polls = 0;
+        flash.transmitter.busy_polls = 0;
+
+        // A chip which still does not answer after the reset stays an error.
+        flash.transmitter.jedec_id = [0xFF; 3];
+        assert_eq!(flash.recover(), Err(Error::Wedged));
+        flash.transmitter.jedec_id = [0xEF, 0x40, 0x18];
+        flash.recover().unwrap();
+        assert!(!flash.is_busy());
     }
 }
@@ -0,0 +1,93 @@
+//! The sector format shared by `kv` and `ringlog`: a 4K sector holding a header and a log of records.
+//!
+//! The header is 12 bytes: a magic identifying the user, a sequence number (u32, little endian) and its inverse.
+//! A record consists of a record header of the user's layout, the data and the CRC-32 of everything before it,
+//! all little endian. A record torn by power loss fails its CRC and ends the log, along with anything after it.
+
+use crate::{crc::Crc32, Error, SPIFlash, Transmitter};
+
+/// Size of a sector.
+pub const SECTOR_SIZE: u32 = 4096;
+
+/// Length of the sector header.
+pub const HEADER_LEN: u32 = 12;
+
+/// Size of the stack buffer records are checked with.
+const CHUNK: usize = 64;
+
+/// Reads the sequence number of the sector at `base`, or `None` if its header is not intact or has another magic.
+pub fn read_header<T: Transmitter>(flash: &mut SPIFlash<T>, base: u32, magic: [u8; 4]) -> Result<Option<u32>, Error> {
+    let header = flash.read_array::<{ HEADER_LEN as usize }>(base)?;
+    let sequence = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+    let check = u32::from_le_bytes([header[8], header[9], header[10], header[11]]);
+    Ok(if header[..4] == magic && check == !sequence { Some(sequence) } else { None })
+}
+
+/// Writes the header of the erased sector at `base`, which makes it valid.
+pub fn write_header<T: Transmitter>(flash: &mut SPIFlash<T>, base: u32, magic: [u8; 4], sequence: u32) -> Result<(), Error> {
+    let mut header = [0; HEADER_LEN as usize];
+    header[..4].copy_from_slice(&magic);
+    header[4..8].copy_from_slice(&sequence.to_le_bytes());
+    header[8..].copy_from_slice(&(!sequence).to_le_bytes());
+    flash.write_bytes(base, &header)
+}
+
+/// Decodes the record at `offset` in the sector at `base`, whose record header is `N` bytes long.
+/// `data_len` returns the length of the data following a record header, or `None` for an erased one.
+/// Returns the record header and the offset after the record, or `None` at the end of the log,
+/// i.e. at an erased or torn record.
+pub fn next_record<T: Transmitter, const N: usize>(
+    flash: &mut SPIFlash<T>,
+    base: u32,
+    offset: u32,
+    data_len: impl Fn(&[u8; N]) -> Option<u32>,
+) -> Result<Option<([u8; N], u32)>, Error> {
+    if offset + N as u32 + 4 > SECTOR_SIZE {
+        return Ok(None);
+    }
+    let header = flash.read_array::<N>(base + offset)?;
+    let end = match data_len(&header) {
+        Some(len) => offset + N as u32 + len + 4,
+        None => return Ok(None),
+    };
+    if end > SECTOR_SIZE {
+        return Ok(None);
+    }
+
+    let mut crc = Crc32::new();
+    crc.update(&header);
+    let mut chunk = [0; CHUNK];
+    let mut address = base + offset + N as u32;
+    while address < base + end - 4 {
+        let n = ((base + end - 4 - address) as usize).min(CHUNK);
+        flash.read_bytes(address, &mut chunk[..n])?;
+        crc.update(&chunk[..n]);
+        address += n as u32;
+    }
+    let stored = flash.read_array::<4>(address)?;
+    if u32::from_le_bytes(stored) != crc.finish() {
+        return Ok(None);
+    }
+    Ok(Some((header, end)))
+}
+
+/// Whether a record of `len` bytes can be written at `offset` in the sector at `base`.
+pub fn fits<T: Transmitter>(flash: &mut SPIFlash<T>, base: u32, offset: u32, len: u32) -> Result<bool, Error> {
+    // Bytes left over from a torn record can not be programmed again, so they count as full.
+    Ok(offset + len <= SECTOR_SIZE && flash.is_erased(base + offset, len)?)
+}
+
+/// Writes a record of `header` and `data` at `offset` in the sector at `base` and returns the offset after it.
+pub fn write_record<T: Transmitter>(flash: &mut SPIFlash<T>, base: u32, offset: u32, header: &[u8], data: &[u8]) -> Result<u32, Error> {
+    let mut crc = Crc32::new();
+    crc.update(header);
+    crc.update(data);
+
+    let mut address = base + offset;
+    flash.write_bytes(address, header)?;
+    address += header.len() as u32;
+    flash.write_bytes(address, data)?;
+    address += data.len() as u32;
+    flash.write_bytes(address, &crc.finish().to_le_bytes())?;
+    Ok(address + 4 - base)
+}
@@ -0,0 +1,516 @@
+//! An in-memory SPI Flash used to test the driver without hardware.
+//! Every frame is decoded into a `Command` first, so malformed or unknown frames are ignored
+//! rather than panicking, which lets the fuzz targets in `fuzz/` drive it with arbitrary input.
+//! Available with the `std` feature, e.g. to try out host tools without hardware.
+
+use core::ops::Range;
+use core::sync::atomic::{AtomicBool, Ordering};
+use std::vec::Vec;
+
+use crate::{DualTransmitter, QuadTransmitter, Transmitter};
+
+/// Emulates a generic SPI NOR Flash behind a `Transmitter`.
+/// Every call to the transmitter is treated as one CS assertion.
+pub struct RamFlash {
+    /// The emulated memory array.
+    pub memory: Vec<u8>,
+    /// The bytes returned by the JEDEC ID command.
+    pub jedec_id: [u8; 3],
+    /// The extended device information following the JEDEC ID, starting with its length byte.
+    pub extended_id: Vec<u8>,
+    /// The device ID returned by the legacy 0x90 command, after the manufacturer ID.
+    pub device_id: u8,
+    /// The SFDP area. Reads beyond its end return 0xFF, just like a chip without SFDP.
+    pub sfdp: Vec<u8>,
+    /// Status registers 1 and 2.
+    pub status: [u8; 2],
+    /// Number of status register reads that still report BUSY, emulating a running operation.
+    pub busy_polls: u32,
+    /// Number of status register reads every program or erase keeps reporting BUSY for.
+    pub operation_polls: u32,
+    /// The bank register, selecting the upper address byte.
+    pub bank: u8,
+    /// Whether the chip is in QPI mode.
+    pub qpi_mode: bool,
+    /// Whether the transmitter uses QPI framing. Commands are garbled if this does not match `qpi_mode`.
+    qpi: bool,
+    /// Whether the chip is in 4-byte address mode.
+    pub four_byte: bool,
+    /// The opcode of the dual or quad I/O read whose continuous read mode the chip is in.
+    /// In this mode every command is treated as that read without its opcode.
+    pub continuous: Option<u8>,
+    /// Status register 3, apart from ADS, which reflects `four_byte`.
+    pub status_3: u8,
+    /// Whether the individual block locks are set. They are emulated as one lock for the whole chip,
+    /// set by 0x7E, cleared by 0x98 and read by 0x3D, without keeping anything from being programmed.
+    pub block_locked: bool,
+    /// The Micron flag status register.
+    pub flag_status: u8,
+    /// The Micron volatile, non-volatile and enhanced volatile configuration registers.
+    pub volatile_config: u8,
+    pub nonvolatile_config: u16,
+    pub enhanced_volatile_config: u8,
+    /// The level of the /WP pin, high while `true`. An unconnected pin (`None`) never protects anything.
+    /// While it is low and SRP0 is set, status register writes are ignored.
+    pub wp: Option<&'static AtomicBool>,
+    /// Emulates a wedged chip, which no longer drives the bus, so everything reads as 0xFF.
+    /// Only a software reset (0x66 0x99) gets it back.
+    pub wedged: bool,
+    /// Whether the chip is in deep power-down (0xB9), ignoring everything but a release (0xAB).
+    pub powered_down: bool,
+    /// Emulates a chip which never takes a write enable, so it ignores every program and erase.
+    pub ignore_write_enable: bool,
+    /// The status polls left of the operation put on hold by a suspend (0x75), if any.
+    suspended_polls: Option<u32>,
+    /// Whether the last command was a volatile status write enable (0x50), which arms the following 0x01.
+    volatile_status_enabled: bool,
+    /// Whether the last command was a reset enable, which arms the following 0x99.
+    reset_enabled: bool,
+    /// A memory offset which reads back with all bits flipped once, emulating a glitch on the bus.
+    /// It is cleared by the first array read covering it.
+    pub corrupt_read: Option<usize>,
+    /// A range of the memory which no longer takes any programming, emulating worn out cells.
+    pub stuck: Option<Range<usize>>,
+    /// Whether the transmitter can hold CS across several transfers with `begin()`.
+    pub cs_hold: bool,
+    /// Emulates a half-duplex bus, on which `send_read` must not be used.
+    pub half_duplex: bool,
+    /// Every transmitted frame, in order.
+    pub log: Vec<Vec<u8>>,
+    /// How every frame in `log` was interpreted.
+    pub commands: Vec<Command>,
+    /// The bytes clocked out while CS is held by `begin()`.
+    held: Option<Vec<u8>>,
+    /// The number of bytes clocked in while CS is held, so consecutive reads continue where the last one stopped.
+    held_read: usize,
+}
+
+impl RamFlash {
+    /// Creates an erased flash of `capacity` bytes without SFDP.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            memory: vec![0xFF; capacity],
+            jedec_id: [0xEF, 0x40, 0x18],
+            extended_id: Vec::new(),
+            device_id: 0x17,
+            sfdp: Vec::new(),
+            status: [0; 2],
+            busy_polls: 0,
+            operation_polls: 0,
+            bank: 0,
+            four_byte: false,
+            qpi_mode: false,
+            qpi: false,
+            continuous: None,
+            status_3: 0,
+            block_locked: false,
+            flag_status: 0x80,
+            volatile_config: 0xFB,
+            nonvolatile_config: 0xFFFF,
+            enhanced_volatile_config: 0xDF,
+            wp: None,
+            wedged: false,
+            ignore_write_enable: false,
+            powered_down: false,
+            suspended_polls: None,
+            reset_enabled: false,
+            volatile_status_enabled: false,
+            corrupt_read: None,
+            stuck: None,
+            cs_hold: true,
+            half_duplex: false,
+            log: Vec::new(),
+            commands: Vec::new(),
+            held: None,
+            held_read: 0,
+        }
+    }
+
+    /// Width of the address following the opcode of array commands.
+    fn address_len(&self) -> usize {
+        if self.four_byte { 4 } else { 3 }
+    }
+
+    /// Maps the address of a read, program or erase onto the memory array,
+    /// including the bank register in 3-byte address mode.
+    /// Addresses wrap around at the end of the memory just like on a real chip.
+    fn offset(&self, address: u32) -> usize {
+        let address = if self.four_byte {
+            address as usize
+        } else {
+            (self.bank as usize) << 24 | address as usize
+        };
+        address % self.memory.len().max(1)
+    }
+
+    fn write_enabled(&mut self) -> bool {
+        let enabled = self.status[0] & WEL > 0;
+        self.status[0] &= !WEL;
+        enabled
+    }
+
+    /// Whether status register writes are blocked by /WP in hardware protected mode.
+    fn status_locked(&self) -> bool {
+        let srp0 = self.status[0] & (1 << 7) > 0;
+        let srp1 = self.status[1] & (1 << 0) > 0;
+        let wp_low = matches!(self.wp, Some(wp) if !wp.load(Ordering::SeqCst));
+        srp0 && !srp1 && wp_low
+    }
+
+    /// Interprets one frame, i.e. everything clocked out during one CS assertion.
+    /// Never panics, whatever the frame holds.
+    pub fn decode(&self, frame: &[u8]) -> Command {
+        if self.qpi != self.qpi_mode {
+            return Command::Garbled;
+        }
+        // In continuous read mode, the frame starts right at the address of the next read.
+        let (opcode, args) = match (self.continuous, frame.split_first()) {
+            (Some(opcode), _) => (opcode, frame),
+            (None, Some((&opcode, args))) => (opcode, args),
+            (None, None) => return Command::Garbled,
+        };
+        let address_len = self.address_len();
+        match opcode {
+            0x03 | 0x0B | 0xBB | 0xEB if args.len() >= address_len => Command::Read {
+                opcode,
+                address: address(&args[..address_len]),
+                mode: match opcode {
+                    0xBB | 0xEB => args.get(address_len).copied(),
+                    _ => None,
+                },
+            },
+            _ if self.continuous.is_some() => Command::ExitContinuous,
+            // SFDP and the legacy ID always take a 3 byte address.
+            0x5A | 0x90 if args.len() >= 3 => Command::Read {
+                opcode,
+                address: address(&args[..3]),
+                mode: None,
+            },
+            0x02 | 0x32 if args.len() >= address_len => Command::Program {
+                address: address(&args[..address_len]),
+                len: args.len() - address_len,
+            },
+            0x20 | 0x52 | 0xD8 if args.len() >= address_len => Command::Erase {
+                address: address(&args[..address_len]),
+                size: match opcode {
+                    0x20 => 4 * 1024,
+                    0x52 => 32 * 1024,
+                    _ => 64 * 1024,
+                },
+            },
+            0x60 | 0xC7 => Command::ChipErase,
+            0xC5 | 0x17 | 0x81 | 0x61 | 0x11 if !args.is_empty() => Command::Simple(opcode),
+            0xB1 if args.len() >= 2 => Command::Simple(opcode),
+            0x3D if args.len() >= address_len => Command::Simple(opcode),
+            0x06 | 0x04 | 0x66 | 0x99 | 0xB9 | 0xAB | 0x7E | 0x98 | 0x75 | 0x7A | 0x50 | 0x30 | 0x01 | 0x38 | 0xFF | 0xB7 | 0xE9 | 0x70 | 0xC8 | 0x05 | 0x35 | 0x15 | 0x9F | 0xAF | 0x85 | 0xB5 | 0x65 => {
+                Command::Simple(opcode)
+            }
+            0x03 | 0x0B | 0xBB | 0xEB | 0x5A | 0x90 | 0x02 | 0x32 | 0x20 | 0x52 | 0xD8 | 0xC5 | 0x17 | 0x81 | 0x61 | 0x11 | 0xB1 | 0x3D => {
+                Command::Truncated(opcode)
+            }
+            _ => Command::Unknown(opcode),
+        }
+    }
+
+    /// Executes a command which does not return data.
+    fn execute(&mut self, command: &Command, frame: &[u8]) {
+        // Whatever is sent in continuous read mode is taken as the address of a read,
+        // which leaves continuous read mode for a reset like 0xFF 0xFF.
+        if self.continuous.take().is_some() {
+            return;
+        }
+        let reset_enabled = core::mem::replace(&mut self.reset_enabled, *command == Command::Simple(0x66));
+        if *command == Command::Simple(0x99) && reset_enabled {
+            self.reset();
+            return;
+        }
+        let volatile_status_enabled = core::mem::replace(&mut self.volatile_status_enabled, *command == Command::Simple(0x50));
+        if self.wedged || self.wake(command) {
+            return;
+        }
+        match *command {
+            Command::Simple(0xB9) => self.powered_down = true,
+            Command::Simple(opcode @ (0x7E | 0x98)) if self.write_enabled() => self.block_locked = opcode == 0x7E,
+            Command::Simple(0x06) if !self.ignore_write_enable => self.status[0] |= WEL,
+            Command::Simple(0x04) => self.status[0] &= !WEL,
+            Command::Simple(0x50) => self.flag_status &= 0x80,
+            Command::Simple(0x30) => self.status[0] &= !(1 << 5 | 1 << 6),
+            Command::Simple(0x01) if (self.write_enabled() || volatile_status_enabled) && !self.status_locked() => {
+                if let Some(status) = frame.get(1) {
+                    self.status[0] = status & !(WEL | BUSY);
+                }
+                if let Some(status) = frame.get(2) {
+                    self.status[1] = *status;
+                }
+            }
+            Command::Simple(0x11) if (self.write_enabled() || volatile_status_enabled) && !self.status_locked() => {
+                self.status_3 = frame[1] & !1;
+            }
+            Command::Simple(0xC5) if self.write_enabled() => self.bank = frame[1],
+            Command::Simple(0x81) if self.write_enabled() => self.volatile_config = frame[1],
+            Command::Simple(0x61) if self.write_enabled() => self.enhanced_volatile_config = frame[1],
+            Command::Simple(0xB1) if self.write_enabled() => self.nonvolatile_config = u16::from_le_bytes([frame[1], frame[2]]),
+            Command::Simple(0x38) if self.status[1] & (1 << 1) > 0 => self.qpi_mode = true,
+            Command::Simple(0xFF) => self.qpi_mode = false,
+            Command::Simple(0xB7) => self.four_byte = true,
+            Command::Simple(0xE9) => self.four_byte = false,
+            Command::Simple(0x17) => self.four_byte = frame[1] & (1 << 7) > 0,
+            // A suspend only takes while an operation is running, and sets SUS (status register 2, bit 7).
+            Command::Simple(0x75) if self.busy_polls > 0 => {
+                self.suspended_polls = Some(core::mem::take(&mut self.busy_polls));
+                self.status[1] |= SUS;
+            }
+            Command::Simple(0x7A) => {
+                if let Some(polls) = self.suspended_polls.take() {
+                    self.busy_polls = polls;
+                    self.status[1] &= !SUS;
+                }
+            }
+            Command::Program { address, len } if self.write_enabled() => {
+                let offset = self.offset(address);
+                self.busy_polls = self.operation_polls;
+                let page = offset & !0xFF;
+                for (i, byte) in frame[frame.len() - len..].iter().enumerate() {
+                    // Programming wraps around within the page.
+                    let address = page + (offset + i) % 256;
+                    if matches!(&self.stuck, Some(stuck) if stuck.contains(&address)) {
+                        continue;
+                    }
+                    if let Some(cell) = self.memory.get_mut(address) {
+                        *cell &= byte;
+                    }
+                }
+            }
+            Command::Erase { address, size } if self.write_enabled() => {
+                self.busy_polls = self.operation_polls;
+                let base = self.offset(address) & !(size as usize - 1);
+                let end = (base + size as usize).min(self.memory.len());
+                self.memory[base..end].iter_mut().for_each(|b| *b = 0xFF);
+            }
+            Command::ChipErase if self.write_enabled() => {
+                self.busy_polls = self.operation_polls;
+                self.memory.iter_mut().for_each(|b| *b = 0xFF);
+            }
+            _ => {}
+        }
+    }
+
+    /// Handles `command` while in deep power-down, where only a release is taken.
+    /// Returns whether the command has to be ignored.
+    fn wake(&mut self, command: &Command) -> bool {
+        if self.powered_down && *command == Command::Simple(0xAB) {
+            self.powered_down = false;
+        }
+        self.powered_down
+    }
+
+    /// Returns to the power-up state, aborting any running operation.
+    fn reset(&mut self) {
+        self.wedged = false;
+        self.status[0] &= !(WEL | BUSY);
+        self.status[1] &= !SUS;
+        self.busy_polls = 0;
+        self.suspended_polls = None;
+        self.bank = 0;
+        self.four_byte = false;
+        self.qpi_mode = false;
+        self.continuous = None;
+    }
+
+    /// Answers a command which returns data. Everything the chip does not drive reads as 0xFF.
+    fn respond(&mut self, command: &Command, response: &mut [u8]) {
+        response.iter_mut().for_each(|b| *b = 0xFF);
+        self.continuous = None;
+        self.reset_enabled = false;
+        self.volatile_status_enabled = false;
+        if self.wedged || self.wake(command) {
+            return;
+        }
+        match *command {
+            // The release from deep power-down returns the electronic signature after three dummy bytes.
+            Command::Simple(0xAB) => response.iter_mut().for_each(|b| *b = self.device_id),
+            Command::Read { opcode: 0x5A, address, .. } => {
+                for (i, b) in response.iter_mut().enumerate() {
+                    *b = *self.sfdp.get(address as usize + i).unwrap_or(&0xFF);
+                }
+            }
+            Command::Read { opcode: 0x90, address, .. } => {
+                let id = [self.jedec_id[0], self.device_id];
+                // An odd address swaps the two IDs.
+                let swap = address as usize & 1;
+                for (i, b) in response.iter_mut().enumerate() {
+                    *b = id[(i + swap) % 2];
+                }
+            }
+            Command::Read { opcode, address, mode } => {
+                if !self.memory.is_empty() {
+                    let offset = self.offset(address);
+                    for (i, b) in response.iter_mut().enumerate() {
+                        let offset = (offset + i) % self.memory.len();
+                        *b = self.memory[offset];
+                        if self.corrupt_read == Some(offset) {
+                            self.corrupt_read = None;
+                            *b = !*b;
+                        }
+                    }
+                }
+                // M5-4 = 10 stays in continuous read mode.
+                if matches!(mode, Some(mode) if mode & 0x30 == 0x20) {
+                    self.continuous = Some(opcode);
+                }
+            }
+            Command::Simple(0x70) => response.iter_mut().for_each(|b| *b = self.flag_status),
+            Command::Simple(0x3D) => response.iter_mut().for_each(|b| *b = self.block_locked as u8),
+            Command::Simple(0xC8) => response.iter_mut().for_each(|b| *b = self.bank),
+            Command::Simple(0x85) => response.iter_mut().for_each(|b| *b = self.volatile_config),
+            Command::Simple(0x65) => response.iter_mut().for_each(|b| *b = self.enhanced_volatile_config),
+            Command::Simple(0xB5) => {
+                for (b, config) in response.iter_mut().zip(&self.nonvolatile_config.to_le_bytes()) {
+                    *b = *config;
+                }
+            }
+            Command::Simple(0x05) => {
+                let busy = if self.busy_polls > 0 { BUSY } else { 0 };
+                self.busy_polls = self.busy_polls.saturating_sub(1);
+                response.iter_mut().for_each(|b| *b = self.status[0] | busy);
+            }
+            Command::Simple(0x35) => response.iter_mut().for_each(|b| *b = self.status[1]),
+            // Bit 0 of status register 3 is ADS, the 4-byte address mode.
+            Command::Simple(0x15) => response.iter_mut().for_each(|b| *b = self.status_3 | self.four_byte as u8),
+            // The JEDEC ID has its own opcode in QPI mode.
+            Command::Simple(opcode @ (0x9F | 0xAF)) if (opcode == 0xAF) == self.qpi_mode => {
+                for (b, id) in response.iter_mut().zip(self.jedec_id.iter().chain(&self.extended_id)) {
+                    *b = *id;
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// A frame as interpreted by `RamFlash`, recorded in `RamFlash::commands`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    /// A command without address, like a write enable or a register access.
+    Simple(u8),
+    /// A read with an address, including the SFDP and legacy ID reads.
+    /// `mode` is the mode byte of dual and quad I/O reads.
+    Read { opcode: u8, address: u32, mode: Option<u8> },
+    /// A page program of `len` bytes.
+    Program { address: u32, len: usize },
+    /// An erase of the `size` byte block containing `address`.
+    Erase { address: u32, size: u32 },
+    ChipErase,
+    /// A frame in continuous read mode which is too short for a read, like the 0xFF 0xFF reset.
+    ExitContinuous,
+    /// A known opcode without the address or argument it needs. It is ignored.
+    Truncated(u8),
+    /// An opcode the emulated chip does not implement. It is ignored.
+    Unknown(u8),
+    /// An empty frame, or one sent with the wrong SPI or QPI framing. It is ignored.
+    Garbled,
+}
+
+const BUSY: u8 = 1 << 0;
+const SUS: u8 = 1 << 7;
+const WEL: u8 = 1 << 1;
+
+/// Decodes a big endian address.
+fn address(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0, |address, &b| address << 8 | b as u32)
+}
+
+impl Transmitter for RamFlash {
+    fn send(&mut self, buffer: &[u8]) {
+        match &mut self.held {
+            Some(held) => held.extend_from_slice(buffer),
+            None => {
+                let command = self.decode(buffer);
+                self.execute(&command, buffer);
+                self.log.push(buffer.to_vec());
+                self.commands.push(command);
+            }
+        }
+    }
+
+    fn read(&mut self, buffer: &mut [u8]) {
+        match self.held.take() {
+            Some(held) => {
+                let command = self.decode(&held);
+                let mut response = vec![0; self.held_read + buffer.len()];
+                self.respond(&command, &mut response);
+                buffer.copy_from_slice(&response[self.held_read..]);
+                self.held_read += buffer.len();
+                self.held = Some(held);
+            }
+            None => buffer.iter_mut().for_each(|b| *b = 0xFF),
+        }
+    }
+
+    fn send_read(&mut self, buffer_tx: &[u8], buffer_rx: &mut [u8]) {
+        match &mut self.held {
+            Some(_) => {
+                self.send(buffer_tx);
+                self.read(buffer_rx);
+            }
+            None => {
+                assert!(!self.half_duplex, "send_read on a half-duplex bus");
+                let command = self.decode(buffer_tx);
+                self.respond(&command, buffer_rx);
+                self.log.push(buffer_tx.to_vec());
+                self.commands.push(command);
+            }
+        }
+    }
+
+    fn begin(&mut self) -> bool {
+        if !self.cs_hold {
+            return false;
+        }
+        self.held = Some(Vec::new());
+        self.held_read = 0;
+        true
+    }
+
+    fn end(&mut self) {
+        if let Some(held) = self.held.take() {
+            let command = self.decode(&held);
+            // A frame which was read from has been answered already.
+            if self.held_read == 0 {
+                self.execute(&command, &held);
+            }
+            self.log.push(held);
+            self.commands.push(command);
+        }
+    }
+
+    fn is_full_duplex(&self) -> bool {
+        !self.half_duplex
+    }
+}
+
+impl DualTransmitter for RamFlash {
+    fn read_dual(&mut self, single: &[u8], dual: &[u8], buffer: &mut [u8]) {
+        let mut command = single.to_vec();
+        command.extend_from_slice(dual);
+        self.send_read(&command, buffer);
+    }
+}
+
+impl QuadTransmitter for RamFlash {
+    fn write_quad(&mut self, header: &[u8], data: &[u8]) {
+        let mut command = header.to_vec();
+        command.extend_from_slice(data);
+        self.send(&command);
+    }
+
+    fn read_quad(&mut self, single: &[u8], quad: &[u8], buffer: &mut [u8]) {
+        let mut command = single.to_vec();
+        command.extend_from_slice(quad);
+        self.send_read(&command, buffer);
+    }
+
+    fn set_qpi(&mut self, enabled: bool) {
+        self.qpi = enabled;
+    }
+}
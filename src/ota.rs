@@ -0,0 +1,275 @@
+//! A/B firmware updates: two image slots and a boot flag telling which one is active.
+//!
+//! An update erases the inactive slot, writes the new image into it and only then flips the boot flag,
+//! after checking the image against the expected CRC-32. A power loss at any point before that leaves
+//! the old slot active and intact.
+//!
+//! The boot flag is a record stored with `SPIFlash::write_redundant()` in the first two sectors of the flag region,
+//! sectors being the smallest erase size (4K on most parts, see `SPIFlash::min_erase_size()`).
+//! Its first copy is complete before the second is touched, so a flip is atomic: a torn first copy falls back
+//! to the second, which still holds the previous flag. The record consists of the active slot, the length
+//! and CRC-32 of its image and the length and CRC-32 of the image in the other slot (all u32, little endian),
+//! so a rollback can check the previous image is still there. A length of `NO_IMAGE` marks a slot without a known image.
+//! Without a valid record, slot 0 is active.
+
+use crate::{crc::Crc32, Error, Partition, SPIFlash, Transmitter};
+
+/// Length of the boot flag record.
+const FLAG_LEN: usize = 20;
+
+/// The image length recorded for a slot without a known image.
+const NO_IMAGE: u32 = u32::MAX;
+
+/// Size of the stack buffer images are checked with.
+const CHUNK: usize = 64;
+
+/// The length and CRC-32 of an image in a slot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Image {
+    len: u32,
+    crc: u32,
+}
+
+/// Runs A/B updates over two slots and a boot flag. See the module documentation for the layout.
+pub struct OtaController {
+    slots: [Partition; 2],
+    flag: u32,
+    /// The sector size, which is the distance of the two copies of the boot flag.
+    sector: u32,
+    /// Index of the slot booted from.
+    active: usize,
+    /// The image in each slot, as far as the boot flag knows.
+    images: [Option<Image>; 2],
+    /// Number of bytes written to the inactive slot since `begin_update()`, `None` outside an update.
+    written: Option<u32>,
+}
+
+impl OtaController {
+    /// Reads the boot flag from the `flag` region and sets up updates of the two `slots`.
+    /// The slots and the flag region must start at a sector boundary, the slots must be a whole number of sectors long
+    /// and the flag region needs two sectors. Returns `Error::OutOfBounds` otherwise,
+    /// and `Error::Overlap` if any two of the regions overlap, as erasing one would erase the other.
+    pub fn mount<T: Transmitter>(flash: &mut SPIFlash<T>, slots: [Partition; 2], flag: Partition) -> Result<Self, Error> {
+        let sector = flash.erase_unit();
+        if (slots[0].offset | slots[1].offset | flag.offset | slots[0].size | slots[1].size) & (sector - 1) != 0
+            || flag.size / 2 < sector
+        {
+            return Err(Error::OutOfBounds);
+        }
+        let mut ranges = [(0, 0); 3];
+        for (range, region) in ranges.iter_mut().zip([slots[0], slots[1], flag].iter()) {
+            *range = (region.offset, region.offset.checked_add(region.size).ok_or(Error::OutOfBounds)?);
+        }
+        for (i, a) in ranges.iter().enumerate() {
+            if ranges[i + 1..].iter().any(|b| a.0 < b.1 && b.0 < a.1) {
+                return Err(Error::Overlap);
+            }
+        }
+        let mut controller = Self {
+            slots,
+            flag: flag.offset,
+            sector,
+            active: 0,
+            images: [None, None],
+            written: None,
+        };
+        let mut record = [0; FLAG_LEN];
+        match flash.read_redundant(controller.flag, controller.flag + sector, &mut record) {
+            Ok(()) => {
+                let word = |i: usize| u32::from_le_bytes([record[4 * i], record[4 * i + 1], record[4 * i + 2], record[4 * i + 3]]);
+                let active = (word(0) & 1) as usize;
+                let image = |len: u32, crc: u32| if len == NO_IMAGE { None } else { Some(Image { len, crc }) };
+                controller.active = active;
+                controller.images[active] = image(word(1), word(2));
+                controller.images[1 - active] = image(word(3), word(4));
+            }
+            Err(Error::BothCopiesBad) => {}
+            Err(e) => return Err(e),
+        }
+        Ok(controller)
+    }
+
+    /// Index of the slot to boot from.
+    pub fn active_slot(&self) -> usize {
+        self.active
+    }
+
+    /// The partition of the slot to boot from.
+    pub fn active(&self) -> Partition {
+        self.slots[self.active]
+    }
+
+    /// Starts an update by erasing the inactive slot. Any update started before is abandoned.
+    /// Blocks until the erase is done.
+    pub fn begin_update<T: Transmitter>(&mut self, flash: &mut SPIFlash<T>) -> Result<(), Error> {
+        let slot = self.slots[1 - self.active];
+        // The erased slot no longer holds the image a rollback could return to.
+        self.images[1 - self.active] = None;
+        self.written = None;
+        flash.erase_range(slot.offset, slot.size)?;
+        self.written = Some(0);
+        Ok(())
+    }
+
+    /// Appends `data` to the image in the inactive slot.
+    /// Returns `Error::OutOfBounds` if it does not fit the slot, or if no update was started with `begin_update()`.
+    pub fn write_chunk<T: Transmitter>(&mut self, flash: &mut SPIFlash<T>, data: &[u8]) -> Result<(), Error> {
+        let slot = self.slots[1 - self.active];
+        let written = self.written.ok_or(Error::OutOfBounds)?;
+        let end = written.checked_add(data.len() as u32).filter(|&end| end <= slot.size).ok_or(Error::OutOfBounds)?;
+        flash.write_bytes(slot.offset + written, data)?;
+        self.written = Some(end);
+        Ok(())
+    }
+
+    /// Reads back the image written since `begin_update()` and, if its CRC-32 is `crc`, makes its slot the active one.
+    /// Returns `Error::BadImage` if the CRC does not match, leaving the old slot active,
+    /// and `Error::OutOfBounds` if no update was started.
+    pub fn finalize<T: Transmitter>(&mut self, flash: &mut SPIFlash<T>, crc: u32) -> Result<(), Error> {
+        let len = self.written.ok_or(Error::OutOfBounds)?;
+        let image = Image { len, crc };
+        let slot = 1 - self.active;
+        if !self.image_intact(flash, slot, image)? {
+            return Err(Error::BadImage);
+        }
+        self.switch_to(flash, slot, image)?;
+        self.written = None;
+        Ok(())
+    }
+
+    /// Makes the inactive slot active again, e.g. because the new image failed to boot.
+    /// Returns `Error::BadImage` if the slot does not hold an intact image any more,
+    /// e.g. because an update started erasing it.
+    pub fn rollback<T: Transmitter>(&mut self, flash: &mut SPIFlash<T>) -> Result<(), Error> {
+        let slot = 1 - self.active;
+        let image = match self.images[slot] {
+            Some(image) if self.written.is_none() && self.image_intact(flash, slot, image)? => image,
+            _ => return Err(Error::BadImage),
+        };
+        self.switch_to(flash, slot, image)
+    }
+
+    /// Writes the boot flag making `slot` with `image` active.
+    fn switch_to<T: Transmitter>(&mut self, flash: &mut SPIFlash<T>, slot: usize, image: Image) -> Result<(), Error> {
+        let previous = self.images[self.active].unwrap_or(Image { len: NO_IMAGE, crc: 0 });
+        let mut record = [0; FLAG_LEN];
+        for (i, word) in [slot as u32, image.len, image.crc, previous.len, previous.crc].iter().enumerate() {
+            record[4 * i..4 * i + 4].copy_from_slice(&word.to_le_bytes());
+        }
+        flash.write_redundant(self.flag, self.flag + self.sector, &record)?;
+        self.images[slot] = Some(image);
+        self.active = slot;
+        Ok(())
+    }
+
+    /// Returns whether `slot` holds `image`.
+    fn image_intact<T: Transmitter>(&self, flash: &mut SPIFlash<T>, slot: usize, image: Image) -> Result<bool, Error> {
+        let offset = self.slots[slot].offset;
+        let mut crc = Crc32::new();
+        let mut chunk = [0; CHUNK];
+        let mut done = 0;
+        while done < image.len {
+            let n = ((image.len - done) as usize).min(CHUNK);
+            flash.read_bytes(offset + done, &mut chunk[..n])?;
+            crc.update(&chunk[..n]);
+            done += n as u32;
+        }
+        Ok(crc.finish() == image.crc)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crc::crc32;
+    use crate::mock::RamFlash;
+    use std::vec::Vec;
+
+    const SLOTS: [Partition; 2] = [Partition { offset: 0x2000, size: 0x2000 }, Partition { offset: 0x4000, size: 0x2000 }];
+    const FLAG: Partition = Partition { offset: 0, size: 0x2000 };
+
+    fn update(ota: &mut OtaController, flash: &mut SPIFlash<RamFlash>, image: &[u8]) -> Result<(), Error> {
+        ota.begin_update(flash)?;
+        for chunk in image.chunks(100) {
+            ota.write_chunk(flash, chunk)?;
+        }
+        ota.finalize(flash, crc32(image))
+    }
+
+    #[test]
+    fn update_and_rollback() {
+        let mut flash = SPIFlash::new(RamFlash::new(0x6000));
+        let mut ota = OtaController::mount(&mut flash, SLOTS, FLAG).unwrap();
+        assert_eq!(ota.active_slot(), 0);
+        // Slot 0 holds no known image, so there is nothing to roll back to.
+        update(&mut ota, &mut flash, &[1; 1000]).unwrap();
+        assert_eq!(ota.active(), SLOTS[1]);
+        assert_eq!(ota.rollback(&mut flash), Err(Error::BadImage));
+
+        update(&mut ota, &mut flash, &[2; 3000]).unwrap();
+        assert_eq!(ota.active_slot(), 0);
+        let mut ota = OtaController::mount(&mut flash, SLOTS, FLAG).unwrap();
+        assert_eq!(ota.active_slot(), 0);
+        ota.rollback(&mut flash).unwrap();
+        assert_eq!(ota.active_slot(), 1);
+        let mut ota = OtaController::mount(&mut flash, SLOTS, FLAG).unwrap();
+        assert_eq!(ota.active_slot(), 1);
+
+        // An update which started erasing the old slot rules out a rollback to it.
+        ota.begin_update(&mut flash).unwrap();
+        assert_eq!(ota.rollback(&mut flash), Err(Error::BadImage));
+        assert_eq!(ota.write_chunk(&mut flash, &[0; 0x2001]), Err(Error::OutOfBounds));
+    }
+
+    #[test]
+    fn keeps_old_slot_until_finalized() {
+        let mut flash = SPIFlash::new(RamFlash::new(0x6000));
+        let mut ota = OtaController::mount(&mut flash, SLOTS, FLAG).unwrap();
+        assert_eq!(ota.write_chunk(&mut flash, b"early"), Err(Error::OutOfBounds));
+        update(&mut ota, &mut flash, b"first").unwrap();
+
+        // Power loss in the middle of an update.
+        ota.begin_update(&mut flash).unwrap();
+        ota.write_chunk(&mut flash, b"sec").unwrap();
+        let mut ota = OtaController::mount(&mut flash, SLOTS, FLAG).unwrap();
+        assert_eq!(ota.active_slot(), 1);
+
+        // An image which does not match its CRC is not activated.
+        ota.begin_update(&mut flash).unwrap();
+        ota.write_chunk(&mut flash, b"second").unwrap();
+        assert_eq!(ota.finalize(&mut flash, crc32(b"other")), Err(Error::BadImage));
+        assert_eq!(ota.active_slot(), 1);
+
+        // A flip torn while writing the first copy of the boot flag falls back to the second one.
+        let second: Vec<u8> = flash.transmitter.memory[0x1000..0x2000].to_vec();
+        ota.finalize(&mut flash, crc32(b"second")).unwrap();
+        assert_eq!(ota.active_slot(), 0);
+        flash.transmitter.memory[0x1000..0x2000].copy_from_slice(&second);
+        flash.transmitter.memory[4] ^= 1;
+        let ota = OtaController::mount(&mut flash, SLOTS, FLAG).unwrap();
+        assert_eq!(ota.active_slot(), 1);
+        assert_eq!(OtaController::mount(&mut flash, SLOTS, Partition { offset: 0, size: 0x1000 }).err(), Some(Error::OutOfBounds));
+    }
+
+    #[test]
+    fn rejects_bad_layouts() {
+        let mut flash = SPIFlash::new(RamFlash::new(0x6000));
+        let short = [SLOTS[0], Partition { offset: 0x4000, size: 0x1800 }];
+        assert_eq!(OtaController::mount(&mut flash, short, FLAG).err(), Some(Error::OutOfBounds));
+        let overlapping = [SLOTS[0], Partition { offset: 0x3000, size: 0x2000 }];
+        assert_eq!(OtaController::mount(&mut flash, overlapping, FLAG).err(), Some(Error::Overlap));
+        assert_eq!(OtaController::mount(&mut flash, SLOTS, Partition { offset: 0x5000, size: 0x2000 }).err(), Some(Error::Overlap));
+        let wrapping = [SLOTS[0], Partition { offset: 0xFFFF_F000, size: 0x2000 }];
+        assert_eq!(OtaController::mount(&mut flash, wrapping, FLAG).err(), Some(Error::OutOfBounds));
+
+        // On a part with 64K sectors the copies of the boot flag need a sector each.
+        let mut flash = SPIFlash::new(RamFlash::new(0x40000));
+        flash.info = Some(crate::ChipInfo { sector_size: 0x10000, ..crate::ChipInfo::new(0x40000) });
+        assert_eq!(OtaController::mount(&mut flash, SLOTS, FLAG).err(), Some(Error::OutOfBounds));
+        let slots = [Partition { offset: 0x20000, size: 0x10000 }, Partition { offset: 0x30000, size: 0x10000 }];
+        let mut ota = OtaController::mount(&mut flash, slots, Partition { offset: 0, size: 0x20000 }).unwrap();
+        update(&mut ota, &mut flash, b"image").unwrap();
+        assert_eq!(flash.transmitter.memory[0x10000], 1);
+        assert_eq!(OtaController::mount(&mut flash, slots, Partition { offset: 0, size: 0x20000 }).unwrap().active_slot(), 1);
+    }
+}
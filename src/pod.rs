@@ -0,0 +1,72 @@
+//! Reading and writing plain-old-data structs, e.g. calibration or configuration records.
+//!
+//! A value is stored as its bytes in memory, exactly as `bytemuck::bytes_of` returns them.
+//! That makes the layout depend on the CPU: multi-byte fields are in its native endianness,
+//! so a record written by a little endian MCU reads back byte-swapped on a big endian host.
+//! Give stored structs `#[repr(C)]`, fixed size fields and explicit padding fields,
+//! which deriving `Pod` enforces anyway, so their layout does not change between builds.
+
+use core::convert::TryFrom;
+use core::mem::size_of;
+
+use bytemuck::Pod;
+
+use crate::{Error, SPIFlash, Transmitter};
+
+impl<T: Transmitter> SPIFlash<T> {
+    /// Reads a `P` from the `size_of::<P>()` bytes at `address`.
+    /// Returns `Error::OutOfBounds` if they run past the end of the chip.
+    pub fn read_pod<P: Pod>(&mut self, address: u32) -> Result<P, Error> {
+        self.check_pod_bounds::<P>(address)?;
+        let mut value = P::zeroed();
+        self.read_bytes(address, bytemuck::bytes_of_mut(&mut value))?;
+        Ok(value)
+    }
+
+    /// Writes the bytes of `value` to `address`, which must be erased.
+    /// Returns `Error::OutOfBounds` if they run past the end of the chip.
+    pub fn write_pod<P: Pod>(&mut self, address: u32, value: &P) -> Result<(), Error> {
+        self.check_pod_bounds::<P>(address)?;
+        self.write_bytes(address, bytemuck::bytes_of(value))
+    }
+
+    fn check_pod_bounds<P: Pod>(&self, address: u32) -> Result<(), Error> {
+        let len = u32::try_from(size_of::<P>()).map_err(|_| Error::OutOfBounds)?;
+        self.check_bounds(address, len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::RamFlash;
+    use crate::ChipInfo;
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    #[repr(C)]
+    struct Calibration {
+        offset: u16,
+        gain: u16,
+        serial: u32,
+    }
+
+    unsafe impl bytemuck::Zeroable for Calibration {}
+    unsafe impl Pod for Calibration {}
+
+    #[test]
+    fn round_trips_struct() {
+        let mut flash = SPIFlash::new(RamFlash::new(4096));
+        flash.info = Some(ChipInfo::new(4096));
+        let calibration = Calibration {
+            offset: 0x1234,
+            gain: 0x5678,
+            serial: 0xDEAD_BEEF,
+        };
+        flash.write_pod(0x100, &calibration).unwrap();
+        assert_eq!(flash.read_pod::<Calibration>(0x100), Ok(calibration));
+        assert_eq!(flash.transmitter.memory[0x100..0x102], 0x1234u16.to_ne_bytes());
+
+        assert_eq!(flash.read_pod::<Calibration>(4092), Err(Error::OutOfBounds));
+        assert_eq!(flash.write_pod(4090, &calibration), Err(Error::OutOfBounds));
+    }
+}
@@ -0,0 +1,255 @@
+//! Bad sector remapping for long-lived data on NOR flash.
+//!
+//! A region of logical sectors is backed by the same number of physical sectors plus a pool of spares.
+//! When writing to a sector keeps failing verification, the sector is marked bad and its logical address
+//! is redirected to a spare. The mapping is persisted in a dedicated map sector, so it survives a reboot.
+//!
+//! The map sector holds 4 byte entries, appended in order:
+//! the logical sector number (u16, little endian), the spare index and the inverted spare index.
+//! An entry whose check byte does not match (e.g. one torn by power loss) is ignored.
+
+use crate::{Error, SPIFlash, Transmitter};
+
+/// Size of a remapped sector. Remapping works on 4K sectors, the smallest erasable unit.
+pub const SECTOR_SIZE: u32 = 4096;
+
+/// Length of a map entry in bytes.
+const ENTRY_LEN: u32 = 4;
+
+/// Marks a spare which does not replace any logical sector.
+const UNUSED: u16 = 0xFFFF;
+
+/// Where the remapped region, its spares and its map live.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RemapLayout {
+    /// Address of the first logical sector. Logical addresses passed to `Remapper` are relative to it.
+    pub data_start: u32,
+    /// Number of logical sectors.
+    pub sectors: u16,
+    /// Address of the first spare sector.
+    pub spare_start: u32,
+    /// Number of spare sectors.
+    pub spares: u8,
+    /// Address of the sector holding the map.
+    pub map_address: u32,
+}
+
+/// Remaps bad sectors of a region to spares. See the module documentation for details.
+pub struct Remapper<'a> {
+    layout: RemapLayout,
+    /// For every spare, the logical sector it replaces.
+    map: &'a mut [u16],
+    /// How often a write is retried before its sector is considered bad.
+    retries: u8,
+}
+
+impl<'a> Remapper<'a> {
+    /// Creates a remapper for `layout`, using `map` to keep one entry per spare in RAM.
+    /// Call `load()` to read the persisted map before using it.
+    pub fn new(layout: RemapLayout, map: &'a mut [u16]) -> Result<Self, Error> {
+        if map.len() < layout.spares as usize {
+            return Err(Error::OutOfBounds);
+        }
+        map.iter_mut().for_each(|entry| *entry = UNUSED);
+        Ok(Self {
+            layout,
+            map,
+            retries: 2,
+        })
+    }
+
+    /// Sets how often a failed write is retried before its sector is remapped.
+    pub fn set_retries(&mut self, retries: u8) {
+        self.retries = retries;
+    }
+
+    /// Reads the persisted map from flash.
+    pub fn load<T: Transmitter>(&mut self, flash: &mut SPIFlash<T>) -> Result<(), Error> {
+        self.map.iter_mut().for_each(|entry| *entry = UNUSED);
+        let mut address = self.layout.map_address;
+        while address < self.layout.map_address + SECTOR_SIZE {
+            let mut entry = [0; ENTRY_LEN as usize];
+            flash.read_bytes(address, &mut entry)?;
+            if entry == [flash.blank_byte(); ENTRY_LEN as usize] {
+                break;
+            }
+            let sector = u16::from_le_bytes([entry[0], entry[1]]);
+            let spare = entry[2];
+            if entry[3] == !spare && spare < self.layout.spares && sector < self.layout.sectors {
+                self.map[spare as usize] = sector;
+            }
+            address += ENTRY_LEN;
+        }
+        Ok(())
+    }
+
+    /// Number of sectors remapped so far.
+    pub fn remapped(&self) -> usize {
+        self.map[..self.layout.spares as usize].iter().filter(|&&sector| sector != UNUSED).count()
+    }
+
+    /// Translates a logical address into the physical address currently backing it.
+    pub fn physical(&self, address: u32) -> Result<u32, Error> {
+        let sector = address / SECTOR_SIZE;
+        if sector >= self.layout.sectors as u32 {
+            return Err(Error::OutOfBounds);
+        }
+        let offset = address % SECTOR_SIZE;
+        // The most recent replacement wins, in case a spare went bad as well.
+        Ok(match self.map[..self.layout.spares as usize].iter().rposition(|&s| s as u32 == sector) {
+            Some(spare) => self.layout.spare_start + spare as u32 * SECTOR_SIZE + offset,
+            None => self.layout.data_start + address,
+        })
+    }
+
+    /// Reads `buffer.len()` bytes at the logical `address`.
+    pub fn read<T: Transmitter>(&self, flash: &mut SPIFlash<T>, address: u32, buffer: &mut [u8]) -> Result<(), Error> {
+        self.per_sector(address, buffer.len() as u32, |offset, physical, len| {
+            flash.read_bytes(physical, &mut buffer[offset..offset + len])
+        })
+    }
+
+    /// Erases the logical sector containing `address`.
+    pub fn erase_sector<T: Transmitter>(&self, flash: &mut SPIFlash<T>, address: u32) -> Result<(), Error> {
+        flash.erase_4k_block(self.physical(address)?)
+    }
+
+    /// Writes `data` at the logical `address`, which must be erased.
+    /// Every sector's part of the write is read back. If it keeps failing verification,
+    /// the sector is copied to a spare, which then backs it from now on.
+    /// Returns `Error::OutOfSpares` if a sector went bad and no spare is left.
+    pub fn write<T: Transmitter>(&mut self, flash: &mut SPIFlash<T>, address: u32, data: &[u8]) -> Result<(), Error> {
+        let mut offset = 0;
+        while offset < data.len() {
+            let logical = address + offset as u32;
+            let len = ((SECTOR_SIZE - logical % SECTOR_SIZE) as usize).min(data.len() - offset);
+            let part = &data[offset..offset + len];
+            let mut attempts = 0;
+            loop {
+                let physical = self.physical(logical)?;
+                if Self::write_verified(flash, physical, part)? {
+                    break;
+                }
+                attempts += 1;
+                if attempts > self.retries {
+                    self.remap(flash, logical)?;
+                    attempts = 0;
+                }
+            }
+            offset += len;
+        }
+        Ok(())
+    }
+
+    /// Programs `data` and reads it back. Returns whether it verified.
+    fn write_verified<T: Transmitter>(flash: &mut SPIFlash<T>, address: u32, data: &[u8]) -> Result<bool, Error> {
+        flash.write_bytes(address, data)?;
+        match flash.verify_pattern(address, data.len() as u32, |a| data[(a - address) as usize]) {
+            Ok(()) => Ok(true),
+            Err(Error::VerifyMismatch(_)) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Moves the logical sector containing `address` to a fresh spare and persists the mapping.
+    fn remap<T: Transmitter>(&mut self, flash: &mut SPIFlash<T>, address: u32) -> Result<(), Error> {
+        let sector = address / SECTOR_SIZE;
+        let spare = self.map[..self.layout.spares as usize]
+            .iter()
+            .position(|&s| s == UNUSED)
+            .ok_or(Error::OutOfSpares)?;
+        let old = self.physical(sector * SECTOR_SIZE)?;
+        let new = self.layout.spare_start + spare as u32 * SECTOR_SIZE;
+
+        // Carry over whatever the bad sector still holds.
+        flash.erase_4k_block(new)?;
+        let mut buffer = [0; 64];
+        for chunk in (0..SECTOR_SIZE).step_by(buffer.len()) {
+            flash.read_bytes(old + chunk, &mut buffer)?;
+            if buffer.iter().any(|&b| b != flash.blank_byte()) {
+                flash.write_bytes(new + chunk, &buffer)?;
+            }
+        }
+
+        self.append_entry(flash, sector as u16, spare as u8)?;
+        self.map[spare] = sector as u16;
+        Ok(())
+    }
+
+    /// Appends a map entry to the first blank slot of the map sector.
+    fn append_entry<T: Transmitter>(&self, flash: &mut SPIFlash<T>, sector: u16, spare: u8) -> Result<(), Error> {
+        let mut address = self.layout.map_address;
+        while address < self.layout.map_address + SECTOR_SIZE {
+            let mut entry = [0; ENTRY_LEN as usize];
+            flash.read_bytes(address, &mut entry)?;
+            if entry == [flash.blank_byte(); ENTRY_LEN as usize] {
+                let sector = sector.to_le_bytes();
+                return flash.write_bytes(address, &[sector[0], sector[1], spare, !spare]);
+            }
+            address += ENTRY_LEN;
+        }
+        Err(Error::OutOfSpares)
+    }
+
+    /// Calls `f(offset, physical, len)` for every sector-sized piece of the logical range.
+    fn per_sector(&self, address: u32, len: u32, mut f: impl FnMut(usize, u32, usize) -> Result<(), Error>) -> Result<(), Error> {
+        let mut offset = 0;
+        while offset < len {
+            let logical = address + offset;
+            let n = (SECTOR_SIZE - logical % SECTOR_SIZE).min(len - offset);
+            f(offset as usize, self.physical(logical)?, n as usize)?;
+            offset += n;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::RamFlash;
+
+    const LAYOUT: RemapLayout = RemapLayout {
+        data_start: 0,
+        sectors: 4,
+        spare_start: 4 * SECTOR_SIZE,
+        spares: 2,
+        map_address: 6 * SECTOR_SIZE,
+    };
+
+    #[test]
+    fn remaps_bad_sector() {
+        let mut ram = RamFlash::new(8 * SECTOR_SIZE as usize);
+        // Sector 1 no longer takes any programming.
+        ram.stuck = Some(SECTOR_SIZE as usize..2 * SECTOR_SIZE as usize);
+        let mut flash = SPIFlash::new(ram);
+
+        let mut map = [0; 2];
+        let mut remapper = Remapper::new(LAYOUT, &mut map).unwrap();
+        remapper.write(&mut flash, SECTOR_SIZE - 2, &[1, 2, 3, 4]).unwrap();
+        assert_eq!(remapper.remapped(), 1);
+        assert_eq!(remapper.physical(SECTOR_SIZE), Ok(4 * SECTOR_SIZE));
+
+        let mut buffer = [0; 4];
+        remapper.read(&mut flash, SECTOR_SIZE - 2, &mut buffer).unwrap();
+        assert_eq!(buffer, [1, 2, 3, 4]);
+
+        // The map survives a reload.
+        let mut map = [0; 2];
+        let mut reloaded = Remapper::new(LAYOUT, &mut map).unwrap();
+        reloaded.load(&mut flash).unwrap();
+        assert_eq!(reloaded.physical(SECTOR_SIZE + 1), Ok(4 * SECTOR_SIZE + 1));
+        assert_eq!(reloaded.physical(0), Ok(0));
+    }
+
+    #[test]
+    fn runs_out_of_spares() {
+        let mut ram = RamFlash::new(8 * SECTOR_SIZE as usize);
+        ram.stuck = Some(0..6 * SECTOR_SIZE as usize);
+        let mut flash = SPIFlash::new(ram);
+
+        let mut map = [0; 2];
+        let mut remapper = Remapper::new(LAYOUT, &mut map).unwrap();
+        assert_eq!(remapper.write(&mut flash, 0, &[0]), Err(Error::OutOfSpares));
+    }
+}
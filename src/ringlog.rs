@@ -0,0 +1,252 @@
+//! A ring buffer log for crash logs and black box recordings, kept in a region of 4K sectors.
+//!
+//! Records are appended to the head sector until the next one does not fit.
+//! Then the sector after the head is erased and becomes the new head, so once the region is full
+//! the oldest sector of records is dropped for every new one.
+//!
+//! Every sector starts with a 12 byte header: the magic `RLG1`, a sequence number (u32, little endian)
+//! and its inverse (see `log_sector`). The sequence number grows by one for every new head sector, so it marks the newest one.
+//! A sector whose erase or header write was torn by power loss has no intact header and is skipped.
+//!
+//! A record consists of the length (u16), the data and the CRC-32 of everything before it, all little endian.
+//! Only the head sector is ever written to, so an `append()` torn by power loss loses just that record:
+//! mounting stops reading the head at it, and the next append starts a new head sector.
+
+use crate::log_sector::{self, HEADER_LEN};
+use crate::{Error, SPIFlash, Transmitter};
+
+/// Size of every sector of the log.
+pub const SECTOR_SIZE: u32 = log_sector::SECTOR_SIZE;
+
+/// The first four bytes of a valid sector.
+const MAGIC: [u8; 4] = *b"RLG1";
+
+/// Length of a record without its data: length and CRC.
+const RECORD_OVERHEAD: u32 = 6;
+
+/// Longest record which fits a sector.
+pub const MAX_RECORD: usize = (SECTOR_SIZE - HEADER_LEN - RECORD_OVERHEAD) as usize;
+
+/// The length of an erased record header.
+const ERASED_LEN: u16 = 0xFFFF;
+
+/// A ring buffer log over a region of sectors. See the module documentation for the layout.
+pub struct RingLog {
+    start: u32,
+    sectors: u32,
+    /// Index of the sector records are appended to.
+    head: u32,
+    sequence: u32,
+    /// Offset of the first free byte in the head sector.
+    end: u32,
+}
+
+impl RingLog {
+    /// Opens the log kept in the `len` bytes at `start`, which must be 4K aligned and span at least two sectors.
+    /// If no sector holds a valid header, an empty log is created in the first one.
+    pub fn mount<T: Transmitter>(flash: &mut SPIFlash<T>, start: u32, len: u32) -> Result<Self, Error> {
+        if (start | len) & (SECTOR_SIZE - 1) != 0 || len < 2 * SECTOR_SIZE {
+            return Err(Error::OutOfBounds);
+        }
+        let mut log = Self {
+            start,
+            sectors: len / SECTOR_SIZE,
+            head: 0,
+            sequence: 0,
+            end: HEADER_LEN,
+        };
+        // The sequence numbers of all sectors lie within a few of each other,
+        // so comparing them relative to any one of them allows for wrap around.
+        let mut newest: Option<(u32, u32)> = None;
+        for sector in 0..log.sectors {
+            if let Some(sequence) = log_sector::read_header(flash, log.base(sector), MAGIC)? {
+                match newest {
+                    Some((_, latest)) if (sequence.wrapping_sub(latest) as i32) <= 0 => {}
+                    _ => newest = Some((sector, sequence)),
+                }
+            }
+        }
+        match newest {
+            Some((sector, sequence)) => {
+                log.head = sector;
+                log.sequence = sequence;
+            }
+            None => {
+                flash.erase_4k_block(start)?;
+                log_sector::write_header(flash, start, MAGIC, 0)?;
+            }
+        }
+        let base = log.base(log.head);
+        while let Some((_, end)) = Self::next_record(flash, base, log.end)? {
+            log.end = end;
+        }
+        Ok(log)
+    }
+
+    /// Appends `record`, erasing the sector after the head and moving on to it if it does not fit.
+    /// That drops the oldest records once the region is full.
+    /// Returns `Error::OutOfBounds` if `record` is longer than `MAX_RECORD`.
+    pub fn append<T: Transmitter>(&mut self, flash: &mut SPIFlash<T>, record: &[u8]) -> Result<(), Error> {
+        if record.len() > MAX_RECORD {
+            return Err(Error::OutOfBounds);
+        }
+        let len = RECORD_OVERHEAD + record.len() as u32;
+        let base = self.base(self.head);
+        if !log_sector::fits(flash, base, self.end, len)? {
+            let head = (self.head + 1) % self.sectors;
+            let sequence = self.sequence.wrapping_add(1);
+            flash.erase_4k_block(self.base(head))?;
+            log_sector::write_header(flash, self.base(head), MAGIC, sequence)?;
+            self.head = head;
+            self.sequence = sequence;
+            self.end = HEADER_LEN;
+        }
+        self.end = Self::write_record(flash, self.base(self.head), self.end, record)?;
+        Ok(())
+    }
+
+    /// Returns a cursor over the records, from the oldest to the newest, see `Entries::read_next()`.
+    pub fn entries(&self) -> Entries {
+        Entries {
+            start: self.start,
+            sectors: self.sectors,
+            first: (self.head + 1) % self.sectors,
+            visited: 0,
+            offset: 0,
+        }
+    }
+
+    /// Address of the sector with index `sector`.
+    fn base(&self, sector: u32) -> u32 {
+        self.start + sector * SECTOR_SIZE
+    }
+
+    /// Decodes the record at `offset` in the sector at `base` and returns its length and the offset after it.
+    /// Returns `None` at the end of the sector, i.e. at an erased or torn record.
+    fn next_record<T: Transmitter>(flash: &mut SPIFlash<T>, base: u32, offset: u32) -> Result<Option<(usize, u32)>, Error> {
+        let data_len = |header: &[u8; 2]| match u16::from_le_bytes(*header) {
+            ERASED_LEN => None,
+            len => Some(len as u32),
+        };
+        Ok(log_sector::next_record(flash, base, offset, data_len)?.map(|(header, end)| (u16::from_le_bytes(header) as usize, end)))
+    }
+
+    /// Writes `record` at `offset` in the sector at `base` and returns the offset after it.
+    fn write_record<T: Transmitter>(flash: &mut SPIFlash<T>, base: u32, offset: u32, record: &[u8]) -> Result<u32, Error> {
+        log_sector::write_record(flash, base, offset, &(record.len() as u16).to_le_bytes(), record)
+    }
+}
+
+/// A cursor over the records of a `RingLog`, oldest first.
+/// It does not borrow the flash, which is passed to every `read_next()` instead.
+/// Appending to the log while reading it may drop the records the cursor is about to visit.
+pub struct Entries {
+    start: u32,
+    sectors: u32,
+    /// Index of the oldest sector.
+    first: u32,
+    /// Number of sectors done so far.
+    visited: u32,
+    /// Offset of the next record in the current sector, 0 before its header is checked.
+    offset: u32,
+}
+
+impl Entries {
+    /// Reads the next record into `buffer` and returns its length, or `None` after the newest record.
+    /// Returns `Error::OutOfBounds` if `buffer` is too small for the record, without moving on,
+    /// so it can be retried with a larger buffer.
+    pub fn read_next<T: Transmitter>(&mut self, flash: &mut SPIFlash<T>, buffer: &mut [u8]) -> Result<Option<usize>, Error> {
+        while self.visited < self.sectors {
+            let base = self.start + (self.first + self.visited) % self.sectors * SECTOR_SIZE;
+            if self.offset == 0 {
+                if log_sector::read_header(flash, base, MAGIC)?.is_none() {
+                    self.visited += 1;
+                    continue;
+                }
+                self.offset = HEADER_LEN;
+            }
+            match RingLog::next_record(flash, base, self.offset)? {
+                Some((len, end)) => {
+                    let data = buffer.get_mut(..len).ok_or(Error::OutOfBounds)?;
+                    flash.read_bytes(base + self.offset + 2, data)?;
+                    self.offset = end;
+                    return Ok(Some(len));
+                }
+                None => {
+                    self.visited += 1;
+                    self.offset = 0;
+                }
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::RamFlash;
+    use std::vec::Vec;
+
+    fn records(log: &RingLog, flash: &mut SPIFlash<RamFlash>) -> Vec<Vec<u8>> {
+        let mut entries = log.entries();
+        let mut buffer = [0; 256];
+        let mut records = Vec::new();
+        while let Some(len) = entries.read_next(flash, &mut buffer).unwrap() {
+            records.push(buffer[..len].to_vec());
+        }
+        records
+    }
+
+    #[test]
+    fn append_and_wrap() {
+        let mut flash = SPIFlash::new(RamFlash::new(4 * 4096));
+        let mut log = RingLog::mount(&mut flash, 0x1000, 0x3000).unwrap();
+        assert_eq!(records(&log, &mut flash), Vec::<Vec<u8>>::new());
+        log.append(&mut flash, b"boot").unwrap();
+        log.append(&mut flash, b"").unwrap();
+        assert_eq!(records(&log, &mut flash), [b"boot".to_vec(), Vec::new()]);
+
+        // 38 records of 100 bytes fit a sector, so 200 of them wrap around to the first sector again.
+        // It holds records 114 to 151 then, followed by the second and the partially filled third.
+        for i in 0..200u8 {
+            log.append(&mut flash, &[i; 100]).unwrap();
+        }
+        let expected: Vec<Vec<u8>> = (114..200).map(|i| [i as u8; 100].to_vec()).collect();
+        assert_eq!(records(&log, &mut flash), expected);
+
+        // The log survives a remount and goes on where it stopped.
+        let mut log = RingLog::mount(&mut flash, 0x1000, 0x3000).unwrap();
+        assert_eq!(records(&log, &mut flash), expected);
+        log.append(&mut flash, b"last").unwrap();
+        assert_eq!(records(&log, &mut flash).last(), Some(&b"last".to_vec()));
+
+        let mut buffer = [0; 2];
+        assert_eq!(log.entries().read_next(&mut flash, &mut buffer), Err(Error::OutOfBounds));
+        assert_eq!(log.append(&mut flash, &[0; MAX_RECORD + 1]), Err(Error::OutOfBounds));
+        assert_eq!(RingLog::mount(&mut flash, 0x1000, 0x1000).err(), Some(Error::OutOfBounds));
+    }
+
+    #[test]
+    fn survives_torn_writes() {
+        let mut flash = SPIFlash::new(RamFlash::new(4 * 4096));
+        let mut log = RingLog::mount(&mut flash, 0x1000, 0x2000).unwrap();
+        log.append(&mut flash, b"kept").unwrap();
+        let end = 0x1000 + log.end as usize;
+        // A record torn after its length and part of the data.
+        flash.transmitter.memory[end..end + 4].copy_from_slice(&[10, 0, b't', b'o']);
+
+        let mut log = RingLog::mount(&mut flash, 0x1000, 0x2000).unwrap();
+        assert_eq!(records(&log, &mut flash), [b"kept".to_vec()]);
+        // The torn bytes can not be programmed again, so the next record goes to the next sector.
+        log.append(&mut flash, b"next").unwrap();
+        assert_eq!(log.head, 1);
+        assert_eq!(records(&log, &mut flash), [b"kept".to_vec(), b"next".to_vec()]);
+
+        // A torn erase of the following sector leaves it without a header, so it is skipped.
+        flash.transmitter.memory[0x1000..0x2000].iter_mut().for_each(|b| *b = 0xFF);
+        let log = RingLog::mount(&mut flash, 0x1000, 0x2000).unwrap();
+        assert_eq!(log.head, 1);
+        assert_eq!(records(&log, &mut flash), [b"next".to_vec()]);
+    }
+}
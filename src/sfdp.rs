@@ -0,0 +1,207 @@
+//! Parsing of the Serial Flash Discoverable Parameters (SFDP, JESD216) tables.
+//!
+//! The SFDP area starts with an 8 byte header, followed by a list of 8 byte parameter headers.
+//! Each parameter header points to a parameter table. The only mandatory one is the basic flash parameter table.
+
+use crate::{AddressWidth, ChipInfo, Error, FourByteMode, ReadMode, TypicalTimes, SPIFLASH_ARRAYREADDUALIO, SPIFLASH_ARRAYREADQUADIO};
+
+/// The first four bytes of a valid SFDP area.
+pub(crate) const SIGNATURE: [u8; 4] = *b"SFDP";
+/// Length of the SFDP header and of every parameter header.
+pub(crate) const HEADER_LEN: usize = 8;
+/// ID of the JEDEC basic flash parameter table.
+pub(crate) const BASIC_TABLE_ID: u16 = 0xFF00;
+/// Number of basic table DWORDs this driver knows how to decode.
+pub(crate) const BASIC_TABLE_DWORDS: usize = 16;
+
+/// Parses the SFDP header and returns the number of parameter headers following it.
+/// Returns `Error::NoSfdp` if the signature does not match, which is what chips without SFDP look like.
+pub(crate) fn parse_header(header: &[u8; HEADER_LEN]) -> Result<usize, Error> {
+    if header[0..4] != SIGNATURE {
+        return Err(Error::NoSfdp);
+    }
+    // NPH is zero based.
+    Ok(header[6] as usize + 1)
+}
+
+/// A parameter header pointing to one of the SFDP parameter tables.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ParameterHeader {
+    pub id: u16,
+    pub major: u8,
+    pub minor: u8,
+    /// Length of the table in DWORDs.
+    pub length: u8,
+    /// Byte address of the table in the SFDP area.
+    pub pointer: u32,
+}
+
+pub(crate) fn parse_parameter_header(header: &[u8; HEADER_LEN]) -> ParameterHeader {
+    ParameterHeader {
+        id: (header[7] as u16) << 8 | header[0] as u16,
+        minor: header[1],
+        major: header[2],
+        length: header[3],
+        pointer: header[4] as u32 | (header[5] as u32) << 8 | (header[6] as u32) << 16,
+    }
+}
+
+/// Converts a little endian byte buffer into DWORDs as used by the SFDP tables.
+/// Missing trailing DWORDs are left at zero.
+pub(crate) fn to_dwords(bytes: &[u8], dwords: &mut [u32]) {
+    for (dword, chunk) in dwords.iter_mut().zip(bytes.chunks_exact(4)) {
+        *dword = chunk[0] as u32 | (chunk[1] as u32) << 8 | (chunk[2] as u32) << 16 | (chunk[3] as u32) << 24;
+    }
+}
+
+/// Decodes the 16 bit fast read `field` of DWORD 3 or 4 into the dummy clocks following a mode byte
+/// of `mode_clocks` clocks, if it describes the `opcode` read and the dummy clocks are whole bytes.
+fn io_read_dummy_cycles(field: u32, opcode: u8, mode: ReadMode, mode_clocks: u8) -> Option<u8> {
+    if (field >> 8) as u8 != opcode {
+        return None;
+    }
+    let latency = (field & 0x1F) as u8 + (field >> 5 & 0b111) as u8;
+    let cycles = latency.checked_sub(mode_clocks)?;
+    mode.dummy_bytes(cycles).map(|_| cycles)
+}
+
+/// Decodes the basic flash parameter table.
+/// `dwords` holds the table starting at DWORD 1, `length` is the number of valid DWORDs.
+pub(crate) fn parse_basic_table(dwords: &[u32; BASIC_TABLE_DWORDS], length: usize) -> Result<ChipInfo, Error> {
+    // DWORD 2 holds the density in bits.
+    // If bit 31 is set, the density is 2^N bits, otherwise it is N + 1 bits.
+    let density = dwords[1];
+    let capacity = if density & (1 << 31) > 0 {
+        let exponent = density & 0x7FFF_FFFF;
+        // The capacity in bytes has to fit a u32.
+        if !(3..=34).contains(&exponent) {
+            return Err(Error::NoSfdp);
+        }
+        1u32 << (exponent - 3)
+    } else {
+        (density / 8).wrapping_add(1)
+    };
+    if capacity == 0 {
+        return Err(Error::NoSfdp);
+    }
+
+    let mut info = ChipInfo::new(capacity);
+
+    // DWORD 3 and 4 hold the wait states (bits 4:0), mode clocks (bits 7:5) and opcode of the fast reads
+    // which DWORD 1 advertises: 1-4-4 in the low half of DWORD 3, 1-2-2 in the high half of DWORD 4.
+    // The mode byte the driver sends covers that many clocks of the latency, the rest are dummy clocks.
+    // Fields for another opcode, or with a latency the driver can not frame, keep the defaults.
+    if length >= 4 {
+        if dwords[0] & (1 << 20) > 0 {
+            if let Some(cycles) = io_read_dummy_cycles(dwords[3] >> 16, SPIFLASH_ARRAYREADDUALIO, ReadMode::DualIo, 4) {
+                info.dual_io_dummy_cycles = cycles;
+            }
+        }
+        if dwords[0] & (1 << 21) > 0 {
+            if let Some(cycles) = io_read_dummy_cycles(dwords[2], SPIFLASH_ARRAYREADQUADIO, ReadMode::QuadIo, 2) {
+                info.quad_io_dummy_cycles = cycles;
+            }
+        }
+    }
+
+    // DWORD 8 and 9 list up to four erase types as (size exponent, opcode) pairs.
+    // The smallest supported one is the sector size. An exponent of 0 marks an unused type,
+    // 32 and above can only come from a corrupt table.
+    let mut sector_type = None;
+    if length >= 9 {
+        sector_type = (0..4)
+            .map(|i| (i, (dwords[7 + i / 2] >> (16 * (i % 2))) as u8))
+            .filter(|&(_, exponent)| (1..=31).contains(&exponent))
+            .min_by_key(|&(_, exponent)| exponent)
+            .map(|(i, _)| i);
+        let i = sector_type.ok_or(Error::NoSfdp)?;
+        info.sector_size = 1 << (dwords[7 + i / 2] >> (16 * (i % 2))) as u8;
+        // The advertised erase types replace the common defaults.
+        for (i, op) in info.erase_ops.iter_mut().enumerate() {
+            let erase_type = dwords[7 + i / 2] >> (16 * (i % 2));
+            let exponent = erase_type as u8;
+            *op = match exponent {
+                1..=31 => (1 << exponent, (erase_type >> 8) as u8),
+                _ => (0, 0),
+            };
+        }
+    }
+
+    // DWORD 10 holds the typical erase time of every erase type
+    // and the multiplier from typical to maximum times.
+    let mut erase_us = [0; 4];
+    if length >= 10 {
+        for (i, typical_us) in erase_us.iter_mut().enumerate() {
+            if info.erase_ops[i].0 > 0 {
+                let field = dwords[9] >> (4 + 7 * i);
+                let unit_ms = [1, 16, 128, 1000][(field >> 5 & 0b11) as usize];
+                *typical_us = ((field & 0x1F) + 1) * unit_ms * 1000;
+            }
+        }
+        if let Some(i) = sector_type {
+            let multiplier = 2 * ((dwords[9] & 0xF) + 1);
+            info.erase_time_us = erase_us[i].saturating_mul(multiplier);
+        }
+    }
+
+    // DWORD 11 holds the page size as 2^N bytes in bits 7:4, the typical page program and chip erase times,
+    // with their own multiplier from typical to maximum.
+    // A page size of a single byte is taken as a table which leaves the field empty, keeping the 256 byte default.
+    if length >= 11 {
+        let exponent = dwords[10] >> 4 & 0xF;
+        if exponent > 0 {
+            info.page_size = 1 << exponent;
+        }
+        let multiplier = 2 * ((dwords[10] & 0xF) + 1);
+        let unit_us = if dwords[10] & (1 << 13) > 0 { 64 } else { 8 };
+        let program_us = ((dwords[10] >> 8 & 0x1F) + 1) * unit_us;
+        info.program_time_us = program_us * multiplier;
+        let unit_ms: u32 = [16, 256, 4000, 64000][(dwords[10] >> 29 & 0b11) as usize];
+        let chip_erase_us = ((dwords[10] >> 24 & 0x1F) + 1) * unit_ms;
+        info.chip_erase_time_us = chip_erase_us.saturating_mul(1000).saturating_mul(multiplier);
+        info.typical_times = Some(TypicalTimes {
+            program_us,
+            erase_us,
+            chip_erase_us: chip_erase_us.saturating_mul(1000),
+        });
+    }
+
+    // DWORD 12 bit 31 is cleared if the part can suspend and resume programs and erases.
+    if length >= 12 {
+        let suspend = dwords[11] & (1 << 31) == 0;
+        info.erase_suspend = suspend;
+        info.program_suspend = suspend;
+    }
+
+    // DWORD 16 lists the supported ways to enter 4-byte address mode in bits 31:24.
+    // The first one this driver implements is used.
+    if length >= 16 {
+        let enter = dwords[15] >> 24;
+        info.four_byte_mode = if enter & (1 << 6) > 0 {
+            FourByteMode::Always
+        } else if enter & (1 << 0) > 0 {
+            FourByteMode::Command
+        } else if enter & (1 << 1) > 0 {
+            FourByteMode::WriteEnableCommand
+        } else if enter & (1 << 3) > 0 {
+            FourByteMode::BankRegister
+        } else {
+            FourByteMode::Unsupported
+        };
+    }
+
+    // DWORD 1 bits 18:17 tell which address widths the part takes: 3-byte only (00), both (01) or 4-byte only (10).
+    // Parts taking both start in 3-byte mode, unless a non-volatile configuration bit says otherwise.
+    // Only DWORD 16 bit 21, one of the ways to exit 4-byte mode in bits 23:14, set if a power cycle leaves it, rules that out.
+    info.power_up_width = match dwords[0] >> 17 & 0b11 {
+        0b00 => Some(AddressWidth::ThreeByte),
+        0b01 if length >= 16 && dwords[15] & (1 << 21) > 0 => Some(AddressWidth::ThreeByte),
+        0b10 => {
+            info.four_byte_mode = FourByteMode::Always;
+            Some(AddressWidth::FourByte)
+        }
+        _ => None,
+    };
+
+    Ok(info)
+}
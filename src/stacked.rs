@@ -0,0 +1,135 @@
+//! Two chips presented as one linear address space.
+
+use crate::{Error, SPIFlash, Transmitter};
+
+/// Stacks two chips, e.g. two identical parts doubling the capacity of a design.
+///
+/// Addresses below the capacity of the first chip go to it, the ones above to the second chip.
+/// Operations crossing the boundary are split into one part per chip.
+pub struct StackedFlash<A, B>
+where
+    A: Transmitter,
+    B: Transmitter,
+{
+    first: SPIFlash<A>,
+    second: SPIFlash<B>,
+    /// Capacity of the first chip, which is where the second one starts.
+    boundary: u32,
+    capacity: u32,
+}
+
+impl<A, B> StackedFlash<A, B>
+where
+    A: Transmitter,
+    B: Transmitter,
+{
+    /// Stacks `second` above `first`. Both have to be identified already.
+    /// Returns `Error::OutOfBounds` if their combined capacity does not fit a `u32`.
+    pub fn new(first: SPIFlash<A>, second: SPIFlash<B>) -> Result<Self, Error> {
+        let boundary = first.capacity()?;
+        let capacity = boundary.checked_add(second.capacity()?).ok_or(Error::OutOfBounds)?;
+        Ok(Self {
+            first,
+            second,
+            boundary,
+            capacity,
+        })
+    }
+
+    /// Releases the two stacked chips.
+    pub fn release(self) -> (SPIFlash<A>, SPIFlash<B>) {
+        (self.first, self.second)
+    }
+
+    /// The combined capacity of both chips in bytes.
+    pub fn capacity(&self) -> u32 {
+        self.capacity
+    }
+
+    /// Checks that `len` bytes at `address` fit and returns how many of them lie on the first chip.
+    fn split(&self, address: u32, len: usize) -> Result<usize, Error> {
+        match address.checked_add(len as u32) {
+            Some(end) if end <= self.capacity => Ok((self.boundary.saturating_sub(address) as usize).min(len)),
+            _ => Err(Error::OutOfBounds),
+        }
+    }
+
+    /// Reads `buffer.len()` bytes at `address`.
+    /// Blocks until the read is done.
+    pub fn read_bytes(&mut self, address: u32, buffer: &mut [u8]) -> Result<(), Error> {
+        let n = self.split(address, buffer.len())?;
+        let (head, tail) = buffer.split_at_mut(n);
+        if !head.is_empty() {
+            self.first.read_bytes(address, head)?;
+        }
+        if !tail.is_empty() {
+            self.second.read_bytes(address + n as u32 - self.boundary, tail)?;
+        }
+        Ok(())
+    }
+
+    /// Writes `data` at `address`, which must be erased.
+    /// Blocks until the write is done.
+    pub fn write_bytes(&mut self, address: u32, data: &[u8]) -> Result<(), Error> {
+        let n = self.split(address, data.len())?;
+        let (head, tail) = data.split_at(n);
+        if !head.is_empty() {
+            self.first.write_bytes(address, head)?;
+        }
+        if !tail.is_empty() {
+            self.second.write_bytes(address + n as u32 - self.boundary, tail)?;
+        }
+        Ok(())
+    }
+
+    /// Erases all erase blocks overlapping the `len` bytes starting at `start`, on either chip (see `SPIFlash::erase_range()`).
+    /// Blocks until the erase is done.
+    pub fn erase_range(&mut self, start: u32, len: u32) -> Result<(), Error> {
+        let n = self.split(start, len as usize)? as u32;
+        if n > 0 {
+            self.first.erase_range(start, n)?;
+        }
+        if n < len {
+            self.second.erase_range(start + n - self.boundary, len - n)?;
+        }
+        Ok(())
+    }
+
+    /// Erases both chips.
+    /// Blocks until both erases are done.
+    pub fn chip_erase(&mut self) -> Result<(), Error> {
+        self.first.chip_erase()?;
+        self.second.chip_erase()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::RamFlash;
+    use crate::ChipInfo;
+
+    fn chip() -> SPIFlash<RamFlash> {
+        let mut flash = SPIFlash::new(RamFlash::new(8192));
+        flash.info = Some(ChipInfo::new(8192));
+        flash
+    }
+
+    #[test]
+    fn write_across_boundary() {
+        let mut stacked = StackedFlash::new(chip(), chip()).unwrap();
+        assert_eq!(stacked.capacity(), 16384);
+        stacked.write_bytes(8190, &[1, 2, 3, 4]).unwrap();
+
+        let mut buffer = [0; 4];
+        stacked.read_bytes(8190, &mut buffer).unwrap();
+        assert_eq!(buffer, [1, 2, 3, 4]);
+        assert_eq!(stacked.first.transmitter.memory[8190..], [1, 2]);
+        assert_eq!(stacked.second.transmitter.memory[..2], [3, 4]);
+
+        stacked.erase_range(8190, 4).unwrap();
+        stacked.read_bytes(8190, &mut buffer).unwrap();
+        assert_eq!(buffer, [0xFF; 4]);
+        assert_eq!(stacked.write_bytes(16383, &[1, 2]), Err(Error::OutOfBounds));
+    }
+}
@@ -0,0 +1,99 @@
+//! Verification of a large image in steps, for main loops which can not block for the whole read back.
+
+use crate::{Error, SPIFlash, Transmitter};
+
+/// Compares the flash against an expected image one chunk per call, see `verify_next()`.
+///
+/// This lets a cooperative scheduler or a main loop feeding a watchdog interleave a long verify with other work.
+/// The chunk size is the length of the buffer passed to `verify_next()`, so it bounds the time of every step.
+pub struct Verifier<'a> {
+    /// Address of the image.
+    address: u32,
+    expected: &'a [u8],
+    /// Number of bytes verified so far.
+    offset: usize,
+}
+
+impl<'a> Verifier<'a> {
+    /// Creates a verifier checking that the flash holds `expected` at `address`.
+    pub fn new(address: u32, expected: &'a [u8]) -> Self {
+        Self {
+            address,
+            expected,
+            offset: 0,
+        }
+    }
+
+    /// Number of bytes verified so far.
+    pub fn verified(&self) -> usize {
+        self.offset
+    }
+
+    /// Number of bytes left to verify.
+    pub fn remaining(&self) -> usize {
+        self.expected.len() - self.offset
+    }
+
+    /// Reads the next `chunk.len()` bytes into `chunk` and compares them against the image.
+    /// Returns `Ok(true)` once the whole image verified and `Ok(false)` if there is more to do.
+    /// Returns `nb::Error::WouldBlock` without reading anything if the chip is busy (see `ReadDuringBusy`),
+    /// and `Error::VerifyMismatch` with the first address that does not match.
+    /// Returns `Error::OutOfBounds` if `chunk` is empty while there is something left to verify.
+    pub fn verify_next<T: Transmitter>(&mut self, flash: &mut SPIFlash<T>, chunk: &mut [u8]) -> nb::Result<bool, Error> {
+        if self.remaining() == 0 {
+            return Ok(true);
+        }
+        if chunk.is_empty() {
+            return Err(nb::Error::Other(Error::OutOfBounds));
+        }
+        let n = chunk.len().min(self.remaining());
+        let address = self.address + self.offset as u32;
+        match flash.read_bytes(address, &mut chunk[..n]) {
+            Ok(()) => {}
+            Err(Error::Busy) => return Err(nb::Error::WouldBlock),
+            Err(e) => return Err(nb::Error::Other(e)),
+        }
+        let expected = &self.expected[self.offset..self.offset + n];
+        if let Some(i) = chunk[..n].iter().zip(expected).position(|(actual, expected)| actual != expected) {
+            return Err(nb::Error::Other(flash.diagnose_mismatch(Error::VerifyMismatch(address + i as u32))));
+        }
+        self.offset += n;
+        Ok(self.remaining() == 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::RamFlash;
+
+    #[test]
+    fn verifies_in_steps() {
+        let mut ram = RamFlash::new(4096);
+        for (i, byte) in ram.memory[100..300].iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        let mut flash = SPIFlash::new(ram);
+        let image: std::vec::Vec<u8> = (0..200).map(|i| i as u8).collect();
+
+        let mut chunk = [0; 64];
+        let mut verifier = Verifier::new(100, &image);
+        assert_eq!(verifier.verify_next(&mut flash, &mut chunk), Ok(false));
+        assert_eq!(verifier.verified(), 64);
+
+        // A busy chip is retried later.
+        flash.transmitter.operation_polls = 1;
+        flash.program_page_async(0, &[0xFF]).unwrap();
+        assert_eq!(verifier.verify_next(&mut flash, &mut chunk), Err(nb::Error::WouldBlock));
+        assert_eq!(nb::block!(verifier.verify_next(&mut flash, &mut chunk)), Ok(false));
+        assert_eq!(verifier.verify_next(&mut flash, &mut chunk), Ok(false));
+        assert_eq!(verifier.verify_next(&mut flash, &mut chunk), Ok(true));
+        assert_eq!(verifier.remaining(), 0);
+
+        flash.transmitter.memory[250] = 0;
+        let mut verifier = Verifier::new(100, &image);
+        assert_eq!(nb::block!(verifier.verify_next(&mut flash, &mut chunk)), Ok(false));
+        assert_eq!(nb::block!(verifier.verify_next(&mut flash, &mut chunk)), Ok(false));
+        assert_eq!(nb::block!(verifier.verify_next(&mut flash, &mut chunk)), Err(Error::VerifyMismatch(250)));
+    }
+}
@@ -0,0 +1,89 @@
+//! Erase counting as a building block for wear leveling.
+
+use crate::{Error, SPIFlash, Transmitter};
+
+/// Counts how often each sector of a region was erased.
+///
+/// The counts live in a caller-provided slice with one entry per sector, starting at sector 0 of the chip.
+/// They are only kept in RAM; persisting them (e.g. in a dedicated sector) is up to the caller.
+/// This does not move any data, it only provides the numbers needed to decide when to rotate.
+/// Only erases issued through the counter are counted.
+pub struct EraseCounter<'a> {
+    counts: &'a mut [u32],
+    sector_size: u32,
+}
+
+impl<'a> EraseCounter<'a> {
+    /// Creates a counter for `counts.len()` sectors of `sector_size` bytes.
+    /// Pass previously persisted counts to continue counting from them.
+    /// Returns `Error::OutOfBounds` if `sector_size` is zero.
+    pub fn new(counts: &'a mut [u32], sector_size: u32) -> Result<Self, Error> {
+        if sector_size == 0 {
+            return Err(Error::OutOfBounds);
+        }
+        Ok(Self {
+            counts,
+            sector_size,
+        })
+    }
+
+    /// How often `sector` was erased, or `None` if it is not covered by the counter.
+    pub fn erase_count(&self, sector: u32) -> Option<u32> {
+        self.counts.get(sector as usize).copied()
+    }
+
+    /// All counts, e.g. to persist them.
+    pub fn counts(&self) -> &[u32] {
+        self.counts
+    }
+
+    /// Records an erase of `len` bytes starting at `address`.
+    /// Use this for erases that were not issued through the counter.
+    /// Returns `Error::OutOfBounds` if the erase does not fit the address space.
+    pub fn record(&mut self, address: u32, len: u32) -> Result<(), Error> {
+        let end = address.checked_add(len).ok_or(Error::OutOfBounds)?;
+        let first = address / self.sector_size;
+        let last = end.div_ceil(self.sector_size);
+        for sector in first..last {
+            if let Some(count) = self.counts.get_mut(sector as usize) {
+                *count = count.saturating_add(1);
+            }
+        }
+        Ok(())
+    }
+
+    /// Erases the 4k block containing `address` and counts the erase.
+    pub fn erase_4k_block<T: Transmitter>(&mut self, flash: &mut SPIFlash<T>, address: u32) -> Result<(), Error> {
+        flash.erase_4k_block(address)?;
+        self.record(address & !0xFFF, 0x1000)
+    }
+
+    /// Erases the entire flash memory and counts an erase for every sector.
+    pub fn chip_erase<T: Transmitter>(&mut self, flash: &mut SPIFlash<T>) -> Result<(), Error> {
+        flash.chip_erase()?;
+        self.counts.iter_mut().for_each(|count| *count = count.saturating_add(1));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::RamFlash;
+
+    #[test]
+    fn counts_erases() {
+        let mut flash = SPIFlash::new(RamFlash::new(16 * 1024));
+        let mut counts = [0; 4];
+        let mut counter = EraseCounter::new(&mut counts, 4096).unwrap();
+        counter.erase_4k_block(&mut flash, 0x1234).unwrap();
+        counter.erase_4k_block(&mut flash, 0x1000).unwrap();
+        counter.chip_erase(&mut flash).unwrap();
+        assert_eq!(counter.counts(), [1, 3, 1, 1]);
+        assert_eq!(counter.erase_count(1), Some(3));
+        assert_eq!(counter.erase_count(4), None);
+        assert_eq!(counter.record(0xFFFF_F000, 0x2000), Err(Error::OutOfBounds));
+        assert_eq!(counter.counts(), [1, 3, 1, 1]);
+        assert_eq!(EraseCounter::new(&mut [0; 4], 0).err(), Some(Error::OutOfBounds));
+    }
+}
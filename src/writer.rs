@@ -0,0 +1,106 @@
+//! Staging of a whole sector in RAM, committed in a single erase and program.
+
+use crate::{Error, SPIFlash, Transmitter};
+
+/// Size of the sector staged by a `SectorWriter`.
+const SECTOR_SIZE: u32 = 4096;
+
+/// Builds the new contents of one 4K sector in a caller-provided buffer and commits them at once.
+///
+/// This keeps the window in which the sector is erased but not yet programmed as short as possible,
+/// which is what config updates want. Call `load()` first to modify the current contents
+/// instead of starting from an erased sector.
+pub struct SectorWriter<'a> {
+    /// Address of the sector.
+    base: u32,
+    buffer: &'a mut [u8],
+    /// Whether `commit()` reads the sector back.
+    verify: bool,
+}
+
+impl<'a> SectorWriter<'a> {
+    /// Creates a writer for the sector at `base`, staging it in `buffer`.
+    /// The staged contents start out erased.
+    /// Returns `Error::OutOfBounds` if `base` is not sector aligned or `buffer` is not exactly one sector long.
+    pub fn new(base: u32, buffer: &'a mut [u8]) -> Result<Self, Error> {
+        if base & (SECTOR_SIZE - 1) != 0 || buffer.len() != SECTOR_SIZE as usize {
+            return Err(Error::OutOfBounds);
+        }
+        buffer.iter_mut().for_each(|b| *b = 0xFF);
+        Ok(Self {
+            base,
+            buffer,
+            verify: false,
+        })
+    }
+
+    /// Sets whether `commit()` reads the sector back and checks it against the staged contents.
+    pub fn set_verify(&mut self, verify: bool) {
+        self.verify = verify;
+    }
+
+    /// Address of the sector.
+    pub fn base(&self) -> u32 {
+        self.base
+    }
+
+    /// The staged contents.
+    pub fn contents(&self) -> &[u8] {
+        self.buffer
+    }
+
+    /// Replaces the staged contents with the current contents of the sector.
+    pub fn load<T: Transmitter>(&mut self, flash: &mut SPIFlash<T>) -> Result<(), Error> {
+        flash.read_bytes(self.base, self.buffer)
+    }
+
+    /// Stages `data` at `offset` within the sector.
+    /// Returns `Error::OutOfBounds` if it does not fit into the sector.
+    pub fn write(&mut self, offset: u32, data: &[u8]) -> Result<(), Error> {
+        let start = offset as usize;
+        let end = start.checked_add(data.len()).ok_or(Error::OutOfBounds)?;
+        self.buffer.get_mut(start..end).ok_or(Error::OutOfBounds)?.copy_from_slice(data);
+        Ok(())
+    }
+
+    /// Erases the sector and programs the staged contents.
+    /// Returns `Error::VerifyMismatch` if verification is enabled and the sector does not read back correctly.
+    pub fn commit<T: Transmitter>(&self, flash: &mut SPIFlash<T>) -> Result<(), Error> {
+        flash.erase_4k_block(self.base)?;
+        // The erased tail does not need programming.
+        let blank = flash.blank_byte();
+        let used = self.buffer.iter().rposition(|&b| b != blank).map_or(0, |last| last + 1);
+        flash.write_bytes(self.base, &self.buffer[..used])?;
+        if self.verify {
+            let base = self.base;
+            flash.verify_pattern(base, SECTOR_SIZE, |a| self.buffer[(a - base) as usize])?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::RamFlash;
+
+    #[test]
+    fn commits_staged_sector() {
+        let mut ram = RamFlash::new(8192);
+        ram.memory[0x1000..0x1004].copy_from_slice(&[1, 2, 3, 4]);
+        ram.memory[0x1FFF] = 0;
+        let mut flash = SPIFlash::new(ram);
+
+        let mut buffer = [0; 4096];
+        let mut writer = SectorWriter::new(0x1000, &mut buffer).unwrap();
+        writer.set_verify(true);
+        writer.load(&mut flash).unwrap();
+        writer.write(2, &[5, 6]).unwrap();
+        assert_eq!(writer.write(4095, &[0, 0]), Err(Error::OutOfBounds));
+        writer.commit(&mut flash).unwrap();
+        assert_eq!(flash.transmitter.memory[0x1000..0x1004], [1, 2, 5, 6]);
+        assert_eq!(flash.transmitter.memory[0x1FFF], 0);
+
+        assert!(SectorWriter::new(0x1001, &mut [0; 4096]).is_err());
+    }
+}